@@ -1,4 +1,48 @@
+use std::{env, fs, io::Write, path::Path};
+
+use flate2::{write::GzEncoder, Compression};
+
+/// The dashboard in `web/`, with their content types, see `src/dashboard.rs`
+const WEB_ASSETS: [(&str, &str); 3] = [
+    ("index.html", "text/html; charset=utf-8"),
+    ("app.js", "text/javascript; charset=utf-8"),
+    ("style.css", "text/css; charset=utf-8"),
+];
+
 fn main() {
     dotenv_build::output(dotenv_build::Config::default()).unwrap();
     embuild::espidf::sysenv::output();
+    compress_web_assets();
+}
+
+/// Gzips the dashboard into `OUT_DIR`, and writes a table of it for
+/// `src/dashboard.rs` to include
+fn compress_web_assets() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut table = String::from("[\n");
+    for (name, content_type) in WEB_ASSETS {
+        let path = Path::new("web").join(name);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let source = fs::read(&path).unwrap_or_else(|e| panic!("Can't read {path:?}: {e}"));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&source).unwrap();
+        let gzip_path = Path::new(&out_dir).join(format!("{name}.gz"));
+        fs::write(&gzip_path, encoder.finish().unwrap()).unwrap();
+
+        // Changes with the content, so browsers only download it again when it did
+        let etag = format!("\\\"{:016x}\\\"", fnv1a(&source));
+        table += &format!(
+            "    WebAsset {{ path: \"/{name}\", content_type: \"{content_type}\", \
+             etag: \"{etag}\", gzip: include_bytes!({gzip_path:?}) }},\n"
+        );
+    }
+    table += "]\n";
+    fs::write(Path::new(&out_dir).join("web_assets.rs"), table).unwrap();
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
 }
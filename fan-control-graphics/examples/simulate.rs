@@ -1,4 +1,4 @@
-use std::sync::{atomic::AtomicU32, Arc};
+use std::sync::Arc;
 
 use embedded_graphics::{
     draw_target::DrawTarget,
@@ -6,9 +6,18 @@ use embedded_graphics::{
     prelude::{RgbColor, Size},
 };
 use embedded_graphics_simulator::{
-    OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+    sdl2::Keycode, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+};
+use fan_control_graphics::{menu::MenuInput, FanState, Interface, InterfaceState};
+use fan_control_logic::{
+    boost::BoostConfig,
+    control::ControlSource,
+    mode::{AtomicControlMode, ControlMode},
+    pid::{Pid, PidConfig},
+    simulation::FanModel,
+    slew::{SlewConfig, SlewLimiter},
+    zero_rpm::ZeroRpm,
 };
-use fan_control_graphics::{Interface, InterfaceControlSource, InterfaceState};
 
 fn main() {
     let mut display = SimulatorDisplay::<Rgb565>::new(Size::new(240, 240));
@@ -17,12 +26,23 @@ fn main() {
     display.clear(Rgb565::BLACK).unwrap();
     window.update(&mut display);
 
+    // Same variable as the firmware uses, e.g. `FAN_COUNT=4 ./simulate-gui.sh`
+    let fan_count = std::env::var("FAN_COUNT")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1);
     let state = Arc::new(InterfaceState {
-        fan_rpm: AtomicU32::new(0),
-        fan_pwm: AtomicU32::new(0),
-        changed_via: InterfaceControlSource::RotaryEncoder,
+        fans: (0..fan_count)
+            .map(|_| FanState {
+                control_mode: AtomicControlMode::new(ControlMode::TargetRpm),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
     });
-    update_state(&state, 0, 0);
+    state.take_control(ControlSource::RotaryEncoder, 0).unwrap();
+    let mut simulation = Simulation::new(fan_count);
+    update_state(&state, &mut simulation, 0, 0);
     let start = std::time::Instant::now();
     let mut last_iteration = std::time::Instant::now();
 
@@ -34,19 +54,103 @@ fn main() {
         let clock_ms = start.elapsed().as_millis() as u32;
         let delta_ms = last_iteration.elapsed().as_millis() as u32;
         last_iteration = std::time::Instant::now();
-        update_state(&state, clock_ms, delta_ms);
+        update_state(&state, &mut simulation, clock_ms, delta_ms);
 
         interface.render(&mut display, clock_ms).unwrap();
         window.update(&mut display);
 
-        if window.events().any(|e| e == SimulatorEvent::Quit) {
-            return;
+        for event in window.events() {
+            let input = match event {
+                SimulatorEvent::Quit => return,
+                // Stands in for a long press of the knob
+                SimulatorEvent::KeyDown {
+                    keycode: Keycode::Space,
+                    ..
+                } => {
+                    let mut menu = state.menu.lock().unwrap();
+                    if menu.is_open() {
+                        menu.close();
+                    } else {
+                        menu.open();
+                    }
+                    continue;
+                }
+                // Same as the boost in the menu, but a minute long
+                SimulatorEvent::KeyDown {
+                    keycode: Keycode::B,
+                    ..
+                } => {
+                    let config = BoostConfig {
+                        duration_ms: 60_000,
+                        ..Default::default()
+                    };
+                    let mut boost = state.boost.lock().unwrap();
+                    boost.start(config, clock_ms as u64).unwrap();
+                    continue;
+                }
+                // Stands in for a double click of the knob
+                SimulatorEvent::KeyDown {
+                    keycode: Keycode::P,
+                    ..
+                } => {
+                    let profile = state
+                        .profiles
+                        .lock()
+                        .unwrap()
+                        .cycle(1)
+                        .map(|profile| profile.name.clone());
+                    if let Some(profile) = profile {
+                        let now_ms = clock_ms as u64;
+                        let _ = state.apply_profile(&profile, ControlSource::RotaryEncoder, now_ms);
+                    }
+                    continue;
+                }
+                SimulatorEvent::KeyDown { keycode, .. } => match keycode {
+                    Keycode::Up => MenuInput::Rotate(-1),
+                    Keycode::Down => MenuInput::Rotate(1),
+                    Keycode::Return => MenuInput::Click,
+                    Keycode::Escape | Keycode::Backspace => MenuInput::Back,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            state
+                .menu
+                .lock()
+                .unwrap()
+                .input(&state, input, clock_ms as u64);
         }
         let delay = 10u64.saturating_sub(delta_ms as u64).max(1);
         std::thread::sleep(std::time::Duration::from_millis(delay));
     }
 }
-fn update_state(state: &Arc<InterfaceState>, clock_ms: u32, delta_ms: u32) {
+struct Simulation {
+    fans: Vec<(FanModel, Pid, SlewLimiter, ZeroRpm)>,
+}
+
+impl Simulation {
+    fn new(fan_count: usize) -> Self {
+        Self {
+            fans: (0..fan_count)
+                .map(|_| {
+                    (
+                        FanModel::default(),
+                        Pid::new(PidConfig::default()),
+                        SlewLimiter::new(SlewConfig::default()),
+                        ZeroRpm::new(Default::default()),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+fn update_state(
+    state: &Arc<InterfaceState>,
+    simulation: &mut Simulation,
+    clock_ms: u32,
+    delta_ms: u32,
+) {
     use std::sync::atomic::Ordering;
     let clock_s = clock_ms as f32 / 1000.0;
     let delta_s = delta_ms as f32 / 1000.0;
@@ -57,41 +161,62 @@ fn update_state(state: &Arc<InterfaceState>, clock_ms: u32, delta_ms: u32) {
     // Time between target changes (seconds)
     const TARGET_CHANGE_INTERVAL: f32 = 6.0;
 
-    // Update target RPM occasionally
-    let preset_index = ((clock_s / TARGET_CHANGE_INTERVAL) as usize) % SPEED_PRESETS.len();
-    let target_rpm = SPEED_PRESETS[preset_index];
-
-    // Get current RPM
-    let current_rpm = state.fan_rpm.load(Ordering::Relaxed) as f32;
-    let target_rpm = target_rpm as f32;
-
-    // Calculate base PWM for target RPM (assume linear relationship)
-    // Maximum RPM (2400) should correspond to PWM 100%
-    const MAX_RPM: f32 = 2000.0;
-    let target_pwm = (target_rpm / MAX_RPM * 100.0).clamp(0.0, 100.0);
-
-    // Get current PWM
-    let current_pwm = state.fan_pwm.load(Ordering::Relaxed) as f32;
-
-    // Very gentle PWM adjustment (no overshooting)
-    let pwm_diff = target_pwm - current_pwm;
-    let new_pwm = (current_pwm + pwm_diff * delta_s * 2.0).clamp(0.0, 100.0);
-    state
-        .fan_pwm
-        .store(new_pwm.round() as u32, Ordering::Relaxed);
-
-    // Simulate fan physics
-    // We want to cover the full RPM range (2400) in 2 seconds
-    // So rate should be 1200 RPM/second or 0.1 RPM/ms
-    const RPM_PER_MS: f32 = 0.6; // 600 RPM per second = full range in 2 seconds
-
-    let rpm_error = target_rpm - current_rpm;
-    let max_rpm_change = RPM_PER_MS * delta_ms as f32;
-    let rpm_change = rpm_error.clamp(-max_rpm_change, max_rpm_change);
-
-    // Add very subtle random variation (±2 RPM maximum)
-    let jitter = (clock_s * 2.0).sin() * 1.0;
-
-    let new_rpm = (current_rpm + rpm_change + jitter).round() as u32;
-    state.fan_rpm.store(new_rpm, Ordering::Relaxed);
+    for (i, (fan_state, (fan, pid, slew, zero_rpm))) in
+        state.fans.iter().zip(&mut simulation.fans).enumerate()
+    {
+        // Update target RPM occasionally, every fan a bit out of step with the others
+        let preset_index = ((clock_s / TARGET_CHANGE_INTERVAL) as usize + i) % SPEED_PRESETS.len();
+        let target_rpm = SPEED_PRESETS[preset_index];
+        fan_state.target_rpm.store(target_rpm, Ordering::Relaxed);
+
+        // Slowly clog up the fan with dust, the controller should compensate for it
+        fan.efficiency = 1.0 - 0.2 * (clock_s / 60.0).min(1.0);
+
+        // Let the controller decide the PWM, like the firmware does in target RPM mode.
+        // Manual mode is left alone, so the duty can be changed in the menu.
+        let pwm = if fan_state.control_mode.load(Ordering::Relaxed) == ControlMode::Manual {
+            fan_state.pwm.load(Ordering::Relaxed) as f32
+        } else {
+            let current_rpm = fan_state.rpm.load(Ordering::Relaxed) as f32;
+            let pwm = pid.update(target_rpm as f32, current_rpm, delta_s);
+            fan_state.pwm.store(pwm.round() as u32, Ordering::Relaxed);
+            pwm
+        };
+        // A boost overrides the duty for a while, like in the firmware
+        let boost = state
+            .boost
+            .lock()
+            .unwrap()
+            .active(i, clock_ms as u64)
+            .map(|boost| boost.duty as f32);
+        let pwm = boost.unwrap_or(pwm);
+        let max_duty = fan_state.max_duty.load(Ordering::Relaxed) as f32;
+        let applied_pwm = slew.update(pwm.min(max_duty), delta_s);
+        if boost.is_none() && applied_pwm != pwm {
+            pid.reset_to(applied_pwm);
+        }
+        // Zero-RPM mode can be switched on in the menu, then stops the fan at low duty
+        zero_rpm.set_config(*fan_state.zero_rpm.lock().unwrap());
+        let applied_pwm = if boost.is_some() {
+            zero_rpm.keep_running(clock_ms as u64);
+            applied_pwm
+        } else {
+            zero_rpm.update(applied_pwm.round() as u32, clock_ms as u64) as f32
+        };
+        fan_state
+            .zero_rpm_stopped
+            .store(zero_rpm.is_stopped(), Ordering::Relaxed);
+        fan_state
+            .applied_pwm
+            .store(applied_pwm.round() as u32, Ordering::Relaxed);
+
+        // Simulate fan physics
+        let rpm = fan.step(applied_pwm, delta_s);
+
+        // Add very subtle random variation (±2 RPM maximum)
+        let jitter = (clock_s * 2.0).sin() * 1.0;
+
+        let new_rpm = (rpm + jitter).max(0.0).round() as u32;
+        fan_state.rpm.store(new_rpm, Ordering::Relaxed);
+    }
 }
@@ -1,6 +1,9 @@
 use std::{
-    sync::{atomic::AtomicU32, Arc},
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
 
 use animations::LeekSpin;
@@ -13,40 +16,236 @@ use embedded_graphics::{
     text::Text,
     Drawable,
 };
+use fan_control_logic::{
+    boost::BoostStack,
+    calibration::FanCalibration,
+    control::{ControlArbiter, ControlError, ControlSource},
+    curve::CurveConfig,
+    fault::{Alarm, AtomicAlarms},
+    knob::KnobConfig,
+    metrics::RenderTimings,
+    mode::{AtomicControlMode, ControlMode},
+    mqtt::MqttConfig,
+    profile::{ProfileError, ProfileMode, Profiles},
+    schedule::{ScheduleConfig, ScheduleState},
+    settings::{BootDuty, DisplayOptions},
+    slew::SlewConfig,
+    spin_up::SpinUpConfig,
+    supervisor::{TaskReport, TaskStatus},
+    tacho::DEFAULT_PULSES_PER_REV,
+    telemetry::TelemetryConfig,
+    temperature::{AtomicCelsius, SensorReadings},
+    zero_rpm::ZeroRpmConfig,
+};
+use menu::{Menu, MenuView};
 use profont::{PROFONT_14_POINT, PROFONT_24_POINT};
 
 pub mod animations;
 pub mod color;
+pub mod menu;
 pub mod rley;
 
 #[derive(Debug, Default)]
 pub struct InterfaceState {
-    pub fan_rpm: AtomicU32,
-    pub fan_pwm: AtomicU32,
-    pub changed_via: InterfaceControlSource,
+    pub fans: Vec<FanState>,
+    /// Temperature the fan curves follow, the hottest of all sensors
+    pub temperature: AtomicCelsius,
+    pub sensors: SensorReadings,
+    /// Who changed the fans last, and who may change them next
+    pub control: Mutex<ControlArbiter>,
+    pub profiles: Mutex<Profiles>,
+    /// Timed duty overrides, on the same clock as [`InterfaceState::take_control`]
+    pub boost: Mutex<BoostStack>,
+    pub schedule: Mutex<ScheduleConfig>,
+    /// What the schedule wants right now, kept up to date by the schedule thread
+    pub scheduled: Mutex<ScheduleState>,
+    /// The wall clock has been set over NTP, the schedule does nothing until then
+    pub clock_synced: AtomicBool,
+    /// How the rotary knob changes the duty
+    pub knob: Mutex<KnobConfig>,
+    pub display: Mutex<DisplayOptions>,
+    /// Settings menu on the screen, driven by the knob
+    pub menu: Mutex<Menu>,
+    pub wifi: Mutex<WifiInfo>,
+    /// How often dashboards get live telemetry
+    pub telemetry: Mutex<TelemetryConfig>,
+    /// Broker and topics Home Assistant finds the fans under
+    pub mqtt: Mutex<MqttConfig>,
+    /// Connected to the MQTT broker right now
+    pub mqtt_connected: AtomicBool,
+    /// Duty the fans start at after a reboot
+    pub boot_duty: Mutex<BootDuty>,
+    /// Health of the supervised fan control tasks
+    pub tasks: Mutex<Vec<TaskReport>>,
+    /// How long the screen takes to draw, for `/metrics`
+    pub render_timings: Mutex<RenderTimings>,
 }
 
 impl InterfaceState {
-    pub fn with_initial_pwm(pwm: u32) -> Self {
+    pub fn with_initial_pwm(fan_count: usize, pwm: u32) -> Self {
         Self {
-            fan_pwm: AtomicU32::new(pwm),
+            fans: (0..fan_count)
+                .map(|_| FanState::with_initial_pwm(pwm))
+                .collect(),
             ..Default::default()
         }
     }
+
+    /// Every change to the fans' duty, mode or target asks here first. `now_ms`
+    /// is the time since boot.
+    pub fn take_control(&self, source: ControlSource, now_ms: u64) -> Result<(), ControlError> {
+        self.control.lock().unwrap().request(source, now_ms)?;
+        // Whatever changes now, the fans aren't running the profile anymore
+        self.profiles.lock().unwrap().active_profile = None;
+        Ok(())
+    }
+
+    /// Sets every fan to the profile called `name`
+    pub fn apply_profile(
+        &self,
+        name: &str,
+        source: ControlSource,
+        now_ms: u64,
+    ) -> Result<(), ProfileError> {
+        let profile = self
+            .profiles
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ProfileError::Unknown(name.to_string()))?;
+        self.take_control(source, now_ms)
+            .map_err(ProfileError::Control)?;
+        for fan in &self.fans {
+            let mode = match &profile.mode {
+                ProfileMode::Manual { duty } => {
+                    fan.pwm.store(*duty, Ordering::Relaxed);
+                    ControlMode::Manual
+                }
+                ProfileMode::TargetRpm { rpm } => {
+                    fan.target_rpm.store(*rpm, Ordering::Relaxed);
+                    ControlMode::TargetRpm
+                }
+                ProfileMode::Curve { curve } => {
+                    *fan.curve.lock().unwrap() = curve.clone();
+                    ControlMode::Curve
+                }
+            };
+            fan.control_mode.store(mode, Ordering::Relaxed);
+        }
+        self.profiles.lock().unwrap().active_profile = Some(profile.name);
+        Ok(())
+    }
+
+    /// Store a new reading (or failed read) from a temperature sensor
+    pub fn publish_temperature(&self, sensor: &str, celsius: Option<f32>) {
+        self.sensors.publish(sensor, celsius);
+        self.temperature
+            .store(self.sensors.hottest(), Ordering::Relaxed);
+    }
+}
+
+/// Connection details for the menu, updated by the WiFi thread
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WifiInfo {
+    pub ssid: String,
+    /// `None` while not connected
+    pub ip: Option<String>,
+    pub rssi: Option<i8>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub enum InterfaceControlSource {
-    #[default]
-    None,
-    Wifi,
-    RotaryEncoder,
+/// State of one PWM/tacho channel
+#[derive(Debug)]
+pub struct FanState {
+    /// Filtered, what everything else goes by
+    pub rpm: AtomicU32,
+    /// Straight from the tacho, before filtering
+    pub raw_rpm: AtomicU32,
+    /// Tacho readings the filter threw away as implausible
+    pub rejected_rpm_readings: AtomicU32,
+    /// Depends on the fan, see its datasheet
+    pub pulses_per_rev: AtomicU32,
+    /// Requested duty cycle
+    pub pwm: AtomicU32,
+    /// Duty cycle the fan actually gets after slew limiting, spin-up kick and
+    /// minimum duty
+    pub applied_pwm: AtomicU32,
+    /// How fast `applied_pwm` follows `pwm`
+    pub slew: Mutex<SlewConfig>,
+    pub control_mode: AtomicControlMode,
+    /// Only used in [`ControlMode::TargetRpm`]
+    pub target_rpm: AtomicU32,
+    /// Used in [`ControlMode::Curve`]
+    pub curve: Mutex<CurveConfig>,
+    /// Set by the tacho thread when the fan doesn't do what it's told
+    pub alarms: AtomicAlarms,
+    /// Result of the last [`ControlMode::Calibration`] sweep
+    pub calibration: Mutex<Option<FanCalibration>>,
+    /// Upper limit of `pwm`, except for calibration and fail-safe
+    pub max_duty: AtomicU32,
+    /// Kick and minimum duty applied on top of `pwm`, a calibration sweep updates
+    /// the thresholds
+    pub spin_up: Mutex<SpinUpConfig>,
+    /// Set by the supervisor while a task controlling this fan isn't running,
+    /// the fan runs at full speed
+    pub fail_safe: AtomicBool,
+    /// Stopping the fan at low duty
+    pub zero_rpm: Mutex<ZeroRpmConfig>,
+    /// Stopped by zero-RPM mode, rather than because anything is wrong
+    pub zero_rpm_stopped: AtomicBool,
 }
 
+impl Default for FanState {
+    fn default() -> Self {
+        Self {
+            rpm: Default::default(),
+            raw_rpm: Default::default(),
+            rejected_rpm_readings: Default::default(),
+            pulses_per_rev: AtomicU32::new(DEFAULT_PULSES_PER_REV),
+            pwm: Default::default(),
+            applied_pwm: Default::default(),
+            slew: Default::default(),
+            control_mode: Default::default(),
+            target_rpm: Default::default(),
+            curve: Default::default(),
+            alarms: Default::default(),
+            calibration: Default::default(),
+            max_duty: AtomicU32::new(100),
+            spin_up: Default::default(),
+            fail_safe: Default::default(),
+            zero_rpm: Default::default(),
+            zero_rpm_stopped: Default::default(),
+        }
+    }
+}
+
+impl FanState {
+    pub fn with_initial_pwm(pwm: u32) -> Self {
+        Self {
+            pwm: AtomicU32::new(pwm),
+            applied_pwm: AtomicU32::new(pwm),
+            ..Default::default()
+        }
+    }
+}
+
+const ALARM_BANNER_Y: i32 = 30;
+const ALARM_BANNER_HEIGHT: i32 = 22;
+
 pub struct Interface {
     state: Arc<InterfaceState>,
     animation: LeekSpin,
-    boot_time: SystemTime,
+    boot_time: Instant,
+    /// Last menu drawn, `None` while the status screen is shown
+    menu_view: Option<MenuView>,
+    /// Draw everything on the next render, not just what changes
+    full_redraw: bool,
+    /// Active profile when the bottom bar was last drawn
+    last_profile: Option<String>,
+    /// Time since boot in ms, see [`Interface::with_clock`]
+    clock: Option<fn() -> u64>,
+    /// The banner was drawn last time, and has to be drawn over once it's gone
+    banner_shown: bool,
 }
 
 impl Interface {
@@ -54,68 +253,334 @@ impl Interface {
         Self {
             state,
             animation: LeekSpin::new(),
-            boot_time: SystemTime::now(),
+            boot_time: Instant::now(),
+            menu_view: None,
+            full_redraw: false,
+            last_profile: None,
+            clock: None,
+            banner_shown: false,
         }
     }
 
+    /// Clock that boosts and the uptime are shown with, the time since the
+    /// interface was created if not set
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    fn now_ms(&self) -> u64 {
+        match self.clock {
+            Some(clock) => clock(),
+            None => self.boot_time.elapsed().as_millis() as u64,
+        }
+    }
+
+    pub fn display_options(&self) -> DisplayOptions {
+        *self.state.display.lock().unwrap()
+    }
+
+    /// Draws the whole screen again on the next render, e.g. after it was cleared
+    pub fn redraw(&mut self) {
+        self.full_redraw = true;
+        self.menu_view = None;
+    }
+
     pub fn render<D>(&mut self, target: &mut D, clock_ms: u32) -> Result<(), D::Error>
     where
         D: DrawTarget<Color = Rgb565>,
     {
-        let (y_min, y_max) = if clock_ms == 0 { (0, 240) } else { (30, 210) };
+        let menu_view = self.state.menu.lock().unwrap().view(&self.state);
+        if let Some(menu_view) = menu_view {
+            if self.menu_view.as_ref() != Some(&menu_view) {
+                menu::render(&menu_view, target)?;
+                self.menu_view = Some(menu_view);
+            }
+            return Ok(());
+        }
+        let banner = self.banner();
+        let banner_gone =
+            std::mem::replace(&mut self.banner_shown, banner.is_some()) && banner.is_none();
+        // The menu was drawn over everything, and the animation doesn't always run to
+        // draw over a banner that's gone
+        let full_redraw = clock_ms == 0
+            || std::mem::take(&mut self.full_redraw)
+            || self.menu_view.take().is_some()
+            || banner_gone;
+        if full_redraw {
+            // Draws a frame straight away
+            self.animation = LeekSpin::new();
+        }
+
+        // With more than one fan, the bottom bar grows to fit a row of duty cycles
+        let multi_fan = self.state.fans.len() > 1;
+        let bottom_bar_y = if multi_fan { 190 } else { 210 };
+
+        // Keep the animation from drawing over the banner
+        let animation_y_min = if banner.is_some() {
+            ALARM_BANNER_Y + ALARM_BANNER_HEIGHT
+        } else {
+            30
+        };
+
+        let (y_min, y_max) = if full_redraw {
+            (0, 240)
+        } else {
+            (animation_y_min as u32, bottom_bar_y as u32)
+        };
         let rpm = self
             .state
-            .fan_rpm
-            .load(std::sync::atomic::Ordering::Relaxed);
-        self.animation
-            .render(target, clock_ms, (y_min, y_max), rpm)?;
+            .fans
+            .iter()
+            .map(|fan| fan.rpm.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0);
+        // Without the animation, the first frame stays up as a still image
+        if full_redraw || self.display_options().animation {
+            self.animation
+                .render(target, clock_ms, (y_min, y_max), rpm)?;
+        }
 
         let top_bg = rgb888_to_rgb565(255u8, 182u8, 140u8);
-        {
-            let rpm_label = format!(
-                "{: >4} RPM",
-                self.state
-                    .fan_rpm
-                    .load(std::sync::atomic::Ordering::Relaxed)
-            );
+        if multi_fan {
+            self.render_fan_columns(target, top_bg)?;
+        } else if let Some(fan) = self.state.fans.first() {
+            let rpm_label = format!("{: >4} RPM", fan.rpm.load(Ordering::Relaxed));
             let mut text_style = MonoTextStyle::new(&PROFONT_24_POINT, Rgb565::BLACK);
 
             text_style.background_color = Some(top_bg);
             Text::new(&rpm_label, Point::new(8, 24 + 2), text_style).draw(target)?;
+
+            let mut text_style = MonoTextStyle::new(&PROFONT_14_POINT, Rgb565::BLACK);
+            text_style.background_color = Some(top_bg);
+            let target_label = match fan.control_mode.load(Ordering::Relaxed) {
+                ControlMode::TargetRpm => {
+                    format!("T:{: >4}", fan.target_rpm.load(Ordering::Relaxed))
+                }
+                ControlMode::Curve => self.temperature_label(),
+                ControlMode::Calibration => " CAL  ".to_string(),
+                ControlMode::Manual => " ".repeat(6),
+            };
+            Text::new(&target_label, Point::new(170, 22), text_style).draw(target)?;
+        }
+
+        if let Some((label, background)) = banner {
+            Rectangle::new(
+                Point::new(0, ALARM_BANNER_Y),
+                Size::new(240, ALARM_BANNER_HEIGHT as u32),
+            )
+            .into_styled(PrimitiveStyle::with_fill(background))
+            .draw(target)?;
+
+            let text_style = MonoTextStyle::new(&PROFONT_14_POINT, Rgb565::WHITE);
+            let width = label.len() as i32 * PROFONT_14_POINT.character_size.width as i32;
+            Text::new(
+                &label,
+                Point::new((240 - width) / 2, ALARM_BANNER_Y + 16),
+                text_style,
+            )
+            .draw(target)?;
         }
 
         {
-            if clock_ms == 0 {
-                Rectangle::new(Point::new(0, 210), Size::new(240, 30))
-                    .into_styled(PrimitiveStyle::with_fill(top_bg))
-                    .draw(target)?;
+            if full_redraw {
+                Rectangle::new(
+                    Point::new(0, bottom_bar_y),
+                    Size::new(240, 240 - bottom_bar_y as u32),
+                )
+                .into_styled(PrimitiveStyle::with_fill(top_bg))
+                .draw(target)?;
             }
             let mut text_style = MonoTextStyle::new(&PROFONT_14_POINT, Rgb565::BLACK);
             text_style.background_color = Some(top_bg);
 
-            let source = match self.state.changed_via {
-                InterfaceControlSource::None => "Boot",
-                InterfaceControlSource::Wifi => "Wifi",
-                InterfaceControlSource::RotaryEncoder => "Rotary",
+            let source = match self.state.control.lock().unwrap().last_change().source {
+                ControlSource::Boot => "Boot",
+                ControlSource::Wifi => "Wifi",
+                ControlSource::RotaryEncoder => "Knob",
+                ControlSource::Automation => "Auto",
             };
-            let source_label = format!("S: {source: <6}");
-            Text::new(&source_label, Point::new(10, 228), text_style).draw(target)?;
+            let profile = self.state.profiles.lock().unwrap().active_profile.clone();
+            if profile != self.last_profile {
+                // The labels below have different lengths with and without a profile
+                Rectangle::new(
+                    Point::new(0, bottom_bar_y),
+                    Size::new(160, 240 - bottom_bar_y as u32),
+                )
+                .into_styled(PrimitiveStyle::with_fill(top_bg))
+                .draw(target)?;
+                self.last_profile = profile.clone();
+            }
+            match &profile {
+                Some(profile) => {
+                    // Takes the place of the uptime
+                    let source_label = format!("S:{source} {profile}");
+                    Text::new(&source_label, Point::new(10, 228), text_style).draw(target)?;
+                }
+                None => {
+                    let source_label = format!("S: {source: <4}");
+                    Text::new(&source_label, Point::new(10, 228), text_style).draw(target)?;
 
-            let uptime = self.boot_time.elapsed().unwrap().as_secs();
-            let uptime_label = format_uptime_secs(uptime);
-            Text::new(&uptime_label, Point::new(114, 228), text_style).draw(target)?;
+                    let uptime = self.now_ms() / 1000;
+                    let uptime_label = format_uptime_secs(uptime);
+                    Text::new(&uptime_label, Point::new(114, 228), text_style).draw(target)?;
+                }
+            }
 
-            let pwm = self
-                .state
-                .fan_pwm
-                .load(std::sync::atomic::Ordering::Relaxed);
-            let pwm = pwm - (pwm % 5);
-            let pwm_label = format!("PWM:{pwm: >3}");
-            Text::new(&pwm_label, Point::new(160, 228), text_style).draw(target)?;
+            if multi_fan {
+                // Duty cycles are shown per fan above, use the space for the temperature
+                let temperature_label = self.temperature_label();
+                Text::new(&temperature_label, Point::new(170, 228), text_style).draw(target)?;
+            } else if let Some(fan) = self.state.fans.first() {
+                let (pwm, applied_pwm) = rounded_duties(fan);
+                let pwm_label = if pwm == applied_pwm {
+                    format!("PWM:{pwm: >3}")
+                } else {
+                    format!("{applied_pwm: >3}>{pwm: <3}")
+                };
+                Text::new(&pwm_label, Point::new(160, 228), text_style).draw(target)?;
+            }
         }
 
         Ok(())
     }
+
+    /// One column per fan: RPM in the top bar, duty cycle in the bottom bar
+    fn render_fan_columns<D>(&self, target: &mut D, background: Rgb565) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let mut text_style = MonoTextStyle::new(&PROFONT_14_POINT, Rgb565::BLACK);
+        text_style.background_color = Some(background);
+
+        let column_width = 240 / self.state.fans.len() as i32;
+        let char_width = PROFONT_14_POINT.character_size.width as i32;
+        for (i, fan) in self.state.fans.iter().enumerate() {
+            let rpm = fan.rpm.load(Ordering::Relaxed);
+            let (pwm, applied_pwm) = rounded_duties(fan);
+            // While ramping, show where the duty is and where it's going
+            let duty = if pwm == applied_pwm {
+                format!("{pwm: >3}%")
+            } else {
+                format!("{applied_pwm}>{pwm}")
+            };
+            let (rpm_label, pwm_label) = if self.state.fans.len() == 2 {
+                (format!("{rpm: >4} RPM"), format!("{}:{duty}", i + 1))
+            } else {
+                (format!("{rpm: >4}"), duty)
+            };
+
+            let x = column_width * i as i32;
+            let rpm_width = rpm_label.len() as i32 * char_width;
+            let pwm_width = pwm_label.len() as i32 * char_width;
+            Text::new(
+                &rpm_label,
+                Point::new(x + (column_width - rpm_width) / 2, 22),
+                text_style,
+            )
+            .draw(target)?;
+            Text::new(
+                &pwm_label,
+                Point::new(x + (column_width - pwm_width) / 2, 206),
+                text_style,
+            )
+            .draw(target)?;
+        }
+        Ok(())
+    }
+
+    /// Text and colour of the banner below the RPM, alarms take precedence over a
+    /// boost countdown, which takes precedence over a fan stopped by zero-RPM mode
+    fn banner(&self) -> Option<(String, Rgb565)> {
+        if let Some(alarm_label) = self.alarm_label() {
+            return Some((alarm_label, Rgb565::RED));
+        }
+        let now_ms = self.now_ms();
+        let boost = self.state.boost.lock().unwrap().newest(now_ms).copied();
+        if let Some(boost) = boost {
+            let countdown = format_countdown_secs(boost.remaining_ms(now_ms).div_ceil(1000));
+            let label = match boost.fan {
+                Some(fan) if self.state.fans.len() > 1 => {
+                    format!("FAN {} BOOST {}% {countdown}", fan + 1, boost.duty)
+                }
+                _ => format!("BOOST {}% {countdown}", boost.duty),
+            };
+            return Some((label, rgb888_to_rgb565(40u8, 90u8, 200u8)));
+        }
+
+        // Not an alarm, the fan is meant to stand still
+        let stopped = self
+            .state
+            .fans
+            .iter()
+            .position(|fan| fan.zero_rpm_stopped.load(Ordering::Relaxed))?;
+        let label = if self.state.fans.len() > 1 {
+            // "FAN n: " wouldn't fit next to it
+            format!("{}: Stopped (zero-RPM)", stopped + 1)
+        } else {
+            "Stopped (zero-RPM)".to_string()
+        };
+        Some((label, rgb888_to_rgb565(40u8, 130u8, 80u8)))
+    }
+
+    /// What to show in the alarm banner, `None` if all fans are fine
+    fn alarm_label(&self) -> Option<String> {
+        // A task that doesn't run is worse than anything it could report
+        let task_label = self.state.tasks.lock().unwrap().iter().find_map(|task| {
+            let description = match task.status {
+                TaskStatus::Running => return None,
+                TaskStatus::Restarting => "RESTARTING",
+                TaskStatus::Failed => "FAILED",
+                TaskStatus::Hung => "HUNG",
+            };
+            Some(format!("{} {description}", task.name))
+        });
+        if task_label.is_some() {
+            return task_label;
+        }
+
+        let multi_fan = self.state.fans.len() > 1;
+        self.state.fans.iter().enumerate().find_map(|(i, fan)| {
+            let alarm = fan.alarms.load(Ordering::Relaxed).iter().next()?;
+            let description = match alarm {
+                Alarm::Stall => "STALLED",
+                Alarm::TachoMissing => "NO TACHO SIGNAL",
+                Alarm::OverSpeed => "OVER-SPEED",
+            };
+            Some(if multi_fan {
+                format!("FAN {}: {description}", i + 1)
+            } else {
+                format!("FAN {description}")
+            })
+        })
+    }
+
+    fn temperature_label(&self) -> String {
+        match self.state.temperature.load(Ordering::Relaxed) {
+            Some(celsius) => format!("{celsius: >5.1}C"),
+            None => "  --C ".to_string(),
+        }
+    }
+}
+
+/// Requested and applied duty, rounded to 5% so the PID controller doesn't make
+/// the labels flicker
+fn rounded_duties(fan: &FanState) -> (u32, u32) {
+    let round = |duty: u32| duty - (duty % 5);
+    (
+        round(fan.pwm.load(Ordering::Relaxed)),
+        round(fan.applied_pwm.load(Ordering::Relaxed)),
+    )
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour on
+fn format_countdown_secs(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    }
 }
 
 fn format_uptime_secs(secs: u64) -> String {
@@ -0,0 +1,495 @@
+//! Settings menu, opened with a long press of the knob.
+//!
+//! The menu only knows about abstract [`MenuInput`]s, so the firmware drives it
+//! with the knob and the simulator with the keyboard.
+
+use std::sync::atomic::Ordering;
+
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::Rgb565,
+    prelude::{DrawTarget, Point, Primitive, RgbColor, Size},
+    primitives::{PrimitiveStyle, Rectangle},
+    text::Text,
+    Drawable,
+};
+use fan_control_logic::{boost::BoostConfig, control::ControlSource, mode::ControlMode};
+use profont::PROFONT_14_POINT;
+
+use crate::{color::rgb888_to_rgb565, InterfaceState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuInput {
+    /// Detents, positive is clockwise
+    Rotate(i32),
+    Click,
+    Back,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Page {
+    #[default]
+    Main,
+    Curve,
+    Display,
+    Wifi,
+}
+
+impl Page {
+    fn title(self) -> &'static str {
+        match self {
+            Page::Main => "Settings",
+            Page::Curve => "Fan curve",
+            Page::Display => "Display",
+            Page::Wifi => "WiFi",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Profile,
+    Fan,
+    Mode,
+    MaxDuty,
+    MinDuty,
+    PulsesPerRev,
+    ZeroRpm,
+    CurveTemperature(usize),
+    CurveDuty(usize),
+    Animation,
+    Flipped,
+}
+
+impl Field {
+    /// Toggled with a click instead of turned
+    fn is_toggle(self) -> bool {
+        matches!(self, Field::ZeroRpm | Field::Animation | Field::Flipped)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ItemKind {
+    Edit(Field),
+    Open(Page),
+    /// Starts a [`BoostConfig::default`] boost and closes the menu to show the
+    /// countdown
+    Boost,
+    ResumeAuto,
+    Back,
+    /// Only shows a value
+    Info,
+}
+
+struct Item {
+    label: String,
+    value: String,
+    kind: ItemKind,
+}
+
+impl Item {
+    fn new(label: impl Into<String>, value: impl Into<String>, kind: ItemKind) -> Self {
+        Self {
+            label: label.into(),
+            value: value.into(),
+            kind,
+        }
+    }
+}
+
+/// Control modes the menu cycles through, calibration is started elsewhere
+const MODES: [ControlMode; 3] = [
+    ControlMode::Manual,
+    ControlMode::TargetRpm,
+    ControlMode::Curve,
+];
+
+/// Where the menu is. Keep it in [`InterfaceState::menu`], feed it with
+/// [`Menu::input`] and the interface shows it instead of the status screen
+/// while it's open.
+#[derive(Debug, Default)]
+pub struct Menu {
+    open: bool,
+    page: Page,
+    selected: usize,
+    /// Rotating changes the selected value instead of moving the selection
+    editing: bool,
+    /// Fan that the fan settings apply to
+    fan: usize,
+}
+
+/// What to draw, see [`Menu::view`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuView {
+    pub title: &'static str,
+    /// Label and value
+    pub rows: Vec<(String, String)>,
+    pub selected: usize,
+    pub editing: bool,
+}
+
+impl Menu {
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn open(&mut self) {
+        *self = Self {
+            open: true,
+            fan: self.fan,
+            ..Default::default()
+        };
+    }
+
+    pub fn close(&mut self) {
+        self.open = false;
+    }
+
+    /// `now_ms` is the time since boot, for [`InterfaceState::take_control`]
+    pub fn input(&mut self, state: &InterfaceState, input: MenuInput, now_ms: u64) {
+        if !self.open {
+            return;
+        }
+        self.fan = self.fan.min(state.fans.len().saturating_sub(1));
+        let items = self.items(state);
+        let Some(item) = items.get(self.selected) else {
+            self.selected = 0;
+            return;
+        };
+
+        if self.editing {
+            match (input, item.kind) {
+                (MenuInput::Rotate(steps), ItemKind::Edit(field)) => {
+                    self.adjust(state, field, steps, now_ms)
+                }
+                _ => self.editing = false,
+            }
+            return;
+        }
+
+        match input {
+            MenuInput::Rotate(steps) => {
+                let last = items.len() as i32 - 1;
+                self.selected = (self.selected as i32 + steps).clamp(0, last) as usize;
+            }
+            MenuInput::Click => match item.kind {
+                ItemKind::Edit(field) if field.is_toggle() => self.adjust(state, field, 1, now_ms),
+                ItemKind::Edit(_) => self.editing = true,
+                ItemKind::Open(page) => {
+                    self.page = page;
+                    self.selected = 0;
+                }
+                ItemKind::Boost => {
+                    if state
+                        .take_control(ControlSource::RotaryEncoder, now_ms)
+                        .is_ok()
+                        && state
+                            .boost
+                            .lock()
+                            .unwrap()
+                            .start(BoostConfig::default(), now_ms)
+                            .is_ok()
+                    {
+                        self.close();
+                    }
+                }
+                ItemKind::ResumeAuto => state.control.lock().unwrap().release(),
+                ItemKind::Back => self.back(state),
+                ItemKind::Info => {}
+            },
+            MenuInput::Back => self.back(state),
+        }
+    }
+
+    /// `None` while closed
+    pub fn view(&self, state: &InterfaceState) -> Option<MenuView> {
+        if !self.open {
+            return None;
+        }
+        Some(MenuView {
+            title: self.page.title(),
+            rows: self
+                .items(state)
+                .into_iter()
+                .map(|item| (item.label, item.value))
+                .collect(),
+            selected: self.selected,
+            editing: self.editing,
+        })
+    }
+
+    fn back(&mut self, state: &InterfaceState) {
+        let page = self.page;
+        if page == Page::Main {
+            self.close();
+            return;
+        }
+        // Back on the item that opened the page
+        self.page = Page::Main;
+        self.selected = self
+            .items(state)
+            .iter()
+            .position(|item| item.kind == ItemKind::Open(page))
+            .unwrap_or(0);
+    }
+
+    fn items(&self, state: &InterfaceState) -> Vec<Item> {
+        let Some(fan) = state.fans.get(self.fan) else {
+            return vec![Item::new("Exit", "", ItemKind::Back)];
+        };
+        match self.page {
+            Page::Main => {
+                let mode = match fan.control_mode.load(Ordering::Relaxed) {
+                    ControlMode::Manual => "Manual",
+                    ControlMode::TargetRpm => "RPM",
+                    ControlMode::Curve => "Curve",
+                    ControlMode::Calibration => "Calib.",
+                };
+                let spin_up = *fan.spin_up.lock().unwrap();
+                let zero_rpm = fan.zero_rpm.lock().unwrap().enabled;
+                let profile = state.profiles.lock().unwrap().active_profile.clone();
+                let boost = BoostConfig::default();
+                let boost_label = format!("{}% {}m", boost.duty, boost.duration_ms / 60_000);
+                vec![
+                    Item::new(
+                        "Profile",
+                        profile.unwrap_or_else(|| "--".to_string()),
+                        ItemKind::Edit(Field::Profile),
+                    ),
+                    Item::new("Boost", boost_label, ItemKind::Boost),
+                    Item::new(
+                        "Fan",
+                        format!("{}/{}", self.fan + 1, state.fans.len()),
+                        ItemKind::Edit(Field::Fan),
+                    ),
+                    Item::new("Mode", mode, ItemKind::Edit(Field::Mode)),
+                    Item::new("Curve", ">", ItemKind::Open(Page::Curve)),
+                    Item::new(
+                        "Min duty",
+                        format!("{}%", spin_up.min_duty),
+                        ItemKind::Edit(Field::MinDuty),
+                    ),
+                    Item::new(
+                        "Max duty",
+                        format!("{}%", fan.max_duty.load(Ordering::Relaxed)),
+                        ItemKind::Edit(Field::MaxDuty),
+                    ),
+                    Item::new(
+                        "Pulses/rev",
+                        fan.pulses_per_rev.load(Ordering::Relaxed).to_string(),
+                        ItemKind::Edit(Field::PulsesPerRev),
+                    ),
+                    Item::new(
+                        "Zero RPM",
+                        if zero_rpm { "On" } else { "Off" },
+                        ItemKind::Edit(Field::ZeroRpm),
+                    ),
+                    Item::new("Display", ">", ItemKind::Open(Page::Display)),
+                    Item::new("WiFi", ">", ItemKind::Open(Page::Wifi)),
+                    Item::new("Resume auto", "", ItemKind::ResumeAuto),
+                    Item::new("Exit", "", ItemKind::Back),
+                ]
+            }
+            Page::Curve => {
+                let curve = fan.curve.lock().unwrap();
+                let mut items = Vec::new();
+                for (i, point) in curve.points.iter().enumerate() {
+                    items.push(Item::new(
+                        format!("{}: Temp", i + 1),
+                        format!("{:.0}C", point.temperature_c),
+                        ItemKind::Edit(Field::CurveTemperature(i)),
+                    ));
+                    items.push(Item::new(
+                        format!("{}: Duty", i + 1),
+                        format!("{:.0}%", point.duty),
+                        ItemKind::Edit(Field::CurveDuty(i)),
+                    ));
+                }
+                items.push(Item::new("Back", "", ItemKind::Back));
+                items
+            }
+            Page::Display => {
+                let display = *state.display.lock().unwrap();
+                let on_off = |on: bool| if on { "On" } else { "Off" };
+                vec![
+                    Item::new(
+                        "Animation",
+                        on_off(display.animation),
+                        ItemKind::Edit(Field::Animation),
+                    ),
+                    Item::new(
+                        "Upside down",
+                        on_off(display.flipped),
+                        ItemKind::Edit(Field::Flipped),
+                    ),
+                    Item::new("Back", "", ItemKind::Back),
+                ]
+            }
+            Page::Wifi => {
+                let wifi = state.wifi.lock().unwrap().clone();
+                vec![
+                    Item::new("SSID", wifi.ssid, ItemKind::Info),
+                    Item::new(
+                        "IP",
+                        wifi.ip.unwrap_or_else(|| "offline".to_string()),
+                        ItemKind::Info,
+                    ),
+                    Item::new(
+                        "Signal",
+                        wifi.rssi
+                            .map_or("--".to_string(), |rssi| format!("{rssi}dBm")),
+                        ItemKind::Info,
+                    ),
+                    Item::new("Back", "", ItemKind::Back),
+                ]
+            }
+        }
+    }
+
+    fn adjust(&mut self, state: &InterfaceState, field: Field, steps: i32, now_ms: u64) {
+        let fan = &state.fans[self.fan];
+        let step = |value: u32, min: u32, max: u32| {
+            (value as i32 + steps).clamp(min as i32, max as i32) as u32
+        };
+        match field {
+            Field::Profile => {
+                // For all fans, unlike everything else on this page
+                let profile = state
+                    .profiles
+                    .lock()
+                    .unwrap()
+                    .cycle(steps)
+                    .map(|profile| profile.name.clone());
+                if let Some(profile) = profile {
+                    let _ = state.apply_profile(&profile, ControlSource::RotaryEncoder, now_ms);
+                }
+            }
+            Field::Fan => {
+                let count = state.fans.len() as i32;
+                self.fan = (self.fan as i32 + steps).rem_euclid(count) as usize;
+            }
+            Field::Mode => {
+                let mode = fan.control_mode.load(Ordering::Relaxed);
+                let index = MODES.iter().position(|&m| m == mode).unwrap_or(0) as i32;
+                let mode = MODES[(index + steps).rem_euclid(MODES.len() as i32) as usize];
+                if state
+                    .take_control(ControlSource::RotaryEncoder, now_ms)
+                    .is_ok()
+                {
+                    fan.control_mode.store(mode, Ordering::Relaxed);
+                }
+            }
+            Field::MaxDuty => {
+                let max_duty = step(fan.max_duty.load(Ordering::Relaxed), 1, 100);
+                fan.max_duty.store(max_duty, Ordering::Relaxed);
+            }
+            Field::MinDuty => {
+                let mut spin_up = fan.spin_up.lock().unwrap();
+                spin_up.min_duty = step(spin_up.min_duty, 0, 100);
+            }
+            Field::PulsesPerRev => {
+                let pulses_per_rev = step(fan.pulses_per_rev.load(Ordering::Relaxed), 1, 8);
+                fan.pulses_per_rev.store(pulses_per_rev, Ordering::Relaxed);
+            }
+            Field::ZeroRpm => {
+                let mut zero_rpm = fan.zero_rpm.lock().unwrap();
+                zero_rpm.enabled = !zero_rpm.enabled;
+            }
+            Field::CurveTemperature(i) => {
+                // Stays between its neighbours, the points have to stay sorted
+                let mut curve = fan.curve.lock().unwrap();
+                let min = i
+                    .checked_sub(1)
+                    .map_or(0.0, |prev| curve.points[prev].temperature_c + 1.0);
+                let max = curve
+                    .points
+                    .get(i + 1)
+                    .map_or(120.0, |next| next.temperature_c - 1.0);
+                let point = &mut curve.points[i];
+                if min > max {
+                    // Less than 2°C between the neighbours, or outside 0-120°C
+                    // already, there's nowhere to go
+                    return;
+                }
+                let temperature_c = (point.temperature_c.round() + steps as f32).clamp(min, max);
+                // Rounding to whole degrees mustn't move it against the turn
+                if (temperature_c - point.temperature_c) * steps as f32 > 0.0 {
+                    point.temperature_c = temperature_c;
+                }
+            }
+            Field::CurveDuty(i) => {
+                let mut curve = fan.curve.lock().unwrap();
+                let point = &mut curve.points[i];
+                point.duty = (point.duty.round() + steps as f32).clamp(0.0, 100.0);
+            }
+            Field::Animation => {
+                let mut display = state.display.lock().unwrap();
+                display.animation = !display.animation;
+            }
+            Field::Flipped => {
+                let mut display = state.display.lock().unwrap();
+                display.flipped = !display.flipped;
+            }
+        }
+    }
+}
+
+const ROW_HEIGHT: i32 = 22;
+const FIRST_ROW_Y: i32 = 40;
+const VISIBLE_ROWS: usize = 9;
+
+/// Draws the whole menu over the screen
+pub(crate) fn render<D>(view: &MenuView, target: &mut D) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let background = rgb888_to_rgb565(255u8, 182u8, 140u8);
+    let char_width = PROFONT_14_POINT.character_size.width as i32;
+
+    Rectangle::new(Point::zero(), Size::new(240, FIRST_ROW_Y as u32))
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(target)?;
+    let title_style = MonoTextStyle::new(&PROFONT_14_POINT, background);
+    let title_width = view.title.len() as i32 * char_width;
+    Text::new(
+        view.title,
+        Point::new((240 - title_width) / 2, 26),
+        title_style,
+    )
+    .draw(target)?;
+
+    // Scroll just far enough to keep the selection on screen
+    let first = view.selected.saturating_sub(VISIBLE_ROWS - 1);
+    for row in 0..VISIBLE_ROWS {
+        let index = first + row;
+        let y = FIRST_ROW_Y + row as i32 * ROW_HEIGHT;
+        let selected = index == view.selected;
+        let (row_background, color) = if selected && !view.editing {
+            (Rgb565::BLACK, background)
+        } else {
+            (background, Rgb565::BLACK)
+        };
+        Rectangle::new(Point::new(0, y), Size::new(240, ROW_HEIGHT as u32))
+            .into_styled(PrimitiveStyle::with_fill(row_background))
+            .draw(target)?;
+
+        let Some((label, value)) = view.rows.get(index) else {
+            continue;
+        };
+        let baseline = y + 16;
+        let style = MonoTextStyle::new(&PROFONT_14_POINT, color);
+        Text::new(label, Point::new(8, baseline), style).draw(target)?;
+
+        let value_width = value.len() as i32 * char_width;
+        let value_x = 232 - value_width;
+        if selected && view.editing {
+            // The value being changed is the only thing highlighted
+            let mut style = MonoTextStyle::new(&PROFONT_14_POINT, background);
+            style.background_color = Some(Rgb565::BLACK);
+            Text::new(value, Point::new(value_x, baseline), style).draw(target)?;
+        } else {
+            Text::new(value, Point::new(value_x, baseline), style).draw(target)?;
+        }
+    }
+    Ok(())
+}
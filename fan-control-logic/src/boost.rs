@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+
+/// Longest boost, so a forgotten one doesn't run for days
+pub const MAX_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
+/// Boosts that can be stacked, starting another one drops the oldest
+pub const MAX_STACKED: usize = 4;
+
+/// What a boost sets the fans to, and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BoostConfig {
+    pub duty: u32,
+    pub duration_ms: u64,
+    /// Every fan if `None`
+    pub fan: Option<usize>,
+}
+
+impl Default for BoostConfig {
+    fn default() -> Self {
+        Self {
+            duty: 100,
+            duration_ms: 10 * 60 * 1000,
+            fan: None,
+        }
+    }
+}
+
+impl BoostConfig {
+    pub fn validate(&self) -> Result<(), BoostError> {
+        if self.duty > 100 {
+            return Err(BoostError::DutyOutOfRange);
+        }
+        if self.duration_ms == 0 || self.duration_ms > MAX_DURATION_MS {
+            return Err(BoostError::InvalidDuration);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoostError {
+    DutyOutOfRange,
+    InvalidDuration,
+}
+
+impl std::fmt::Display for BoostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoostError::DutyOutOfRange => write!(f, "Duty must be 0-100"),
+            BoostError::InvalidDuration => write!(f, "Duration must be 1-{MAX_DURATION_MS} ms"),
+        }
+    }
+}
+
+impl std::error::Error for BoostError {}
+
+/// A running boost
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Boost {
+    pub duty: u32,
+    pub fan: Option<usize>,
+    pub started_ms: u64,
+    pub until_ms: u64,
+}
+
+impl Boost {
+    pub fn remaining_ms(&self, now_ms: u64) -> u64 {
+        self.until_ms.saturating_sub(now_ms)
+    }
+
+    fn applies_to(&self, fan: usize) -> bool {
+        self.fan.map_or(true, |boost_fan| boost_fan == fan)
+    }
+}
+
+/// Timed duty overrides on top of whatever mode the fans are in.
+///
+/// The newest boost that hasn't run out wins, when it does the one below it (or
+/// the mode) is back in charge without anything having to be restored. All times
+/// are on the caller's clock, e.g. ms since boot.
+#[derive(Debug, Clone, Default)]
+pub struct BoostStack {
+    /// Oldest first
+    boosts: Vec<Boost>,
+}
+
+impl BoostStack {
+    pub fn start(&mut self, config: BoostConfig, now_ms: u64) -> Result<Boost, BoostError> {
+        config.validate()?;
+        self.expire(now_ms);
+        if self.boosts.len() >= MAX_STACKED {
+            self.boosts.remove(0);
+        }
+        let boost = Boost {
+            duty: config.duty,
+            fan: config.fan,
+            started_ms: now_ms,
+            until_ms: now_ms + config.duration_ms,
+        };
+        self.boosts.push(boost);
+        Ok(boost)
+    }
+
+    /// Ends the newest boost early, the one below takes over again
+    pub fn cancel(&mut self, now_ms: u64) -> Option<Boost> {
+        self.expire(now_ms);
+        self.boosts.pop()
+    }
+
+    pub fn clear(&mut self) {
+        self.boosts.clear();
+    }
+
+    /// Drops the boosts that have run out and returns them
+    pub fn expire(&mut self, now_ms: u64) -> Vec<Boost> {
+        let (expired, running) = self
+            .boosts
+            .iter()
+            .partition(|boost| boost.remaining_ms(now_ms) == 0);
+        self.boosts = running;
+        expired
+    }
+
+    /// Boosts that haven't run out, oldest first
+    pub fn running(&self, now_ms: u64) -> impl Iterator<Item = &Boost> {
+        self.boosts
+            .iter()
+            .filter(move |boost| boost.remaining_ms(now_ms) > 0)
+    }
+
+    /// The boost in charge of `fan`, if any
+    pub fn active(&self, fan: usize, now_ms: u64) -> Option<&Boost> {
+        self.running(now_ms)
+            .filter(|boost| boost.applies_to(fan))
+            .last()
+    }
+
+    /// Newest boost that hasn't run out, for whatever fan
+    pub fn newest(&self, now_ms: u64) -> Option<&Boost> {
+        self.running(now_ms).last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boost(duty: u32, duration_ms: u64, fan: Option<usize>) -> BoostConfig {
+        BoostConfig {
+            duty,
+            duration_ms,
+            fan,
+        }
+    }
+
+    fn duties(stack: &BoostStack, now_ms: u64) -> [Option<u32>; 2] {
+        [0, 1].map(|fan| stack.active(fan, now_ms).map(|boost| boost.duty))
+    }
+
+    #[test]
+    fn newest_boost_wins() {
+        let mut stack = BoostStack::default();
+        stack.start(boost(60, 10_000, None), 0).unwrap();
+        stack.start(boost(80, 5_000, None), 1_000).unwrap();
+        assert_eq!(duties(&stack, 2_000), [Some(80), Some(80)]);
+        assert_eq!(stack.newest(2_000).unwrap().duty, 80);
+        assert_eq!(stack.running(2_000).count(), 2);
+    }
+
+    #[test]
+    fn per_fan_and_all_fan_boosts() {
+        let mut stack = BoostStack::default();
+        stack.start(boost(60, 10_000, None), 0).unwrap();
+        stack.start(boost(100, 10_000, Some(1)), 1_000).unwrap();
+        assert_eq!(duties(&stack, 2_000), [Some(60), Some(100)]);
+
+        // A newer boost for every fan takes over the one for fan 1 as well
+        stack.start(boost(70, 10_000, None), 3_000).unwrap();
+        assert_eq!(duties(&stack, 4_000), [Some(70), Some(70)]);
+    }
+
+    #[test]
+    fn expiry_reverts_to_the_one_below() {
+        let mut stack = BoostStack::default();
+        stack.start(boost(60, 10_000, None), 0).unwrap();
+        stack.start(boost(80, 2_000, Some(0)), 1_000).unwrap();
+        assert_eq!(duties(&stack, 2_999), [Some(80), Some(60)]);
+        assert_eq!(duties(&stack, 3_000), [Some(60), Some(60)]);
+        assert_eq!(duties(&stack, 10_000), [None, None]);
+
+        let expired = stack.expire(10_000);
+        assert_eq!(
+            expired.iter().map(|boost| boost.duty).collect::<Vec<_>>(),
+            [60, 80]
+        );
+        assert_eq!(stack.running(0).count(), 0);
+    }
+
+    #[test]
+    fn cancel_ends_the_newest() {
+        let mut stack = BoostStack::default();
+        stack.start(boost(60, 10_000, None), 0).unwrap();
+        stack.start(boost(80, 10_000, None), 1_000).unwrap();
+        assert_eq!(stack.cancel(2_000).unwrap().duty, 80);
+        assert_eq!(duties(&stack, 2_000), [Some(60), Some(60)]);
+
+        // Ones that ran out already don't count
+        stack.start(boost(90, 1_000, None), 3_000).unwrap();
+        assert_eq!(stack.cancel(5_000).unwrap().duty, 60);
+        assert_eq!(stack.cancel(5_000), None);
+
+        stack.start(boost(60, 10_000, None), 6_000).unwrap();
+        stack.clear();
+        assert_eq!(stack.newest(6_000), None);
+    }
+
+    #[test]
+    fn oldest_is_dropped_when_full() {
+        let mut stack = BoostStack::default();
+        for i in 0..MAX_STACKED as u32 + 1 {
+            stack.start(boost(50 + i, 10_000, None), 0).unwrap();
+        }
+        let running: Vec<u32> = stack.running(0).map(|boost| boost.duty).collect();
+        assert_eq!(running.len(), MAX_STACKED);
+        assert_eq!(running[0], 51);
+
+        // Expired boosts make room first
+        let mut stack = BoostStack::default();
+        stack.start(boost(40, 1_000, None), 0).unwrap();
+        for i in 1..MAX_STACKED as u32 {
+            stack.start(boost(50 + i, 10_000, None), 0).unwrap();
+        }
+        stack.start(boost(90, 10_000, None), 2_000).unwrap();
+        assert_eq!(stack.running(2_000).count(), MAX_STACKED);
+        assert_eq!(stack.running(2_000).next().unwrap().duty, 51);
+    }
+
+    #[test]
+    fn invalid_boosts() {
+        let mut stack = BoostStack::default();
+        assert_eq!(
+            stack.start(boost(101, 1_000, None), 0),
+            Err(BoostError::DutyOutOfRange)
+        );
+        assert_eq!(
+            stack.start(boost(100, 0, None), 0),
+            Err(BoostError::InvalidDuration)
+        );
+        assert_eq!(
+            stack.start(boost(100, MAX_DURATION_MS + 1, None), 0),
+            Err(BoostError::InvalidDuration)
+        );
+        assert_eq!(stack.newest(0), None);
+    }
+}
@@ -0,0 +1,222 @@
+/// Timing of the push button gestures, in ms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ButtonConfig {
+    /// The contacts have to settle this long before a press or release counts
+    pub debounce_ms: u64,
+    /// A second press within this long after releasing makes a double click
+    pub double_click_ms: u64,
+    pub long_press_ms: u64,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 20,
+            double_click_ms: 300,
+            long_press_ms: 800,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// Reported once the double click window has passed
+    Click,
+    DoubleClick,
+    /// Reported while the button is still held
+    LongPress,
+    /// The knob turned this far while the button was held, the release
+    /// afterwards isn't a click
+    PressAndTurn(i32),
+}
+
+/// Turns the raw push button level into gestures.
+///
+/// Feed it the level regularly with [`ButtonRecognizer::update`] and knob turns
+/// with [`ButtonRecognizer::turn`].
+#[derive(Debug, Clone)]
+pub struct ButtonRecognizer {
+    config: ButtonConfig,
+    /// Debounced level
+    pressed: bool,
+    /// Last raw level and since when
+    raw: (bool, u64),
+    pressed_at_ms: u64,
+    /// The current press is already something other than a click
+    used: bool,
+    /// Release of a click that may still become a double click
+    pending_click_ms: Option<u64>,
+}
+
+impl ButtonRecognizer {
+    pub fn new(config: ButtonConfig) -> Self {
+        Self {
+            config,
+            pressed: false,
+            raw: (false, 0),
+            pressed_at_ms: 0,
+            used: false,
+            pending_click_ms: None,
+        }
+    }
+
+    /// Debounced level
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    pub fn update(&mut self, raw_pressed: bool, now_ms: u64) -> Option<Gesture> {
+        if let Some(released_ms) = self.pending_click_ms {
+            // A second press that's still bouncing doesn't let the window run out
+            let released = !self.pressed && !self.raw.0;
+            if released && now_ms - released_ms >= self.config.double_click_ms {
+                self.pending_click_ms = None;
+                return Some(Gesture::Click);
+            }
+        }
+
+        if raw_pressed != self.raw.0 {
+            self.raw = (raw_pressed, now_ms);
+        }
+        let settled = now_ms - self.raw.1 >= self.config.debounce_ms;
+        if settled && self.raw.0 != self.pressed {
+            self.pressed = self.raw.0;
+            return if self.pressed {
+                self.pressed_at_ms = now_ms;
+                self.used = false;
+                None
+            } else {
+                self.released(now_ms)
+            };
+        }
+
+        let long = now_ms - self.pressed_at_ms >= self.config.long_press_ms;
+        if self.pressed && !self.used && long {
+            self.used = true;
+            self.pending_click_ms = None;
+            return Some(Gesture::LongPress);
+        }
+        None
+    }
+
+    /// Reports a turn of the knob. Returns a gesture, and the turn shouldn't be
+    /// used for anything else, if the button is held.
+    pub fn turn(&mut self, steps: i32) -> Option<Gesture> {
+        if !self.pressed || steps == 0 {
+            return None;
+        }
+        self.used = true;
+        self.pending_click_ms = None;
+        Some(Gesture::PressAndTurn(steps))
+    }
+
+    fn released(&mut self, now_ms: u64) -> Option<Gesture> {
+        if self.used {
+            return None;
+        }
+        match self.pending_click_ms.take() {
+            Some(_) => Some(Gesture::DoubleClick),
+            None => {
+                self.pending_click_ms = Some(now_ms);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_MS: u64 = 5;
+
+    /// Samples a raw level trace, `(time, pressed)` changes, until `end_ms`.
+    /// `turns` are knob turns at a time.
+    fn gestures(trace: &[(u64, bool)], turns: &[(u64, i32)], end_ms: u64) -> Vec<(u64, Gesture)> {
+        let mut button = ButtonRecognizer::new(ButtonConfig::default());
+        let mut gestures = Vec::new();
+        for now_ms in (0..=end_ms).step_by(SAMPLE_MS as usize) {
+            let pressed = trace
+                .iter()
+                .rev()
+                .find(|&&(at_ms, _)| at_ms <= now_ms)
+                .is_some_and(|&(_, pressed)| pressed);
+            if let Some(gesture) = button.update(pressed, now_ms) {
+                gestures.push((now_ms, gesture));
+            }
+            for &(_, steps) in turns.iter().filter(|&&(at_ms, _)| at_ms == now_ms) {
+                if let Some(gesture) = button.turn(steps) {
+                    gestures.push((now_ms, gesture));
+                }
+            }
+        }
+        gestures
+    }
+
+    #[test]
+    fn click_after_the_double_click_window() {
+        // Released at 200, settled at 220, the window closes 300ms later
+        assert_eq!(
+            gestures(&[(100, true), (200, false)], &[], 2_000),
+            [(520, Gesture::Click)]
+        );
+    }
+
+    #[test]
+    fn bounces_are_filtered() {
+        let trace = [
+            (100, true),
+            (105, false),
+            (110, true),
+            (115, false),
+            (120, true),
+            (300, false),
+            (305, true),
+            (310, false),
+        ];
+        assert_eq!(gestures(&trace, &[], 2_000), [(630, Gesture::Click)]);
+        // Too short to be a press at all
+        assert_eq!(gestures(&[(100, true), (110, false)], &[], 2_000), []);
+    }
+
+    #[test]
+    fn double_click_at_the_window_edge() {
+        // The second press starts just before the window closes
+        let trace = [(100, true), (200, false), (515, true), (600, false)];
+        assert_eq!(gestures(&trace, &[], 2_000), [(620, Gesture::DoubleClick)]);
+
+        // And just after, that's two clicks
+        let trace = [(100, true), (200, false), (525, true), (600, false)];
+        assert_eq!(
+            gestures(&trace, &[], 2_000),
+            [(520, Gesture::Click), (920, Gesture::Click)]
+        );
+    }
+
+    #[test]
+    fn long_press_is_no_click() {
+        assert_eq!(
+            gestures(&[(100, true), (1_200, false)], &[], 3_000),
+            [(920, Gesture::LongPress)]
+        );
+    }
+
+    #[test]
+    fn long_press_ends_a_pending_click() {
+        let trace = [(100, true), (200, false), (300, true), (1_500, false)];
+        assert_eq!(gestures(&trace, &[], 3_000), [(1_120, Gesture::LongPress)]);
+    }
+
+    #[test]
+    fn press_and_turn_is_no_click() {
+        assert_eq!(
+            gestures(&[(100, true), (400, false)], &[(200, 2), (250, -1)], 2_000),
+            [
+                (200, Gesture::PressAndTurn(2)),
+                (250, Gesture::PressAndTurn(-1)),
+            ]
+        );
+        // Turning without the button is left to the knob
+        assert_eq!(gestures(&[], &[(200, 2)], 1_000), []);
+    }
+}
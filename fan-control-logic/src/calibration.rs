@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+
+/// Settled RPM at one duty cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub duty: u32,
+    pub rpm: u32,
+}
+
+/// What a calibration sweep found out about a fan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanCalibration {
+    /// Lowest duty that gets the fan going from standstill
+    pub min_start_duty: u32,
+    /// Lowest duty that keeps an already spinning fan running, usually lower
+    /// than `min_start_duty`
+    pub min_running_duty: u32,
+    pub max_rpm: u32,
+    /// Duty→RPM curve, sweeping up from 0%
+    pub points: Vec<CalibrationPoint>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibrationError {
+    /// Not a single step reached `spinning_rpm`, the fan or tacho isn't connected
+    NeverSpun,
+}
+
+impl std::fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalibrationError::NeverSpun => write!(f, "Fan never spun up, check the wiring"),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepConfig {
+    /// Duty increment between steps
+    pub step: u32,
+    /// Anything below this counts as stopped
+    pub spinning_rpm: u32,
+    /// Two samples in a row closer than this are considered settled
+    pub settle_tolerance_rpm: u32,
+    pub min_samples_per_step: u32,
+    /// Give up waiting for a fan that keeps hunting and take the last sample
+    pub max_samples_per_step: u32,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        Self {
+            step: 5,
+            spinning_rpm: 100,
+            settle_tolerance_rpm: 30,
+            min_samples_per_step: 2,
+            max_samples_per_step: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SweepStatus {
+    Running,
+    Done(FanCalibration),
+    Failed(CalibrationError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Let the fan come to rest at 0% before sweeping up
+    SpinDown,
+    Rising,
+    Falling,
+}
+
+/// Steps a fan through the duty range and records where it settles.
+///
+/// First spins the fan down at 0%, then sweeps up to 100% to find the start
+/// duty and the duty→RPM curve, then back down until the fan stops to find the
+/// lowest running duty. Set the fan to [`CalibrationSweep::duty`] and call
+/// [`CalibrationSweep::update`] with every new RPM reading, never more than once
+/// per tacho measurement.
+#[derive(Debug, Clone)]
+pub struct CalibrationSweep {
+    config: SweepConfig,
+    phase: Phase,
+    duty: u32,
+    samples: u32,
+    last_rpm: Option<u32>,
+    points: Vec<CalibrationPoint>,
+    min_start_duty: Option<u32>,
+    min_running_duty: u32,
+}
+
+impl CalibrationSweep {
+    pub fn new(config: SweepConfig) -> Self {
+        Self {
+            config,
+            phase: Phase::SpinDown,
+            duty: 0,
+            samples: 0,
+            last_rpm: None,
+            points: Vec::new(),
+            min_start_duty: None,
+            min_running_duty: 0,
+        }
+    }
+
+    /// Duty the fan should run at right now
+    pub fn duty(&self) -> u32 {
+        self.duty
+    }
+
+    pub fn update(&mut self, rpm: u32) -> SweepStatus {
+        let Some(rpm) = self.settled(rpm) else {
+            return SweepStatus::Running;
+        };
+        let spinning = rpm >= self.config.spinning_rpm;
+
+        match self.phase {
+            Phase::SpinDown => {
+                self.phase = Phase::Rising;
+                self.record(rpm, spinning);
+            }
+            Phase::Rising => self.record(rpm, spinning),
+            Phase::Falling => {
+                if !spinning {
+                    return self.finish();
+                }
+                self.min_running_duty = self.duty;
+            }
+        }
+
+        match self.phase {
+            Phase::Rising if self.duty < 100 => self.set_duty(self.duty + self.config.step),
+            Phase::Rising => {
+                if self.min_start_duty.is_none() {
+                    return SweepStatus::Failed(CalibrationError::NeverSpun);
+                }
+                self.phase = Phase::Falling;
+                self.min_running_duty = 100;
+                self.set_duty(100u32.saturating_sub(self.config.step));
+            }
+            Phase::Falling if self.duty > 0 => {
+                self.set_duty(self.duty.saturating_sub(self.config.step))
+            }
+            // Still spinning at 0%, a fan that can't be stopped with PWM
+            Phase::Falling => return self.finish(),
+            Phase::SpinDown => unreachable!(),
+        }
+        SweepStatus::Running
+    }
+
+    /// The reading to record for the current step, once it has settled
+    fn settled(&mut self, rpm: u32) -> Option<u32> {
+        self.samples += 1;
+        let last_rpm = self.last_rpm.replace(rpm);
+        let stable =
+            last_rpm.is_some_and(|last| last.abs_diff(rpm) <= self.config.settle_tolerance_rpm);
+        let settled = (stable && self.samples >= self.config.min_samples_per_step)
+            || self.samples >= self.config.max_samples_per_step;
+        settled.then_some(rpm)
+    }
+
+    fn record(&mut self, rpm: u32, spinning: bool) {
+        self.points.push(CalibrationPoint {
+            duty: self.duty,
+            rpm,
+        });
+        if spinning && self.min_start_duty.is_none() {
+            self.min_start_duty = Some(self.duty);
+        }
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        self.duty = duty.min(100);
+        self.samples = 0;
+        self.last_rpm = None;
+    }
+
+    fn finish(&self) -> SweepStatus {
+        SweepStatus::Done(FanCalibration {
+            min_start_duty: self.min_start_duty.unwrap_or(0),
+            min_running_duty: self.min_running_duty,
+            max_rpm: self.points.iter().map(|point| point.rpm).max().unwrap_or(0),
+            points: self.points.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a sweep against a fan that starts from standstill at `start_duty`,
+    /// keeps turning down to `running_duty` and does 2000 RPM at 100%
+    fn sweep(start_duty: u32, running_duty: u32) -> Result<FanCalibration, CalibrationError> {
+        let mut sweep = CalibrationSweep::new(SweepConfig::default());
+        let mut rpm = 0;
+        for _ in 0..1000 {
+            let duty = sweep.duty();
+            let spinning = duty >= start_duty || (rpm > 0 && duty >= running_duty);
+            rpm = if spinning { 200 + duty * 18 } else { 0 };
+            match sweep.update(rpm) {
+                SweepStatus::Running => {}
+                SweepStatus::Done(calibration) => return Ok(calibration),
+                SweepStatus::Failed(e) => return Err(e),
+            }
+        }
+        panic!("sweep never finished");
+    }
+
+    #[test]
+    fn finds_start_and_running_duty() {
+        let calibration = sweep(25, 15).unwrap();
+        assert_eq!(calibration.min_start_duty, 25);
+        assert_eq!(calibration.min_running_duty, 15);
+        assert_eq!(calibration.max_rpm, 2000);
+        // Every step on the way up
+        let duties: Vec<u32> = calibration.points.iter().map(|point| point.duty).collect();
+        assert_eq!(duties, (0..=100).step_by(5).collect::<Vec<_>>());
+        assert_eq!(calibration.points[4], CalibrationPoint { duty: 20, rpm: 0 });
+        assert_eq!(
+            calibration.points[5],
+            CalibrationPoint { duty: 25, rpm: 650 }
+        );
+    }
+
+    #[test]
+    fn fan_that_never_stops() {
+        let calibration = sweep(0, 0).unwrap();
+        assert_eq!(calibration.min_start_duty, 0);
+        assert_eq!(calibration.min_running_duty, 0);
+    }
+
+    #[test]
+    fn fan_that_never_spins() {
+        assert_eq!(sweep(101, 101), Err(CalibrationError::NeverSpun));
+    }
+
+    #[test]
+    fn waits_for_the_rpm_to_settle() {
+        let mut sweep = CalibrationSweep::new(SweepConfig::default());
+        // Still spinning down
+        for rpm in [800, 400, 10] {
+            assert!(matches!(sweep.update(rpm), SweepStatus::Running));
+            assert_eq!(sweep.duty(), 0);
+        }
+        sweep.update(0);
+        assert_eq!(sweep.duty(), 5);
+
+        // A fan that keeps hunting is only waited for so long
+        for i in 0..9 {
+            sweep.update(if i % 2 == 0 { 0 } else { 500 });
+            assert_eq!(sweep.duty(), 5);
+        }
+        sweep.update(0);
+        assert_eq!(sweep.duty(), 10);
+    }
+}
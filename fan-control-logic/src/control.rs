@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+
+/// Who last changed how the fans are driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlSource {
+    /// Nobody since boot, the saved settings are in charge
+    #[default]
+    Boot,
+    RotaryEncoder,
+    Wifi,
+    /// Schedules and other rules running on the device itself
+    Automation,
+}
+
+impl ControlSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlSource::Boot => "boot",
+            ControlSource::RotaryEncoder => "rotary_encoder",
+            ControlSource::Wifi => "wifi",
+            ControlSource::Automation => "automation",
+        }
+    }
+
+    /// Set by a person, rather than by the device
+    pub fn is_manual(self) -> bool {
+        matches!(self, ControlSource::RotaryEncoder | ControlSource::Wifi)
+    }
+}
+
+/// Who may take control when. Both holds are in ms, 0 turns them off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlPolicy {
+    /// Automation waits this long after a manual change before taking over again
+    pub manual_hold_ms: u64,
+    /// The knob is ignored this long after a change over WiFi, so nobody
+    /// fiddling with it undoes remote control
+    pub knob_lock_ms: u64,
+}
+
+impl Default for ControlPolicy {
+    fn default() -> Self {
+        Self {
+            manual_hold_ms: 30 * 60 * 1000,
+            knob_lock_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ControlChange {
+    pub source: ControlSource,
+    /// Time of the change, on the clock passed to [`ControlArbiter::request`]
+    pub at_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    /// Automation is held off by a manual change
+    ManualOverride {
+        remaining_ms: u64,
+    },
+    KnobLocked {
+        remaining_ms: u64,
+    },
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::ManualOverride { remaining_ms } => write!(
+                f,
+                "Manual override active for another {} s",
+                remaining_ms.div_ceil(1000)
+            ),
+            ControlError::KnobLocked { remaining_ms } => write!(
+                f,
+                "Knob locked by remote control for another {} s",
+                remaining_ms.div_ceil(1000)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Decides whether a source may change the fans, and remembers who did last.
+///
+/// Every writer asks first with [`ControlArbiter::request`], and only changes the
+/// fans if that succeeds.
+#[derive(Debug, Clone, Default)]
+pub struct ControlArbiter {
+    policy: ControlPolicy,
+    last: ControlChange,
+    last_manual_ms: Option<u64>,
+    last_wifi_ms: Option<u64>,
+}
+
+impl ControlArbiter {
+    pub fn new(policy: ControlPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> ControlPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: ControlPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn last_change(&self) -> ControlChange {
+        self.last
+    }
+
+    /// Records the change if `source` may make it
+    pub fn request(&mut self, source: ControlSource, now_ms: u64) -> Result<(), ControlError> {
+        match source {
+            ControlSource::Automation => {
+                if let Some(remaining_ms) = self.manual_hold_remaining(now_ms) {
+                    return Err(ControlError::ManualOverride { remaining_ms });
+                }
+            }
+            ControlSource::RotaryEncoder => {
+                if let Some(remaining_ms) = self.knob_lock_remaining(now_ms) {
+                    return Err(ControlError::KnobLocked { remaining_ms });
+                }
+            }
+            ControlSource::Boot | ControlSource::Wifi => {}
+        }
+
+        self.last = ControlChange {
+            source,
+            at_ms: now_ms,
+        };
+        if source.is_manual() {
+            self.last_manual_ms = Some(now_ms);
+        }
+        if source == ControlSource::Wifi {
+            self.last_wifi_ms = Some(now_ms);
+        }
+        Ok(())
+    }
+
+    /// Hands control back to automation straight away
+    pub fn release(&mut self) {
+        self.last_manual_ms = None;
+        self.last_wifi_ms = None;
+    }
+
+    /// How much longer automation is held off, `None` if it isn't
+    pub fn manual_hold_remaining(&self, now_ms: u64) -> Option<u64> {
+        remaining(self.last_manual_ms, self.policy.manual_hold_ms, now_ms)
+    }
+
+    /// How much longer the knob is ignored, `None` if it isn't
+    pub fn knob_lock_remaining(&self, now_ms: u64) -> Option<u64> {
+        remaining(self.last_wifi_ms, self.policy.knob_lock_ms, now_ms)
+    }
+}
+
+fn remaining(since_ms: Option<u64>, hold_ms: u64, now_ms: u64) -> Option<u64> {
+    let until_ms = since_ms? + hold_ms;
+    (until_ms > now_ms).then(|| until_ms - now_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arbiter(manual_hold_ms: u64, knob_lock_ms: u64) -> ControlArbiter {
+        ControlArbiter::new(ControlPolicy {
+            manual_hold_ms,
+            knob_lock_ms,
+        })
+    }
+
+    #[test]
+    fn automation_waits_for_manual_hold() {
+        let mut arbiter = arbiter(60_000, 0);
+        assert_eq!(arbiter.request(ControlSource::Automation, 0), Ok(()));
+        arbiter
+            .request(ControlSource::RotaryEncoder, 1_000)
+            .unwrap();
+        assert_eq!(
+            arbiter.request(ControlSource::Automation, 21_000),
+            Err(ControlError::ManualOverride {
+                remaining_ms: 40_000
+            })
+        );
+        assert_eq!(arbiter.last_change().source, ControlSource::RotaryEncoder);
+
+        assert_eq!(arbiter.request(ControlSource::Automation, 61_000), Ok(()));
+        assert_eq!(
+            arbiter.last_change(),
+            ControlChange {
+                source: ControlSource::Automation,
+                at_ms: 61_000
+            }
+        );
+    }
+
+    #[test]
+    fn wifi_starts_manual_hold_too() {
+        let mut arbiter = arbiter(60_000, 0);
+        arbiter.request(ControlSource::Wifi, 0).unwrap();
+        assert_eq!(arbiter.manual_hold_remaining(59_999), Some(1));
+        assert_eq!(arbiter.manual_hold_remaining(60_000), None);
+        // Automation doesn't hold itself off
+        arbiter.request(ControlSource::Automation, 60_000).unwrap();
+        assert_eq!(arbiter.request(ControlSource::Automation, 60_001), Ok(()));
+    }
+
+    #[test]
+    fn knob_locked_after_wifi() {
+        let mut arbiter = arbiter(0, 10_000);
+        arbiter.request(ControlSource::Wifi, 5_000).unwrap();
+        assert_eq!(
+            arbiter.request(ControlSource::RotaryEncoder, 6_000),
+            Err(ControlError::KnobLocked {
+                remaining_ms: 9_000
+            })
+        );
+        assert_eq!(arbiter.last_change().source, ControlSource::Wifi);
+        // Wifi itself isn't held off
+        assert_eq!(arbiter.request(ControlSource::Wifi, 7_000), Ok(()));
+        assert_eq!(arbiter.knob_lock_remaining(16_999), Some(1));
+        assert_eq!(
+            arbiter.request(ControlSource::RotaryEncoder, 17_000),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn knob_doesnt_lock_the_knob() {
+        let mut arbiter = arbiter(0, 10_000);
+        arbiter.request(ControlSource::RotaryEncoder, 0).unwrap();
+        assert_eq!(arbiter.request(ControlSource::RotaryEncoder, 1), Ok(()));
+    }
+
+    #[test]
+    fn release_hands_back_control() {
+        let mut arbiter = arbiter(60_000, 10_000);
+        arbiter.request(ControlSource::Wifi, 0).unwrap();
+        arbiter.release();
+        assert_eq!(arbiter.manual_hold_remaining(1), None);
+        assert_eq!(arbiter.knob_lock_remaining(1), None);
+        assert_eq!(arbiter.request(ControlSource::Automation, 1), Ok(()));
+        assert_eq!(arbiter.request(ControlSource::RotaryEncoder, 2), Ok(()));
+    }
+
+    #[test]
+    fn zero_turns_holds_off() {
+        let mut arbiter = arbiter(0, 0);
+        arbiter.request(ControlSource::Wifi, 0).unwrap();
+        assert_eq!(arbiter.manual_hold_remaining(0), None);
+        assert_eq!(arbiter.knob_lock_remaining(0), None);
+        assert_eq!(arbiter.request(ControlSource::RotaryEncoder, 0), Ok(()));
+        assert_eq!(arbiter.request(ControlSource::Automation, 0), Ok(()));
+    }
+
+    #[test]
+    fn error_rounds_up_to_seconds() {
+        let error = ControlError::ManualOverride {
+            remaining_ms: 1_001,
+        };
+        assert_eq!(error.to_string(), "Manual override active for another 2 s");
+    }
+}
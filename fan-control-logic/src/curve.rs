@@ -0,0 +1,457 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Maps a temperature to a duty cycle (percent)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub temperature_c: f32,
+    pub duty: f32,
+}
+
+impl CurvePoint {
+    pub const fn new(temperature_c: f32, duty: f32) -> Self {
+        Self {
+            temperature_c,
+            duty,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Smooth curve through the points that never overshoots between them
+    /// (Fritsch-Carlson), so a rising set of points gives a rising curve.
+    MonotoneCubic,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurveError {
+    TooFewPoints,
+    /// Points must be sorted by strictly increasing temperature
+    UnsortedPoints,
+    DutyOutOfRange(f32),
+    NotFinite,
+    NegativeHysteresis,
+}
+
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CurveError::TooFewPoints => write!(f, "curve needs at least two points"),
+            CurveError::UnsortedPoints => {
+                write!(f, "curve points must have strictly increasing temperatures")
+            }
+            CurveError::DutyOutOfRange(duty) => write!(f, "duty {duty} is not within 0-100"),
+            CurveError::NotFinite => write!(f, "curve values must be finite numbers"),
+            CurveError::NegativeHysteresis => write!(f, "hysteresis can't be negative"),
+        }
+    }
+}
+
+impl std::error::Error for CurveError {}
+
+/// Temperature to duty curve through a set of points.
+///
+/// Below the first point the first duty is used, above the last point the last
+/// duty is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanCurve {
+    points: Vec<CurvePoint>,
+    interpolation: Interpolation,
+    /// Slopes at each point, only used for [`Interpolation::MonotoneCubic`]
+    tangents: Vec<f32>,
+}
+
+impl FanCurve {
+    pub fn new(points: Vec<CurvePoint>, interpolation: Interpolation) -> Result<Self, CurveError> {
+        if points.len() < 2 {
+            return Err(CurveError::TooFewPoints);
+        }
+        for point in &points {
+            if !point.temperature_c.is_finite() || !point.duty.is_finite() {
+                return Err(CurveError::NotFinite);
+            }
+            if !(0.0..=100.0).contains(&point.duty) {
+                return Err(CurveError::DutyOutOfRange(point.duty));
+            }
+        }
+        if points
+            .windows(2)
+            .any(|pair| pair[0].temperature_c >= pair[1].temperature_c)
+        {
+            return Err(CurveError::UnsortedPoints);
+        }
+
+        let tangents = match interpolation {
+            Interpolation::Linear => Vec::new(),
+            Interpolation::MonotoneCubic => monotone_tangents(&points),
+        };
+
+        Ok(Self {
+            points,
+            interpolation,
+            tangents,
+        })
+    }
+
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn duty_at(&self, temperature_c: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if temperature_c <= first.temperature_c {
+            return first.duty;
+        }
+        if temperature_c >= last.temperature_c {
+            return last.duty;
+        }
+
+        // There are at least two points here, otherwise we would have returned above
+        let i = self
+            .points
+            .windows(2)
+            .position(|pair| temperature_c < pair[1].temperature_c)
+            .unwrap_or(self.points.len() - 2);
+        let (a, b) = (self.points[i], self.points[i + 1]);
+        let h = b.temperature_c - a.temperature_c;
+        let t = (temperature_c - a.temperature_c) / h;
+
+        let duty = match self.interpolation {
+            Interpolation::Linear => a.duty + (b.duty - a.duty) * t,
+            Interpolation::MonotoneCubic => {
+                // Cubic Hermite spline
+                let (t2, t3) = (t * t, t * t * t);
+                let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+                let h10 = t3 - 2.0 * t2 + t;
+                let h01 = -2.0 * t3 + 3.0 * t2;
+                let h11 = t3 - t2;
+                h00 * a.duty
+                    + h10 * h * self.tangents[i]
+                    + h01 * b.duty
+                    + h11 * h * self.tangents[i + 1]
+            }
+        };
+        duty.clamp(0.0, 100.0)
+    }
+}
+
+/// Fritsch-Carlson tangents, see
+/// <https://en.wikipedia.org/wiki/Monotone_cubic_interpolation>
+fn monotone_tangents(points: &[CurvePoint]) -> Vec<f32> {
+    let n = points.len();
+    if n < 2 {
+        return vec![0.0; n];
+    }
+
+    let secants: Vec<f32> = points
+        .windows(2)
+        .map(|pair| (pair[1].duty - pair[0].duty) / (pair[1].temperature_c - pair[0].temperature_c))
+        .collect();
+
+    let mut tangents = Vec::with_capacity(n);
+    tangents.push(secants[0]);
+    for i in 1..n - 1 {
+        if secants[i - 1] * secants[i] <= 0.0 {
+            // Local extremum or flat section, keep it flat
+            tangents.push(0.0);
+        } else {
+            tangents.push((secants[i - 1] + secants[i]) / 2.0);
+        }
+    }
+    tangents.push(secants[n - 2]);
+
+    for i in 0..n - 1 {
+        if secants[i] == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let alpha = tangents[i] / secants[i];
+        let beta = tangents[i + 1] / secants[i];
+        let sum = alpha * alpha + beta * beta;
+        if sum > 9.0 {
+            let tau = 3.0 / sum.sqrt();
+            tangents[i] = tau * alpha * secants[i];
+            tangents[i + 1] = tau * beta * secants[i];
+        }
+    }
+
+    tangents
+}
+
+/// User facing fan curve settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CurveConfig {
+    pub points: Vec<CurvePoint>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    /// How far the temperature has to drop before the duty follows the curve down
+    #[serde(default)]
+    pub hysteresis_c: f32,
+    /// Minimum time between lowering the duty
+    #[serde(default)]
+    pub min_hold_secs: u32,
+}
+
+impl Default for CurveConfig {
+    fn default() -> Self {
+        Self {
+            points: vec![
+                CurvePoint::new(30.0, 20.0),
+                CurvePoint::new(50.0, 50.0),
+                CurvePoint::new(70.0, 100.0),
+            ],
+            interpolation: Interpolation::Linear,
+            hysteresis_c: 2.0,
+            min_hold_secs: 10,
+        }
+    }
+}
+
+impl CurveConfig {
+    pub fn to_curve(&self) -> Result<FanCurve, CurveError> {
+        if !self.hysteresis_c.is_finite() {
+            return Err(CurveError::NotFinite);
+        }
+        if self.hysteresis_c < 0.0 {
+            return Err(CurveError::NegativeHysteresis);
+        }
+        FanCurve::new(self.points.clone(), self.interpolation)
+    }
+}
+
+/// Follows a [`FanCurve`] over time, with hysteresis and a minimum hold time.
+///
+/// Rising temperatures raise the duty right away, since being too slow is worse
+/// than being too loud. When the temperature falls, the curve is followed as if
+/// it was `hysteresis_c` warmer, and the duty is lowered at most once every
+/// `min_hold_secs`. That keeps a temperature hovering around a curve point from
+/// making the fan hunt up and down.
+#[derive(Debug, Clone)]
+pub struct CurveFollower {
+    config: CurveConfig,
+    curve: FanCurve,
+    duty: Option<f32>,
+    last_change_ms: u64,
+}
+
+impl CurveFollower {
+    pub fn new(config: CurveConfig) -> Result<Self, CurveError> {
+        let curve = config.to_curve()?;
+        Ok(Self {
+            config,
+            curve,
+            duty: None,
+            last_change_ms: 0,
+        })
+    }
+
+    pub fn config(&self) -> &CurveConfig {
+        &self.config
+    }
+
+    pub fn update(&mut self, temperature_c: f32, now_ms: u64) -> f32 {
+        let rising = self.curve.duty_at(temperature_c);
+        let Some(duty) = self.duty else {
+            return self.set(rising, now_ms);
+        };
+        if rising > duty {
+            return self.set(rising, now_ms);
+        }
+
+        let falling = self.curve.duty_at(temperature_c + self.config.hysteresis_c);
+        let hold_ms = self.config.min_hold_secs as u64 * 1000;
+        let held_long_enough = now_ms.saturating_sub(self.last_change_ms) >= hold_ms;
+        if falling < duty && held_long_enough {
+            return self.set(falling, now_ms);
+        }
+
+        duty
+    }
+
+    fn set(&mut self, duty: f32, now_ms: u64) -> f32 {
+        self.duty = Some(duty);
+        self.last_change_ms = now_ms;
+        duty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(interpolation: Interpolation) -> FanCurve {
+        CurveConfig {
+            interpolation,
+            ..Default::default()
+        }
+        .to_curve()
+        .unwrap()
+    }
+
+    fn config(points: &[(f32, f32)]) -> CurveConfig {
+        CurveConfig {
+            points: points
+                .iter()
+                .map(|&(temperature_c, duty)| CurvePoint::new(temperature_c, duty))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn assert_monotone(curve: &FanCurve, from_c: f32, to_c: f32) {
+        let mut last = curve.duty_at(from_c);
+        let mut temperature_c = from_c;
+        while temperature_c <= to_c {
+            let duty = curve.duty_at(temperature_c);
+            // Allowing for rounding where the curve is flat
+            assert!(
+                duty >= last - 1e-4,
+                "{duty} after {last} at {temperature_c}"
+            );
+            last = duty;
+            temperature_c += 0.1;
+        }
+    }
+
+    #[test]
+    fn linear_between_points() {
+        let curve = curve(Interpolation::Linear);
+        assert_eq!(curve.duty_at(30.0), 20.0);
+        assert_eq!(curve.duty_at(40.0), 35.0);
+        assert_eq!(curve.duty_at(50.0), 50.0);
+        assert_eq!(curve.duty_at(60.0), 75.0);
+        assert_eq!(curve.duty_at(70.0), 100.0);
+    }
+
+    #[test]
+    fn clamped_outside_the_points() {
+        for interpolation in [Interpolation::Linear, Interpolation::MonotoneCubic] {
+            let curve = curve(interpolation);
+            assert_eq!(curve.duty_at(-40.0), 20.0);
+            assert_eq!(curve.duty_at(29.9), 20.0);
+            assert_eq!(curve.duty_at(70.1), 100.0);
+            assert_eq!(curve.duty_at(150.0), 100.0);
+        }
+    }
+
+    #[test]
+    fn monotone_cubic_through_the_points() {
+        let curve = curve(Interpolation::MonotoneCubic);
+        assert_eq!(curve.duty_at(30.0), 20.0);
+        assert!((curve.duty_at(50.0) - 50.0).abs() < 1e-4);
+        assert_eq!(curve.duty_at(70.0), 100.0);
+        // Tangents 1.5, 2 and 2.5 %/°C, so it bends below the straight line
+        assert!((curve.duty_at(40.0) - 33.75).abs() < 1e-4);
+        assert_monotone(&curve, 20.0, 80.0);
+    }
+
+    #[test]
+    fn monotone_cubic_does_not_overshoot() {
+        let curve = config(&[(30.0, 20.0), (40.0, 20.0), (50.0, 100.0), (60.0, 100.0)]);
+        let curve = FanCurve::new(curve.points, Interpolation::MonotoneCubic).unwrap();
+        assert_monotone(&curve, 20.0, 70.0);
+        for temperature_c in [32.0, 35.0, 38.0] {
+            assert!((curve.duty_at(temperature_c) - 20.0).abs() < 1e-4);
+        }
+        for temperature_c in [52.0, 55.0, 58.0] {
+            assert!((curve.duty_at(temperature_c) - 100.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(CurveConfig::default().to_curve().is_ok());
+        assert_eq!(
+            config(&[]).to_curve().unwrap_err(),
+            CurveError::TooFewPoints
+        );
+        assert_eq!(
+            config(&[(40.0, 50.0)]).to_curve().unwrap_err(),
+            CurveError::TooFewPoints
+        );
+        assert_eq!(
+            config(&[(50.0, 50.0), (30.0, 20.0)])
+                .to_curve()
+                .unwrap_err(),
+            CurveError::UnsortedPoints
+        );
+        assert_eq!(
+            config(&[(30.0, 20.0), (30.0, 50.0)])
+                .to_curve()
+                .unwrap_err(),
+            CurveError::UnsortedPoints
+        );
+        assert_eq!(
+            config(&[(30.0, 20.0), (f32::NAN, 50.0)])
+                .to_curve()
+                .unwrap_err(),
+            CurveError::NotFinite
+        );
+        assert_eq!(
+            config(&[(30.0, f32::NAN), (50.0, 50.0)])
+                .to_curve()
+                .unwrap_err(),
+            CurveError::NotFinite
+        );
+        assert_eq!(
+            config(&[(30.0, 20.0), (50.0, 101.0)])
+                .to_curve()
+                .unwrap_err(),
+            CurveError::DutyOutOfRange(101.0)
+        );
+
+        for (hysteresis_c, error) in [
+            (f32::NAN, CurveError::NotFinite),
+            (-1.0, CurveError::NegativeHysteresis),
+        ] {
+            let config = CurveConfig {
+                hysteresis_c,
+                ..Default::default()
+            };
+            assert_eq!(config.to_curve().unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn follower_rises_right_away() {
+        let mut follower = CurveFollower::new(CurveConfig::default()).unwrap();
+        assert_eq!(follower.update(40.0, 0), 35.0);
+        // Well within the hold time
+        assert_eq!(follower.update(50.0, 1_000), 50.0);
+        assert_eq!(follower.update(60.0, 1_100), 75.0);
+    }
+
+    #[test]
+    fn follower_hysteresis_when_falling() {
+        let mut follower = CurveFollower::new(CurveConfig::default()).unwrap();
+        assert_eq!(follower.update(50.0, 0), 50.0);
+        // Within 2 °C of where it rose to
+        assert_eq!(follower.update(49.0, 20_000), 50.0);
+        assert_eq!(follower.update(48.0, 20_000), 50.0);
+        // Follows the curve 2 °C warmer
+        assert_eq!(follower.update(47.0, 20_000), 48.5);
+    }
+
+    #[test]
+    fn follower_holds_before_lowering_again() {
+        let mut follower = CurveFollower::new(CurveConfig::default()).unwrap();
+        assert_eq!(follower.update(50.0, 0), 50.0);
+        assert_eq!(follower.update(40.0, 9_999), 50.0);
+        assert_eq!(follower.update(40.0, 10_000), 38.0);
+        assert_eq!(follower.update(35.0, 15_000), 38.0);
+        assert_eq!(follower.update(35.0, 20_000), 30.5);
+        // Rising isn't held back
+        assert_eq!(follower.update(45.0, 20_500), 42.5);
+    }
+}
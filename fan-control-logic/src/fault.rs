@@ -0,0 +1,275 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Something wrong with a fan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    /// The fan used to spin, but doesn't anymore even though it's told to
+    Stall,
+    /// Never seen a single tacho pulse while the fan should be spinning. Looks
+    /// the same as a stall from the outside, but a fan that has never reported any
+    /// RPM since boot is far more likely to have a loose tacho wire.
+    TachoMissing,
+    /// Faster than any fan we expect, usually noise on the tacho wire
+    OverSpeed,
+}
+
+impl Alarm {
+    pub const ALL: [Alarm; 3] = [Alarm::Stall, Alarm::TachoMissing, Alarm::OverSpeed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Alarm::Stall => "stall",
+            Alarm::TachoMissing => "tacho_missing",
+            Alarm::OverSpeed => "over_speed",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            Alarm::Stall => 1 << 0,
+            Alarm::TachoMissing => 1 << 1,
+            Alarm::OverSpeed => 1 << 2,
+        }
+    }
+}
+
+/// Set of active alarms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Alarms(u8);
+
+impl Alarms {
+    pub fn contains(self, alarm: Alarm) -> bool {
+        self.0 & alarm.bit() != 0
+    }
+
+    pub fn insert(&mut self, alarm: Alarm) {
+        self.0 |= alarm.bit();
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Alarm> {
+        Alarm::ALL
+            .into_iter()
+            .filter(move |&alarm| self.contains(alarm))
+    }
+}
+
+/// [`Alarms`] that can be shared between threads
+#[derive(Debug, Default)]
+pub struct AtomicAlarms(AtomicU8);
+
+impl AtomicAlarms {
+    pub fn load(&self, order: Ordering) -> Alarms {
+        Alarms(self.0.load(order))
+    }
+
+    pub fn store(&self, alarms: Alarms, order: Ordering) {
+        self.0.store(alarms.0, order);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// At or above this duty the fan has to spin
+    pub min_spinning_duty: u32,
+    /// Anything below this counts as not spinning
+    pub min_spinning_rpm: u32,
+    /// How long the fan may not spin before raising an alarm. Has to cover
+    /// spinning up from standstill.
+    pub stall_ms: u64,
+    pub max_rpm: u32,
+    pub over_speed_ms: u64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            min_spinning_duty: 20,
+            min_spinning_rpm: 100,
+            stall_ms: 5_000,
+            max_rpm: 5_000,
+            over_speed_ms: 3_000,
+        }
+    }
+}
+
+/// Compares commanded duty with measured RPM over time.
+///
+/// Feed it every new RPM reading. Conditions have to hold for a while before an
+/// alarm is raised, and alarms clear as soon as the condition is gone.
+#[derive(Debug, Clone)]
+pub struct FaultDetector {
+    config: FaultConfig,
+    /// Has the fan ever reported RPM since boot?
+    seen_spinning: bool,
+    not_spinning_since_ms: Option<u64>,
+    over_speed_since_ms: Option<u64>,
+}
+
+impl FaultDetector {
+    pub fn new(config: FaultConfig) -> Self {
+        Self {
+            config,
+            seen_spinning: false,
+            not_spinning_since_ms: None,
+            over_speed_since_ms: None,
+        }
+    }
+
+    pub fn update(&mut self, duty: u32, rpm: u32, now_ms: u64) -> Alarms {
+        let config = self.config;
+        let mut alarms = Alarms::default();
+
+        let spinning = rpm >= config.min_spinning_rpm;
+        if spinning {
+            self.seen_spinning = true;
+        }
+
+        let should_spin = duty >= config.min_spinning_duty;
+        if should_spin && !spinning {
+            let since = *self.not_spinning_since_ms.get_or_insert(now_ms);
+            if now_ms.saturating_sub(since) >= config.stall_ms {
+                alarms.insert(if self.seen_spinning {
+                    Alarm::Stall
+                } else {
+                    Alarm::TachoMissing
+                });
+            }
+        } else {
+            self.not_spinning_since_ms = None;
+        }
+
+        if rpm > config.max_rpm {
+            let since = *self.over_speed_since_ms.get_or_insert(now_ms);
+            if now_ms.saturating_sub(since) >= config.over_speed_ms {
+                alarms.insert(Alarm::OverSpeed);
+            }
+        } else {
+            self.over_speed_since_ms = None;
+        }
+
+        alarms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarms(alarms: &[Alarm]) -> Alarms {
+        let mut set = Alarms::default();
+        for &alarm in alarms {
+            set.insert(alarm);
+        }
+        set
+    }
+
+    #[test]
+    fn healthy_fan() {
+        let mut detector = FaultDetector::new(FaultConfig::default());
+        for now_ms in (0..20_000).step_by(1_000) {
+            assert!(detector.update(50, 1_200, now_ms).is_empty());
+        }
+    }
+
+    #[test]
+    fn stall_after_spinning() {
+        let mut detector = FaultDetector::new(FaultConfig::default());
+        detector.update(50, 1_200, 0);
+        assert!(detector.update(50, 0, 1_000).is_empty());
+        assert!(detector.update(50, 0, 5_999).is_empty());
+        assert_eq!(detector.update(50, 0, 6_000), alarms(&[Alarm::Stall]));
+        // Clears as soon as it spins again
+        assert!(detector.update(50, 900, 7_000).is_empty());
+        // And the timer starts over
+        assert!(detector.update(50, 0, 8_000).is_empty());
+        assert!(detector.update(50, 0, 12_999).is_empty());
+        assert_eq!(detector.update(50, 0, 13_000), alarms(&[Alarm::Stall]));
+    }
+
+    #[test]
+    fn tacho_missing_without_a_pulse_since_boot() {
+        let mut detector = FaultDetector::new(FaultConfig::default());
+        assert!(detector.update(50, 0, 0).is_empty());
+        assert!(detector.update(50, 0, 4_999).is_empty());
+        assert_eq!(
+            detector.update(50, 0, 5_000),
+            alarms(&[Alarm::TachoMissing])
+        );
+        // RPM below the threshold is no proof the tacho works
+        assert_eq!(
+            detector.update(50, 99, 6_000),
+            alarms(&[Alarm::TachoMissing])
+        );
+    }
+
+    #[test]
+    fn low_duty_may_stop_the_fan() {
+        let mut detector = FaultDetector::new(FaultConfig::default());
+        detector.update(50, 1_200, 0);
+        for now_ms in (1_000..20_000).step_by(1_000) {
+            assert!(detector.update(19, 0, now_ms).is_empty());
+        }
+        // Turning the duty up starts the stall timer only then
+        assert!(detector.update(20, 0, 20_000).is_empty());
+        assert!(detector.update(20, 0, 24_999).is_empty());
+        assert_eq!(detector.update(20, 0, 25_000), alarms(&[Alarm::Stall]));
+        // Turning it down again clears it
+        assert!(detector.update(0, 0, 25_100).is_empty());
+    }
+
+    #[test]
+    fn over_speed() {
+        let mut detector = FaultDetector::new(FaultConfig::default());
+        assert!(detector.update(50, 5_000, 0).is_empty());
+        assert!(detector.update(50, 5_001, 1_000).is_empty());
+        assert!(detector.update(50, 9_000, 3_999).is_empty());
+        assert_eq!(
+            detector.update(50, 9_000, 4_000),
+            alarms(&[Alarm::OverSpeed])
+        );
+        assert!(detector.update(50, 1_200, 4_500).is_empty());
+        // A single spike doesn't raise it
+        assert!(detector.update(50, 9_000, 5_000).is_empty());
+        assert!(detector.update(50, 1_200, 6_000).is_empty());
+        assert!(detector.update(50, 9_000, 8_500).is_empty());
+    }
+
+    #[test]
+    fn custom_thresholds() {
+        let mut detector = FaultDetector::new(FaultConfig {
+            min_spinning_duty: 30,
+            min_spinning_rpm: 300,
+            stall_ms: 1_000,
+            max_rpm: 2_000,
+            over_speed_ms: 0,
+        });
+        detector.update(30, 300, 0);
+        assert!(detector.update(29, 0, 100).is_empty());
+        assert!(detector.update(30, 299, 200).is_empty());
+        assert_eq!(detector.update(30, 299, 1_200), alarms(&[Alarm::Stall]));
+        assert_eq!(
+            detector.update(30, 2_001, 1_300),
+            alarms(&[Alarm::OverSpeed])
+        );
+    }
+
+    #[test]
+    fn alarm_set() {
+        let set = alarms(&[Alarm::OverSpeed, Alarm::Stall]);
+        assert!(set.contains(Alarm::Stall));
+        assert!(!set.contains(Alarm::TachoMissing));
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [Alarm::Stall, Alarm::OverSpeed]
+        );
+
+        let shared = AtomicAlarms::default();
+        assert!(shared.load(Ordering::Relaxed).is_empty());
+        shared.store(set, Ordering::Relaxed);
+        assert_eq!(shared.load(Ordering::Relaxed), set);
+    }
+}
@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+
+/// How rotary encoder counts turn into duty cycle changes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KnobConfig {
+    /// Quadrature counts between two clicks of the knob, usually 4, some encoders
+    /// have 2 or 1
+    pub counts_per_detent: u32,
+    /// % per detent when turning slowly
+    pub slow_step: u32,
+    /// % per detent when spinning the knob fast
+    pub fast_step: u32,
+    /// Up to this speed every detent is a `slow_step`
+    pub slow_detents_per_sec: f32,
+    /// From this speed on every detent is a `fast_step`, in between the step grows
+    /// linearly
+    pub fast_detents_per_sec: f32,
+}
+
+impl Default for KnobConfig {
+    fn default() -> Self {
+        Self {
+            counts_per_detent: 4,
+            slow_step: 1,
+            fast_step: 5,
+            slow_detents_per_sec: 4.0,
+            fast_detents_per_sec: 15.0,
+        }
+    }
+}
+
+impl KnobConfig {
+    pub fn validate(&self) -> Result<(), KnobError> {
+        if self.counts_per_detent == 0 || self.slow_step == 0 || self.fast_step < self.slow_step {
+            return Err(KnobError::InvalidStep);
+        }
+        let valid = |speed: f32| speed.is_finite() && speed >= 0.0;
+        if !valid(self.slow_detents_per_sec)
+            || !valid(self.fast_detents_per_sec)
+            || self.fast_detents_per_sec <= self.slow_detents_per_sec
+        {
+            return Err(KnobError::InvalidSpeed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KnobError {
+    InvalidStep,
+    InvalidSpeed,
+}
+
+impl std::fmt::Display for KnobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KnobError::InvalidStep => write!(
+                f,
+                "Counts per detent and steps must be at least 1, with the fast step at least the slow one"
+            ),
+            KnobError::InvalidSpeed => {
+                write!(f, "Speeds must be positive, with the fast speed above the slow one")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KnobError {}
+
+/// % per detent when turning at `detents_per_sec`
+pub fn detent_step(config: &KnobConfig, detents_per_sec: f32) -> u32 {
+    let range = config.fast_detents_per_sec - config.slow_detents_per_sec;
+    let t = ((detents_per_sec - config.slow_detents_per_sec) / range).clamp(0.0, 1.0);
+    let extra = (config.fast_step.saturating_sub(config.slow_step)) as f32 * t;
+    config.slow_step + extra.round() as u32
+}
+
+/// Turns encoder counts into duty cycle changes, one step per detent, with bigger
+/// steps the faster the knob turns.
+#[derive(Debug, Clone)]
+pub struct Knob {
+    config: KnobConfig,
+    /// Counts that don't add up to a detent yet
+    remainder: i32,
+    last_detent_ms: Option<u64>,
+}
+
+impl Knob {
+    /// Detents further apart than this are turned slowly, however many there are
+    const SLOW_AFTER_MS: u64 = 1000;
+
+    pub fn new(config: KnobConfig) -> Self {
+        Self {
+            config,
+            remainder: 0,
+            last_detent_ms: None,
+        }
+    }
+
+    pub fn config(&self) -> KnobConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: KnobConfig) {
+        if config.counts_per_detent != self.config.counts_per_detent {
+            self.remainder = 0;
+        }
+        self.config = config;
+    }
+
+    /// `counts` since the previous update, returns the change in %
+    pub fn update(&mut self, counts: i32, now_ms: u64) -> i32 {
+        let detents = self.detents(counts);
+        if detents == 0 {
+            return 0;
+        }
+
+        let detents_per_sec = match self.last_detent_ms {
+            Some(last_ms) if now_ms - last_ms < Self::SLOW_AFTER_MS => {
+                detents.unsigned_abs() as f32 * 1000.0 / (now_ms - last_ms).max(1) as f32
+            }
+            _ => 0.0,
+        };
+        self.last_detent_ms = Some(now_ms);
+        detents * detent_step(&self.config, detents_per_sec) as i32
+    }
+
+    /// Like [`Knob::update`], but always a `slow_step` per detent
+    pub fn update_fine(&mut self, counts: i32) -> i32 {
+        self.detents(counts) * self.config.slow_step as i32
+    }
+
+    /// Whole detents in `counts` and what's left over from before, for menus that
+    /// move one item per detent
+    pub fn detents(&mut self, counts: i32) -> i32 {
+        let counts_per_detent = self.config.counts_per_detent.max(1) as i32;
+        self.remainder += counts;
+        let detents = self.remainder / counts_per_detent;
+        self.remainder %= counts_per_detent;
+        detents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_grows_with_speed() {
+        let config = KnobConfig::default();
+        assert_eq!(detent_step(&config, 0.0), 1);
+        assert_eq!(detent_step(&config, 4.0), 1);
+        // Halfway between slow and fast
+        assert_eq!(detent_step(&config, 9.5), 3);
+        assert_eq!(detent_step(&config, 15.0), 5);
+        assert_eq!(detent_step(&config, 100.0), 5);
+    }
+
+    #[test]
+    fn slow_turning() {
+        let mut knob = Knob::new(KnobConfig::default());
+        assert_eq!(knob.update(4, 0), 1);
+        assert_eq!(knob.update(4, 500), 1);
+        assert_eq!(knob.update(-4, 1_000), -1);
+        // After a pause even a burst of detents counts as slow
+        assert_eq!(knob.update(8, 5_000), 2);
+    }
+
+    #[test]
+    fn fast_turning() {
+        let mut knob = Knob::new(KnobConfig::default());
+        assert_eq!(knob.update(4, 0), 1);
+        // 2 detents in 50ms is 40 detents/s
+        assert_eq!(knob.update(8, 50), 10);
+        assert_eq!(knob.update(-8, 100), -10);
+    }
+
+    #[test]
+    fn interpolated_step() {
+        let mut knob = Knob::new(KnobConfig::default());
+        knob.update(4, 0);
+        // 10 detents/s, 6/11 of the way from slow to fast
+        assert_eq!(knob.update(4, 100), 3);
+    }
+
+    #[test]
+    fn counts_carry_over() {
+        let mut knob = Knob::new(KnobConfig::default());
+        assert_eq!(knob.update(3, 0), 0);
+        assert_eq!(knob.update(1, 2_000), 1);
+        assert_eq!(knob.update(-3, 4_000), 0);
+        assert_eq!(knob.update(-2, 6_000), -1);
+        // -1 left over, turning back cancels it out first
+        assert_eq!(knob.update(4, 8_000), 0);
+        assert_eq!(knob.update(1, 10_000), 1);
+    }
+
+    #[test]
+    fn detents_and_fine_steps() {
+        let mut knob = Knob::new(KnobConfig {
+            slow_step: 2,
+            ..KnobConfig::default()
+        });
+        assert_eq!(knob.detents(9), 2);
+        assert_eq!(knob.detents(-6), -1);
+        assert_eq!(knob.update_fine(-3), -2);
+        assert_eq!(knob.update_fine(4), 2);
+    }
+
+    #[test]
+    fn new_counts_per_detent_drops_remainder() {
+        let mut knob = Knob::new(KnobConfig::default());
+        assert_eq!(knob.detents(3), 0);
+        knob.set_config(KnobConfig {
+            counts_per_detent: 2,
+            ..KnobConfig::default()
+        });
+        assert_eq!(knob.detents(1), 0);
+        assert_eq!(knob.detents(1), 1);
+
+        // Other changes keep it
+        assert_eq!(knob.detents(1), 0);
+        knob.set_config(KnobConfig {
+            counts_per_detent: 2,
+            fast_step: 10,
+            ..KnobConfig::default()
+        });
+        assert_eq!(knob.detents(1), 1);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(KnobConfig::default().validate().is_ok());
+        let invalid = [
+            KnobConfig {
+                counts_per_detent: 0,
+                ..KnobConfig::default()
+            },
+            KnobConfig {
+                fast_step: 0,
+                ..KnobConfig::default()
+            },
+            KnobConfig {
+                fast_detents_per_sec: 4.0,
+                ..KnobConfig::default()
+            },
+            KnobConfig {
+                slow_detents_per_sec: f32::NAN,
+                ..KnobConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
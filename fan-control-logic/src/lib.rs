@@ -0,0 +1,29 @@
+//! Hardware independent fan control logic.
+//!
+//! Everything in here is plain Rust without any ESP-IDF dependencies, so it can
+//! be developed and tested on the host machine.
+
+pub mod boost;
+pub mod button;
+pub mod calibration;
+pub mod control;
+pub mod curve;
+pub mod fault;
+pub mod knob;
+pub mod metrics;
+pub mod mode;
+pub mod mqtt;
+pub mod pid;
+pub mod profile;
+pub mod schedule;
+pub mod sensor;
+pub mod settings;
+pub mod simulation;
+pub mod slew;
+pub mod spin_up;
+pub mod supervisor;
+pub mod tacho;
+pub mod telemetry;
+pub mod temperature;
+pub mod timezone;
+pub mod zero_rpm;
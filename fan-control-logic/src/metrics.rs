@@ -0,0 +1,259 @@
+//! Metrics in the Prometheus text format, or OpenMetrics for scrapers that ask
+//! for it.
+
+use std::fmt::Write;
+
+/// Frame times of the screen, summarised every few frames
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderTimings {
+    /// Of the last batch of frames, `None` until the first is done
+    pub last: Option<TimingSummary>,
+    /// Frames since boot
+    pub count: u64,
+    /// Time spent rendering them
+    pub sum_ms: u64,
+}
+
+impl RenderTimings {
+    /// Adds a batch of frame times
+    pub fn record(&mut self, samples_ms: &mut [u32]) {
+        if let Some(summary) = TimingSummary::from_samples(samples_ms) {
+            self.last = Some(summary);
+            self.count += samples_ms.len() as u64;
+            self.sum_ms += samples_ms.iter().map(|&ms| u64::from(ms)).sum::<u64>();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingSummary {
+    pub min_ms: u32,
+    pub max_ms: u32,
+    pub avg_ms: u32,
+    pub p50_ms: u32,
+    pub p90_ms: u32,
+    pub p99_ms: u32,
+}
+
+impl TimingSummary {
+    /// Sorts `samples_ms`, `None` if there are none
+    pub fn from_samples(samples_ms: &mut [u32]) -> Option<Self> {
+        if samples_ms.is_empty() {
+            return None;
+        }
+        samples_ms.sort_unstable();
+        // In integers, 0.9 isn't exact as a float and 100 samples would give the 90th
+        let percentile = |percent: usize| samples_ms[samples_ms.len() * percent / 100];
+        let sum: u64 = samples_ms.iter().map(|&ms| u64::from(ms)).sum();
+        Some(Self {
+            min_ms: samples_ms[0],
+            max_ms: samples_ms[samples_ms.len() - 1],
+            avg_ms: (sum / samples_ms.len() as u64) as u32,
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+        })
+    }
+
+    /// Quantiles as a Prometheus summary has them, the minimum is 0 and the
+    /// maximum 1
+    pub fn quantiles(&self) -> [(f32, u32); 5] {
+        [
+            (0.0, self.min_ms),
+            (0.5, self.p50_ms),
+            (0.9, self.p90_ms),
+            (0.99, self.p99_ms),
+            (1.0, self.max_ms),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Gauge,
+    Summary,
+}
+
+impl MetricType {
+    fn name(self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Summary => "summary",
+        }
+    }
+}
+
+/// Writes one metric family after the other
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    text: String,
+    openmetrics: bool,
+}
+
+/// Content types of the two formats
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+impl MetricsWriter {
+    /// OpenMetrics if the scraper's `Accept` header asks for it
+    pub fn for_accept(accept: Option<&str>) -> Self {
+        Self {
+            text: String::new(),
+            openmetrics: accept
+                .is_some_and(|accept| accept.contains("application/openmetrics-text")),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        if self.openmetrics {
+            OPENMETRICS_CONTENT_TYPE
+        } else {
+            PROMETHEUS_CONTENT_TYPE
+        }
+    }
+
+    /// Starts a metric family, its samples follow
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) -> &mut Self {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {}", metric_type.name());
+        self
+    }
+
+    /// Adds a sample to the family started last
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.text.push(',');
+                }
+                let _ = write!(self.text, "{label}=\"{}\"", escape(value));
+            }
+            self.text.push('}');
+        }
+        // Rust writes infinity as `inf`, the formats want `+Inf`
+        let _ = if value.is_finite() {
+            writeln!(self.text, " {value}")
+        } else if value.is_nan() {
+            writeln!(self.text, " NaN")
+        } else if value > 0.0 {
+            writeln!(self.text, " +Inf")
+        } else {
+            writeln!(self.text, " -Inf")
+        };
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        if self.openmetrics {
+            self.text.push_str("# EOF\n");
+        }
+        self.text
+    }
+}
+
+/// Label values are quoted, with backslashes, quotes and newlines escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_one_sample() {
+        let summary = TimingSummary::from_samples(&mut [7]).unwrap();
+        assert_eq!(summary.quantiles().map(|(_, ms)| ms), [7; 5]);
+        assert_eq!(summary.avg_ms, 7);
+        assert_eq!(TimingSummary::from_samples(&mut []), None);
+    }
+
+    #[test]
+    fn summary_of_100_samples() {
+        let mut samples: Vec<u32> = (1..=100).rev().collect();
+        let summary = TimingSummary::from_samples(&mut samples).unwrap();
+        assert_eq!(
+            summary,
+            TimingSummary {
+                min_ms: 1,
+                max_ms: 100,
+                avg_ms: 50,
+                p50_ms: 51,
+                p90_ms: 91,
+                p99_ms: 100,
+            }
+        );
+        assert_eq!(samples[0], 1);
+    }
+
+    #[test]
+    fn render_timings_add_up() {
+        let mut timings = RenderTimings::default();
+        timings.record(&mut [10, 30]);
+        timings.record(&mut []);
+        timings.record(&mut [20]);
+        assert_eq!(timings.count, 3);
+        assert_eq!(timings.sum_ms, 60);
+        assert_eq!(timings.last.unwrap().max_ms, 20);
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let mut writer = MetricsWriter::for_accept(Some("text/plain"));
+        writer
+            .family("fan_rpm", MetricType::Gauge, "Fan speed")
+            .sample("fan_rpm", &[("fan", "0")], 1200.0)
+            .sample("fan_rpm", &[("fan", "1"), ("name", "Rear")], 850.5);
+        assert_eq!(writer.content_type(), PROMETHEUS_CONTENT_TYPE);
+        assert_eq!(
+            writer.finish(),
+            "# HELP fan_rpm Fan speed\n\
+             # TYPE fan_rpm gauge\n\
+             fan_rpm{fan=\"0\"} 1200\n\
+             fan_rpm{fan=\"1\",name=\"Rear\"} 850.5\n"
+        );
+    }
+
+    #[test]
+    fn openmetrics_ends_with_eof() {
+        for accept in [
+            "application/openmetrics-text; version=1.0.0",
+            "application/openmetrics-text;version=0.0.1,text/plain;q=0.5",
+        ] {
+            let mut writer = MetricsWriter::for_accept(Some(accept));
+            writer.sample("up", &[], 1.0);
+            assert_eq!(writer.content_type(), OPENMETRICS_CONTENT_TYPE);
+            assert_eq!(writer.finish(), "up 1\n# EOF\n");
+        }
+        let writer = MetricsWriter::for_accept(None);
+        assert_eq!(writer.content_type(), PROMETHEUS_CONTENT_TYPE);
+        assert_eq!(writer.finish(), "");
+    }
+
+    #[test]
+    fn label_values_escaped() {
+        let mut writer = MetricsWriter::default();
+        writer.sample("profile", &[("name", "a\\b \"c\"\nd")], 1.0);
+        assert_eq!(
+            writer.finish(),
+            concat!(r#"profile{name="a\\b \"c\"\nd"} 1"#, "\n")
+        );
+    }
+
+    #[test]
+    fn non_finite_values() {
+        let mut writer = MetricsWriter::default();
+        writer
+            .sample("a", &[], f64::INFINITY)
+            .sample("b", &[], f64::NEG_INFINITY)
+            .sample("c", &[], f64::NAN)
+            .sample("d", &[], -0.25);
+        assert_eq!(writer.finish(), "a +Inf\nb -Inf\nc NaN\nd -0.25\n");
+    }
+}
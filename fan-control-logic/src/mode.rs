@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// How the fan duty cycle is decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMode {
+    /// Duty cycle is set directly (rotary knob, `POST /pwm`)
+    #[default]
+    Manual,
+    /// Duty cycle is adjusted by a PID controller to hold a target RPM
+    TargetRpm,
+    /// Duty cycle follows the temperature through a fan curve
+    Curve,
+    /// Duty cycle is swept through the whole range to characterise the fan,
+    /// goes back to the previous mode when done
+    Calibration,
+}
+
+impl ControlMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlMode::Manual => "manual",
+            ControlMode::TargetRpm => "target_rpm",
+            ControlMode::Curve => "curve",
+            ControlMode::Calibration => "calibration",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ControlMode::TargetRpm,
+            2 => ControlMode::Curve,
+            3 => ControlMode::Calibration,
+            _ => ControlMode::Manual,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            ControlMode::Manual => 0,
+            ControlMode::TargetRpm => 1,
+            ControlMode::Curve => 2,
+            ControlMode::Calibration => 3,
+        }
+    }
+}
+
+/// [`ControlMode`] that can be shared between threads, like the other atomics
+/// in the interface state.
+#[derive(Debug, Default)]
+pub struct AtomicControlMode(AtomicU8);
+
+impl AtomicControlMode {
+    pub fn new(mode: ControlMode) -> Self {
+        Self(AtomicU8::new(mode.as_u8()))
+    }
+
+    pub fn load(&self, order: Ordering) -> ControlMode {
+        ControlMode::from_u8(self.0.load(order))
+    }
+
+    pub fn store(&self, mode: ControlMode, order: Ordering) {
+        self.0.store(mode.as_u8(), order);
+    }
+}
@@ -0,0 +1,681 @@
+//! MQTT topics and Home Assistant discovery.
+//!
+//! Everything lives under the configured base topic, e.g. with `fan-control`:
+//!
+//! - `fan-control/availability`: `online`, or `offline` when the controller is gone
+//! - `fan-control/fan/0/rpm`, `.../duty`, `.../applied_duty`, `.../mode`,
+//!   `.../alarms` and `.../power` (`ON`/`OFF`)
+//! - `fan-control/temperature`, `fan-control/profile` and `fan-control/changed_via`
+//!
+//! Commands go to the same topic with `/set` appended: `fan/0/duty/set` (0-100),
+//! `fan/0/power/set` (`ON`/`OFF`), `fan/0/mode/set` and `profile/set`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{mode::ControlMode, telemetry::MIN_INTERVAL_MS};
+
+/// Broker and topics, changeable at runtime
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    /// e.g. `mqtt://192.168.1.10:1883`, or `mqtts://` for TLS
+    pub url: String,
+    /// Empty to connect without logging in
+    pub username: String,
+    pub password: String,
+    /// Prefix of every topic, also what Home Assistant tells devices apart by
+    pub base_topic: String,
+    /// Where Home Assistant looks for devices, empty to not announce any
+    pub discovery_prefix: String,
+    /// Never publish changes more often than this
+    pub min_interval_ms: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "mqtt://homeassistant.local:1883".to_string(),
+            username: String::new(),
+            password: String::new(),
+            base_topic: "fan-control".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            min_interval_ms: 2000,
+        }
+    }
+}
+
+const URL_SCHEMES: [&str; 4] = ["mqtt://", "mqtts://", "ws://", "wss://"];
+
+/// Payloads of the availability topic
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+/// Home Assistant has no value for a sensor or select
+pub const NONE: &str = "None";
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), MqttError> {
+        let host = URL_SCHEMES
+            .iter()
+            .find_map(|scheme| self.url.strip_prefix(scheme));
+        if host.map_or(true, str::is_empty) {
+            return Err(MqttError::InvalidUrl);
+        }
+        if !valid_topic(&self.base_topic) {
+            return Err(MqttError::InvalidTopic("base_topic"));
+        }
+        if !self.discovery_prefix.is_empty() && !valid_topic(&self.discovery_prefix) {
+            return Err(MqttError::InvalidTopic("discovery_prefix"));
+        }
+        if self.min_interval_ms < MIN_INTERVAL_MS {
+            return Err(MqttError::IntervalTooShort);
+        }
+        Ok(())
+    }
+
+    /// The password is write-only, it's blanked wherever the settings are read
+    pub fn redacted(&self) -> Self {
+        Self {
+            password: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Settings that were read back have no password, leaving it empty keeps the
+    /// one in `current`
+    pub fn keep_password(&mut self, current: &MqttConfig) {
+        if self.password.is_empty() {
+            self.password.clone_from(&current.password);
+        }
+    }
+
+    pub fn topics(&self) -> Topics<'_> {
+        Topics {
+            base: &self.base_topic,
+        }
+    }
+
+    /// Home Assistant announces itself here when it (re)starts
+    pub fn birth_topic(&self) -> Option<String> {
+        (!self.discovery_prefix.is_empty()).then(|| format!("{}/status", self.discovery_prefix))
+    }
+
+    /// Client ID, and what every entity's unique ID starts with
+    pub fn node_id(&self) -> String {
+        self.base_topic.replace('/', "_")
+    }
+}
+
+/// Topics that can be published to and subscribed to as they are
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && !topic.starts_with('/')
+        && !topic.ends_with('/')
+        && !topic.contains(['+', '#', '\0'])
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttError {
+    InvalidUrl,
+    /// Name of the setting
+    InvalidTopic(&'static str),
+    IntervalTooShort,
+    /// Not one of the command topics
+    UnknownTopic(String),
+    InvalidPayload(String),
+}
+
+impl std::fmt::Display for MqttError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MqttError::InvalidUrl => write!(
+                f,
+                "The broker URL must start with one of {} and name a host",
+                URL_SCHEMES.join(", ")
+            ),
+            MqttError::InvalidTopic(setting) => write!(
+                f,
+                "{setting} can't be empty, start or end with / or contain + or #"
+            ),
+            MqttError::IntervalTooShort => write!(
+                f,
+                "The minimum interval must be at least {MIN_INTERVAL_MS} ms"
+            ),
+            MqttError::UnknownTopic(topic) => write!(f, "Unknown topic {topic}"),
+            MqttError::InvalidPayload(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+/// Names of the topics under one base topic
+#[derive(Debug, Clone, Copy)]
+pub struct Topics<'a> {
+    base: &'a str,
+}
+
+impl Topics<'_> {
+    pub fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    /// State of one fan, e.g. `fan(0, "rpm")`
+    pub fn fan(&self, fan: usize, field: &str) -> String {
+        format!("{}/fan/{fan}/{field}", self.base)
+    }
+
+    /// State of the whole controller, e.g. `device("temperature")`
+    pub fn device(&self, field: &str) -> String {
+        format!("{}/{field}", self.base)
+    }
+
+    /// What to subscribe to for every command
+    pub fn command_filters(&self) -> [String; 2] {
+        [
+            format!("{}/fan/+/+/set", self.base),
+            format!("{}/profile/set", self.base),
+        ]
+    }
+
+    /// Makes sense of a message to one of the command topics, fans from
+    /// `fan_count` on don't have any
+    pub fn parse_command(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        fan_count: usize,
+    ) -> Result<MqttCommand, MqttError> {
+        let unknown = || MqttError::UnknownTopic(topic.to_string());
+        let path = topic
+            .strip_prefix(self.base)
+            .and_then(|path| path.strip_prefix('/'))
+            .and_then(|path| path.strip_suffix("/set"))
+            .ok_or_else(unknown)?;
+        let payload = std::str::from_utf8(payload)
+            .map_err(|_| MqttError::InvalidPayload("Payload isn't text".to_string()))?
+            .trim();
+
+        if path == "profile" {
+            return Ok(MqttCommand::Profile(payload.to_string()));
+        }
+        let (fan, field) = path
+            .strip_prefix("fan/")
+            .and_then(|path| path.split_once('/'))
+            .ok_or_else(unknown)?;
+        let fan = fan
+            .parse()
+            .ok()
+            .filter(|fan| *fan < fan_count)
+            .ok_or_else(unknown)?;
+        match field {
+            "duty" => {
+                let percent = payload
+                    .parse()
+                    .ok()
+                    .filter(|percent| *percent <= 100)
+                    .ok_or_else(|| {
+                        MqttError::InvalidPayload(format!("Duty must be 0-100, not {payload:?}"))
+                    })?;
+                Ok(MqttCommand::Duty { fan, percent })
+            }
+            "power" => match payload {
+                "ON" => Ok(MqttCommand::Power { fan, on: true }),
+                "OFF" => Ok(MqttCommand::Power { fan, on: false }),
+                _ => Err(MqttError::InvalidPayload(format!(
+                    "Power must be ON or OFF, not {payload:?}"
+                ))),
+            },
+            "mode" => {
+                let mode = serde_json::from_value(Value::String(payload.to_string()))
+                    .ok()
+                    .filter(|mode| SELECTABLE_MODES.contains(mode))
+                    .ok_or_else(|| {
+                        MqttError::InvalidPayload(format!("Unknown mode {payload:?}"))
+                    })?;
+                Ok(MqttCommand::Mode { fan, mode })
+            }
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Something a command topic asks for
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttCommand {
+    /// Manual mode at this duty
+    Duty {
+        fan: usize,
+        percent: u32,
+    },
+    /// Off is manual mode at 0%, on goes back to a duty the fan ran at before
+    Power {
+        fan: usize,
+        on: bool,
+    },
+    Mode {
+        fan: usize,
+        mode: ControlMode,
+    },
+    /// Name of the profile to switch every fan to
+    Profile(String),
+}
+
+/// Modes offered in Home Assistant, calibration is started from the menu
+const SELECTABLE_MODES: [ControlMode; 3] = [
+    ControlMode::Manual,
+    ControlMode::TargetRpm,
+    ControlMode::Curve,
+];
+
+/// What the controller looks like to Home Assistant
+pub struct Discovery<'a> {
+    pub config: &'a MqttConfig,
+    pub fan_count: usize,
+    /// Options of the profile select, which is left out without any profiles
+    pub profiles: &'a [String],
+    pub firmware_version: &'a str,
+}
+
+impl Discovery<'_> {
+    /// Retained config messages as topic and payload, nothing if discovery is off
+    pub fn messages(&self) -> Vec<(String, Value)> {
+        if self.config.discovery_prefix.is_empty() {
+            return Vec::new();
+        }
+        let topics = self.config.topics();
+        let mut messages = Vec::new();
+        for fan in 0..self.fan_count {
+            let name = format!("Fan {}", fan + 1);
+            messages.push(self.entity(
+                "fan",
+                &format!("fan{fan}"),
+                json!({
+                    "name": name,
+                    "state_topic": topics.fan(fan, "power"),
+                    "command_topic": topics.fan(fan, "power/set"),
+                    "percentage_state_topic": topics.fan(fan, "duty"),
+                    "percentage_command_topic": topics.fan(fan, "duty/set"),
+                }),
+            ));
+            messages.push(self.entity(
+                "sensor",
+                &format!("fan{fan}_rpm"),
+                json!({
+                    "name": format!("{name} speed"),
+                    "state_topic": topics.fan(fan, "rpm"),
+                    "unit_of_measurement": "RPM",
+                    "state_class": "measurement",
+                    "icon": "mdi:fan",
+                }),
+            ));
+            messages.push(self.entity(
+                "sensor",
+                &format!("fan{fan}_applied_duty"),
+                json!({
+                    "name": format!("{name} applied duty"),
+                    "state_topic": topics.fan(fan, "applied_duty"),
+                    "unit_of_measurement": "%",
+                    "state_class": "measurement",
+                    "icon": "mdi:percent",
+                }),
+            ));
+            messages.push(self.entity(
+                "sensor",
+                &format!("fan{fan}_alarms"),
+                json!({
+                    "name": format!("{name} alarms"),
+                    "state_topic": topics.fan(fan, "alarms"),
+                    "icon": "mdi:alert",
+                    "entity_category": "diagnostic",
+                }),
+            ));
+            messages.push(self.entity(
+                "select",
+                &format!("fan{fan}_mode"),
+                json!({
+                    "name": format!("{name} mode"),
+                    "state_topic": topics.fan(fan, "mode"),
+                    "command_topic": topics.fan(fan, "mode/set"),
+                    "options": SELECTABLE_MODES.map(ControlMode::name),
+                }),
+            ));
+        }
+        messages.push(self.entity(
+            "sensor",
+            "temperature",
+            json!({
+                "name": "Temperature",
+                "state_topic": topics.device("temperature"),
+                "device_class": "temperature",
+                "unit_of_measurement": "°C",
+                "state_class": "measurement",
+            }),
+        ));
+        if !self.profiles.is_empty() {
+            messages.push(self.entity(
+                "select",
+                "profile",
+                json!({
+                    "name": "Profile",
+                    "state_topic": topics.device("profile"),
+                    "command_topic": topics.device("profile/set"),
+                    "options": self.profiles,
+                }),
+            ));
+        }
+        messages
+    }
+
+    /// Adds what every entity has in common to `payload`
+    fn entity(&self, component: &str, object_id: &str, mut payload: Value) -> (String, Value) {
+        let node_id = self.config.node_id();
+        let common = json!({
+            "unique_id": format!("{node_id}_{object_id}"),
+            "availability_topic": self.config.topics().availability(),
+            "device": {
+                "identifiers": [node_id],
+                "name": "Fan control",
+                "model": "ESP32 fan controller",
+                "sw_version": self.firmware_version,
+            },
+        });
+        if let (Value::Object(payload), Value::Object(common)) = (&mut payload, common) {
+            payload.extend(common);
+        }
+        (self.config_topic(component, object_id), payload)
+    }
+
+    fn config_topic(&self, component: &str, object_id: &str) -> String {
+        format!(
+            "{}/{component}/{}/{object_id}/config",
+            self.config.discovery_prefix,
+            self.config.node_id()
+        )
+    }
+}
+
+/// Time to wait before each attempt to reconnect, doubling up to a limit
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min_ms: u64,
+    max_ms: u64,
+    next_ms: u64,
+}
+
+impl Backoff {
+    pub fn new(min_ms: u64, max_ms: u64) -> Self {
+        Self {
+            min_ms,
+            max_ms,
+            next_ms: min_ms,
+        }
+    }
+
+    pub fn next_delay_ms(&mut self) -> u64 {
+        let delay_ms = self.next_ms;
+        self.next_ms = delay_ms.saturating_mul(2).min(self.max_ms);
+        delay_ms
+    }
+
+    /// Connected, start over with the shortest delay next time
+    pub fn reset(&mut self) {
+        self.next_ms = self.min_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn parse(topic: &str, payload: &str) -> Result<MqttCommand, MqttError> {
+        config()
+            .topics()
+            .parse_command(topic, payload.as_bytes(), 2)
+    }
+
+    fn invalid_payload(result: Result<MqttCommand, MqttError>) -> bool {
+        matches!(result, Err(MqttError::InvalidPayload(_)))
+    }
+
+    fn discovery(profiles: &[String]) -> Vec<(String, Value)> {
+        Discovery {
+            config: &config(),
+            fan_count: 2,
+            profiles,
+            firmware_version: "1.2.3",
+        }
+        .messages()
+    }
+
+    fn find<'a>(messages: &'a [(String, Value)], topic: &str) -> &'a Value {
+        &messages
+            .iter()
+            .find(|(message_topic, _)| message_topic == topic)
+            .unwrap_or_else(|| panic!("no message to {topic}"))
+            .1
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(config().validate(), Ok(()));
+        for url in ["", "http://broker", "mqtt://", "broker:1883"] {
+            let config = MqttConfig {
+                url: url.to_string(),
+                ..config()
+            };
+            assert_eq!(config.validate(), Err(MqttError::InvalidUrl), "{url}");
+        }
+        for topic in ["", "/fans", "fans/", "fans/+", "fans/#"] {
+            let config = MqttConfig {
+                base_topic: topic.to_string(),
+                ..config()
+            };
+            assert_eq!(
+                config.validate(),
+                Err(MqttError::InvalidTopic("base_topic")),
+                "{topic}"
+            );
+        }
+        let no_discovery = MqttConfig {
+            discovery_prefix: String::new(),
+            ..config()
+        };
+        assert_eq!(no_discovery.validate(), Ok(()));
+        let too_often = MqttConfig {
+            min_interval_ms: MIN_INTERVAL_MS - 1,
+            ..config()
+        };
+        assert_eq!(too_often.validate(), Err(MqttError::IntervalTooShort));
+    }
+
+    #[test]
+    fn topics() {
+        let config = MqttConfig {
+            base_topic: "house/fans".to_string(),
+            ..config()
+        };
+        let topics = config.topics();
+        assert_eq!(topics.availability(), "house/fans/availability");
+        assert_eq!(topics.fan(1, "rpm"), "house/fans/fan/1/rpm");
+        assert_eq!(topics.device("temperature"), "house/fans/temperature");
+        assert_eq!(
+            topics.command_filters(),
+            ["house/fans/fan/+/+/set", "house/fans/profile/set"]
+        );
+        assert_eq!(config.node_id(), "house_fans");
+        assert_eq!(
+            config.birth_topic().as_deref(),
+            Some("homeassistant/status")
+        );
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse("fan-control/fan/1/duty/set", " 40\n"),
+            Ok(MqttCommand::Duty {
+                fan: 1,
+                percent: 40
+            })
+        );
+        assert_eq!(
+            parse("fan-control/fan/0/power/set", "OFF"),
+            Ok(MqttCommand::Power { fan: 0, on: false })
+        );
+        assert_eq!(
+            parse("fan-control/fan/0/power/set", "ON"),
+            Ok(MqttCommand::Power { fan: 0, on: true })
+        );
+        assert_eq!(
+            parse("fan-control/fan/0/mode/set", "target_rpm"),
+            Ok(MqttCommand::Mode {
+                fan: 0,
+                mode: ControlMode::TargetRpm
+            })
+        );
+        assert_eq!(
+            parse("fan-control/profile/set", "Quiet"),
+            Ok(MqttCommand::Profile("Quiet".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_payloads() {
+        for duty in ["101", "-1", "40.5", "", "max"] {
+            assert!(
+                invalid_payload(parse("fan-control/fan/0/duty/set", duty)),
+                "{duty}"
+            );
+        }
+        for power in ["on", "1", "true", ""] {
+            assert!(
+                invalid_payload(parse("fan-control/fan/0/power/set", power)),
+                "{power}"
+            );
+        }
+        for mode in ["calibration", "auto", "Manual"] {
+            assert!(
+                invalid_payload(parse("fan-control/fan/0/mode/set", mode)),
+                "{mode}"
+            );
+        }
+        let not_text =
+            config()
+                .topics()
+                .parse_command("fan-control/fan/0/duty/set", &[0xff, 0xfe], 2);
+        assert!(invalid_payload(not_text));
+    }
+
+    #[test]
+    fn unknown_topics() {
+        for topic in [
+            "fan-control/fan/0/duty",
+            "fan-control/fan/0/rpm/set",
+            "fan-control/fan/x/duty/set",
+            "fan-control/fan/-1/duty/set",
+            "fan-control/fan/0/set",
+            "fan-control/temperature/set",
+            "fan-control-2/fan/0/duty/set",
+            "other/fan/0/duty/set",
+            // Only 2 fans
+            "fan-control/fan/2/duty/set",
+        ] {
+            assert_eq!(
+                parse(topic, "40"),
+                Err(MqttError::UnknownTopic(topic.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn discovery_entities() {
+        let messages = discovery(&[]);
+        // Fan, 3 sensors and the mode select per fan, and the temperature
+        assert_eq!(messages.len(), 2 * 5 + 1);
+
+        let fan = find(&messages, "homeassistant/fan/fan-control/fan1/config");
+        assert_eq!(fan["name"], "Fan 2");
+        assert_eq!(fan["unique_id"], "fan-control_fan1");
+        assert_eq!(fan["state_topic"], "fan-control/fan/1/power");
+        assert_eq!(fan["command_topic"], "fan-control/fan/1/power/set");
+        assert_eq!(fan["percentage_state_topic"], "fan-control/fan/1/duty");
+        assert_eq!(
+            fan["percentage_command_topic"],
+            "fan-control/fan/1/duty/set"
+        );
+        assert_eq!(fan["availability_topic"], "fan-control/availability");
+        assert_eq!(fan["device"]["identifiers"], json!(["fan-control"]));
+        assert_eq!(fan["device"]["sw_version"], "1.2.3");
+
+        let rpm = find(
+            &messages,
+            "homeassistant/sensor/fan-control/fan0_rpm/config",
+        );
+        assert_eq!(rpm["state_topic"], "fan-control/fan/0/rpm");
+        assert_eq!(rpm["unit_of_measurement"], "RPM");
+        let temperature = find(
+            &messages,
+            "homeassistant/sensor/fan-control/temperature/config",
+        );
+        assert_eq!(temperature["device_class"], "temperature");
+        assert_eq!(temperature["state_topic"], "fan-control/temperature");
+
+        let mode = find(
+            &messages,
+            "homeassistant/select/fan-control/fan0_mode/config",
+        );
+        assert_eq!(mode["command_topic"], "fan-control/fan/0/mode/set");
+        assert_eq!(mode["options"], json!(["manual", "target_rpm", "curve"]));
+
+        assert!(messages
+            .iter()
+            .all(|(topic, _)| topic != "homeassistant/select/fan-control/profile/config"));
+    }
+
+    #[test]
+    fn discovery_profile_select() {
+        let profiles = ["Quiet".to_string(), "Full".to_string()];
+        let messages = discovery(&profiles);
+        assert_eq!(messages.len(), 2 * 5 + 2);
+        let profile = find(&messages, "homeassistant/select/fan-control/profile/config");
+        assert_eq!(profile["state_topic"], "fan-control/profile");
+        assert_eq!(profile["command_topic"], "fan-control/profile/set");
+        assert_eq!(profile["options"], json!(["Quiet", "Full"]));
+    }
+
+    #[test]
+    fn discovery_off() {
+        let config = MqttConfig {
+            discovery_prefix: String::new(),
+            ..config()
+        };
+        let discovery = Discovery {
+            config: &config,
+            fan_count: 2,
+            profiles: &[],
+            firmware_version: "1.2.3",
+        };
+        assert!(discovery.messages().is_empty());
+        assert_eq!(config.birth_topic(), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut backoff = Backoff::new(1_000, 5_000);
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay_ms()).collect();
+        assert_eq!(delays, [1_000, 2_000, 4_000, 5_000, 5_000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay_ms(), 1_000);
+        assert_eq!(backoff.next_delay_ms(), 2_000);
+    }
+}
@@ -0,0 +1,169 @@
+/// Tuning and limits for [`Pid`].
+///
+/// The controller works in "RPM in, duty percent out", so the gains are
+/// expressed as duty percent per RPM of error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidConfig {
+    pub kp: f32,
+    /// Integral gain, per second
+    pub ki: f32,
+    /// Derivative gain, in seconds. Applied to the measurement, not the error,
+    /// so setpoint changes don't cause a derivative kick.
+    pub kd: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        // Tuned for a ~2000 RPM PC fan where the tacho reports once per second.
+        // Being slow is fine, oscillating audibly is not.
+        Self {
+            kp: 0.01,
+            ki: 0.03,
+            kd: 0.0,
+            output_min: 0.0,
+            output_max: 100.0,
+        }
+    }
+}
+
+/// PID controller with output clamping and anti-windup.
+///
+/// Anti-windup is done by clamping the integral term to the output range and by
+/// not integrating past the point where the output saturates in the direction of
+/// the error. That way, asking for more RPM than the fan can deliver doesn't leave a
+/// huge integral behind that overshoots once the target is lowered again.
+#[derive(Debug, Clone)]
+pub struct Pid {
+    config: PidConfig,
+    integral: f32,
+    last_measurement: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            last_measurement: None,
+        }
+    }
+
+    pub fn config(&self) -> &PidConfig {
+        &self.config
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_measurement = None;
+    }
+
+    /// Reset the controller so that the next output continues from `output`.
+    ///
+    /// Used when switching into closed-loop mode, so the fan doesn't jump from
+    /// whatever duty it had before to zero.
+    pub fn reset_to(&mut self, output: f32) {
+        self.integral = output.clamp(self.config.output_min, self.config.output_max);
+        self.last_measurement = None;
+    }
+
+    /// Run one controller step and return the new output (duty percent).
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt_s: f32) -> f32 {
+        let PidConfig {
+            kp,
+            ki,
+            kd,
+            output_min,
+            output_max,
+        } = self.config;
+
+        let error = setpoint - measurement;
+        let proportional = kp * error;
+
+        let derivative = match self.last_measurement {
+            Some(last) if dt_s > 0.0 => -kd * (measurement - last) / dt_s,
+            _ => 0.0,
+        };
+        self.last_measurement = Some(measurement);
+
+        let candidate_integral = (self.integral + ki * error * dt_s).clamp(output_min, output_max);
+
+        // Conditional integration: the integral may grow until the output hits its
+        // limit, but not push it further into saturation. It never has to move
+        // back for that, only the proportional and derivative terms changed.
+        let low = self.integral.min(output_min - proportional - derivative);
+        let high = self.integral.max(output_max - proportional - derivative);
+        self.integral = candidate_integral.clamp(low, high);
+
+        (proportional + self.integral + derivative).clamp(output_min, output_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::FanModel;
+
+    /// The tacho reports once per second
+    const DT_S: f32 = 1.0;
+
+    /// Runs the loop for `secs` and returns the last RPM
+    fn run(pid: &mut Pid, fan: &mut FanModel, target: f32, secs: u32) -> f32 {
+        let mut duty = 0.0;
+        for _ in 0..secs {
+            let rpm = fan.step(duty, DT_S);
+            duty = pid.update(target, rpm, DT_S);
+        }
+        fan.rpm()
+    }
+
+    #[test]
+    fn settles_on_target_rpm() {
+        let mut pid = Pid::new(PidConfig::default());
+        let mut fan = FanModel::default();
+        let rpm = run(&mut pid, &mut fan, 1200.0, 120);
+        assert!((rpm - 1200.0).abs() < 20.0, "settled at {rpm}");
+    }
+
+    #[test]
+    fn settles_on_a_weaker_fan() {
+        let mut pid = Pid::new(PidConfig::default());
+        let mut fan = FanModel::default();
+        fan.efficiency = 0.7;
+        let rpm = run(&mut pid, &mut fan, 1200.0, 120);
+        assert!((rpm - 1200.0).abs() < 20.0, "settled at {rpm}");
+    }
+
+    #[test]
+    fn clamps_output() {
+        let mut pid = Pid::new(PidConfig::default());
+        assert_eq!(pid.update(100_000.0, 0.0, DT_S), 100.0);
+        let mut pid = Pid::new(PidConfig::default());
+        assert_eq!(pid.update(0.0, 100_000.0, DT_S), 0.0);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut pid = Pid::new(PidConfig::default());
+        let mut fan = FanModel::default();
+        // More than the fan can do, the output sits at 100% the whole time
+        run(&mut pid, &mut fan, 5000.0, 300);
+        assert_eq!(pid.update(5000.0, fan.rpm(), DT_S), 100.0);
+
+        // Comes down right away instead of unwinding five minutes of error
+        let duty = pid.update(1000.0, fan.rpm(), DT_S);
+        assert!(duty < 100.0, "duty {duty}");
+        let rpm = run(&mut pid, &mut fan, 1000.0, 60);
+        assert!((rpm - 1000.0).abs() < 20.0, "settled at {rpm}");
+    }
+
+    #[test]
+    fn reset_to_continues_from_output() {
+        let mut pid = Pid::new(PidConfig::default());
+        pid.reset_to(40.0);
+        assert_eq!(pid.update(800.0, 800.0, DT_S), 40.0);
+        pid.reset_to(250.0);
+        assert_eq!(pid.update(800.0, 800.0, DT_S), 100.0);
+    }
+}
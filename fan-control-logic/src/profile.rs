@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control::ControlError,
+    curve::{CurveConfig, CurveError, CurvePoint, Interpolation},
+};
+
+/// Longest name that fits the status bar
+pub const MAX_NAME_LEN: usize = 8;
+
+/// What a profile sets every fan to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ProfileMode {
+    Manual { duty: u32 },
+    TargetRpm { rpm: u32 },
+    Curve { curve: CurveConfig },
+}
+
+/// Named set of fan settings to switch to in one go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(flatten)]
+    pub mode: ProfileMode,
+}
+
+impl Profile {
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        if self.name.is_empty() || self.name.chars().count() > MAX_NAME_LEN {
+            return Err(ProfileError::InvalidName);
+        }
+        match &self.mode {
+            ProfileMode::Manual { duty } if *duty > 100 => Err(ProfileError::DutyOutOfRange),
+            ProfileMode::Curve { curve } => {
+                curve.to_curve().map_err(ProfileError::InvalidCurve)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProfileError {
+    InvalidName,
+    DutyOutOfRange,
+    InvalidCurve(CurveError),
+    Unknown(String),
+    /// Not allowed to change the fans right now
+    Control(ControlError),
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::InvalidName => {
+                write!(f, "Profile names must be 1-{MAX_NAME_LEN} characters")
+            }
+            ProfileError::DutyOutOfRange => write!(f, "Duty must be 0-100"),
+            ProfileError::InvalidCurve(e) => write!(f, "Invalid curve: {e}"),
+            ProfileError::Unknown(name) => write!(f, "No profile called {name}"),
+            ProfileError::Control(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// All profiles, and the one the fans were last set to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profiles {
+    pub profiles: Vec<Profile>,
+    /// Cleared by any other change to the fans
+    pub active_profile: Option<String>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        let curve = |points: [(f32, f32); 3]| CurveConfig {
+            points: points
+                .into_iter()
+                .map(|(temperature_c, duty)| CurvePoint::new(temperature_c, duty))
+                .collect(),
+            interpolation: Interpolation::MonotoneCubic,
+            ..Default::default()
+        };
+        Self {
+            profiles: vec![
+                Profile {
+                    name: "Silent".to_string(),
+                    mode: ProfileMode::Curve {
+                        curve: curve([(35.0, 0.0), (55.0, 30.0), (75.0, 70.0)]),
+                    },
+                },
+                Profile {
+                    name: "Balanced".to_string(),
+                    mode: ProfileMode::Curve {
+                        curve: CurveConfig::default(),
+                    },
+                },
+                Profile {
+                    name: "Turbo".to_string(),
+                    mode: ProfileMode::Manual { duty: 100 },
+                },
+            ],
+            active_profile: None,
+        }
+    }
+}
+
+impl Profiles {
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Adds a profile, or replaces the one with the same name
+    pub fn insert(&mut self, profile: Profile) -> Result<(), ProfileError> {
+        profile.validate()?;
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), ProfileError> {
+        let index = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or_else(|| ProfileError::Unknown(name.to_string()))?;
+        self.profiles.remove(index);
+        if self.active_profile.as_deref() == Some(name) {
+            self.active_profile = None;
+        }
+        Ok(())
+    }
+
+    /// Profile `steps` away from the active one, wrapping around. Starts from the
+    /// first one if none is active.
+    pub fn cycle(&self, steps: i32) -> Option<&Profile> {
+        let count = self.profiles.len() as i32;
+        if count == 0 {
+            return None;
+        }
+        let index = match self
+            .active_profile
+            .as_deref()
+            .and_then(|name| self.profiles.iter().position(|p| p.name == name))
+        {
+            Some(active) => (active as i32 + steps).rem_euclid(count),
+            None => 0,
+        };
+        self.profiles.get(index as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manual(name: &str, duty: u32) -> Profile {
+        Profile {
+            name: name.to_string(),
+            mode: ProfileMode::Manual { duty },
+        }
+    }
+
+    fn names(profiles: &Profiles) -> Vec<&str> {
+        profiles.profiles.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    fn active(profiles: &[&str], active: Option<&str>) -> Profiles {
+        Profiles {
+            profiles: profiles.iter().map(|name| manual(name, 50)).collect(),
+            active_profile: active.map(str::to_string),
+        }
+    }
+
+    fn cycled(profiles: &Profiles, steps: i32) -> Option<&str> {
+        profiles.cycle(steps).map(|p| p.name.as_str())
+    }
+
+    #[test]
+    fn validation() {
+        for profile in Profiles::default().profiles {
+            assert_eq!(profile.validate(), Ok(()), "{}", profile.name);
+        }
+        assert_eq!(manual("Night", 100).validate(), Ok(()));
+        assert_eq!(
+            manual("Night", 101).validate(),
+            Err(ProfileError::DutyOutOfRange)
+        );
+        assert_eq!(manual("", 50).validate(), Err(ProfileError::InvalidName));
+        // Counted in characters, not bytes
+        assert_eq!(manual("Lüfter°C", 50).validate(), Ok(()));
+        assert_eq!(
+            manual("Overnight", 50).validate(),
+            Err(ProfileError::InvalidName)
+        );
+        let target_rpm = Profile {
+            name: "Fixed".to_string(),
+            mode: ProfileMode::TargetRpm { rpm: 1200 },
+        };
+        assert_eq!(target_rpm.validate(), Ok(()));
+
+        let bad_curve = Profile {
+            name: "Curve".to_string(),
+            mode: ProfileMode::Curve {
+                curve: CurveConfig {
+                    points: vec![CurvePoint::new(50.0, 50.0), CurvePoint::new(30.0, 20.0)],
+                    ..Default::default()
+                },
+            },
+        };
+        assert_eq!(
+            bad_curve.validate(),
+            Err(ProfileError::InvalidCurve(CurveError::UnsortedPoints))
+        );
+    }
+
+    #[test]
+    fn insert_adds_or_replaces() {
+        let mut profiles = Profiles::default();
+        profiles.insert(manual("Night", 30)).unwrap();
+        assert_eq!(names(&profiles), ["Silent", "Balanced", "Turbo", "Night"]);
+
+        profiles.insert(manual("Turbo", 90)).unwrap();
+        assert_eq!(names(&profiles), ["Silent", "Balanced", "Turbo", "Night"]);
+        assert_eq!(profiles.get("Turbo"), Some(&manual("Turbo", 90)));
+
+        assert_eq!(
+            profiles.insert(manual("Turbo", 101)),
+            Err(ProfileError::DutyOutOfRange)
+        );
+        assert_eq!(profiles.get("Turbo"), Some(&manual("Turbo", 90)));
+    }
+
+    #[test]
+    fn remove_clears_active() {
+        let mut profiles = active(&["A", "B", "C"], Some("B"));
+        profiles.remove("A").unwrap();
+        assert_eq!(profiles.active_profile.as_deref(), Some("B"));
+        profiles.remove("B").unwrap();
+        assert_eq!(profiles.active_profile, None);
+        assert_eq!(names(&profiles), ["C"]);
+        assert_eq!(
+            profiles.remove("B"),
+            Err(ProfileError::Unknown("B".to_string()))
+        );
+    }
+
+    #[test]
+    fn cycle_wraps_around() {
+        let profiles = active(&["A", "B", "C"], Some("C"));
+        assert_eq!(cycled(&profiles, 1), Some("A"));
+        assert_eq!(cycled(&profiles, -1), Some("B"));
+        assert_eq!(cycled(&profiles, 0), Some("C"));
+        assert_eq!(cycled(&profiles, 4), Some("A"));
+        let profiles = active(&["A", "B", "C"], Some("A"));
+        assert_eq!(cycled(&profiles, -1), Some("C"));
+        assert_eq!(cycled(&profiles, -5), Some("B"));
+    }
+
+    #[test]
+    fn cycle_without_active_profile() {
+        assert_eq!(cycled(&active(&["A", "B"], None), 1), Some("A"));
+        assert_eq!(cycled(&active(&["A", "B"], None), -1), Some("A"));
+        // Removed since it was activated
+        assert_eq!(cycled(&active(&["A", "B"], Some("Gone")), 1), Some("A"));
+        assert_eq!(cycled(&active(&[], None), 1), None);
+    }
+}
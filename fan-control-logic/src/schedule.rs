@@ -0,0 +1,382 @@
+//! Profiles and duty caps by weekday and time of day.
+//!
+//! Rules are windows of wall clock time, and [`evaluate`] only looks at where the
+//! clock is now. That keeps DST simple: a window that starts in the hour skipped
+//! in spring starts when the clock jumps past it, and in autumn the repeated hour
+//! doesn't start anything twice.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::timezone::{LocalTime, TimeZone, TimeZoneError, Weekday};
+
+/// `HH:MM`, in minutes after midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(pub u32);
+
+impl TimeOfDay {
+    pub fn new(hour: u32, minute: u32) -> Self {
+        Self(hour * 60 + minute)
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl std::str::FromStr for TimeOfDay {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScheduleError::InvalidTime(s.to_string());
+        let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+        let hour: u32 = hour.parse().map_err(|_| invalid())?;
+        let minute: u32 = minute.parse().map_err(|_| invalid())?;
+        if hour > 23 || minute > 59 {
+            return Err(invalid());
+        }
+        Ok(Self::new(hour, minute))
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Applies `profile` and/or caps the duty at `duty_cap` from `start` until `end`.
+/// A window that ends before it starts runs over midnight, into the next day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    /// Days the window starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// The same as `start` for the whole day
+    pub end: TimeOfDay,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub duty_cap: Option<u32>,
+}
+
+impl ScheduleRule {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn is_active(&self, now: &LocalTime) -> bool {
+        let minute = now.minute_of_day();
+        let (start, end) = (self.start.0, self.end.0);
+        if start < end {
+            self.starts_on(now.weekday) && (start..end).contains(&minute)
+        } else {
+            // Also the rest of yesterday's window, if it runs over midnight
+            (self.starts_on(now.weekday) && minute >= start)
+                || (self.starts_on(now.weekday.previous()) && minute < end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Host name or address, e.g. a local NTP server
+    pub ntp_server: String,
+    /// POSIX TZ rule, see [`TimeZone::parse`]
+    pub timezone: String,
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            ntp_server: "pool.ntp.org".to_string(),
+            timezone: "UTC0".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl ScheduleConfig {
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        if self.ntp_server.is_empty() {
+            return Err(ScheduleError::MissingNtpServer);
+        }
+        self.time_zone()?;
+        for rule in &self.rules {
+            if rule.profile.is_none() && rule.duty_cap.is_none() {
+                return Err(ScheduleError::EmptyRule);
+            }
+            if rule.duty_cap.is_some_and(|cap| cap > 100) {
+                return Err(ScheduleError::DutyOutOfRange);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn time_zone(&self) -> Result<TimeZone, ScheduleError> {
+        TimeZone::parse(&self.timezone).map_err(ScheduleError::InvalidTimeZone)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    MissingNtpServer,
+    InvalidTimeZone(TimeZoneError),
+    InvalidTime(String),
+    /// A rule has neither a profile nor a duty cap
+    EmptyRule,
+    DutyOutOfRange,
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::MissingNtpServer => write!(f, "An NTP server is needed"),
+            ScheduleError::InvalidTimeZone(e) => e.fmt(f),
+            ScheduleError::InvalidTime(time) => {
+                write!(f, "Invalid time {time:?}, expected HH:MM")
+            }
+            ScheduleError::EmptyRule => {
+                write!(f, "Every rule needs a profile, a duty cap or both")
+            }
+            ScheduleError::DutyOutOfRange => write!(f, "Duty caps must be 0-100"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// What the schedule wants right now
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScheduleState {
+    pub profile: Option<String>,
+    pub duty_cap: Option<u32>,
+}
+
+/// What `rules` want at `now`. Without the time, before the clock is synced,
+/// nothing applies. Of overlapping rules, the last profile and the lowest cap
+/// win.
+pub fn evaluate(rules: &[ScheduleRule], now: Option<&LocalTime>) -> ScheduleState {
+    let Some(now) = now else {
+        return ScheduleState::default();
+    };
+    let mut state = ScheduleState::default();
+    for rule in rules.iter().filter(|rule| rule.is_active(now)) {
+        if let Some(profile) = &rule.profile {
+            state.profile = Some(profile.clone());
+        }
+        if let Some(cap) = rule.duty_cap {
+            state.duty_cap = Some(state.duty_cap.map_or(cap, |lowest| lowest.min(cap)));
+        }
+    }
+    state
+}
+
+/// Switches to a scheduled profile once when its window starts, rather than
+/// every time the schedule is looked at, so changes made during the window
+/// stick. Keeps asking until the switch worked, e.g. while a manual change
+/// holds off automation.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileSwitcher {
+    scheduled: Option<String>,
+    pending: Option<String>,
+}
+
+impl ProfileSwitcher {
+    /// Profile to switch to now, report back with [`ProfileSwitcher::switched`]
+    pub fn update(&mut self, state: &ScheduleState) -> Option<&str> {
+        if state.profile != self.scheduled {
+            self.scheduled = state.profile.clone();
+            self.pending = state.profile.clone();
+        }
+        self.pending.as_deref()
+    }
+
+    pub fn switched(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(weekday: Weekday, hour: u32, minute: u32) -> LocalTime {
+        LocalTime {
+            year: 2024,
+            month: 1,
+            day: 1,
+            weekday,
+            hour,
+            minute,
+            second: 0,
+            is_dst: false,
+        }
+    }
+
+    fn rule(days: &[Weekday], start: &str, end: &str) -> ScheduleRule {
+        ScheduleRule {
+            days: days.to_vec(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            profile: Some("quiet".to_string()),
+            duty_cap: None,
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let rule = rule(&[Weekday::Mon], "08:00", "17:30");
+        assert!(!rule.is_active(&at(Weekday::Mon, 7, 59)));
+        assert!(rule.is_active(&at(Weekday::Mon, 8, 0)));
+        assert!(rule.is_active(&at(Weekday::Mon, 17, 29)));
+        assert!(!rule.is_active(&at(Weekday::Mon, 17, 30)));
+        assert!(!rule.is_active(&at(Weekday::Tue, 12, 0)));
+    }
+
+    #[test]
+    fn window_over_midnight() {
+        let rule = rule(&[Weekday::Fri], "22:00", "06:00");
+        assert!(!rule.is_active(&at(Weekday::Fri, 21, 59)));
+        assert!(rule.is_active(&at(Weekday::Fri, 23, 0)));
+        assert!(rule.is_active(&at(Weekday::Sat, 5, 59)));
+        assert!(!rule.is_active(&at(Weekday::Sat, 6, 0)));
+        // Only the night from Friday on
+        assert!(!rule.is_active(&at(Weekday::Sat, 23, 0)));
+        assert!(!rule.is_active(&at(Weekday::Fri, 5, 0)));
+    }
+
+    #[test]
+    fn whole_day_window() {
+        let rule = rule(&[Weekday::Sun], "08:00", "08:00");
+        assert!(!rule.is_active(&at(Weekday::Sun, 7, 59)));
+        assert!(rule.is_active(&at(Weekday::Sun, 8, 0)));
+        assert!(rule.is_active(&at(Weekday::Mon, 7, 59)));
+        assert!(!rule.is_active(&at(Weekday::Mon, 8, 0)));
+
+        // Every day, all the time
+        let rule = self::rule(&[], "00:00", "00:00");
+        for weekday in Weekday::ALL {
+            assert!(rule.is_active(&at(weekday, 0, 0)));
+            assert!(rule.is_active(&at(weekday, 23, 59)));
+        }
+    }
+
+    #[test]
+    fn spring_forward_skips_into_the_window() {
+        let tz = TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let rules = [rule(&[], "02:30", "04:00")];
+        // 2024-03-31, the clock jumps from 01:59:59 to 03:00:00
+        let before = tz.local_time(1_711_846_799);
+        let after = tz.local_time(1_711_846_800);
+        assert_eq!(evaluate(&rules, Some(&before)).profile, None);
+        assert_eq!(
+            evaluate(&rules, Some(&after)).profile.as_deref(),
+            Some("quiet")
+        );
+    }
+
+    #[test]
+    fn overlapping_rules() {
+        let rules = [
+            ScheduleRule {
+                duty_cap: Some(60),
+                ..rule(&[], "20:00", "08:00")
+            },
+            ScheduleRule {
+                profile: Some("night".to_string()),
+                duty_cap: Some(40),
+                ..rule(&[], "22:00", "06:00")
+            },
+            ScheduleRule {
+                profile: None,
+                duty_cap: Some(50),
+                ..rule(&[], "21:00", "23:00")
+            },
+        ];
+        assert_eq!(
+            evaluate(&rules, Some(&at(Weekday::Mon, 22, 30))),
+            ScheduleState {
+                profile: Some("night".to_string()),
+                duty_cap: Some(40),
+            }
+        );
+        assert_eq!(
+            evaluate(&rules, Some(&at(Weekday::Mon, 21, 0))),
+            ScheduleState {
+                profile: Some("quiet".to_string()),
+                duty_cap: Some(50),
+            }
+        );
+        assert_eq!(
+            evaluate(&rules, Some(&at(Weekday::Mon, 12, 0))),
+            ScheduleState::default()
+        );
+    }
+
+    #[test]
+    fn nothing_without_the_time() {
+        let rules = [rule(&[], "00:00", "00:00")];
+        assert_eq!(evaluate(&rules, None), ScheduleState::default());
+    }
+
+    #[test]
+    fn switcher_retries_until_switched() {
+        let night = ScheduleState {
+            profile: Some("night".to_string()),
+            duty_cap: None,
+        };
+        let mut switcher = ProfileSwitcher::default();
+        assert_eq!(switcher.update(&ScheduleState::default()), None);
+        assert_eq!(switcher.update(&night), Some("night"));
+        // Didn't work, e.g. a manual change holds off automation
+        assert_eq!(switcher.update(&night), Some("night"));
+        switcher.switched();
+        // Changes made during the window stick
+        assert_eq!(switcher.update(&night), None);
+
+        // The next window switches again
+        assert_eq!(switcher.update(&ScheduleState::default()), None);
+        assert_eq!(switcher.update(&night), Some("night"));
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!("07:05".parse(), Ok(TimeOfDay::new(7, 5)));
+        assert_eq!(TimeOfDay::new(7, 5).to_string(), "07:05");
+        for invalid in ["24:00", "12:60", "1200", "ab:cd", ""] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let mut config = ScheduleConfig {
+            rules: vec![rule(&[], "22:00", "06:00")],
+            ..ScheduleConfig::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        config.rules[0].duty_cap = Some(101);
+        assert_eq!(config.validate(), Err(ScheduleError::DutyOutOfRange));
+        config.rules[0].profile = None;
+        config.rules[0].duty_cap = None;
+        assert_eq!(config.validate(), Err(ScheduleError::EmptyRule));
+        config.rules.clear();
+        config.timezone = "CET".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(ScheduleError::InvalidTimeZone(_))
+        ));
+    }
+}
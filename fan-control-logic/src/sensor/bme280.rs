@@ -0,0 +1,192 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::{SensorError, TemperatureSource};
+
+/// SDO pin low, 0x77 when high
+pub const DEFAULT_ADDRESS: u8 = 0x76;
+
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_CALIBRATION_T: u8 = 0x88;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_TEMP_MSB: u8 = 0xFA;
+
+const CHIP_ID_BME280: u8 = 0x60;
+const CHIP_ID_BMP280: u8 = 0x58;
+
+/// Temperature oversampling x1, pressure skipped, forced mode
+const CTRL_MEAS_FORCED_T1: u8 = (0b001 << 5) | 0b01;
+/// Max measurement time with only temperature at x1 oversampling
+const MEASUREMENT_TIME_MS: u32 = 10;
+
+/// Temperature compensation values burned into each chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemperatureCalibration {
+    pub dig_t1: u16,
+    pub dig_t2: i16,
+    pub dig_t3: i16,
+}
+
+impl TemperatureCalibration {
+    pub fn from_registers(data: &[u8; 6]) -> Self {
+        Self {
+            dig_t1: u16::from_le_bytes([data[0], data[1]]),
+            dig_t2: i16::from_le_bytes([data[2], data[3]]),
+            dig_t3: i16::from_le_bytes([data[4], data[5]]),
+        }
+    }
+
+    /// Compensation formula from the datasheet (section 4.2.3), in °C
+    pub fn compensate(&self, adc_t: i32) -> f32 {
+        let t1 = self.dig_t1 as i32;
+        let t2 = self.dig_t2 as i32;
+        let t3 = self.dig_t3 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * t2) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * t3) >> 14;
+        let t_fine = var1 + var2;
+        ((t_fine * 5 + 128) >> 8) as f32 / 100.0
+    }
+}
+
+/// Bosch BME280 (or BMP280), only the temperature part
+pub struct Bme280<I, D> {
+    i2c: I,
+    delay: D,
+    address: u8,
+    calibration: TemperatureCalibration,
+}
+
+impl<I: I2c, D: DelayNs> Bme280<I, D> {
+    pub fn new(mut i2c: I, delay: D, address: u8) -> Result<Self, SensorError> {
+        let mut chip_id = [0u8];
+        i2c.write_read(address, &[REG_CHIP_ID], &mut chip_id)
+            .map_err(SensorError::bus)?;
+        if chip_id[0] != CHIP_ID_BME280 && chip_id[0] != CHIP_ID_BMP280 {
+            return Err(SensorError::NotFound);
+        }
+
+        let mut calibration = [0u8; 6];
+        i2c.write_read(address, &[REG_CALIBRATION_T], &mut calibration)
+            .map_err(SensorError::bus)?;
+
+        Ok(Self {
+            i2c,
+            delay,
+            address,
+            calibration: TemperatureCalibration::from_registers(&calibration),
+        })
+    }
+}
+
+impl<I: I2c, D: DelayNs> TemperatureSource for Bme280<I, D> {
+    fn name(&self) -> &str {
+        "bme280"
+    }
+
+    fn read_celsius(&mut self) -> Result<f32, SensorError> {
+        self.i2c
+            .write(self.address, &[REG_CTRL_MEAS, CTRL_MEAS_FORCED_T1])
+            .map_err(SensorError::bus)?;
+        self.delay.delay_ms(MEASUREMENT_TIME_MS);
+
+        let mut data = [0u8; 3];
+        self.i2c
+            .write_read(self.address, &[REG_TEMP_MSB], &mut data)
+            .map_err(SensorError::bus)?;
+        let adc_t = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | ((data[2] as i32) >> 4);
+        if adc_t == 0x80000 {
+            // Temperature measurement skipped, the value after reset
+            return Err(SensorError::InvalidReading);
+        }
+        Ok(self.calibration.compensate(adc_t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction};
+
+    use super::*;
+
+    /// Example values of the datasheet (BMP280, section 8.2)
+    const CALIBRATION: [u8; 6] = [0x70, 0x6B, 0x43, 0x67, 0x18, 0xFC];
+
+    fn probe() -> Vec<Transaction> {
+        vec![
+            Transaction::write_read(DEFAULT_ADDRESS, vec![REG_CHIP_ID], vec![CHIP_ID_BME280]),
+            Transaction::write_read(
+                DEFAULT_ADDRESS,
+                vec![REG_CALIBRATION_T],
+                CALIBRATION.to_vec(),
+            ),
+        ]
+    }
+
+    fn measure(data: Vec<u8>) -> Vec<Transaction> {
+        vec![
+            Transaction::write(DEFAULT_ADDRESS, vec![REG_CTRL_MEAS, CTRL_MEAS_FORCED_T1]),
+            Transaction::write_read(DEFAULT_ADDRESS, vec![REG_TEMP_MSB], data),
+        ]
+    }
+
+    #[test]
+    fn datasheet_compensation() {
+        let calibration = TemperatureCalibration::from_registers(&CALIBRATION);
+        assert_eq!(
+            calibration,
+            TemperatureCalibration {
+                dig_t1: 27504,
+                dig_t2: 26435,
+                dig_t3: -1000,
+            }
+        );
+        assert_eq!(calibration.compensate(519888), 25.08);
+    }
+
+    #[test]
+    fn reads_over_the_bus() {
+        // 519888 = 0x7EED0
+        let mut i2c = I2cMock::new(&[probe(), measure(vec![0x7E, 0xED, 0x00])].concat());
+        let mut sensor = Bme280::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS).unwrap();
+        assert_eq!(sensor.read_celsius(), Ok(25.08));
+        i2c.done();
+    }
+
+    #[test]
+    fn wrong_chip_id() {
+        let mut i2c = I2cMock::new(&[Transaction::write_read(
+            DEFAULT_ADDRESS,
+            vec![REG_CHIP_ID],
+            vec![0x55],
+        )]);
+        assert!(matches!(
+            Bme280::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS),
+            Err(SensorError::NotFound)
+        ));
+        i2c.done();
+    }
+
+    #[test]
+    fn skipped_measurement() {
+        let mut i2c = I2cMock::new(&[probe(), measure(vec![0x80, 0x00, 0x00])].concat());
+        let mut sensor = Bme280::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS).unwrap();
+        assert_eq!(sensor.read_celsius(), Err(SensorError::InvalidReading));
+        i2c.done();
+    }
+
+    #[test]
+    fn bus_error() {
+        let mut i2c =
+            I2cMock::new(&[
+                Transaction::write_read(DEFAULT_ADDRESS, vec![REG_CHIP_ID], vec![0])
+                    .with_error(ErrorKind::Other),
+            ]);
+        assert!(matches!(
+            Bme280::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS),
+            Err(SensorError::Bus(_))
+        ));
+        i2c.done();
+    }
+}
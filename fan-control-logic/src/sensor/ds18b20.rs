@@ -0,0 +1,150 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use super::onewire::{crc8_maxim, OneWire};
+use super::{SensorError, TemperatureSource};
+
+const SKIP_ROM: u8 = 0xCC;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+
+/// Worst case conversion time at the default 12-bit resolution
+const CONVERSION_TIME_MS: u32 = 750;
+
+/// DS18B20 digital thermometer, as the only device on a 1-Wire bus.
+pub struct Ds18b20<P, D> {
+    bus: OneWire<P, D>,
+}
+
+impl<P, D> Ds18b20<P, D>
+where
+    P: InputPin + OutputPin,
+    D: DelayNs,
+{
+    pub fn new(bus: OneWire<P, D>) -> Self {
+        Self { bus }
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), SensorError> {
+        if !self.bus.reset()? {
+            return Err(SensorError::NotFound);
+        }
+        self.bus.write_byte(SKIP_ROM)?;
+        self.bus.write_byte(command)
+    }
+}
+
+impl<P, D> TemperatureSource for Ds18b20<P, D>
+where
+    P: InputPin + OutputPin,
+    D: DelayNs,
+{
+    fn name(&self) -> &str {
+        "ds18b20"
+    }
+
+    fn read_celsius(&mut self) -> Result<f32, SensorError> {
+        self.command(CONVERT_T)?;
+        self.bus.delay_ms(CONVERSION_TIME_MS);
+
+        self.command(READ_SCRATCHPAD)?;
+        let mut scratchpad = [0u8; 9];
+        for byte in scratchpad.iter_mut() {
+            *byte = self.bus.read_byte()?;
+        }
+        parse_scratchpad(&scratchpad)
+    }
+}
+
+/// Temperature from the 9 byte scratchpad (8 data bytes + CRC)
+pub fn parse_scratchpad(scratchpad: &[u8; 9]) -> Result<f32, SensorError> {
+    if scratchpad.iter().all(|&b| b == 0xFF) {
+        // Bus floating high, nobody is driving it
+        return Err(SensorError::NotFound);
+    }
+    if crc8_maxim(&scratchpad[..8]) != scratchpad[8] {
+        return Err(SensorError::Crc);
+    }
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    let celsius = raw as f32 / 16.0;
+    if !(-55.0..=125.0).contains(&celsius) {
+        return Err(SensorError::InvalidReading);
+    }
+    Ok(celsius)
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction};
+
+    use super::*;
+    use crate::sensor::onewire::tests::{read_byte, reset, write_byte};
+
+    /// Scratchpad with a valid CRC for a raw temperature
+    fn scratchpad(raw: i16) -> [u8; 9] {
+        let [lsb, msb] = raw.to_le_bytes();
+        let mut scratchpad = [lsb, msb, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0];
+        scratchpad[8] = crc8_maxim(&scratchpad[..8]);
+        scratchpad
+    }
+
+    #[test]
+    fn power_on_scratchpad() {
+        assert_eq!(
+            parse_scratchpad(&[0x50, 0x05, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0x1C]),
+            Ok(85.0)
+        );
+    }
+
+    #[test]
+    fn datasheet_conversions() {
+        // Table 1 of the datasheet
+        assert_eq!(parse_scratchpad(&scratchpad(0x07D0)), Ok(125.0));
+        assert_eq!(parse_scratchpad(&scratchpad(0x0191)), Ok(25.0625));
+        assert_eq!(parse_scratchpad(&scratchpad(0x0008)), Ok(0.5));
+        assert_eq!(parse_scratchpad(&scratchpad(0x0000)), Ok(0.0));
+        assert_eq!(parse_scratchpad(&scratchpad(0xFFF8u16 as i16)), Ok(-0.5));
+        assert_eq!(parse_scratchpad(&scratchpad(0xFF5Eu16 as i16)), Ok(-10.125));
+        assert_eq!(parse_scratchpad(&scratchpad(0xFC90u16 as i16)), Ok(-55.0));
+    }
+
+    #[test]
+    fn rejects_bad_scratchpads() {
+        assert_eq!(parse_scratchpad(&[0xFF; 9]), Err(SensorError::NotFound));
+
+        let mut corrupted = scratchpad(0x0191);
+        corrupted[0] ^= 0x01;
+        assert_eq!(parse_scratchpad(&corrupted), Err(SensorError::Crc));
+
+        assert_eq!(
+            parse_scratchpad(&scratchpad(0x07E0)),
+            Err(SensorError::InvalidReading)
+        );
+    }
+
+    fn sensor(transactions: &[Transaction]) -> (Ds18b20<PinMock, NoopDelay>, PinMock) {
+        let pin = PinMock::new(&[&[Transaction::set(State::High)], transactions].concat());
+        let bus = OneWire::new(pin.clone(), NoopDelay::new()).unwrap();
+        (Ds18b20::new(bus), pin)
+    }
+
+    #[test]
+    fn reads_over_the_bus() {
+        let mut transactions = [reset(true), write_byte(), write_byte()].concat();
+        transactions.extend([reset(true), write_byte(), write_byte()].concat());
+        for byte in scratchpad(0x0191) {
+            transactions.extend(read_byte(byte));
+        }
+        let (mut sensor, mut pin) = sensor(&transactions);
+        assert_eq!(sensor.read_celsius(), Ok(25.0625));
+        pin.done();
+    }
+
+    #[test]
+    fn no_presence_pulse() {
+        let (mut sensor, mut pin) = sensor(&reset(false));
+        assert_eq!(sensor.read_celsius(), Err(SensorError::NotFound));
+        pin.done();
+    }
+}
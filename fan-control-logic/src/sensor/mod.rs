@@ -0,0 +1,97 @@
+//! Temperature sensor drivers.
+//!
+//! The drivers only depend on the `embedded-hal` traits, so they work with the
+//! ESP-IDF peripherals on the device and with mocked buses on the host.
+
+use std::fmt;
+
+pub mod bme280;
+pub mod ds18b20;
+pub mod ntc;
+pub mod onewire;
+pub mod sht3x;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorError {
+    /// The bus (I2C, 1-Wire, ADC) returned an error
+    Bus(String),
+    /// No device answered
+    NotFound,
+    /// The data read back didn't match its checksum
+    Crc,
+    /// The device answered, but with a value that can't be a real temperature
+    InvalidReading,
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::Bus(e) => write!(f, "bus error: {e}"),
+            SensorError::NotFound => write!(f, "no sensor found"),
+            SensorError::Crc => write!(f, "checksum mismatch"),
+            SensorError::InvalidReading => write!(f, "invalid reading"),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+impl SensorError {
+    pub fn bus(e: impl fmt::Debug) -> Self {
+        SensorError::Bus(format!("{e:?}"))
+    }
+}
+
+/// Something that can measure a temperature.
+pub trait TemperatureSource {
+    /// Short name, shown in the HTTP API and logs
+    fn name(&self) -> &str;
+
+    /// Take a new reading in °C. Allowed to block until the measurement is done.
+    fn read_celsius(&mut self) -> Result<f32, SensorError>;
+}
+
+/// CRC-8 as used by Sensirion (polynomial 0x31, init 0xFF) and Dallas/Maxim
+/// (polynomial 0x31 reflected, init 0x00) sensors.
+pub(crate) fn crc8(data: &[u8], polynomial: u8, init: u8, reflected: bool) -> u8 {
+    let mut crc = init;
+    for &byte in data {
+        if reflected {
+            crc ^= byte;
+            for _ in 0..8 {
+                crc = if crc & 0x01 != 0 {
+                    (crc >> 1) ^ polynomial.reverse_bits()
+                } else {
+                    crc >> 1
+                };
+            }
+        } else {
+            crc ^= byte;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 {
+                    (crc << 1) ^ polynomial
+                } else {
+                    crc << 1
+                };
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc8_check_values() {
+        // Catalogue check values, the CRC of the ASCII digits 1-9
+        assert_eq!(crc8(b"123456789", 0x31, 0x00, true), 0xA1);
+        assert_eq!(crc8(b"123456789", 0x31, 0xFF, false), 0xF7);
+    }
+
+    #[test]
+    fn crc8_sensirion_datasheet_example() {
+        assert_eq!(crc8(&[0xBE, 0xEF], 0x31, 0xFF, false), 0x92);
+    }
+}
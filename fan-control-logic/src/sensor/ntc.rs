@@ -0,0 +1,198 @@
+use super::{SensorError, TemperatureSource};
+
+/// ADC channel measuring the voltage divider the thermistor sits in.
+///
+/// `embedded-hal` 1.0 has no ADC trait, so the firmware implements this for its
+/// ESP-IDF ADC channel.
+pub trait DividerAdc {
+    /// Divider output as a fraction of the divider supply, 0.0-1.0
+    fn read_ratio(&mut self) -> Result<f32, SensorError>;
+}
+
+/// Steinhart–Hart coefficients: 1/T = A + B·ln(R) + C·ln(R)³, with T in kelvin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SteinhartHart {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl SteinhartHart {
+    /// Coefficients for the simpler beta model, from the datasheet values
+    /// (usually R25 and B25/85)
+    pub fn from_beta(r0_ohm: f64, t0_celsius: f64, beta: f64) -> Self {
+        let t0 = t0_celsius + KELVIN;
+        Self {
+            a: 1.0 / t0 - r0_ohm.ln() / beta,
+            b: 1.0 / beta,
+            c: 0.0,
+        }
+    }
+
+    pub fn celsius(&self, resistance_ohm: f64) -> f64 {
+        let ln_r = resistance_ohm.ln();
+        1.0 / (self.a + self.b * ln_r + self.c * ln_r * ln_r * ln_r) - KELVIN
+    }
+}
+
+const KELVIN: f64 = 273.15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NtcWiring {
+    /// Supply → series resistor → ADC → NTC → ground
+    NtcToGround,
+    /// Supply → NTC → ADC → series resistor → ground
+    NtcToSupply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtcConfig {
+    pub series_resistor_ohm: f64,
+    pub wiring: NtcWiring,
+    pub coefficients: SteinhartHart,
+}
+
+impl Default for NtcConfig {
+    /// Common 10kΩ, B=3950 thermistor with a 10kΩ series resistor
+    fn default() -> Self {
+        Self {
+            series_resistor_ohm: 10_000.0,
+            wiring: NtcWiring::NtcToGround,
+            coefficients: SteinhartHart::from_beta(10_000.0, 25.0, 3950.0),
+        }
+    }
+}
+
+impl NtcConfig {
+    /// Thermistor resistance for a divider ratio, `None` if the ratio means the
+    /// thermistor is shorted or disconnected
+    pub fn resistance_ohm(&self, ratio: f32) -> Option<f64> {
+        let ratio = ratio as f64;
+        if ratio <= 0.0 || ratio >= 1.0 {
+            return None;
+        }
+        Some(match self.wiring {
+            NtcWiring::NtcToGround => self.series_resistor_ohm * ratio / (1.0 - ratio),
+            NtcWiring::NtcToSupply => self.series_resistor_ohm * (1.0 - ratio) / ratio,
+        })
+    }
+}
+
+/// NTC thermistor read through an ADC
+pub struct Ntc<A> {
+    adc: A,
+    config: NtcConfig,
+}
+
+impl<A: DividerAdc> Ntc<A> {
+    pub fn new(adc: A, config: NtcConfig) -> Self {
+        Self { adc, config }
+    }
+}
+
+impl<A: DividerAdc> TemperatureSource for Ntc<A> {
+    fn name(&self) -> &str {
+        "ntc"
+    }
+
+    fn read_celsius(&mut self) -> Result<f32, SensorError> {
+        let ratio = self.adc.read_ratio()?;
+        let resistance = self
+            .config
+            .resistance_ohm(ratio)
+            .ok_or(SensorError::NotFound)?;
+        let celsius = self.config.coefficients.celsius(resistance);
+        if !celsius.is_finite() || !(-55.0..=150.0).contains(&celsius) {
+            return Err(SensorError::InvalidReading);
+        }
+        Ok(celsius as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedRatio(Result<f32, SensorError>);
+
+    impl DividerAdc for FixedRatio {
+        fn read_ratio(&mut self) -> Result<f32, SensorError> {
+            self.0.clone()
+        }
+    }
+
+    fn read(ratio: Result<f32, SensorError>, config: NtcConfig) -> Result<f32, SensorError> {
+        Ntc::new(FixedRatio(ratio), config).read_celsius()
+    }
+
+    #[test]
+    fn beta_model_round_trip() {
+        let coefficients = SteinhartHart::from_beta(10_000.0, 25.0, 3950.0);
+        assert!((coefficients.celsius(10_000.0) - 25.0).abs() < 1e-9);
+        for celsius in [-20.0, 0.0, 50.0, 85.0, 120.0] {
+            // R = R0·exp(B·(1/T - 1/T0))
+            let resistance =
+                10_000.0 * (3950.0 * (1.0 / (celsius + KELVIN) - 1.0 / (25.0 + KELVIN))).exp();
+            assert!((coefficients.celsius(resistance) - celsius).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn steinhart_hart_coefficients() {
+        // Fitted to a 10kΩ thermistor at 0, 25 and 50 °C
+        let coefficients = SteinhartHart {
+            a: 1.125308852e-3,
+            b: 2.34711863e-4,
+            c: 8.5663516e-8,
+        };
+        for (resistance, celsius) in [(32_650.0, 0.0), (10_000.0, 25.0), (3_603.0, 50.0)] {
+            assert!((coefficients.celsius(resistance) - celsius).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn divider_wiring() {
+        let to_ground = NtcConfig::default();
+        let to_supply = NtcConfig {
+            wiring: NtcWiring::NtcToSupply,
+            ..NtcConfig::default()
+        };
+        assert_eq!(to_ground.resistance_ohm(0.5), Some(10_000.0));
+        assert_eq!(to_supply.resistance_ohm(0.5), Some(10_000.0));
+        // Hotter thermistor, lower resistance
+        assert_eq!(to_ground.resistance_ohm(0.25), Some(10_000.0 / 3.0));
+        assert_eq!(to_supply.resistance_ohm(0.75), Some(10_000.0 / 3.0));
+
+        let celsius = read(Ok(0.5), to_ground).unwrap();
+        assert!((celsius - 25.0).abs() < 0.01);
+        assert!(read(Ok(0.25), to_ground).unwrap() > 25.0);
+        assert!(read(Ok(0.25), to_supply).unwrap() < 25.0);
+    }
+
+    #[test]
+    fn open_or_shorted() {
+        assert_eq!(
+            read(Ok(0.0), NtcConfig::default()),
+            Err(SensorError::NotFound)
+        );
+        assert_eq!(
+            read(Ok(1.0), NtcConfig::default()),
+            Err(SensorError::NotFound)
+        );
+    }
+
+    #[test]
+    fn implausible_temperature() {
+        // 0.01 is about 101Ω, far above 150 °C
+        assert_eq!(
+            read(Ok(0.01), NtcConfig::default()),
+            Err(SensorError::InvalidReading)
+        );
+    }
+
+    #[test]
+    fn adc_error() {
+        let error = SensorError::Bus("timeout".into());
+        assert_eq!(read(Err(error.clone()), NtcConfig::default()), Err(error));
+    }
+}
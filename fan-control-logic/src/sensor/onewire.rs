@@ -0,0 +1,170 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use super::{crc8, SensorError};
+
+/// Bit-banged 1-Wire bus master (standard speed).
+///
+/// `pin` has to be an open drain pin with a pull-up (4.7kΩ external is what the
+/// datasheets want), so that setting it high releases the bus.
+pub struct OneWire<P, D> {
+    pin: P,
+    delay: D,
+}
+
+impl<P, D> OneWire<P, D>
+where
+    P: InputPin + OutputPin,
+    D: DelayNs,
+{
+    pub fn new(mut pin: P, delay: D) -> Result<Self, SensorError> {
+        pin.set_high().map_err(SensorError::bus)?;
+        Ok(Self { pin, delay })
+    }
+
+    /// Reset pulse. Returns `true` if a device answered with a presence pulse.
+    pub fn reset(&mut self) -> Result<bool, SensorError> {
+        self.pin.set_low().map_err(SensorError::bus)?;
+        self.delay.delay_us(480);
+        self.pin.set_high().map_err(SensorError::bus)?;
+        self.delay.delay_us(70);
+        let present = self.pin.is_low().map_err(SensorError::bus)?;
+        self.delay.delay_us(410);
+        Ok(present)
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), SensorError> {
+        let (low_us, release_us) = if bit { (6, 64) } else { (60, 10) };
+        self.pin.set_low().map_err(SensorError::bus)?;
+        self.delay.delay_us(low_us);
+        self.pin.set_high().map_err(SensorError::bus)?;
+        self.delay.delay_us(release_us);
+        Ok(())
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, SensorError> {
+        self.pin.set_low().map_err(SensorError::bus)?;
+        self.delay.delay_us(6);
+        self.pin.set_high().map_err(SensorError::bus)?;
+        self.delay.delay_us(9);
+        let bit = self.pin.is_high().map_err(SensorError::bus)?;
+        self.delay.delay_us(55);
+        Ok(bit)
+    }
+
+    /// Bytes go out least significant bit first
+    pub fn write_byte(&mut self, byte: u8) -> Result<(), SensorError> {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, SensorError> {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << i;
+            }
+        }
+        Ok(byte)
+    }
+
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay.delay_ms(ms);
+    }
+}
+
+/// Dallas/Maxim CRC-8, used for 1-Wire ROM codes and scratchpads
+pub fn crc8_maxim(data: &[u8]) -> u8 {
+    crc8(data, 0x31, 0x00, true)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, NoopDelay, Transaction as Delay};
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction};
+    use embedded_hal_mock::eh1::MockError;
+
+    use super::*;
+
+    /// Pin transactions of a reset, `present` if a device pulls the bus low
+    pub(crate) fn reset(present: bool) -> Vec<Transaction> {
+        let sampled = if present { State::Low } else { State::High };
+        vec![
+            Transaction::set(State::Low),
+            Transaction::set(State::High),
+            Transaction::get(sampled),
+        ]
+    }
+
+    /// Every bit is a low pulse, only its length tells ones from zeros
+    pub(crate) fn write_byte() -> Vec<Transaction> {
+        (0..8)
+            .flat_map(|_| [Transaction::set(State::Low), Transaction::set(State::High)])
+            .collect()
+    }
+
+    pub(crate) fn read_byte(byte: u8) -> Vec<Transaction> {
+        (0..8)
+            .flat_map(|i| {
+                let state = if byte & (1 << i) != 0 {
+                    State::High
+                } else {
+                    State::Low
+                };
+                [
+                    Transaction::set(State::Low),
+                    Transaction::set(State::High),
+                    Transaction::get(state),
+                ]
+            })
+            .collect()
+    }
+
+    fn bus(transactions: &[Transaction]) -> (OneWire<PinMock, NoopDelay>, PinMock) {
+        let pin = PinMock::new(&[&[Transaction::set(State::High)], transactions].concat());
+        let bus = OneWire::new(pin.clone(), NoopDelay::new()).unwrap();
+        (bus, pin)
+    }
+
+    #[test]
+    fn reset_detects_presence() {
+        let (mut bus, mut pin) = bus(&[reset(true), reset(false)].concat());
+        assert_eq!(bus.reset(), Ok(true));
+        assert_eq!(bus.reset(), Ok(false));
+        pin.done();
+    }
+
+    #[test]
+    fn reads_least_significant_bit_first() {
+        let (mut bus, mut pin) = bus(&read_byte(0xA5));
+        assert_eq!(bus.read_byte(), Ok(0xA5));
+        pin.done();
+    }
+
+    #[test]
+    fn write_slot_timing() {
+        let pin =
+            PinMock::new(&[&[Transaction::set(State::High)], write_byte().as_slice()].concat());
+        // 0x01: a short low pulse for the one, long ones for the zeros
+        let mut expected = vec![Delay::delay_us(6), Delay::delay_us(64)];
+        for _ in 1..8 {
+            expected.extend([Delay::delay_us(60), Delay::delay_us(10)]);
+        }
+        let delay = CheckedDelay::new(&expected);
+        let mut bus = OneWire::new(pin.clone(), delay.clone()).unwrap();
+        bus.write_byte(0x01).unwrap();
+        pin.clone().done();
+        delay.clone().done();
+    }
+
+    #[test]
+    fn pin_errors_are_bus_errors() {
+        let (mut bus, mut pin) = bus(&[
+            Transaction::set(State::Low).with_error(MockError::Io(std::io::ErrorKind::Other))
+        ]);
+        assert!(matches!(bus.reset(), Err(SensorError::Bus(_))));
+        pin.done();
+    }
+}
@@ -0,0 +1,118 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use super::{crc8, SensorError, TemperatureSource};
+
+/// ADDR pin low
+pub const DEFAULT_ADDRESS: u8 = 0x44;
+
+/// Single shot, high repeatability, no clock stretching
+const MEASURE_HIGH_REPEATABILITY: [u8; 2] = [0x24, 0x00];
+/// Max measurement duration at high repeatability
+const MEASUREMENT_TIME_MS: u32 = 16;
+
+/// Sensirion SHT30/31/35 humidity and temperature sensor
+pub struct Sht3x<I, D> {
+    i2c: I,
+    delay: D,
+    address: u8,
+}
+
+impl<I: I2c, D: DelayNs> Sht3x<I, D> {
+    pub fn new(i2c: I, delay: D, address: u8) -> Self {
+        Self {
+            i2c,
+            delay,
+            address,
+        }
+    }
+}
+
+impl<I: I2c, D: DelayNs> TemperatureSource for Sht3x<I, D> {
+    fn name(&self) -> &str {
+        "sht3x"
+    }
+
+    fn read_celsius(&mut self) -> Result<f32, SensorError> {
+        self.i2c
+            .write(self.address, &MEASURE_HIGH_REPEATABILITY)
+            .map_err(SensorError::bus)?;
+        self.delay.delay_ms(MEASUREMENT_TIME_MS);
+
+        let mut data = [0u8; 6];
+        self.i2c
+            .read(self.address, &mut data)
+            .map_err(SensorError::bus)?;
+        parse_measurement(&data)
+    }
+}
+
+/// Temperature from a measurement: temperature (2 bytes + CRC), humidity (2 bytes + CRC)
+pub fn parse_measurement(data: &[u8; 6]) -> Result<f32, SensorError> {
+    if crc8(&data[0..2], 0x31, 0xFF, false) != data[2] {
+        return Err(SensorError::Crc);
+    }
+    let raw = u16::from_be_bytes([data[0], data[1]]);
+    Ok(-45.0 + 175.0 * raw as f32 / 65535.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction};
+
+    use super::*;
+
+    /// Measurement with valid CRCs, humidity at 50%
+    fn measurement(raw: u16) -> [u8; 6] {
+        let [msb, lsb] = raw.to_be_bytes();
+        let humidity = [0x80, 0x00];
+        [
+            msb,
+            lsb,
+            crc8(&[msb, lsb], 0x31, 0xFF, false),
+            humidity[0],
+            humidity[1],
+            crc8(&humidity, 0x31, 0xFF, false),
+        ]
+    }
+
+    #[test]
+    fn conversion() {
+        assert_eq!(parse_measurement(&measurement(0x0000)), Ok(-45.0));
+        assert_eq!(parse_measurement(&measurement(0xFFFF)), Ok(130.0));
+        let celsius = parse_measurement(&measurement(0x6666)).unwrap();
+        assert!((celsius - 25.0).abs() < 0.01, "{celsius}");
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut data = measurement(0x6666);
+        data[2] ^= 0x01;
+        assert_eq!(parse_measurement(&data), Err(SensorError::Crc));
+    }
+
+    #[test]
+    fn reads_over_the_bus() {
+        let mut i2c = I2cMock::new(&[
+            Transaction::write(DEFAULT_ADDRESS, MEASURE_HIGH_REPEATABILITY.to_vec()),
+            Transaction::read(DEFAULT_ADDRESS, measurement(0x0000).to_vec()),
+        ]);
+        let mut sensor = Sht3x::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS);
+        assert_eq!(sensor.read_celsius(), Ok(-45.0));
+        i2c.done();
+    }
+
+    #[test]
+    fn no_acknowledge() {
+        let mut i2c = I2cMock::new(&[Transaction::write(
+            DEFAULT_ADDRESS,
+            MEASURE_HIGH_REPEATABILITY.to_vec(),
+        )
+        .with_error(ErrorKind::Other)]);
+        let mut sensor = Sht3x::new(i2c.clone(), NoopDelay::new(), DEFAULT_ADDRESS);
+        assert!(matches!(sensor.read_celsius(), Err(SensorError::Bus(_))));
+        i2c.done();
+    }
+}
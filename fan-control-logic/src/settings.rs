@@ -0,0 +1,489 @@
+//! Settings that survive a reboot.
+//!
+//! Stored as JSON with a `version` field. When the schema changes in a way
+//! `#[serde(default)]` can't cover (renames, moved fields, new meaning), bump
+//! [`SCHEMA_VERSION`] and add a migration from the previous version to
+//! [`MIGRATIONS`], so settings saved by older firmware keep working.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    control::ControlPolicy, curve::CurveConfig, knob::KnobConfig, mode::ControlMode,
+    mqtt::MqttConfig, profile::Profiles, schedule::ScheduleConfig, slew::SlewConfig,
+    spin_up::SpinUpConfig, tacho::DEFAULT_PULSES_PER_REV, telemetry::TelemetryConfig,
+    zero_rpm::ZeroRpmConfig,
+};
+
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` turns version `n + 1` into version `n + 2`
+const MIGRATIONS: [fn(&mut Value); SCHEMA_VERSION as usize - 1] = [];
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub boot_duty: BootDuty,
+    pub control: ControlPolicy,
+    pub knob: KnobConfig,
+    pub display: DisplayOptions,
+    #[serde(flatten)]
+    pub profiles: Profiles,
+    pub schedule: ScheduleConfig,
+    pub telemetry: TelemetryConfig,
+    pub mqtt: MqttConfig,
+    pub fans: Vec<FanSettings>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayOptions {
+    pub animation: bool,
+    /// Upside down, for mounting the screen the other way around
+    pub flipped: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            animation: true,
+            flipped: false,
+        }
+    }
+}
+
+/// What duty the fans start at after a reboot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootDuty {
+    /// Same duty and control mode as before the reboot
+    #[default]
+    RestoreLast,
+    /// Always start in manual mode at this duty
+    Fixed(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanSettings {
+    pub pwm: u32,
+    pub mode: ControlMode,
+    pub target_rpm: u32,
+    pub curve: CurveConfig,
+    pub spin_up: SpinUpConfig,
+    pub slew: SlewConfig,
+    pub pulses_per_rev: u32,
+    pub max_duty: u32,
+    pub zero_rpm: ZeroRpmConfig,
+}
+
+impl Default for FanSettings {
+    fn default() -> Self {
+        Self {
+            pwm: 50,
+            mode: ControlMode::Manual,
+            target_rpm: 0,
+            curve: CurveConfig::default(),
+            spin_up: SpinUpConfig::default(),
+            slew: SlewConfig::default(),
+            pulses_per_rev: DEFAULT_PULSES_PER_REV,
+            max_duty: 100,
+            zero_rpm: ZeroRpmConfig::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// For showing over the API, without the MQTT password
+    pub fn redacted(&self) -> Self {
+        Self {
+            mqtt: self.mqtt.redacted(),
+            ..self.clone()
+        }
+    }
+
+    /// Duty and mode `fan` should start in, according to [`BootDuty`]
+    pub fn boot_state(&self, fan: &FanSettings) -> (u32, ControlMode) {
+        match self.boot_duty {
+            BootDuty::RestoreLast => (fan.pwm, fan.mode),
+            BootDuty::Fixed(duty) => (duty.min(100), ControlMode::Manual),
+        }
+    }
+
+    /// Checks everything the API would check if it was set piece by piece
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |field: &str, e: &dyn std::fmt::Display| {
+            SettingsError::Invalid(format!("{field}: {e}"))
+        };
+        if matches!(self.boot_duty, BootDuty::Fixed(duty) if duty > 100) {
+            return Err(invalid("boot_duty", &"Duty must be 0-100"));
+        }
+        self.knob.validate().map_err(|e| invalid("knob", &e))?;
+        for profile in &self.profiles.profiles {
+            profile.validate().map_err(|e| invalid("profiles", &e))?;
+        }
+        self.schedule
+            .validate()
+            .map_err(|e| invalid("schedule", &e))?;
+        self.telemetry
+            .validate()
+            .map_err(|e| invalid("telemetry", &e))?;
+        self.mqtt.validate().map_err(|e| invalid("mqtt", &e))?;
+        for (i, fan) in self.fans.iter().enumerate() {
+            let field = |name: &str| format!("fans[{i}].{name}");
+            if fan.pwm > 100 {
+                return Err(invalid(&field("pwm"), &"Duty must be 0-100"));
+            }
+            if !(1..=100).contains(&fan.max_duty) {
+                return Err(invalid(&field("max_duty"), &"Duty must be 1-100"));
+            }
+            if !(1..=8).contains(&fan.pulses_per_rev) {
+                return Err(invalid(
+                    &field("pulses_per_rev"),
+                    &"Pulses per revolution must be 1-8",
+                ));
+            }
+            fan.curve
+                .to_curve()
+                .map_err(|e| invalid(&field("curve"), &e))?;
+            fan.spin_up
+                .validate()
+                .map_err(|e| invalid(&field("spin_up"), &e))?;
+            fan.slew
+                .validate()
+                .map_err(|e| invalid(&field("slew"), &e))?;
+            fan.zero_rpm
+                .validate()
+                .map_err(|e| invalid(&field("zero_rpm"), &e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Json(serde_json::Error),
+    /// Saved by newer firmware, we can't know what changed. Or no version at all.
+    UnknownVersion(u64),
+    /// Readable, but a value is out of range, see [`Settings::validate`]
+    Invalid(String),
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::Json(e) => write!(f, "Invalid settings: {e}"),
+            SettingsError::UnknownVersion(version) => write!(
+                f,
+                "Unknown settings version {version}, supported are 1-{SCHEMA_VERSION}"
+            ),
+            SettingsError::Invalid(e) => write!(f, "Invalid settings: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl From<serde_json::Error> for SettingsError {
+    fn from(e: serde_json::Error) -> Self {
+        SettingsError::Json(e)
+    }
+}
+
+pub fn to_json(settings: &Settings) -> Result<Value, SettingsError> {
+    let mut value = serde_json::to_value(settings)?;
+    if let Value::Object(object) = &mut value {
+        object.insert("version".to_string(), SCHEMA_VERSION.into());
+    }
+    Ok(value)
+}
+
+/// Parses settings saved by any version of the firmware up to this one
+pub fn from_json(mut value: Value) -> Result<Settings, SettingsError> {
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if !(1..=SCHEMA_VERSION).contains(&version) {
+        return Err(SettingsError::UnknownVersion(version));
+    }
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut value);
+    }
+    if let Value::Object(object) = &mut value {
+        object.remove("version");
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Applies a JSON merge patch (RFC 7386) to `target`: objects are merged, `null`
+/// removes a field, anything else replaces it, arrays included
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(object) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                object.remove(&key);
+            } else {
+                merge_patch(object.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Holds back writes until the settings have stopped changing, to save flash
+/// wear while the knob is turned.
+#[derive(Debug, Clone)]
+pub struct SaveDebouncer<T> {
+    /// Save once nothing has changed for this long
+    quiet_ms: u64,
+    /// Save at least this often while things keep changing
+    max_delay_ms: u64,
+    saved: Option<T>,
+    /// Latest unsaved value, when it first and last changed
+    pending: Option<(T, u64, u64)>,
+}
+
+impl<T: Clone + PartialEq> SaveDebouncer<T> {
+    /// `saved` is what's already in flash, if anything
+    pub fn new(saved: Option<T>, quiet_ms: u64, max_delay_ms: u64) -> Self {
+        Self {
+            quiet_ms,
+            max_delay_ms,
+            saved,
+            pending: None,
+        }
+    }
+
+    /// Call regularly with the current value, returns the value to save now if any.
+    /// The returned value counts as saved, call [`SaveDebouncer::save_failed`] if
+    /// it wasn't.
+    pub fn update(&mut self, current: &T, now_ms: u64) -> Option<T> {
+        if self.saved.as_ref() == Some(current) {
+            self.pending = None;
+            return None;
+        }
+
+        let (first_change_ms, last_change_ms) = match &self.pending {
+            Some((pending, first, last)) if pending == current => (*first, *last),
+            Some((_, first, _)) => (*first, now_ms),
+            None => (now_ms, now_ms),
+        };
+        let quiet = now_ms.saturating_sub(last_change_ms) >= self.quiet_ms;
+        let overdue = now_ms.saturating_sub(first_change_ms) >= self.max_delay_ms;
+        if quiet || overdue {
+            self.pending = None;
+            self.saved = Some(current.clone());
+            return self.saved.clone();
+        }
+
+        self.pending = Some((current.clone(), first_change_ms, last_change_ms));
+        None
+    }
+
+    /// Try again with the next update
+    pub fn save_failed(&mut self) {
+        self.saved = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            boot_duty: BootDuty::Fixed(40),
+            display: DisplayOptions {
+                animation: false,
+                flipped: true,
+            },
+            fans: vec![
+                FanSettings {
+                    pwm: 70,
+                    mode: ControlMode::Curve,
+                    ..FanSettings::default()
+                },
+                FanSettings {
+                    mode: ControlMode::TargetRpm,
+                    target_rpm: 1_200,
+                    pulses_per_rev: 4,
+                    ..FanSettings::default()
+                },
+            ],
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = to_json(&settings()).unwrap();
+        assert_eq!(value["version"], SCHEMA_VERSION);
+        assert_eq!(value["boot_duty"], json!({ "fixed": 40 }));
+        assert_eq!(value["fans"][0]["mode"], "curve");
+        assert_eq!(from_json(value).unwrap(), settings());
+    }
+
+    #[test]
+    fn unknown_or_missing_version() {
+        let mut value = to_json(&settings()).unwrap();
+        value["version"] = (SCHEMA_VERSION + 1).into();
+        assert!(matches!(
+            from_json(value.clone()),
+            Err(SettingsError::UnknownVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+
+        value["version"] = 0.into();
+        assert!(matches!(
+            from_json(value.clone()),
+            Err(SettingsError::UnknownVersion(0))
+        ));
+
+        value.as_object_mut().unwrap().remove("version");
+        assert!(matches!(
+            from_json(value),
+            Err(SettingsError::UnknownVersion(0))
+        ));
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        assert_eq!(
+            from_json(json!({ "version": 1 })).unwrap(),
+            Settings::default()
+        );
+
+        let settings = from_json(json!({
+            "version": 1,
+            "display": { "flipped": true },
+            "fans": [{ "pwm": 80 }],
+        }))
+        .unwrap();
+        assert_eq!(
+            settings.display,
+            DisplayOptions {
+                animation: true,
+                flipped: true,
+            }
+        );
+        assert_eq!(
+            settings.fans,
+            [FanSettings {
+                pwm: 80,
+                ..FanSettings::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn wrong_types_are_errors() {
+        assert!(matches!(
+            from_json(json!({ "version": 1, "fans": [{ "pwm": "high" }] })),
+            Err(SettingsError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn validation() {
+        assert!(settings().validate().is_ok());
+
+        let mut invalid = settings();
+        invalid.fans[1].pulses_per_rev = 9;
+        assert!(matches!(
+            invalid.validate(),
+            Err(SettingsError::Invalid(e)) if e.starts_with("fans[1].pulses_per_rev")
+        ));
+
+        let mut invalid = settings();
+        invalid.boot_duty = BootDuty::Fixed(101);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn boot_state() {
+        let mut settings = settings();
+        assert_eq!(
+            settings.boot_state(&settings.fans[0]),
+            (40, ControlMode::Manual)
+        );
+        settings.boot_duty = BootDuty::RestoreLast;
+        assert_eq!(
+            settings.boot_state(&settings.fans[0]),
+            (70, ControlMode::Curve)
+        );
+    }
+
+    #[test]
+    fn mqtt_password_is_write_only() {
+        let mut settings = settings();
+        settings.mqtt.password = "secret".to_string();
+        let shown = to_json(&settings.redacted()).unwrap();
+        assert_eq!(shown["mqtt"]["password"], "");
+
+        // Sent back as it was read, or without it
+        let mut sent_back = from_json(shown).unwrap();
+        sent_back.mqtt.keep_password(&settings.mqtt);
+        assert_eq!(sent_back, settings);
+
+        let mut changed = settings.clone();
+        changed.mqtt.password = "new".to_string();
+        changed.mqtt.keep_password(&settings.mqtt);
+        assert_eq!(changed.mqtt.password, "new");
+    }
+
+    #[test]
+    fn merge_patch_rfc_7386() {
+        let mut target = json!({ "a": "b", "c": { "d": "e", "f": "g" }, "list": [1, 2] });
+        merge_patch(
+            &mut target,
+            json!({ "a": "z", "c": { "f": null }, "list": [3], "new": { "x": 1 } }),
+        );
+        assert_eq!(
+            target,
+            json!({ "a": "z", "c": { "d": "e" }, "list": [3], "new": { "x": 1 } })
+        );
+    }
+
+    #[test]
+    fn debouncer_waits_for_quiet() {
+        let mut debouncer = SaveDebouncer::new(Some(0), 1_000, 5_000);
+        assert_eq!(debouncer.update(&0, 0), None);
+        assert_eq!(debouncer.update(&1, 100), None);
+        assert_eq!(debouncer.update(&2, 500), None);
+        assert_eq!(debouncer.update(&2, 1_499), None);
+        assert_eq!(debouncer.update(&2, 1_500), Some(2));
+        assert_eq!(debouncer.update(&2, 3_000), None);
+    }
+
+    #[test]
+    fn debouncer_saves_while_changing() {
+        let mut debouncer = SaveDebouncer::new(Some(0), 1_000, 5_000);
+        for (i, now_ms) in (0..5_000).step_by(500).enumerate() {
+            assert_eq!(debouncer.update(&(i + 1), now_ms), None);
+        }
+        assert_eq!(debouncer.update(&11, 5_000), Some(11));
+    }
+
+    #[test]
+    fn debouncer_change_back_cancels() {
+        let mut debouncer = SaveDebouncer::new(Some(0), 1_000, 5_000);
+        assert_eq!(debouncer.update(&1, 0), None);
+        assert_eq!(debouncer.update(&0, 500), None);
+        assert_eq!(debouncer.update(&0, 10_000), None);
+    }
+
+    #[test]
+    fn debouncer_retries_failed_saves() {
+        let mut debouncer = SaveDebouncer::new(None, 1_000, 5_000);
+        assert_eq!(debouncer.update(&1, 0), None);
+        assert_eq!(debouncer.update(&1, 1_000), Some(1));
+        debouncer.save_failed();
+        assert_eq!(debouncer.update(&1, 1_100), None);
+        assert_eq!(debouncer.update(&1, 2_100), Some(1));
+    }
+}
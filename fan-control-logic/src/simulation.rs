@@ -0,0 +1,51 @@
+/// Very rough model of a PWM controlled PC fan.
+///
+/// Good enough to exercise control loops on the host: the fan has a maximum
+/// speed, needs a minimum duty to keep turning, and only changes speed so fast.
+/// `efficiency` can be lowered to simulate dust, low supply voltage or an aging
+/// fan that doesn't reach the same RPM for the same duty anymore.
+#[derive(Debug, Clone)]
+pub struct FanModel {
+    pub max_rpm: f32,
+    /// Below this duty (percent) the fan stops
+    pub min_duty: f32,
+    /// How fast the fan can change speed
+    pub rpm_per_sec: f32,
+    /// 1.0 = brand new fan, lower = fan reaches a lower RPM for the same duty
+    pub efficiency: f32,
+    rpm: f32,
+}
+
+impl Default for FanModel {
+    fn default() -> Self {
+        Self {
+            max_rpm: 2000.0,
+            min_duty: 10.0,
+            rpm_per_sec: 600.0,
+            efficiency: 1.0,
+            rpm: 0.0,
+        }
+    }
+}
+
+impl FanModel {
+    /// RPM the fan settles at for a given duty
+    pub fn steady_state_rpm(&self, duty: f32) -> f32 {
+        if duty < self.min_duty {
+            return 0.0;
+        }
+        self.max_rpm * self.efficiency * (duty.clamp(0.0, 100.0) / 100.0)
+    }
+
+    /// Advance the simulation by `dt_s` seconds with the given duty and return the new RPM
+    pub fn step(&mut self, duty: f32, dt_s: f32) -> f32 {
+        let target = self.steady_state_rpm(duty);
+        let max_change = self.rpm_per_sec * dt_s;
+        self.rpm += (target - self.rpm).clamp(-max_change, max_change);
+        self.rpm
+    }
+
+    pub fn rpm(&self) -> f32 {
+        self.rpm
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+/// How fast the applied duty may follow the requested one, in %/s. 0 means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlewConfig {
+    pub rise_per_sec: f32,
+    pub fall_per_sec: f32,
+}
+
+impl Default for SlewConfig {
+    fn default() -> Self {
+        Self {
+            rise_per_sec: 10.0,
+            fall_per_sec: 5.0,
+        }
+    }
+}
+
+impl SlewConfig {
+    pub fn validate(&self) -> Result<(), SlewError> {
+        let valid = |rate: f32| rate.is_finite() && rate >= 0.0;
+        if !valid(self.rise_per_sec) || !valid(self.fall_per_sec) {
+            return Err(SlewError::InvalidRate);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlewError {
+    InvalidRate,
+}
+
+impl std::fmt::Display for SlewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlewError::InvalidRate => write!(f, "Slew rates must be positive numbers, or 0"),
+        }
+    }
+}
+
+impl std::error::Error for SlewError {}
+
+/// Ramps towards the requested duty instead of jumping to it, so the fan doesn't
+/// audibly surge on big changes.
+#[derive(Debug, Clone)]
+pub struct SlewLimiter {
+    config: SlewConfig,
+    /// `None` until the first update, which is applied as is
+    applied: Option<f32>,
+}
+
+impl SlewLimiter {
+    pub fn new(config: SlewConfig) -> Self {
+        Self {
+            config,
+            applied: None,
+        }
+    }
+
+    pub fn config(&self) -> &SlewConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: SlewConfig) {
+        self.config = config;
+    }
+
+    /// Moves the applied duty towards `requested` by at most `dt_s` worth of slew
+    pub fn update(&mut self, requested: f32, dt_s: f32) -> f32 {
+        let Some(applied) = self.applied else {
+            self.applied = Some(requested);
+            return requested;
+        };
+
+        let limit = |rate: f32| {
+            if rate > 0.0 {
+                rate * dt_s
+            } else {
+                f32::INFINITY
+            }
+        };
+        let delta = (requested - applied).clamp(
+            -limit(self.config.fall_per_sec),
+            limit(self.config.rise_per_sec),
+        );
+        let applied = applied + delta;
+        self.applied = Some(applied);
+        applied
+    }
+
+    /// Continue from `duty` without ramping, e.g. when something else has been
+    /// driving the fan
+    pub fn reset_to(&mut self, duty: f32) {
+        self.applied = Some(duty);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_update_is_applied() {
+        let mut slew = SlewLimiter::new(SlewConfig::default());
+        assert_eq!(slew.update(60.0, 0.1), 60.0);
+    }
+
+    #[test]
+    fn separate_rise_and_fall_rates() {
+        let mut slew = SlewLimiter::new(SlewConfig::default());
+        slew.update(0.0, 0.0);
+        assert_eq!(slew.update(100.0, 1.0), 10.0);
+        assert_eq!(slew.update(100.0, 0.5), 15.0);
+        assert_eq!(slew.update(0.0, 1.0), 10.0);
+        assert_eq!(slew.update(0.0, 1.0), 5.0);
+        // Doesn't overshoot
+        assert_eq!(slew.update(0.0, 2.0), 0.0);
+        assert_eq!(slew.update(3.0, 1.0), 3.0);
+    }
+
+    #[test]
+    fn zero_is_no_limit() {
+        let mut slew = SlewLimiter::new(SlewConfig {
+            rise_per_sec: 0.0,
+            fall_per_sec: 5.0,
+        });
+        slew.update(0.0, 0.0);
+        assert_eq!(slew.update(100.0, 0.1), 100.0);
+        assert_eq!(slew.update(0.0, 1.0), 95.0);
+
+        slew.set_config(SlewConfig {
+            rise_per_sec: 10.0,
+            fall_per_sec: 0.0,
+        });
+        assert_eq!(slew.update(0.0, 0.1), 0.0);
+        assert_eq!(slew.update(100.0, 1.0), 10.0);
+    }
+
+    #[test]
+    fn reset_to_jumps() {
+        let mut slew = SlewLimiter::new(SlewConfig::default());
+        slew.update(0.0, 0.0);
+        slew.reset_to(80.0);
+        assert_eq!(slew.update(100.0, 1.0), 90.0);
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(SlewConfig::default().validate(), Ok(()));
+        for (rise_per_sec, fall_per_sec) in [(-1.0, 5.0), (10.0, f32::NAN), (f32::INFINITY, 5.0)] {
+            let config = SlewConfig {
+                rise_per_sec,
+                fall_per_sec,
+            };
+            assert_eq!(config.validate(), Err(SlewError::InvalidRate));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::FanCalibration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpinUpConfig {
+    /// Lowest duty the fan keeps running at, anything below switches it off
+    pub min_duty: u32,
+    /// Starting from standstill below this duty needs a kick
+    pub start_duty: u32,
+    pub kick_duty: u32,
+    pub kick_ms: u64,
+}
+
+impl Default for SpinUpConfig {
+    fn default() -> Self {
+        Self {
+            min_duty: 10,
+            start_duty: 30,
+            kick_duty: 100,
+            kick_ms: 1000,
+        }
+    }
+}
+
+impl SpinUpConfig {
+    /// Use the thresholds measured by a calibration sweep
+    pub fn with_calibration(self, calibration: &FanCalibration) -> Self {
+        Self {
+            min_duty: calibration.min_running_duty,
+            start_duty: calibration.min_start_duty,
+            ..self
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SpinUpError> {
+        if self.min_duty > 100 || self.start_duty > 100 || self.kick_duty > 100 {
+            return Err(SpinUpError::DutyOutOfRange);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpinUpError {
+    DutyOutOfRange,
+}
+
+impl std::fmt::Display for SpinUpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpinUpError::DutyOutOfRange => write!(f, "Duty cycles must be 0-100"),
+        }
+    }
+}
+
+impl std::error::Error for SpinUpError {}
+
+/// Turns the commanded duty into what the fan actually gets.
+///
+/// Below `min_duty` the fan is off (0%) instead of stalling at a duty it can't
+/// turn at. Going from off to a duty below `start_duty` runs the fan at
+/// `kick_duty` for `kick_ms` first to get it going.
+#[derive(Debug, Clone)]
+pub struct SpinUp {
+    config: SpinUpConfig,
+    running: bool,
+    kick_until_ms: Option<u64>,
+}
+
+impl SpinUp {
+    pub fn new(config: SpinUpConfig) -> Self {
+        Self {
+            config,
+            running: false,
+            kick_until_ms: None,
+        }
+    }
+
+    pub fn config(&self) -> &SpinUpConfig {
+        &self.config
+    }
+
+    /// Takes effect from the next update, a kick in progress keeps going
+    pub fn set_config(&mut self, config: SpinUpConfig) {
+        self.config = config;
+    }
+
+    /// Call every control period, not just when the duty changes, so kicks end on time
+    pub fn update(&mut self, duty: u32, now_ms: u64) -> u32 {
+        let config = self.config;
+        if duty == 0 || duty < config.min_duty {
+            self.running = false;
+            self.kick_until_ms = None;
+            return 0;
+        }
+
+        if !self.running {
+            self.running = true;
+            if duty < config.start_duty {
+                self.kick_until_ms = Some(now_ms + config.kick_ms);
+            }
+        }
+
+        match self.kick_until_ms {
+            Some(until_ms) if now_ms < until_ms => duty.max(config.kick_duty).min(100),
+            _ => {
+                self.kick_until_ms = None;
+                duty.min(100)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kicks_from_standstill() {
+        let mut spin_up = SpinUp::new(SpinUpConfig::default());
+        assert_eq!(spin_up.update(20, 0), 100);
+        assert_eq!(spin_up.update(20, 999), 100);
+        assert_eq!(spin_up.update(20, 1_000), 20);
+        assert_eq!(spin_up.update(20, 5_000), 20);
+    }
+
+    #[test]
+    fn kick_never_lowers_the_duty() {
+        let mut spin_up = SpinUp::new(SpinUpConfig {
+            kick_duty: 20,
+            ..Default::default()
+        });
+        assert_eq!(spin_up.update(25, 0), 25);
+        assert_eq!(spin_up.update(15, 500), 20);
+        assert_eq!(spin_up.update(15, 1_000), 15);
+    }
+
+    #[test]
+    fn off_below_min_duty() {
+        let mut spin_up = SpinUp::new(SpinUpConfig::default());
+        assert_eq!(spin_up.update(0, 0), 0);
+        assert_eq!(spin_up.update(9, 100), 0);
+        assert_eq!(spin_up.update(10, 200), 100);
+        assert_eq!(spin_up.update(10, 1_200), 10);
+        // Stopping ends the kick, and starting again kicks again
+        assert_eq!(spin_up.update(9, 1_300), 0);
+        assert_eq!(spin_up.update(20, 1_400), 100);
+        assert_eq!(spin_up.update(5, 1_500), 0);
+        assert_eq!(spin_up.update(20, 1_600), 100);
+    }
+
+    #[test]
+    fn no_kick_when_running() {
+        let mut spin_up = SpinUp::new(SpinUpConfig::default());
+        // Starts on its own at this duty
+        assert_eq!(spin_up.update(30, 0), 30);
+        assert_eq!(spin_up.update(15, 100), 15);
+        assert_eq!(spin_up.update(10, 200), 10);
+    }
+
+    #[test]
+    fn clamped_to_100() {
+        let mut spin_up = SpinUp::new(SpinUpConfig::default());
+        assert_eq!(spin_up.update(150, 0), 100);
+    }
+
+    #[test]
+    fn config_from_calibration() {
+        let calibration = FanCalibration {
+            min_start_duty: 25,
+            min_running_duty: 15,
+            max_rpm: 2000,
+            points: Vec::new(),
+        };
+        let config = SpinUpConfig::default().with_calibration(&calibration);
+        assert_eq!(
+            config,
+            SpinUpConfig {
+                min_duty: 15,
+                start_duty: 25,
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.validate(), Ok(()));
+        let config = SpinUpConfig {
+            kick_duty: 101,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Err(SpinUpError::DutyOutOfRange));
+    }
+}
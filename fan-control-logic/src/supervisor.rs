@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Proof of life from a supervised task, the time of its last loop iteration
+#[derive(Debug, Default)]
+pub struct Heartbeat(AtomicU64);
+
+impl Heartbeat {
+    pub fn beat(&self, now_ms: u64) {
+        self.0.store(now_ms, Ordering::Relaxed);
+    }
+
+    pub fn last_ms(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    /// Died, waiting to be restarted
+    Restarting,
+    /// Died too often, given up on
+    Failed,
+    /// Stopped sending heartbeats while still running. It can't be stopped, so it
+    /// can't be replaced either.
+    Hung,
+}
+
+impl TaskStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskStatus::Running => "running",
+            TaskStatus::Restarting => "restarting",
+            TaskStatus::Failed => "failed",
+            TaskStatus::Hung => "hung",
+        }
+    }
+}
+
+/// What the supervisor should do about a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskAction {
+    None,
+    /// Nothing controls the fan, run it at full speed
+    FailSafe,
+    Restart,
+    /// Run the fan at full speed and stop feeding the watchdog, so the chip resets
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    /// Give up after this many restarts within `window_ms`
+    pub max_restarts: usize,
+    pub window_ms: u64,
+    /// Wait this long before restarting, in case the task died from something passing
+    pub delay_ms: u64,
+    /// A running task without a heartbeat for this long is hung
+    pub heartbeat_timeout_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            window_ms: 60_000,
+            delay_ms: 1_000,
+            heartbeat_timeout_ms: 5_000,
+        }
+    }
+}
+
+/// Decides when a supervised task has to be restarted or given up on
+#[derive(Debug, Clone)]
+pub struct TaskMonitor {
+    policy: RestartPolicy,
+    status: TaskStatus,
+    /// When the task was restarted, within the policy window
+    restarts_ms: Vec<u64>,
+    died_ms: Option<u64>,
+}
+
+impl TaskMonitor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            status: TaskStatus::Running,
+            restarts_ms: Vec::new(),
+            died_ms: None,
+        }
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status
+    }
+
+    /// Number of restarts within the policy window
+    pub fn recent_restarts(&self) -> usize {
+        self.restarts_ms.len()
+    }
+
+    /// `finished` is whether the task's thread has exited, by panicking or otherwise
+    pub fn update(&mut self, now_ms: u64, last_heartbeat_ms: u64, finished: bool) -> TaskAction {
+        let policy = self.policy;
+        match self.status {
+            TaskStatus::Failed => return TaskAction::FailSafe,
+            TaskStatus::Hung => return TaskAction::Reset,
+            TaskStatus::Running | TaskStatus::Restarting => {}
+        }
+
+        if !finished {
+            if now_ms.saturating_sub(last_heartbeat_ms) > policy.heartbeat_timeout_ms {
+                self.status = TaskStatus::Hung;
+                return TaskAction::Reset;
+            }
+            self.status = TaskStatus::Running;
+            return TaskAction::None;
+        }
+
+        let died_ms = *self.died_ms.get_or_insert(now_ms);
+        self.restarts_ms
+            .retain(|&restart_ms| now_ms.saturating_sub(restart_ms) < policy.window_ms);
+        if self.restarts_ms.len() >= policy.max_restarts {
+            self.status = TaskStatus::Failed;
+            return TaskAction::FailSafe;
+        }
+        if now_ms.saturating_sub(died_ms) < policy.delay_ms {
+            self.status = TaskStatus::Restarting;
+            return TaskAction::FailSafe;
+        }
+
+        self.restarts_ms.push(now_ms);
+        self.died_ms = None;
+        self.status = TaskStatus::Running;
+        TaskAction::Restart
+    }
+}
+
+/// How a supervised task is doing, for the screen and http
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskReport {
+    pub name: String,
+    pub status: TaskStatus,
+    pub recent_restarts: usize,
+}
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+
+/// Most PC fans pulse the tacho wire twice per revolution
+pub const DEFAULT_PULSES_PER_REV: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TachoWindowConfig {
+    /// RPM is averaged over this long
+    pub window_ms: u64,
+    /// At low RPM the window grows up to this long, until it holds `min_counts`
+    pub max_window_ms: u64,
+    pub min_counts: u64,
+}
+
+impl Default for TachoWindowConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 1_000,
+            max_window_ms: 4_000,
+            min_counts: 8,
+        }
+    }
+}
+
+/// RPM from a free running pulse counter, sampled regularly.
+///
+/// The counter is never paused or cleared, so no pulses are lost between samples
+/// and the RPM can be recalculated on every sample instead of once per window.
+/// Slow fans give few pulses per window, so the window grows until it holds enough
+/// of them, which trades some lag for resolution exactly where it's needed.
+#[derive(Debug, Clone)]
+pub struct TachoWindow {
+    config: TachoWindowConfig,
+    /// (time, total counts since start), oldest first
+    samples: VecDeque<(u64, u64)>,
+    last_raw: Option<u32>,
+    total: u64,
+}
+
+impl TachoWindow {
+    pub fn new(config: TachoWindowConfig) -> Self {
+        Self {
+            config,
+            samples: VecDeque::new(),
+            last_raw: None,
+            total: 0,
+        }
+    }
+
+    /// `raw` is the counter value, which wraps back to 0 when it reaches `limit`.
+    /// Sample often enough that it can't wrap more than once in between.
+    pub fn push(&mut self, now_ms: u64, raw: u32, limit: u32) {
+        if let Some(last_raw) = self.last_raw {
+            self.total += (raw + limit - last_raw) as u64 % limit as u64;
+        }
+        self.last_raw = Some(raw);
+
+        self.samples.push_back((now_ms, self.total));
+        while self
+            .samples
+            .front()
+            .is_some_and(|&(time_ms, _)| now_ms - time_ms > self.config.max_window_ms)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Counts per revolution is pulses per revolution times counted edges per pulse
+    pub fn rpm(&self, counts_per_rev: u32) -> u32 {
+        let Some(&(now_ms, total)) = self.samples.back() else {
+            return 0;
+        };
+
+        // Newest sample that's at least a window old, or older if there aren't
+        // enough counts in the window yet
+        let mut start = None;
+        for &(time_ms, count) in self.samples.iter().rev().skip(1) {
+            start = Some((time_ms, count));
+            let full_window = now_ms - time_ms >= self.config.window_ms;
+            if full_window && total - count >= self.config.min_counts {
+                break;
+            }
+        }
+
+        match start {
+            Some((time_ms, count)) if now_ms > time_ms && counts_per_rev > 0 => {
+                let revolutions = (total - count) as f64 / counts_per_rev as f64;
+                (revolutions * 60_000.0 / (now_ms - time_ms) as f64).round() as u32
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RpmFilterConfig {
+    /// Median of this many accepted readings, removes single outliers
+    pub median_len: usize,
+    /// Weight of a new reading in the exponential moving average, 1 disables it
+    pub smoothing: f32,
+    /// Fastest a real fan speeds up or slows down, in RPM/s
+    pub max_acceleration: f32,
+    /// Allowed on top of `max_acceleration`, so slow sampling doesn't reject
+    /// everything
+    pub tolerance_rpm: f32,
+    /// After this many rejections in a row, the readings are believed after all
+    pub max_rejected_in_row: u32,
+}
+
+impl Default for RpmFilterConfig {
+    fn default() -> Self {
+        Self {
+            median_len: 5,
+            smoothing: 0.3,
+            max_acceleration: 3_000.0,
+            tolerance_rpm: 200.0,
+            max_rejected_in_row: 10,
+        }
+    }
+}
+
+/// Cleans up RPM readings.
+///
+/// PWM edges couple into the tacho line and show up as extra pulses, so readings
+/// that would need the fan to accelerate faster than it physically can are
+/// thrown away first. What's left goes through a median, for the odd glitch
+/// that's still plausible, and an exponential moving average for the jitter.
+#[derive(Debug, Clone)]
+pub struct RpmFilter {
+    config: RpmFilterConfig,
+    /// Accepted readings, oldest first
+    recent: VecDeque<u32>,
+    /// Last accepted reading
+    accepted: Option<u32>,
+    smoothed: Option<f32>,
+    rejected_in_row: u32,
+    rejected: u32,
+}
+
+impl RpmFilter {
+    pub fn new(config: RpmFilterConfig) -> Self {
+        Self {
+            config,
+            recent: VecDeque::new(),
+            accepted: None,
+            smoothed: None,
+            rejected_in_row: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Readings thrown away so far
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    /// Feeds a raw reading taken `dt_s` after the previous one, returns the
+    /// filtered RPM
+    pub fn update(&mut self, raw: u32, dt_s: f32) -> u32 {
+        if let Some(accepted) = self.accepted {
+            let max_step = self.config.max_acceleration * dt_s + self.config.tolerance_rpm;
+            let plausible = (raw as f32 - accepted as f32).abs() <= max_step;
+            if !plausible && self.rejected_in_row < self.config.max_rejected_in_row {
+                self.rejected_in_row += 1;
+                self.rejected = self.rejected.saturating_add(1);
+                return self.output();
+            }
+            if !plausible {
+                // Consistently somewhere else, so that's where the fan is now
+                self.recent.clear();
+                self.smoothed = None;
+            }
+        }
+        self.rejected_in_row = 0;
+        self.accepted = Some(raw);
+
+        self.recent.push_back(raw);
+        while self.recent.len() > self.config.median_len.max(1) {
+            self.recent.pop_front();
+        }
+        let mut sorted: Vec<u32> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2] as f32;
+
+        let smoothing = self.config.smoothing.clamp(0.0, 1.0);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => smoothed + (median - smoothed) * smoothing,
+            None => median,
+        });
+        self.output()
+    }
+
+    fn output(&self) -> u32 {
+        self.smoothed.unwrap_or(0.0).round() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the PCNT counter wraps back to 0
+    const LIMIT: u32 = i16::MAX as u32;
+
+    /// Samples every 100 ms, `counts(i)` is the total counted by the `i`th
+    fn sampled(samples: u64, first_raw: u32, counts: impl Fn(u64) -> u64) -> TachoWindow {
+        let mut window = TachoWindow::new(TachoWindowConfig::default());
+        for i in 0..samples {
+            let raw = (first_raw as u64 + counts(i)) % LIMIT as u64;
+            window.push(i * 100, raw as u32, LIMIT);
+        }
+        window
+    }
+
+    fn filter(smoothing: f32, median_len: usize) -> RpmFilter {
+        RpmFilter::new(RpmFilterConfig {
+            smoothing,
+            median_len,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn rpm_over_the_window() {
+        // 40 counts/s
+        let window = sampled(20, 0, |i| i * 4);
+        assert_eq!(window.rpm(2), 1200);
+        // Twice the counts per revolution, half the speed
+        assert_eq!(window.rpm(4), 600);
+        assert_eq!(window.rpm(0), 0);
+    }
+
+    #[test]
+    fn counter_wraps_around() {
+        let window = sampled(20, LIMIT - 10, |i| i * 4);
+        assert_eq!(window.rpm(2), 1200);
+        // Right at the limit, which the counter never shows
+        let window = sampled(20, LIMIT - 40, |i| i * 4);
+        assert_eq!(window.rpm(2), 1200);
+    }
+
+    #[test]
+    fn window_grows_for_slow_fans() {
+        // A count every 500 ms, the last just before the newest sample. Only 2 in
+        // a second, but 8 in 4 s.
+        let window = sampled(61, 0, |i| i.div_ceil(5));
+        assert_eq!(window.rpm(2), 60);
+    }
+
+    #[test]
+    fn stopped_fan() {
+        assert_eq!(TachoWindow::new(TachoWindowConfig::default()).rpm(2), 0);
+        assert_eq!(sampled(1, 100, |_| 0).rpm(2), 0);
+        assert_eq!(sampled(50, 100, |_| 0).rpm(2), 0);
+        // Stopped after running, once the old counts are out of the window
+        let window = sampled(100, 0, |i| i.min(20) * 4);
+        assert_eq!(window.rpm(2), 0);
+    }
+
+    #[test]
+    fn filter_smooths() {
+        let mut filter = filter(0.3, 1);
+        assert_eq!(filter.update(1000, 0.1), 1000);
+        assert_eq!(filter.update(1400, 0.1), 1120);
+        assert_eq!(filter.update(1400, 0.1), 1204);
+        assert_eq!(filter.rejected(), 0);
+    }
+
+    #[test]
+    fn filter_median() {
+        let mut filter = filter(1.0, 5);
+        for _ in 0..3 {
+            assert_eq!(filter.update(1000, 0.1), 1000);
+        }
+        // Plausible, but only the one reading
+        assert_eq!(filter.update(1400, 0.1), 1000);
+        assert_eq!(filter.update(1000, 0.1), 1000);
+        assert_eq!(filter.rejected(), 0);
+    }
+
+    #[test]
+    fn filter_rejects_spikes() {
+        let mut filter = RpmFilter::new(RpmFilterConfig::default());
+        for _ in 0..5 {
+            filter.update(1000, 0.1);
+        }
+        // At most 3000 RPM/s * 0.1 s + 200 RPM away
+        assert_eq!(filter.update(5000, 0.1), 1000);
+        assert_eq!(filter.rejected(), 1);
+        assert_eq!(filter.update(0, 0.1), 1000);
+        assert_eq!(filter.rejected(), 2);
+        assert_eq!(filter.update(1500, 0.1), 1000);
+        assert_eq!(filter.rejected(), 2);
+        // A longer gap allows a bigger step
+        filter.update(2000, 1.0);
+        assert_eq!(filter.rejected(), 2);
+    }
+
+    #[test]
+    fn filter_follows_real_steps() {
+        let mut filter = filter(1.0, 5);
+        for _ in 0..5 {
+            filter.update(1000, 0.1);
+        }
+        for _ in 0..10 {
+            assert_eq!(filter.update(3000, 0.1), 1000);
+        }
+        assert_eq!(filter.rejected(), 10);
+        // Believed after 10 in a row, without the old readings in the median
+        assert_eq!(filter.update(3000, 0.1), 3000);
+        assert_eq!(filter.update(2900, 0.1), 3000);
+        assert_eq!(filter.rejected(), 10);
+    }
+
+    #[test]
+    fn filter_follows_ramps() {
+        let mut filter = filter(1.0, 1);
+        for i in 0..20 {
+            assert_eq!(filter.update(1000 + i * 250, 0.1), 1000 + i * 250);
+        }
+        assert_eq!(filter.rejected(), 0);
+    }
+}
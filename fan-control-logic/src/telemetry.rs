@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+/// How often live telemetry is pushed to connected dashboards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Never more often than this, however much changes
+    pub min_interval_ms: u64,
+    /// Even if nothing changed, so clients can tell the connection is alive
+    pub keepalive_ms: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            min_interval_ms: 500,
+            keepalive_ms: 15_000,
+        }
+    }
+}
+
+/// Fastest the HTTP server can keep up with
+pub const MIN_INTERVAL_MS: u64 = 100;
+
+impl TelemetryConfig {
+    pub fn validate(&self) -> Result<(), TelemetryError> {
+        if self.min_interval_ms < MIN_INTERVAL_MS {
+            return Err(TelemetryError::IntervalTooShort);
+        }
+        if self.keepalive_ms < self.min_interval_ms {
+            return Err(TelemetryError::KeepaliveTooShort);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelemetryError {
+    IntervalTooShort,
+    KeepaliveTooShort,
+}
+
+impl std::fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TelemetryError::IntervalTooShort => {
+                write!(
+                    f,
+                    "The minimum interval must be at least {MIN_INTERVAL_MS} ms"
+                )
+            }
+            TelemetryError::KeepaliveTooShort => {
+                write!(
+                    f,
+                    "The keepalive can't be shorter than the minimum interval"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {}
+
+/// Decides when to send a value that's looked at all the time: as soon as it
+/// changes, but not more often than the minimum interval.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeThrottle<T> {
+    /// Last value sent, and when
+    sent: Option<(T, u64)>,
+}
+
+impl<T: Clone + PartialEq> ChangeThrottle<T> {
+    /// Call regularly with the current value, true if it should be sent now.
+    /// The first value is always sent.
+    pub fn update(&mut self, current: &T, config: &TelemetryConfig, now_ms: u64) -> bool {
+        let send = match &self.sent {
+            None => true,
+            Some((sent, sent_ms)) => {
+                let elapsed_ms = now_ms.saturating_sub(*sent_ms);
+                (elapsed_ms >= config.min_interval_ms && sent != current)
+                    || elapsed_ms >= config.keepalive_ms
+            }
+        };
+        if send {
+            self.sent = Some((current.clone(), now_ms));
+        }
+        send
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Temperature in °C that can be shared between threads.
+///
+/// Stored as the bits of an `f32`, with NaN meaning "no reading yet".
+#[derive(Debug)]
+pub struct AtomicCelsius(AtomicU32);
+
+impl Default for AtomicCelsius {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl AtomicCelsius {
+    pub fn new(celsius: Option<f32>) -> Self {
+        Self(AtomicU32::new(celsius.unwrap_or(f32::NAN).to_bits()))
+    }
+
+    pub fn load(&self, order: Ordering) -> Option<f32> {
+        let celsius = f32::from_bits(self.0.load(order));
+        (!celsius.is_nan()).then_some(celsius)
+    }
+
+    pub fn store(&self, celsius: Option<f32>, order: Ordering) {
+        self.0.store(celsius.unwrap_or(f32::NAN).to_bits(), order);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SensorReading {
+    pub sensor: String,
+    /// `None` if the last read failed
+    pub celsius: Option<f32>,
+    pub failed_reads: u32,
+}
+
+/// Latest reading of every temperature sensor, keyed by sensor name
+#[derive(Debug, Default)]
+pub struct SensorReadings(Mutex<Vec<SensorReading>>);
+
+impl SensorReadings {
+    pub fn publish(&self, sensor: &str, celsius: Option<f32>) {
+        let mut readings = self.0.lock().unwrap();
+        let index = match readings.iter().position(|r| r.sensor == sensor) {
+            Some(index) => index,
+            None => {
+                readings.push(SensorReading {
+                    sensor: sensor.to_string(),
+                    celsius: None,
+                    failed_reads: 0,
+                });
+                readings.len() - 1
+            }
+        };
+        let reading = &mut readings[index];
+        reading.celsius = celsius;
+        if celsius.is_none() {
+            reading.failed_reads = reading.failed_reads.saturating_add(1);
+        }
+    }
+
+    /// Highest current reading, the one the fans should react to
+    pub fn hottest(&self) -> Option<f32> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|r| r.celsius)
+            .max_by(f32::total_cmp)
+    }
+
+    pub fn snapshot(&self) -> Vec<SensorReading> {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,432 @@
+//! Local time from UTC with POSIX TZ rules, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+//!
+//! Only the `Mm.w.d` form of DST rules is supported, which is what every zone in
+//! use today can be written with.
+
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    /// Days since 1970-01-01, which was a Thursday
+    fn from_days(days: i64) -> Self {
+        Self::ALL[(days + 3).rem_euclid(7) as usize]
+    }
+
+    pub fn previous(self) -> Self {
+        Self::ALL[(self as usize + 6) % 7]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Weekday::Mon => "Mon",
+            Weekday::Tue => "Tue",
+            Weekday::Wed => "Wed",
+            Weekday::Thu => "Thu",
+            Weekday::Fri => "Fri",
+            Weekday::Sat => "Sat",
+            Weekday::Sun => "Sun",
+        }
+    }
+}
+
+/// Wall clock time somewhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1-12
+    pub month: u32,
+    /// 1-31
+    pub day: u32,
+    pub weekday: Weekday,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub is_dst: bool,
+}
+
+impl LocalTime {
+    pub fn minute_of_day(&self) -> u32 {
+        self.hour * 60 + self.minute
+    }
+}
+
+impl std::fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.weekday.name()
+        )
+    }
+}
+
+/// `Mm.w.d/time`: day `d` (0 is Sunday) of week `w` (5 is the last one) of month
+/// `m`, at `time` seconds after midnight local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transition {
+    month: u32,
+    week: u32,
+    weekday: u32,
+    time_secs: i64,
+}
+
+impl Transition {
+    /// Local midnight of the day of the transition in `year`, in days since 1970
+    fn day(&self, year: i32) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let (next_year, next_month) = match self.month {
+            12 => (year + 1, 1),
+            month => (year, month + 1),
+        };
+        let month_len = days_from_civil(next_year, next_month, 1) - first;
+        // 1970-01-01 was a Thursday, day 4 counting from Sunday
+        let first_weekday = (first + 4).rem_euclid(7);
+        let mut day =
+            (self.weekday as i64 - first_weekday).rem_euclid(7) + 7 * (self.week as i64 - 1);
+        while day >= month_len {
+            day -= 7;
+        }
+        first + day
+    }
+
+    /// UTC time of the transition in `year`, `offset_secs` is in effect until then
+    fn at(&self, year: i32, offset_secs: i64) -> i64 {
+        self.day(year) * SECS_PER_DAY + self.time_secs - offset_secs
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dst {
+    /// East of UTC
+    offset_secs: i64,
+    start: Transition,
+    end: Transition,
+}
+
+/// A POSIX TZ rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeZone {
+    /// East of UTC, the other way around than in the TZ string
+    offset_secs: i64,
+    dst: Option<Dst>,
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::UTC
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZoneError(pub String);
+
+impl std::fmt::Display for TimeZoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid time zone {:?}, expected a POSIX TZ rule like CET-1CEST,M3.5.0,M10.5.0/3",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TimeZoneError {}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone {
+        offset_secs: 0,
+        dst: None,
+    };
+
+    pub fn parse(tz: &str) -> Result<Self, TimeZoneError> {
+        let error = || TimeZoneError(tz.to_string());
+        let mut parser = Parser(tz);
+        parser.name().ok_or_else(error)?;
+        let offset_secs = -parser.time().ok_or_else(error)?;
+        if parser.0.is_empty() {
+            return Ok(Self {
+                offset_secs,
+                dst: None,
+            });
+        }
+
+        parser.name().ok_or_else(error)?;
+        // An hour ahead of standard time unless it says otherwise
+        let dst_offset_secs = if parser.0.starts_with(',') {
+            offset_secs + 3600
+        } else {
+            -parser.time().ok_or_else(error)?
+        };
+        let start = parser.transition().ok_or_else(error)?;
+        let end = parser.transition().ok_or_else(error)?;
+        if !parser.0.is_empty() {
+            return Err(error());
+        }
+        Ok(Self {
+            offset_secs,
+            dst: Some(Dst {
+                offset_secs: dst_offset_secs,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Local time at `unix_secs`
+    pub fn local_time(&self, unix_secs: i64) -> LocalTime {
+        let (offset_secs, is_dst) = match &self.dst {
+            Some(dst) if self.is_dst(dst, unix_secs) => (dst.offset_secs, true),
+            _ => (self.offset_secs, false),
+        };
+        let local_secs = unix_secs + offset_secs;
+        let days = local_secs.div_euclid(SECS_PER_DAY);
+        let secs_of_day = local_secs.rem_euclid(SECS_PER_DAY) as u32;
+        let (year, month, day) = civil_from_days(days);
+        LocalTime {
+            year,
+            month,
+            day,
+            weekday: Weekday::from_days(days),
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            is_dst,
+        }
+    }
+
+    fn is_dst(&self, dst: &Dst, unix_secs: i64) -> bool {
+        let (year, _, _) = civil_from_days((unix_secs + self.offset_secs).div_euclid(SECS_PER_DAY));
+        let start = dst.start.at(year, self.offset_secs);
+        let end = dst.end.at(year, dst.offset_secs);
+        if start < end {
+            (start..end).contains(&unix_secs)
+        } else {
+            // Southern hemisphere, DST over new year
+            !(end..start).contains(&unix_secs)
+        }
+    }
+}
+
+/// What's left of a TZ string
+struct Parser<'a>(&'a str);
+
+impl Parser<'_> {
+    /// `CET` or `<+03>`
+    fn name(&mut self) -> Option<&str> {
+        let len = if let Some(quoted) = self.0.strip_prefix('<') {
+            quoted.find('>')? + 2
+        } else {
+            self.0
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.0.len())
+        };
+        if len < 3 {
+            return None;
+        }
+        let (name, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(name)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds
+    fn time(&mut self) -> Option<i64> {
+        let sign = match self.0.chars().next()? {
+            '-' => -1,
+            '+' => 1,
+            _ => 0,
+        };
+        if sign != 0 {
+            self.0 = &self.0[1..];
+        }
+        let mut secs = 0;
+        for (i, unit) in [3600, 60, 1].into_iter().enumerate() {
+            if i > 0 {
+                match self.0.strip_prefix(':') {
+                    Some(rest) => self.0 = rest,
+                    None => break,
+                }
+            }
+            secs += self.number()? * unit;
+        }
+        Some(if sign < 0 { -secs } else { secs })
+    }
+
+    /// `,Mm.w.d[/time]`
+    fn transition(&mut self) -> Option<Transition> {
+        self.0 = self.0.strip_prefix(",M")?;
+        let month = self.number()?;
+        self.0 = self.0.strip_prefix('.')?;
+        let week = self.number()?;
+        self.0 = self.0.strip_prefix('.')?;
+        let weekday = self.number()?;
+        let time_secs = match self.0.strip_prefix('/') {
+            Some(rest) => {
+                self.0 = rest;
+                self.time()?
+            }
+            None => 2 * 3600,
+        };
+        let valid = (1..=12).contains(&month) && (1..=5).contains(&week) && weekday <= 6;
+        valid.then_some(Transition {
+            month: month as u32,
+            week: week as u32,
+            weekday: weekday as u32,
+            time_secs,
+        })
+    }
+
+    fn number(&mut self) -> Option<i64> {
+        let len = self
+            .0
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.0.len());
+        let (digits, rest) = self.0.split_at(len);
+        let number = digits.parse().ok()?;
+        self.0 = rest;
+        Some(number)
+    }
+}
+
+/// Days since 1970-01-01 to (year, month, day), from
+/// <https://howardhinnant.github.io/date_algorithms.html>
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400) as i32 + (month <= 2) as i32;
+    (year, month, day)
+}
+
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EU: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const US: &str = "EST5EDT,M3.2.0,M11.1.0";
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    /// (day, hour, minute, second, is_dst)
+    fn local(tz: &str, unix_secs: i64) -> (u32, u32, u32, u32, bool) {
+        let time = TimeZone::parse(tz).unwrap().local_time(unix_secs);
+        (time.day, time.hour, time.minute, time.second, time.is_dst)
+    }
+
+    #[test]
+    fn eu_transitions() {
+        // 2024-03-31 01:00 UTC, clocks go from 02:00 to 03:00
+        assert_eq!(local(EU, 1_711_846_799), (31, 1, 59, 59, false));
+        assert_eq!(local(EU, 1_711_846_800), (31, 3, 0, 0, true));
+        // 2024-10-27 01:00 UTC, clocks go from 03:00 back to 02:00
+        assert_eq!(local(EU, 1_729_990_799), (27, 2, 59, 59, true));
+        assert_eq!(local(EU, 1_729_990_800), (27, 2, 0, 0, false));
+    }
+
+    #[test]
+    fn us_transitions() {
+        // 2024-03-10 07:00 UTC, second Sunday of March
+        assert_eq!(local(US, 1_710_053_999), (10, 1, 59, 59, false));
+        assert_eq!(local(US, 1_710_054_000), (10, 3, 0, 0, true));
+        // 2024-11-03 06:00 UTC, first Sunday of November
+        assert_eq!(local(US, 1_730_613_599), (3, 1, 59, 59, true));
+        assert_eq!(local(US, 1_730_613_600), (3, 1, 0, 0, false));
+    }
+
+    #[test]
+    fn southern_hemisphere() {
+        // DST over new year, 2024-01-15 and 2024-07-01 12:00 UTC
+        assert_eq!(local(SYDNEY, 1_705_320_000), (15, 23, 0, 0, true));
+        assert_eq!(local(SYDNEY, 1_719_835_200), (1, 22, 0, 0, false));
+        // Ends 2024-04-07 at 03:00 local
+        assert_eq!(local(SYDNEY, 1_712_419_199), (7, 2, 59, 59, true));
+        assert_eq!(local(SYDNEY, 1_712_419_200), (7, 2, 0, 0, false));
+        // Starts 2024-10-06 at 02:00 local
+        assert_eq!(local(SYDNEY, 1_728_143_999), (6, 1, 59, 59, false));
+        assert_eq!(local(SYDNEY, 1_728_144_000), (6, 3, 0, 0, true));
+    }
+
+    #[test]
+    fn dates() {
+        let utc = TimeZone::UTC;
+        assert_eq!(utc.local_time(0).to_string(), "1970-01-01 00:00:00 Thu");
+        assert_eq!(
+            utc.local_time(951_782_400).to_string(),
+            "2000-02-29 00:00:00 Tue"
+        );
+        assert_eq!(
+            utc.local_time(1_735_689_540).to_string(),
+            "2024-12-31 23:59:00 Tue"
+        );
+        // Before 1970
+        assert_eq!(utc.local_time(-1).to_string(), "1969-12-31 23:59:59 Wed");
+    }
+
+    #[test]
+    fn fixed_offsets() {
+        assert_eq!(TimeZone::parse("UTC0"), Ok(TimeZone::UTC));
+        assert_eq!(local("<+03>-3", 0), (1, 3, 0, 0, false));
+        assert_eq!(local("IST-5:30", 0), (1, 5, 30, 0, false));
+        assert_eq!(local("HST10", 0), (31, 14, 0, 0, false));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for tz in [
+            "",
+            "CET",
+            "X-1",
+            "CET-1CEST",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+            "CET-1CEST,M3.6.0,M10.5.0",
+            "CET-1CEST,M3.5.7,M10.5.0",
+            "CET-1CEST,J60,M10.5.0",
+            "CET-1CEST,M3.5.0,M10.5.0/3x",
+        ] {
+            assert!(TimeZone::parse(tz).is_err(), "{tz:?}");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+
+/// When to stop the fan completely, for setups that can cool passively at low load
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZeroRpmConfig {
+    pub enabled: bool,
+    /// Stop the fan once the duty drops below this
+    pub stop_below: u32,
+    /// Start it again once the duty reaches this, above `stop_below` so it doesn't
+    /// cycle on the edge
+    pub start_at: u32,
+    /// Stay stopped at least this long
+    pub min_off_ms: u64,
+    /// Keep running at least this long
+    pub min_on_ms: u64,
+}
+
+impl Default for ZeroRpmConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stop_below: 20,
+            start_at: 30,
+            min_off_ms: 60_000,
+            min_on_ms: 60_000,
+        }
+    }
+}
+
+impl ZeroRpmConfig {
+    pub fn validate(&self) -> Result<(), ZeroRpmError> {
+        if self.start_at > 100 {
+            return Err(ZeroRpmError::DutyOutOfRange);
+        }
+        if self.start_at <= self.stop_below {
+            return Err(ZeroRpmError::NoHysteresis);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZeroRpmError {
+    DutyOutOfRange,
+    NoHysteresis,
+}
+
+impl std::fmt::Display for ZeroRpmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZeroRpmError::DutyOutOfRange => write!(f, "Duty cycles must be 0-100"),
+            ZeroRpmError::NoHysteresis => {
+                write!(f, "The start duty has to be above the stop duty")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ZeroRpmError {}
+
+/// Stops the fan at low duty and starts it again at a higher one, holding each
+/// state for a minimum time.
+#[derive(Debug, Clone)]
+pub struct ZeroRpm {
+    config: ZeroRpmConfig,
+    stopped: bool,
+    /// When the fan last stopped or started, `None` until the first update
+    since_ms: Option<u64>,
+}
+
+impl ZeroRpm {
+    pub fn new(config: ZeroRpmConfig) -> Self {
+        Self {
+            config,
+            stopped: false,
+            since_ms: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: ZeroRpmConfig) {
+        self.config = config;
+    }
+
+    /// Stopped on purpose, not because anything is wrong
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Call every control period, returns `duty`, or 0 while the fan is stopped
+    pub fn update(&mut self, duty: u32, now_ms: u64) -> u32 {
+        let config = self.config;
+        if !config.enabled {
+            self.keep_running(now_ms);
+            return duty;
+        }
+
+        let since_ms = *self.since_ms.get_or_insert(now_ms);
+        let held_ms = now_ms.saturating_sub(since_ms);
+        if self.stopped {
+            if duty >= config.start_at && held_ms >= config.min_off_ms {
+                self.stopped = false;
+                self.since_ms = Some(now_ms);
+            }
+        } else if duty < config.stop_below && held_ms >= config.min_on_ms {
+            self.stopped = true;
+            self.since_ms = Some(now_ms);
+        }
+
+        if self.stopped {
+            0
+        } else {
+            duty
+        }
+    }
+
+    /// Starts the fan straight away if it's stopped, e.g. for a boost
+    pub fn keep_running(&mut self, now_ms: u64) {
+        if self.stopped || self.since_ms.is_none() {
+            self.stopped = false;
+            self.since_ms = Some(now_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zero_rpm(min_off_ms: u64, min_on_ms: u64) -> ZeroRpm {
+        ZeroRpm::new(ZeroRpmConfig {
+            enabled: true,
+            min_off_ms,
+            min_on_ms,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(ZeroRpmConfig::default().validate(), Ok(()));
+        let config = |stop_below, start_at| ZeroRpmConfig {
+            stop_below,
+            start_at,
+            ..Default::default()
+        };
+        assert_eq!(config(20, 20).validate(), Err(ZeroRpmError::NoHysteresis));
+        assert_eq!(config(30, 20).validate(), Err(ZeroRpmError::NoHysteresis));
+        assert_eq!(
+            config(50, 101).validate(),
+            Err(ZeroRpmError::DutyOutOfRange)
+        );
+        assert_eq!(config(0, 100).validate(), Ok(()));
+    }
+
+    #[test]
+    fn stop_and_start_hysteresis() {
+        let mut zero_rpm = zero_rpm(0, 0);
+        assert_eq!(zero_rpm.update(25, 0), 25);
+        assert_eq!(zero_rpm.update(20, 1), 20);
+        assert_eq!(zero_rpm.update(19, 2), 0);
+        assert!(zero_rpm.is_stopped());
+        // Between the thresholds it stays stopped
+        assert_eq!(zero_rpm.update(25, 3), 0);
+        assert_eq!(zero_rpm.update(29, 4), 0);
+        assert_eq!(zero_rpm.update(30, 5), 30);
+        assert!(!zero_rpm.is_stopped());
+        // And keeps running
+        assert_eq!(zero_rpm.update(25, 6), 25);
+    }
+
+    #[test]
+    fn min_on_and_off_times() {
+        let mut zero_rpm = zero_rpm(60_000, 30_000);
+        assert_eq!(zero_rpm.update(50, 0), 50);
+        assert_eq!(zero_rpm.update(10, 29_999), 10);
+        assert_eq!(zero_rpm.update(10, 30_000), 0);
+        assert_eq!(zero_rpm.update(50, 89_999), 0);
+        assert_eq!(zero_rpm.update(50, 90_000), 50);
+        assert_eq!(zero_rpm.update(10, 119_999), 10);
+        assert_eq!(zero_rpm.update(10, 120_000), 0);
+    }
+
+    #[test]
+    fn disabled_keeps_running() {
+        let mut zero_rpm = ZeroRpm::new(ZeroRpmConfig::default());
+        assert_eq!(zero_rpm.update(5, 0), 5);
+        assert_eq!(zero_rpm.update(0, 100_000), 0);
+        assert!(!zero_rpm.is_stopped());
+    }
+
+    #[test]
+    fn disabling_starts_the_fan() {
+        let mut zero_rpm = zero_rpm(60_000, 0);
+        assert_eq!(zero_rpm.update(10, 0), 0);
+        zero_rpm.set_config(ZeroRpmConfig::default());
+        assert_eq!(zero_rpm.update(10, 1_000), 10);
+        assert!(!zero_rpm.is_stopped());
+    }
+
+    #[test]
+    fn keep_running_starts_the_fan() {
+        let mut zero_rpm = zero_rpm(60_000, 30_000);
+        assert_eq!(zero_rpm.update(10, 0), 10);
+        assert_eq!(zero_rpm.update(10, 30_000), 0);
+        // e.g. a boost, long before the fan could start on its own
+        zero_rpm.keep_running(31_000);
+        assert!(!zero_rpm.is_stopped());
+        // Then held on like any other start
+        assert_eq!(zero_rpm.update(10, 60_999), 10);
+        assert_eq!(zero_rpm.update(10, 61_000), 0);
+    }
+}
@@ -0,0 +1,574 @@
+//! Versioned REST API under `/api/v2`.
+//!
+//! Unlike the first API at `/`, every response is JSON with a proper content type,
+//! errors come as `{"error": {"status": 400, "code": "invalid_request", "message":
+//! "..."}}`, input is checked instead of clamped, and browsers may call it from
+//! dashboards served elsewhere (CORS).
+
+use std::{
+    ffi::CStr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use embedded_svc::{
+    http::{Headers, Method},
+    io::{Read, Write},
+};
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::{
+    control::{ControlError, ControlSource},
+    curve::CurveConfig,
+    mode::ControlMode,
+    profile::{Profile, ProfileError},
+    settings::{self as logic_settings, SettingsError},
+    supervisor::{TaskReport, TaskStatus},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    settings, threads,
+    wifi_control::{self, MAX_LEN},
+};
+
+const PREFIX: &str = "/api/v2";
+
+/// Dashboards may be served from anywhere, e.g. a file on someone's laptop
+const ALLOW_ORIGIN: (&str, &str) = ("Access-Control-Allow-Origin", "*");
+
+/// Error response, also what the handlers return when something's wrong
+#[derive(Debug, Serialize)]
+pub(crate) struct ApiError {
+    status: u16,
+    /// Stable, for clients to tell errors apart
+    code: &'static str,
+    /// For people
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn bad_request(message: impl ToString) -> Self {
+        Self::new(400, "invalid_request", message)
+    }
+
+    fn not_found(message: impl ToString) -> Self {
+        Self::new(404, "not_found", message)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<ControlError> for ApiError {
+    fn from(e: ControlError) -> Self {
+        Self::new(409, "control_locked", e)
+    }
+}
+
+impl From<ProfileError> for ApiError {
+    fn from(e: ProfileError) -> Self {
+        match e {
+            ProfileError::Unknown(_) => Self::not_found(e),
+            ProfileError::Control(e) => e.into(),
+            _ => Self::bad_request(e),
+        }
+    }
+}
+
+impl From<SettingsError> for ApiError {
+    fn from(e: SettingsError) -> Self {
+        Self::bad_request(e)
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorEnvelope<'a> {
+    pub error: &'a ApiError,
+}
+
+/// Commands apply to every fan unless `fan` is given
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PwmCommand {
+    #[serde(default)]
+    pub fan: Option<usize>,
+    pub percent: u32,
+}
+
+impl PwmCommand {
+    /// Switches to manual mode at `percent`
+    pub fn apply(&self, state: &InterfaceState) -> Result<(), ApiError> {
+        if self.percent > 100 {
+            return Err(ApiError::bad_request("percent must be 0-100"));
+        }
+        let fans = selected_fans(state, self.fan)?;
+        state.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+        for fan in fans {
+            fan.control_mode
+                .store(ControlMode::Manual, Ordering::Relaxed);
+            fan.pwm.store(self.percent, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RpmCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    rpm: u32,
+}
+
+impl RpmCommand {
+    /// Holds `rpm` with the PID controller
+    pub fn apply(&self, state: &InterfaceState) -> Result<(), ApiError> {
+        let fans = selected_fans(state, self.fan)?;
+        state.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+        for fan in fans {
+            fan.target_rpm.store(self.rpm, Ordering::Relaxed);
+            fan.control_mode
+                .store(ControlMode::TargetRpm, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ModeCommand {
+    #[serde(default)]
+    pub fan: Option<usize>,
+    pub mode: ControlMode,
+}
+
+impl ModeCommand {
+    pub fn apply(&self, state: &InterfaceState) -> Result<(), ApiError> {
+        let fans = selected_fans(state, self.fan)?;
+        state.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+        for fan in fans {
+            fan.control_mode.store(self.mode, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ActiveProfileCommand {
+    pub name: String,
+}
+
+impl ActiveProfileCommand {
+    /// Switches every fan to the profile called `name`
+    pub fn apply(&self, state: &InterfaceState) -> Result<(), ApiError> {
+        state.apply_profile(&self.name, ControlSource::Wifi, threads::uptime_ms())?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct FanAlarms {
+    fan: usize,
+    /// See [`fan_control_logic::fault::Alarm::name`]
+    alarms: Vec<&'static str>,
+    /// Not an alarm, the fan is meant to stand still
+    zero_rpm_stopped: bool,
+}
+
+#[derive(Serialize)]
+struct Alarms {
+    fans: Vec<FanAlarms>,
+    /// Supervised tasks that aren't running
+    tasks: Vec<TaskReport>,
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    firmware_version: &'static str,
+    idf_version: String,
+    fan_count: usize,
+    uptime_secs: u64,
+    free_heap_bytes: u32,
+    wifi_ssid: String,
+    /// `None` while not connected
+    ip: Option<String>,
+    /// Signal strength of the access point in dBm
+    rssi: Option<i8>,
+    mqtt_connected: bool,
+}
+
+/// Registers the `/api/v2` handlers, the server has to match wildcard URIs
+pub fn register(
+    server: &mut EspHttpServer<'static>,
+    state: &Arc<InterfaceState>,
+) -> anyhow::Result<()> {
+    // GET /api/v2/status - Current state of every fan, same as `GET /`
+    let state_clone = state.clone();
+    route(server, "/status", Method::Get, move |_| {
+        Ok(wifi_control::create_fan_status(&state_clone))
+    })?;
+
+    // POST /api/v2/pwm - Switches to manual mode at `percent` and returns status
+    let state_clone = state.clone();
+    route(server, "/pwm", Method::Post, move |req| {
+        read_json::<PwmCommand>(req)?.apply(&state_clone)?;
+        Ok(wifi_control::create_fan_status(&state_clone))
+    })?;
+
+    // POST /api/v2/rpm - Holds `rpm` with the PID controller and returns status
+    let state_clone = state.clone();
+    route(server, "/rpm", Method::Post, move |req| {
+        read_json::<RpmCommand>(req)?.apply(&state_clone)?;
+        Ok(wifi_control::create_fan_status(&state_clone))
+    })?;
+
+    // POST /api/v2/mode - Switches the control mode, e.g. back to following the curve,
+    // and returns status
+    let state_clone = state.clone();
+    route(server, "/mode", Method::Post, move |req| {
+        read_json::<ModeCommand>(req)?.apply(&state_clone)?;
+        Ok(wifi_control::create_fan_status(&state_clone))
+    })?;
+
+    // GET /api/v2/config - Every setting that's kept across reboots
+    let state_clone = state.clone();
+    route(server, "/config", Method::Get, move |_| {
+        config(&state_clone)
+    })?;
+
+    // PUT /api/v2/config - Replaces the settings, anything left out goes back to its
+    // default, and returns them
+    let state_clone = state.clone();
+    route(server, "/config", Method::Put, move |req| {
+        let value: Value = read_json_up_to(req, config_max_len(&state_clone))?;
+        replace_config(&state_clone, value)
+    })?;
+
+    // PATCH /api/v2/config - Changes only the settings given, as a JSON merge patch,
+    // and returns them
+    let state_clone = state.clone();
+    route(server, "/config", Method::Patch, move |req| {
+        let patch: Value = read_json_up_to(req, config_max_len(&state_clone))?;
+        let mut value = config(&state_clone)?;
+        logic_settings::merge_patch(&mut value, patch);
+        replace_config(&state_clone, value)
+    })?;
+
+    // PATCH /api/v2/config/fans/{fan} - Changes only the settings given for one fan, as a
+    // JSON merge patch, and returns all settings
+    let state_clone = state.clone();
+    route(server, "/config/fans/*", Method::Patch, move |req| {
+        let fan = fan_param(&state_clone, req.uri(), "/config/fans/")?;
+        let patch: Value = read_json(req)?;
+        let mut value = config(&state_clone)?;
+        logic_settings::merge_patch(&mut value["fans"][fan], patch);
+        replace_config(&state_clone, value)
+    })?;
+
+    // GET /api/v2/profiles - Every profile and the active one
+    let state_clone = state.clone();
+    route(server, "/profiles", Method::Get, move |_| {
+        Ok(state_clone.profiles.lock().unwrap().clone())
+    })?;
+
+    // PUT /api/v2/profiles/{name} - Adds or replaces a profile and returns all of them
+    let state_clone = state.clone();
+    route(server, "/profiles/*", Method::Put, move |req| {
+        let name = path_param(req.uri(), "/profiles/")?;
+        let profile: Profile = read_json(req)?;
+        if profile.name != name {
+            return Err(ApiError::bad_request(format!(
+                "Profile is called {:?} but was sent to {name:?}",
+                profile.name
+            )));
+        }
+        let mut profiles = state_clone.profiles.lock().unwrap();
+        profiles.insert(profile)?;
+        Ok(profiles.clone())
+    })?;
+
+    // DELETE /api/v2/profiles/{name} - Removes a profile and returns the rest
+    let state_clone = state.clone();
+    route(server, "/profiles/*", Method::Delete, move |req| {
+        let name = path_param(req.uri(), "/profiles/")?;
+        let mut profiles = state_clone.profiles.lock().unwrap();
+        profiles.remove(&name)?;
+        Ok(profiles.clone())
+    })?;
+
+    // PUT /api/v2/active_profile - Switches every fan to the profile called `name`
+    // and returns all profiles
+    let state_clone = state.clone();
+    route(server, "/active_profile", Method::Put, move |req| {
+        read_json::<ActiveProfileCommand>(req)?.apply(&state_clone)?;
+        Ok(state_clone.profiles.lock().unwrap().clone())
+    })?;
+
+    // GET /api/v2/curves - The fan curve of every fan
+    let state_clone = state.clone();
+    route(server, "/curves", Method::Get, move |_| {
+        let curves: Vec<CurveConfig> = state_clone
+            .fans
+            .iter()
+            .map(|fan| fan.curve.lock().unwrap().clone())
+            .collect();
+        Ok(curves)
+    })?;
+
+    // GET /api/v2/curves/{fan} - The fan curve of one fan
+    let state_clone = state.clone();
+    route(server, "/curves/*", Method::Get, move |req| {
+        let fan = fan_param(&state_clone, req.uri(), "/curves/")?;
+        let curve = state_clone.fans[fan].curve.lock().unwrap().clone();
+        Ok(curve)
+    })?;
+
+    // PUT /api/v2/curves/{fan} - Replaces the fan curve of one fan and returns it. The
+    // fan only follows it in curve mode, see `POST /api/v2/mode`.
+    let state_clone = state.clone();
+    route(server, "/curves/*", Method::Put, move |req| {
+        let fan = fan_param(&state_clone, req.uri(), "/curves/")?;
+        let curve: CurveConfig = read_json(req)?;
+        curve.to_curve().map_err(ApiError::bad_request)?;
+        *state_clone.fans[fan].curve.lock().unwrap() = curve.clone();
+        Ok(curve)
+    })?;
+
+    // GET /api/v2/alarms - Fan alarms and supervised tasks that aren't running
+    let state_clone = state.clone();
+    route(server, "/alarms", Method::Get, move |_| {
+        let fans = state_clone
+            .fans
+            .iter()
+            .enumerate()
+            .map(|(i, fan)| FanAlarms {
+                fan: i,
+                alarms: fan
+                    .alarms
+                    .load(Ordering::Relaxed)
+                    .iter()
+                    .map(|alarm| alarm.name())
+                    .collect(),
+                zero_rpm_stopped: fan.zero_rpm_stopped.load(Ordering::Relaxed),
+            })
+            .collect();
+        let tasks = state_clone
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|task| task.status != TaskStatus::Running)
+            .cloned()
+            .collect();
+        Ok(Alarms { fans, tasks })
+    })?;
+
+    // GET /api/v2/device - Firmware, memory and network details
+    let state_clone = state.clone();
+    route(server, "/device", Method::Get, move |_| {
+        let wifi = state_clone.wifi.lock().unwrap().clone();
+        // SAFETY: Returns a static C string
+        let idf_version = unsafe { CStr::from_ptr(esp_idf_svc::sys::esp_get_idf_version()) };
+        Ok(DeviceInfo {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            idf_version: idf_version.to_string_lossy().into_owned(),
+            fan_count: state_clone.fans.len(),
+            uptime_secs: threads::uptime_ms() / 1000,
+            // SAFETY: Only reads a counter
+            free_heap_bytes: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+            wifi_ssid: wifi.ssid,
+            ip: wifi.ip,
+            rssi: wifi.rssi,
+            mqtt_connected: state_clone.mqtt_connected.load(Ordering::Relaxed),
+        })
+    })?;
+
+    // OPTIONS /api/v2/* - CORS preflight, browsers ask before anything but a simple GET
+    server.fn_handler(&format!("{PREFIX}/*"), Method::Options, |req| {
+        let headers = [
+            ALLOW_ORIGIN,
+            (
+                "Access-Control-Allow-Methods",
+                "GET, POST, PUT, PATCH, DELETE, OPTIONS",
+            ),
+            ("Access-Control-Allow-Headers", "Content-Type"),
+            ("Access-Control-Max-Age", "86400"),
+        ];
+        req.into_response(204, None, &headers)?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // Anything else under /api/v2, registered last so it only gets what nothing else
+    // matched
+    for method in [
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Patch,
+        Method::Delete,
+    ] {
+        route(server, "/*", method, |req| -> Result<(), ApiError> {
+            Err(ApiError::not_found(format!(
+                "No such resource {}",
+                req.uri()
+            )))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Registers `handler` for `PREFIX` + `uri`, and responds with what it returns as
+/// JSON, or the error in an envelope
+fn route<T, F>(
+    server: &mut EspHttpServer<'static>,
+    uri: &str,
+    method: Method,
+    handler: F,
+) -> anyhow::Result<()>
+where
+    T: Serialize,
+    F: Fn(&mut Request<&mut EspHttpConnection>) -> Result<T, ApiError> + Send + 'static,
+{
+    server.fn_handler(&format!("{PREFIX}{uri}"), method, move |mut req| {
+        let (status, json) = match handler(&mut req) {
+            Ok(body) => (200, serde_json::to_string(&body)?),
+            Err(error) => {
+                let json = serde_json::to_string(&ErrorEnvelope { error: &error })?;
+                (error.status, json)
+            }
+        };
+        let headers = [ALLOW_ORIGIN, ("Content-Type", "application/json")];
+        req.into_response(status, None, &headers)?
+            .write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+    Ok(())
+}
+
+/// Parses the request body as a `C`, it has to be JSON
+fn read_json<C: DeserializeOwned>(
+    req: &mut Request<&mut EspHttpConnection>,
+) -> Result<C, ApiError> {
+    read_json_up_to(req, MAX_LEN)
+}
+
+/// Like [`read_json`], for bodies that may be longer than [`MAX_LEN`]
+fn read_json_up_to<C: DeserializeOwned>(
+    req: &mut Request<&mut EspHttpConnection>,
+    max_len: usize,
+) -> Result<C, ApiError> {
+    // Without a content type is fine, curl doesn't set one unless asked to
+    if let Some(content_type) = req.content_type() {
+        if !content_type.starts_with("application/json") {
+            return Err(ApiError::new(
+                415,
+                "unsupported_media_type",
+                format!("Expected application/json, got {content_type}"),
+            ));
+        }
+    }
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > max_len {
+        return Err(ApiError::new(
+            413,
+            "payload_too_large",
+            format!("Requests can be up to {max_len} bytes"),
+        ));
+    }
+    let mut buf = vec![0; len];
+    req.read_exact(&mut buf)
+        .map_err(|e| ApiError::bad_request(format!("Failed to read the request: {e:?}")))?;
+    serde_json::from_slice(&buf)
+        .map_err(|e| ApiError::new(400, "invalid_json", format!("Invalid JSON: {e}")))
+}
+
+/// The fan a command is for, or every fan if it doesn't say
+pub(crate) fn selected_fans(
+    state: &InterfaceState,
+    fan: Option<usize>,
+) -> Result<&[fan_control_graphics::FanState], ApiError> {
+    wifi_control::selected_fans(state, fan).map_err(ApiError::bad_request)
+}
+
+/// The fan number at the end of `uri`, after `path`
+fn fan_param(state: &InterfaceState, uri: &str, path: &str) -> Result<usize, ApiError> {
+    let param = path_param(uri, path)?;
+    param
+        .parse()
+        .ok()
+        .filter(|&fan| fan < state.fans.len())
+        .ok_or_else(|| ApiError::not_found(format!("There is no fan {param}")))
+}
+
+/// Percent-decoded rest of `uri` after `PREFIX` + `path`, without the query string
+fn path_param(uri: &str, path: &str) -> Result<String, ApiError> {
+    let uri = uri.split('?').next().unwrap_or_default();
+    let encoded = uri
+        .strip_prefix(PREFIX)
+        .and_then(|uri| uri.strip_prefix(path))
+        .filter(|param| !param.is_empty() && !param.contains('/'))
+        .ok_or_else(|| ApiError::not_found(format!("No such resource {uri}")))?;
+
+    let invalid = || ApiError::bad_request(format!("Invalid URL encoding in {encoded:?}"));
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Longest `/api/v2/config` body: the settings of every fan are about 500 bytes,
+/// twice that leaves room for longer curves, and the rest is for profiles and
+/// schedule rules
+fn config_max_len(state: &InterfaceState) -> usize {
+    4096 + 1024 * state.fans.len()
+}
+
+/// Current settings as JSON, with their schema version
+fn config(state: &InterfaceState) -> Result<Value, ApiError> {
+    let snapshot = settings::snapshot(state, &Default::default());
+    Ok(logic_settings::to_json(&snapshot.redacted())?)
+}
+
+/// Checks and applies settings in the format of `GET /api/v2/config`, and returns
+/// them as they are now
+fn replace_config(state: &InterfaceState, mut value: Value) -> Result<Value, ApiError> {
+    // The current version unless it says otherwise
+    if let Value::Object(object) = &mut value {
+        object
+            .entry("version")
+            .or_insert(logic_settings::SCHEMA_VERSION.into());
+    }
+    let mut new_settings = logic_settings::from_json(value)?;
+    new_settings.mqtt.keep_password(&state.mqtt.lock().unwrap());
+    new_settings.validate()?;
+    // Changing only the display or the MQTT broker doesn't take the fans over
+    if settings::changes_control(state, &new_settings) {
+        state.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+    }
+    settings::replace(state, &new_settings);
+    config(state)
+}
@@ -0,0 +1,65 @@
+//! Web dashboard for people, at `/` in a browser.
+//!
+//! The files in `web/` are gzipped by `build.rs` and served as they are, every
+//! browser can unpack them. The page itself uses `/api/v2`.
+
+use embedded_svc::{
+    http::{Headers, Method},
+    io::Write,
+};
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+
+struct WebAsset {
+    path: &'static str,
+    content_type: &'static str,
+    /// Hash of the content, quoted
+    etag: &'static str,
+    gzip: &'static [u8],
+}
+
+static ASSETS: &[WebAsset] = &include!(concat!(env!("OUT_DIR"), "/web_assets.rs"));
+
+const INDEX: &str = "/index.html";
+
+/// Registers a handler for every file of the dashboard, but not `/`, that's up to
+/// whoever handles it, see [`wants_html`] and [`serve_index`]
+pub fn register(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    for asset in ASSETS {
+        server.fn_handler(asset.path, Method::Get, move |req| serve(req, asset))?;
+    }
+    Ok(())
+}
+
+/// A browser opening the page, rather than a script asking for JSON
+pub fn wants_html(req: &Request<&mut EspHttpConnection>) -> bool {
+    req.header("Accept")
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+pub fn serve_index(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+    let index = ASSETS
+        .iter()
+        .find(|asset| asset.path == INDEX)
+        .expect("build.rs includes index.html");
+    serve(req, index)
+}
+
+fn serve(req: Request<&mut EspHttpConnection>, asset: &WebAsset) -> anyhow::Result<()> {
+    // Checked every time, but only downloaded again after a firmware update
+    let cache_headers = [("Cache-Control", "no-cache"), ("ETag", asset.etag)];
+    if req.header("If-None-Match") == Some(asset.etag) {
+        req.into_response(304, None, &cache_headers)?;
+        return Ok(());
+    }
+    let headers = [
+        cache_headers[0],
+        cache_headers[1],
+        ("Content-Type", asset.content_type),
+        ("Content-Encoding", "gzip"),
+        // `/` is JSON for anything but a browser
+        ("Vary", "Accept"),
+    ];
+    req.into_response(200, None, &headers)?
+        .write_all(asset.gzip)?;
+    Ok(())
+}
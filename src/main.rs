@@ -1,18 +1,52 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use anyhow::Context;
 use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use fan_control_graphics::InterfaceState;
+use fan_control_logic::calibration::FanCalibration;
 use screen::ScreenBuilder;
+use storage::Storage;
+use supervisor::Supervisor;
 use threads::EspThread;
 
+mod api;
+mod dashboard;
+mod metrics;
+mod mqtt;
 mod pwm;
 mod rotary_encoder;
+mod schedule;
 mod screen;
+#[cfg(any(
+    feature = "ds18b20",
+    feature = "ntc",
+    feature = "bme280",
+    feature = "sht3x"
+))]
+mod sensors;
+mod settings;
+mod storage;
+mod supervisor;
 mod tacho;
+mod telemetry;
 mod threads;
 mod wifi_control;
 
+const MAX_FANS: usize = 4;
+
+/// Number of fans wired up, set with `FAN_COUNT` in `.env` (1-4, defaults to 1)
+fn fan_count() -> usize {
+    let count = option_env!("FAN_COUNT").map_or(1, |count| {
+        count.parse().expect("FAN_COUNT must be a number")
+    });
+    assert!(
+        (1..=MAX_FANS).contains(&count),
+        "FAN_COUNT must be between 1 and {MAX_FANS}"
+    );
+    count
+}
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -36,41 +70,133 @@ fn main() -> anyhow::Result<()> {
     .build()
     .context("Failed to initialize screen")?;
 
-    let state = Arc::new(InterfaceState::with_initial_pwm(50));
-    let interface = fan_control_graphics::Interface::new(state.clone());
+    let nvs = EspDefaultNvsPartition::take()?;
+    let storage = Arc::new(Storage::new(nvs.clone()).context("Failed to open NVS")?);
+
+    let fan_count = fan_count();
+    let state = Arc::new(InterfaceState::with_initial_pwm(fan_count, 50));
+    for (fan, fan_state) in state.fans.iter().enumerate() {
+        match storage.load::<FanCalibration>(&storage::calibration_key(fan)) {
+            Ok(Some(calibration)) => {
+                let mut spin_up = fan_state.spin_up.lock().unwrap();
+                *spin_up = spin_up.with_calibration(&calibration);
+                *fan_state.calibration.lock().unwrap() = Some(calibration);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to load calibration of fan {fan}: {:?}", e),
+        }
+    }
+    // After the calibration, spin-up thresholds may have been tuned by hand since
+    let saved_settings = settings::load(&storage);
+    if let Some(saved_settings) = &saved_settings {
+        settings::apply(&state, saved_settings);
+    }
+    let interface =
+        fan_control_graphics::Interface::new(state.clone()).with_clock(threads::uptime_ms);
 
     let dt = peripherals.pins.gpio33;
     let clk = peripherals.pins.gpio32;
+    let sw = peripherals.pins.gpio13;
     let pcnt = peripherals.pcnt0;
     let state_clone = state.clone();
     let rotary_encoder_thread = EspThread::new("rotary_encoder::rotary_encoder_thread")
-        .spawn(move || rotary_encoder::rotary_encoder_thread(pcnt, clk, dt, state_clone));
+        .spawn(move || rotary_encoder::rotary_encoder_thread(pcnt, clk, dt, sw, state_clone));
 
-    let pcnt = peripherals.pcnt1;
-    let pin = peripherals.pins.gpio27;
-    let tacho = tacho::Tacho::new(pcnt, pin).context("Failed to initialize tacho")?;
-    let state_clone = state.clone();
-    let tacho_thread =
-        EspThread::new("tacho::tacho_thread").spawn(move || tacho::tacho_loop(state_clone, tacho));
+    let tachos = tacho::TachoBuilder {
+        pcnt1: peripherals.pcnt1,
+        pcnt2: peripherals.pcnt2,
+        pcnt3: peripherals.pcnt3,
+        pcnt4: peripherals.pcnt4,
+        fan0: peripherals.pins.gpio27,
+        fan1: peripherals.pins.gpio14,
+        fan2: peripherals.pins.gpio36,
+        fan3: peripherals.pins.gpio39,
+    }
+    .build(fan_count)
+    .context("Failed to initialize tacho")?;
 
-    let ledc = peripherals.ledc;
-    let pwm = pwm::PwmControl::new(ledc.timer0, ledc.channel0, peripherals.pins.gpio26)
-        .context("Failed to initialize PWM control")?;
-    let state_clone = state.clone();
-    let pwm_thread = EspThread::new("pwm::pwm_control_thread")
-        .spawn(move || pwm::pwm_control_thread(pwm, state_clone));
+    let pwms = pwm::PwmBuilder {
+        ledc: peripherals.ledc,
+        fan0: peripherals.pins.gpio26,
+        fan1: peripherals.pins.gpio25,
+        fan2: peripherals.pins.gpio16,
+        fan3: peripherals.pins.gpio17,
+    }
+    .build(fan_count)
+    .context("Failed to initialize PWM control")?;
+
+    // Shared with the supervisor, which runs the fan at full speed if its threads die
+    let pwms: Vec<_> = pwms
+        .into_iter()
+        .map(|pwm| Arc::new(Mutex::new(pwm)))
+        .collect();
+    let mut supervisor = Supervisor::new(state.clone(), pwms.clone());
+    for (fan, (tacho, pwm)) in tachos.into_iter().zip(pwms).enumerate() {
+        let tacho = Arc::new(Mutex::new(tacho));
+        let state_clone = state.clone();
+        supervisor.add(format!("tacho[{fan}]"), fan, move |heartbeat| {
+            let (state, tacho) = (state_clone.clone(), tacho.clone());
+            EspThread::new(format!("tacho::tacho_thread[{fan}]"))
+                .spawn(move || tacho::tacho_loop(state, fan, tacho, heartbeat))
+        });
+
+        let state_clone = state.clone();
+        let storage_clone = storage.clone();
+        supervisor.add(format!("pwm[{fan}]"), fan, move |heartbeat| {
+            let (state, storage, pwm) = (state_clone.clone(), storage_clone.clone(), pwm.clone());
+            EspThread::new(format!("pwm::pwm_control_thread[{fan}]"))
+                .spawn(move || pwm::pwm_control_thread(pwm, state, fan, storage, heartbeat))
+        });
+    }
+    let supervisor_thread = supervisor.spawn(peripherals.twdt);
+
+    #[allow(unused_mut)]
+    let mut sensor_threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
+    #[cfg(feature = "ds18b20")]
+    sensor_threads.push(
+        sensors::spawn_ds18b20(peripherals.pins.gpio19, state.clone())
+            .context("Failed to initialize DS18B20")?,
+    );
+    #[cfg(feature = "ntc")]
+    sensor_threads.push(
+        sensors::spawn_ntc(peripherals.adc1, peripherals.pins.gpio34, state.clone())
+            .context("Failed to initialize NTC")?,
+    );
+    #[cfg(any(feature = "bme280", feature = "sht3x"))]
+    sensor_threads.extend(
+        sensors::spawn_i2c_sensors(
+            peripherals.i2c0,
+            peripherals.pins.gpio21,
+            peripherals.pins.gpio22,
+            state.clone(),
+        )
+        .context("Failed to initialize I2C sensors")?,
+    );
+
+    let settings_thread =
+        settings::spawn_settings_thread(state.clone(), storage.clone(), saved_settings);
+
+    let schedule_thread = schedule::spawn_schedule_thread(state.clone());
+
+    let mqtt_thread = mqtt::spawn_mqtt_thread(state.clone());
 
     log::info!("Spawning render thread");
+    let state_clone = state.clone();
     let render_thread = EspThread::new("screen::render_loop")
         .with_stack_size(16)
-        .spawn(move || screen::render_loop(interface, screen));
+        .spawn(move || screen::render_loop(interface, state_clone, screen));
 
-    let wifi_thread = wifi_control::spawn_wifi_control_thread(state, peripherals.modem);
+    let wifi_thread = wifi_control::spawn_wifi_control_thread(state, peripherals.modem, nvs);
 
     wifi_thread.join().unwrap();
     render_thread.join().unwrap();
     rotary_encoder_thread.join().unwrap();
-    pwm_thread.join().unwrap();
-    tacho_thread.join().unwrap();
+    settings_thread.join().unwrap();
+    schedule_thread.join().unwrap();
+    mqtt_thread.join().unwrap();
+    supervisor_thread.join().unwrap();
+    for sensor_thread in sensor_threads {
+        sensor_thread.join().unwrap();
+    }
     Ok(())
 }
@@ -0,0 +1,171 @@
+//! Metrics for Prometheus at `/metrics`, in OpenMetrics for scrapers that ask
+//! for it.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use embedded_svc::{
+    http::{Headers, Method},
+    io::Write,
+};
+use esp_idf_svc::http::server::EspHttpServer;
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::metrics::{MetricType, MetricsWriter};
+
+use crate::threads;
+
+pub fn register(
+    server: &mut EspHttpServer<'static>,
+    state: &Arc<InterfaceState>,
+) -> anyhow::Result<()> {
+    // GET /metrics - Fans, memory, WiFi and render times for Prometheus
+    let state = state.clone();
+    server.fn_handler("/metrics", Method::Get, move |req| {
+        let mut metrics = MetricsWriter::for_accept(req.header("Accept"));
+        write_metrics(&mut metrics, &state);
+        let headers = [
+            ("Content-Type", metrics.content_type()),
+            ("Cache-Control", "no-store"),
+        ];
+        req.into_response(200, None, &headers)?
+            .write_all(metrics.finish().as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+    Ok(())
+}
+
+fn write_metrics(metrics: &mut MetricsWriter, state: &InterfaceState) {
+    let fans: Vec<String> = (0..state.fans.len()).map(|fan| fan.to_string()).collect();
+    let per_fan = |metrics: &mut MetricsWriter, name: &str, value: &dyn Fn(usize) -> u32| {
+        for (fan, label) in fans.iter().enumerate() {
+            metrics.sample(name, &[("fan", label.as_str())], f64::from(value(fan)));
+        }
+    };
+
+    metrics.family(
+        "fan_control_fan_rpm",
+        MetricType::Gauge,
+        "Fan speed from the tacho",
+    );
+    per_fan(metrics, "fan_control_fan_rpm", &|fan| {
+        state.fans[fan].rpm.load(Ordering::Relaxed)
+    });
+    metrics.family(
+        "fan_control_fan_commanded_duty_percent",
+        MetricType::Gauge,
+        "Duty cycle the fan is set to",
+    );
+    per_fan(metrics, "fan_control_fan_commanded_duty_percent", &|fan| {
+        state.fans[fan].pwm.load(Ordering::Relaxed)
+    });
+    metrics.family(
+        "fan_control_fan_applied_duty_percent",
+        MetricType::Gauge,
+        "Duty cycle the fan gets after slew limiting, spin-up and zero-RPM",
+    );
+    per_fan(metrics, "fan_control_fan_applied_duty_percent", &|fan| {
+        state.fans[fan].applied_pwm.load(Ordering::Relaxed)
+    });
+
+    metrics
+        .family(
+            "fan_control_uptime_seconds",
+            MetricType::Gauge,
+            "Time since boot",
+        )
+        .sample(
+            "fan_control_uptime_seconds",
+            &[],
+            threads::uptime_ms() as f64 / 1000.0,
+        );
+
+    // SAFETY: Only read counters
+    let (free_heap, min_free_heap) = unsafe {
+        (
+            esp_idf_svc::sys::esp_get_free_heap_size(),
+            esp_idf_svc::sys::esp_get_minimum_free_heap_size(),
+        )
+    };
+    metrics
+        .family(
+            "fan_control_heap_free_bytes",
+            MetricType::Gauge,
+            "Free heap",
+        )
+        .sample("fan_control_heap_free_bytes", &[], f64::from(free_heap));
+    metrics
+        .family(
+            "fan_control_heap_min_free_bytes",
+            MetricType::Gauge,
+            "Least free heap since boot",
+        )
+        .sample(
+            "fan_control_heap_min_free_bytes",
+            &[],
+            f64::from(min_free_heap),
+        );
+
+    let stacks = threads::stack_usage();
+    metrics.family(
+        "fan_control_task_stack_min_free_bytes",
+        MetricType::Gauge,
+        "Least free stack of a task since it started, its high-water mark",
+    );
+    for stack in &stacks {
+        metrics.sample(
+            "fan_control_task_stack_min_free_bytes",
+            &[("task", stack.name.as_str())],
+            f64::from(stack.min_free_bytes),
+        );
+    }
+    metrics.family(
+        "fan_control_task_stack_size_bytes",
+        MetricType::Gauge,
+        "Stack size of a task, for those that don't use the default",
+    );
+    for stack in &stacks {
+        if let Some(size_bytes) = stack.size_bytes {
+            metrics.sample(
+                "fan_control_task_stack_size_bytes",
+                &[("task", stack.name.as_str())],
+                size_bytes as f64,
+            );
+        }
+    }
+
+    metrics.family(
+        "fan_control_wifi_rssi_dbm",
+        MetricType::Gauge,
+        "Signal strength of the access point, missing while not connected",
+    );
+    if let Some(rssi) = state.wifi.lock().unwrap().rssi {
+        metrics.sample("fan_control_wifi_rssi_dbm", &[], f64::from(rssi));
+    }
+
+    // Quantiles of the last 100 frames, the sum and count are since boot
+    let render_timings = *state.render_timings.lock().unwrap();
+    metrics.family(
+        "fan_control_render_duration_seconds",
+        MetricType::Summary,
+        "Time to draw a frame on the screen",
+    );
+    if let Some(summary) = render_timings.last {
+        for (quantile, ms) in summary.quantiles() {
+            metrics.sample(
+                "fan_control_render_duration_seconds",
+                &[("quantile", quantile.to_string().as_str())],
+                f64::from(ms) / 1000.0,
+            );
+        }
+    }
+    metrics
+        .sample(
+            "fan_control_render_duration_seconds_sum",
+            &[],
+            render_timings.sum_ms as f64 / 1000.0,
+        )
+        .sample(
+            "fan_control_render_duration_seconds_count",
+            &[],
+            render_timings.count as f64,
+        );
+}
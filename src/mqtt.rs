@@ -0,0 +1,397 @@
+//! MQTT client for Home Assistant, see `fan_control_logic::mqtt` for the topics.
+//!
+//! The controller announces its fans, sensors and selects over MQTT discovery,
+//! publishes what changes as retained messages and follows the command topics.
+//! The broker can be changed at runtime through the settings, the client then
+//! reconnects right away. Lost connections are retried with backoff.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use embedded_svc::mqtt::client::{Details, EventPayload, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::{
+    mode::ControlMode,
+    mqtt::{Backoff, Discovery, MqttCommand, MqttConfig, Topics, NONE, OFFLINE, ONLINE},
+    telemetry::{ChangeThrottle, TelemetryConfig},
+};
+
+use crate::{
+    api::{ActiveProfileCommand, ApiError, ModeCommand, PwmCommand},
+    telemetry::{self, Telemetry},
+    threads::{self, EspThread},
+};
+
+/// How often to look for changes to publish, and whether to connect at all
+const CHECK_INTERVAL_MS: u64 = 500;
+const MIN_BACKOFF_MS: u64 = 2_000;
+const MAX_BACKOFF_MS: u64 = 5 * 60 * 1000;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Long enough to never happen, reconnecting is done here with backoff instead
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Duty for turning on a fan that hasn't run since boot
+const DEFAULT_ON_DUTY: u32 = 50;
+const STACK_SIZE_KB: usize = 8;
+const EVENT_STACK_SIZE_KB: usize = 6;
+
+/// What the event thread passes on from the client
+enum Event {
+    Connected,
+    Disconnected,
+    Received { topic: String, payload: Vec<u8> },
+}
+
+/// Why a session ended
+enum SessionEnd {
+    Disconnected,
+    ConfigChanged,
+}
+
+pub fn spawn_mqtt_thread(state: Arc<InterfaceState>) -> JoinHandle<()> {
+    EspThread::new("mqtt::mqtt_thread")
+        .with_stack_size(STACK_SIZE_KB)
+        .spawn(move || mqtt_thread(state))
+}
+
+fn mqtt_thread(state: Arc<InterfaceState>) {
+    let mut backoff = Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS);
+    // Last duty each fan ran at, for turning it back on
+    let mut on_duty = vec![DEFAULT_ON_DUTY; state.fans.len()];
+    loop {
+        let config = state.mqtt.lock().unwrap().clone();
+        let wifi_connected = state.wifi.lock().unwrap().ip.is_some();
+        if !config.enabled || !wifi_connected {
+            backoff.reset();
+            esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS as u32);
+            continue;
+        }
+
+        log::info!("Connecting to MQTT broker {}", config.url);
+        match session(&state, &config, &mut backoff, &mut on_duty) {
+            Ok(SessionEnd::ConfigChanged) => {
+                log::info!("MQTT settings changed, reconnecting");
+                backoff.reset();
+                continue;
+            }
+            Ok(SessionEnd::Disconnected) => log::warn!("Disconnected from MQTT broker"),
+            Err(e) => log::error!("MQTT client failed: {:?}", e),
+        }
+
+        let delay_ms = backoff.next_delay_ms();
+        log::info!("Reconnecting to MQTT broker in {} s", delay_ms / 1000);
+        let retry_at = threads::uptime_ms() + delay_ms;
+        while threads::uptime_ms() < retry_at && *state.mqtt.lock().unwrap() == config {
+            esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS as u32);
+        }
+    }
+}
+
+/// Connects and keeps publishing until the connection is lost or the settings
+/// change
+fn session(
+    state: &InterfaceState,
+    config: &MqttConfig,
+    backoff: &mut Backoff,
+    on_duty: &mut [u32],
+) -> anyhow::Result<SessionEnd> {
+    let topics = config.topics();
+    let availability = topics.availability();
+    let node_id = config.node_id();
+    let optional = |value: &str| Some(value).filter(|value| !value.is_empty());
+    let client_config = MqttClientConfiguration {
+        client_id: Some(&node_id),
+        username: optional(&config.username),
+        password: optional(&config.password),
+        keep_alive_interval: Some(KEEP_ALIVE),
+        reconnect_timeout: Some(RECONNECT_TIMEOUT),
+        // The broker publishes this for us when the controller goes away
+        lwt: Some(LwtConfiguration {
+            topic: &availability,
+            payload: OFFLINE.as_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        // For `mqtts://` brokers with a certificate from a public CA
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    let (mut client, mut connection) = EspMqttClient::new(&config.url, &client_config)?;
+
+    let (events_tx, events) = mpsc::channel();
+    let event_thread = EspThread::new("mqtt::event_thread")
+        .with_stack_size(EVENT_STACK_SIZE_KB)
+        .spawn(move || {
+            // Ends when the client is dropped
+            while let Ok(event) = connection.next() {
+                let event = match event.payload() {
+                    EventPayload::Connected(_) => Event::Connected,
+                    EventPayload::Disconnected => Event::Disconnected,
+                    EventPayload::Received {
+                        topic: Some(topic),
+                        data,
+                        details: Details::Complete,
+                        ..
+                    } => Event::Received {
+                        topic: topic.to_string(),
+                        payload: data.to_vec(),
+                    },
+                    EventPayload::Error(e) => {
+                        log::warn!("MQTT error: {:?}", e);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if events_tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+    let result = run(state, config, &mut client, &events, backoff, on_duty);
+
+    if state.mqtt_connected.swap(false, Ordering::Relaxed) {
+        // Leaving on purpose doesn't trigger the last will
+        let _ = client.publish(&availability, QoS::AtLeastOnce, true, OFFLINE.as_bytes());
+    }
+    drop(client);
+    event_thread.join().unwrap();
+    result
+}
+
+fn run(
+    state: &InterfaceState,
+    config: &MqttConfig,
+    client: &mut EspMqttClient<'static>,
+    events: &mpsc::Receiver<Event>,
+    backoff: &mut Backoff,
+    on_duty: &mut [u32],
+) -> anyhow::Result<SessionEnd> {
+    let topics = config.topics();
+    let throttle_config = TelemetryConfig {
+        min_interval_ms: config.min_interval_ms,
+        // Retained, nobody misses anything
+        keepalive_ms: u64::MAX,
+    };
+    let mut throttle = ChangeThrottle::default();
+    // What the broker has, so only changes are published
+    let mut published = HashMap::new();
+    let mut discovered = HashMap::new();
+    loop {
+        match events.recv_timeout(Duration::from_millis(CHECK_INTERVAL_MS)) {
+            Ok(Event::Connected) => {
+                log::info!("Connected to MQTT broker {}", config.url);
+                state.mqtt_connected.store(true, Ordering::Relaxed);
+                backoff.reset();
+                for filter in topics.command_filters() {
+                    client.subscribe(&filter, QoS::AtLeastOnce)?;
+                }
+                if let Some(birth_topic) = config.birth_topic() {
+                    client.subscribe(&birth_topic, QoS::AtLeastOnce)?;
+                }
+                client.publish(
+                    &topics.availability(),
+                    QoS::AtLeastOnce,
+                    true,
+                    ONLINE.as_bytes(),
+                )?;
+                // The broker may have forgotten, e.g. after a restart without persistence
+                published.clear();
+                discovered.clear();
+                throttle = ChangeThrottle::default();
+            }
+            Ok(Event::Disconnected) => {
+                state.mqtt_connected.store(false, Ordering::Relaxed);
+                return Ok(SessionEnd::Disconnected);
+            }
+            Ok(Event::Received { topic, payload }) => {
+                if config.birth_topic().as_ref() == Some(&topic) {
+                    if payload == ONLINE.as_bytes() {
+                        log::info!("Home Assistant restarted, announcing again");
+                        published.clear();
+                        discovered.clear();
+                        throttle = ChangeThrottle::default();
+                    }
+                } else {
+                    handle_command(state, &topics, &topic, &payload, on_duty);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("MQTT event thread stopped"),
+        }
+
+        if *state.mqtt.lock().unwrap() != *config {
+            return Ok(SessionEnd::ConfigChanged);
+        }
+        if !state.mqtt_connected.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let telemetry = telemetry::telemetry(state);
+        for fan in &telemetry.fans {
+            if fan.pwm_percent > 0 {
+                on_duty[fan.fan] = fan.pwm_percent;
+            }
+        }
+        if throttle.update(&telemetry, &throttle_config, threads::uptime_ms()) {
+            publish_discovery(state, config, client, &mut discovered)?;
+            for (topic, payload) in state_messages(&topics, &telemetry) {
+                if published.get(&topic) != Some(&payload) {
+                    client.publish(&topic, QoS::AtMostOnce, true, payload.as_bytes())?;
+                    published.insert(topic, payload);
+                }
+            }
+        }
+    }
+}
+
+/// Announces everything that isn't yet, and removes what's gone, e.g. the
+/// profile select after deleting the last profile
+fn publish_discovery(
+    state: &InterfaceState,
+    config: &MqttConfig,
+    client: &mut EspMqttClient<'static>,
+    discovered: &mut HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let profiles: Vec<String> = state
+        .profiles
+        .lock()
+        .unwrap()
+        .profiles
+        .iter()
+        .map(|profile| profile.name.clone())
+        .collect();
+    let discovery = Discovery {
+        config,
+        fan_count: state.fans.len(),
+        profiles: &profiles,
+        firmware_version: env!("CARGO_PKG_VERSION"),
+    };
+    let messages: HashMap<String, String> = discovery
+        .messages()
+        .into_iter()
+        .map(|(topic, payload)| (topic, payload.to_string()))
+        .collect();
+
+    for topic in discovered.keys() {
+        if !messages.contains_key(topic) {
+            client.publish(topic, QoS::AtLeastOnce, true, &[])?;
+        }
+    }
+    for (topic, payload) in &messages {
+        if discovered.get(topic) != Some(payload) {
+            client.publish(topic, QoS::AtLeastOnce, true, payload.as_bytes())?;
+        }
+    }
+    *discovered = messages;
+    Ok(())
+}
+
+/// Topic and payload of every state topic
+fn state_messages(topics: &Topics, telemetry: &Telemetry) -> Vec<(String, String)> {
+    let mut messages = Vec::new();
+    for fan in &telemetry.fans {
+        let alarms = if fan.alarms.is_empty() {
+            "none".to_string()
+        } else {
+            fan.alarms.join(", ")
+        };
+        let power = if fan.pwm_percent > 0 { "ON" } else { "OFF" };
+        messages.extend([
+            (topics.fan(fan.fan, "rpm"), fan.rpm.to_string()),
+            (topics.fan(fan.fan, "duty"), fan.pwm_percent.to_string()),
+            (
+                topics.fan(fan.fan, "applied_duty"),
+                fan.applied_pwm_percent.to_string(),
+            ),
+            (topics.fan(fan.fan, "mode"), fan.mode.to_string()),
+            (topics.fan(fan.fan, "alarms"), alarms),
+            (topics.fan(fan.fan, "power"), power.to_string()),
+        ]);
+    }
+    let temperature = telemetry
+        .temperature_c
+        .map_or(NONE.to_string(), |celsius| format!("{celsius:.1}"));
+    messages.extend([
+        (topics.device("temperature"), temperature),
+        (
+            topics.device("profile"),
+            telemetry
+                .active_profile
+                .as_deref()
+                .unwrap_or(NONE)
+                .to_string(),
+        ),
+        (
+            topics.device("changed_via"),
+            telemetry.changed_via.to_string(),
+        ),
+    ]);
+    messages
+}
+
+/// Does what a command topic asks for, there's nobody to answer so problems are
+/// only logged
+fn handle_command(
+    state: &InterfaceState,
+    topics: &Topics,
+    topic: &str,
+    payload: &[u8],
+    on_duty: &[u32],
+) {
+    let command = match topics.parse_command(topic, payload, state.fans.len()) {
+        Ok(command) => command,
+        Err(e) => {
+            log::warn!("Ignoring MQTT message: {e}");
+            return;
+        }
+    };
+    log::info!("MQTT command: {:?}", command);
+    let result = match command {
+        MqttCommand::Duty { fan, percent } => PwmCommand {
+            fan: Some(fan),
+            percent,
+        }
+        .apply(state),
+        MqttCommand::Power { fan, on } => power(state, fan, on, on_duty),
+        MqttCommand::Mode { fan, mode } => ModeCommand {
+            fan: Some(fan),
+            mode,
+        }
+        .apply(state),
+        MqttCommand::Profile(name) => ActiveProfileCommand { name }.apply(state),
+    };
+    if let Err(e) = result {
+        log::warn!("MQTT command failed: {e}");
+    }
+}
+
+/// Off is 0% in manual mode. On does nothing to a fan that's running, and
+/// otherwise goes back to the last duty it ran at.
+fn power(state: &InterfaceState, fan: usize, on: bool, on_duty: &[u32]) -> Result<(), ApiError> {
+    let running = state.fans.get(fan).map_or(false, |fan_state| {
+        fan_state.control_mode.load(Ordering::Relaxed) != ControlMode::Manual
+            || fan_state.pwm.load(Ordering::Relaxed) > 0
+    });
+    if on && running {
+        return Ok(());
+    }
+    let percent = if on {
+        on_duty.get(fan).copied().unwrap_or(DEFAULT_ON_DUTY)
+    } else {
+        0
+    };
+    PwmCommand {
+        fan: Some(fan),
+        percent,
+    }
+    .apply(state)
+}
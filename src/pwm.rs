@@ -1,13 +1,26 @@
 use anyhow::Result;
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::ledc::*;
-use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::units::FromValueType;
-use fan_control_graphics::InterfaceState;
-use std::sync::Arc;
+use fan_control_graphics::{FanState, InterfaceState};
+use fan_control_logic::calibration::{CalibrationSweep, SweepConfig, SweepStatus};
+use fan_control_logic::curve::CurveFollower;
+use fan_control_logic::mode::ControlMode;
+use fan_control_logic::pid::{Pid, PidConfig};
+use fan_control_logic::slew::{SlewConfig, SlewLimiter};
+use fan_control_logic::spin_up::{SpinUp, SpinUpConfig};
+use fan_control_logic::supervisor::Heartbeat;
+use fan_control_logic::zero_rpm::ZeroRpm;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::storage::{self, Storage};
+use crate::threads;
 
 pub struct PwmControl {
     channel: LedcDriver<'static>,
+    spin_up: SpinUp,
+    /// Duty last written to the channel
+    output: Option<u32>,
 }
 
 /// PWM resolution used for fan control.
@@ -20,50 +33,299 @@ pub struct PwmControl {
 ///   - 100% = 1023 (constant HIGH, fan stopped)
 const RESOLUTION: Resolution = Resolution::Bits10;
 
-impl PwmControl {
-    pub fn new(
-        timer: TIMER0,
-        channel: CHANNEL0,
-        pin: impl Peripheral<P = impl OutputPin> + 'static,
-    ) -> Result<Self> {
+/// PWM outputs for up to four fans.
+///
+/// All channels share LEDC timer 0, so every fan runs at the same 25kHz.
+/// GPIO16/17 are used by PSRAM on WROVER modules, pick other pins there.
+pub struct PwmBuilder {
+    pub ledc: LEDC,
+    pub fan0: Gpio26,
+    pub fan1: Gpio25,
+    pub fan2: Gpio16,
+    pub fan3: Gpio17,
+}
+
+impl PwmBuilder {
+    pub fn build(self, fan_count: usize) -> Result<Vec<PwmControl>> {
+        let Self {
+            ledc,
+            fan0,
+            fan1,
+            fan2,
+            fan3,
+        } = self;
+
         // Configure timer for 25kHz operation (standard for PC fans)
         let timer_config = config::TimerConfig::new()
             .frequency(25.kHz().into())
             .resolution(RESOLUTION);
 
-        let timer = LedcTimerDriver::new(timer, &timer_config)?;
+        // Every channel borrows the timer for as long as the firmware runs
+        let timer = &*Box::leak(Box::new(LedcTimerDriver::new(ledc.timer0, &timer_config)?));
+
+        // Configure channels
+        let mut channels = vec![LedcDriver::new(ledc.channel0, timer, fan0)?];
+        if fan_count > 1 {
+            channels.push(LedcDriver::new(ledc.channel1, timer, fan1)?);
+        }
+        if fan_count > 2 {
+            channels.push(LedcDriver::new(ledc.channel2, timer, fan2)?);
+        }
+        if fan_count > 3 {
+            channels.push(LedcDriver::new(ledc.channel3, timer, fan3)?);
+        }
+
+        Ok(channels
+            .into_iter()
+            .map(|channel| PwmControl {
+                channel,
+                spin_up: SpinUp::new(SpinUpConfig::default()),
+                output: None,
+            })
+            .collect())
+    }
+}
 
-        // Configure channel
-        let channel = LedcDriver::new(channel, timer, pin)?;
+impl PwmControl {
+    /// Applies the commanded duty with the spin-up kick and minimum duty floor.
+    ///
+    /// Call every control period, not only when the duty changes, so kicks end on
+    /// time. Returns the duty the fan actually got.
+    pub fn update(&mut self, percent: u32, now_ms: u64) -> Result<u32> {
+        let output = self.spin_up.update(percent, now_ms);
+        self.write_duty(output)?;
+        Ok(output)
+    }
+
+    /// Applies the duty as is, for calibrating the fan or running it at full speed
+    pub fn update_unshaped(&mut self, percent: u32) -> Result<u32> {
+        // Start over with a kick once shaping takes over again
+        self.spin_up.update(0, 0);
+        self.write_duty(percent)?;
+        Ok(percent)
+    }
 
-        Ok(Self { channel })
+    pub fn set_spin_up_config(&mut self, config: SpinUpConfig) {
+        self.spin_up.set_config(config);
     }
 
-    pub fn set_duty(&mut self, percent: u32) -> Result<()> {
+    fn write_duty(&mut self, percent: u32) -> Result<()> {
+        let percent = percent.min(100);
+        if self.output == Some(percent) {
+            return Ok(());
+        }
         const RESOLUTION_MAX_VALUE: u32 = 2u32.pow(RESOLUTION.bits() as u32) - 1;
         // Invert percentage to match fan speed (0% = full speed, 100% = stopped)
         // When we pull pin high, it uses a transistor to pull the pwm line down
-        let percent = (100 - percent).clamp(0, 100);
+        let inverted = 100 - percent;
         // Convert percentage (0-100) to duty cycle value (0-1023 for 10-bit resolution)
-        let duty = ((percent as u32) * RESOLUTION_MAX_VALUE) / 100;
+        let duty = (inverted * RESOLUTION_MAX_VALUE) / 100;
         self.channel.set_duty(duty)?;
+        self.output = Some(percent);
         Ok(())
     }
 }
 
-pub fn pwm_control_thread(mut pwm: PwmControl, state: Arc<InterfaceState>) {
+/// A calibration sweep in progress, and what to go back to when it's done
+struct Calibration {
+    sweep: CalibrationSweep,
+    resume_mode: ControlMode,
+    resume_pwm: u32,
+    since_sample_ms: u32,
+}
+
+impl Calibration {
+    fn resume(&self, fan_state: &FanState) {
+        use std::sync::atomic::Ordering;
+
+        fan_state
+            .control_mode
+            .store(self.resume_mode, Ordering::Relaxed);
+        fan_state.pwm.store(self.resume_pwm, Ordering::Relaxed);
+    }
+}
+
+/// Controls one fan. `pwm` is shared with the supervisor, which takes over the fan
+/// if this thread dies.
+pub fn pwm_control_thread(
+    pwm: Arc<Mutex<PwmControl>>,
+    state: Arc<InterfaceState>,
+    fan: usize,
+    storage: Arc<Storage>,
+    heartbeat: Arc<Heartbeat>,
+) {
     use std::sync::atomic::Ordering;
 
-    let mut last_pwm_value = 0;
+    const PERIOD_MS: u32 = 100;
+    /// Used in curve mode while there is no temperature reading
+    const FAILSAFE_DUTY: u32 = 100;
+    /// Time at each duty of the sweep. The RPM is averaged over the last second
+    /// (`TachoWindowConfig::window_ms`), so that long for the window to hold only
+    /// pulses from the new duty, plus some for the fan to settle.
+    const CALIBRATION_SAMPLE_MS: u32 = 1500;
+
+    let fan_state = &state.fans[fan];
+    let mut pid = Pid::new(PidConfig::default());
+    let mut curve_config = None;
+    let mut curve_follower: Option<CurveFollower> = None;
+    let mut calibration: Option<Calibration> = None;
+    let mut slew = SlewLimiter::new(SlewConfig::default());
+    let mut zero_rpm = ZeroRpm::new(Default::default());
+    let mut last_mode = ControlMode::Manual;
+    let mut pwm_failed = false;
     loop {
-        let pwm_value = state.fan_pwm.load(Ordering::Relaxed);
-        if pwm_value != last_pwm_value {
-            last_pwm_value = pwm_value;
-            if let Err(e) = pwm.set_duty(pwm_value) {
-                log::error!("Failed to set PWM duty cycle: {:?}", e);
+        let now_ms = threads::uptime_ms();
+        heartbeat.beat(now_ms);
+        let mode = fan_state.control_mode.load(Ordering::Relaxed);
+        // Stacks on top of the mode, which carries on underneath and takes over again
+        // once the boost runs out
+        let boost = state
+            .boost
+            .lock()
+            .unwrap()
+            .active(fan, now_ms)
+            .map(|boost| boost.duty);
+        match mode {
+            ControlMode::Manual => {}
+            // The fan isn't doing what the PID asks for, don't let it wind up meanwhile
+            ControlMode::TargetRpm if boost.is_some() => {}
+            ControlMode::TargetRpm => {
+                if last_mode != ControlMode::TargetRpm {
+                    // Continue from the current duty instead of starting over from 0
+                    pid.reset_to(fan_state.pwm.load(Ordering::Relaxed) as f32);
+                }
+                let target_rpm = fan_state.target_rpm.load(Ordering::Relaxed) as f32;
+                let rpm = fan_state.rpm.load(Ordering::Relaxed) as f32;
+                let duty = pid.update(target_rpm, rpm, PERIOD_MS as f32 / 1000.0);
+                fan_state.pwm.store(duty.round() as u32, Ordering::Relaxed);
+            }
+            ControlMode::Curve => {
+                let config = fan_state.curve.lock().unwrap().clone();
+                if last_mode != ControlMode::Curve || curve_config.as_ref() != Some(&config) {
+                    curve_follower = CurveFollower::new(config.clone())
+                        .inspect_err(|e| log::error!("Invalid fan curve for fan {fan}: {e}"))
+                        .ok();
+                    curve_config = Some(config);
+                }
+                let duty = match (
+                    curve_follower.as_mut(),
+                    state.temperature.load(Ordering::Relaxed),
+                ) {
+                    (Some(follower), Some(celsius)) => {
+                        follower.update(celsius, now_ms).round() as u32
+                    }
+                    _ => FAILSAFE_DUTY,
+                };
+                fan_state.pwm.store(duty, Ordering::Relaxed);
+            }
+            ControlMode::Calibration => {
+                let calibration = calibration.get_or_insert_with(|| {
+                    log::info!("Calibrating fan {fan}");
+                    Calibration {
+                        sweep: CalibrationSweep::new(SweepConfig::default()),
+                        // Restarted right after the last sweep, there's nothing better to go
+                        // back to
+                        resume_mode: match last_mode {
+                            ControlMode::Calibration => ControlMode::Manual,
+                            mode => mode,
+                        },
+                        resume_pwm: fan_state.pwm.load(Ordering::Relaxed),
+                        since_sample_ms: 0,
+                    }
+                });
+                calibration.since_sample_ms += PERIOD_MS;
+                let status = if calibration.since_sample_ms >= CALIBRATION_SAMPLE_MS {
+                    calibration.since_sample_ms = 0;
+                    let rpm = fan_state.rpm.load(Ordering::Relaxed);
+                    calibration.sweep.update(rpm)
+                } else {
+                    SweepStatus::Running
+                };
+
+                match status {
+                    SweepStatus::Running => {
+                        fan_state
+                            .pwm
+                            .store(calibration.sweep.duty(), Ordering::Relaxed);
+                    }
+                    SweepStatus::Done(result) => {
+                        log::info!("Calibrated fan {fan}: {result:?}");
+                        if let Err(e) = storage.save(&storage::calibration_key(fan), &result) {
+                            log::error!("Failed to save calibration of fan {fan}: {:?}", e);
+                        }
+                        let mut spin_up = fan_state.spin_up.lock().unwrap();
+                        *spin_up = spin_up.with_calibration(&result);
+                        drop(spin_up);
+                        *fan_state.calibration.lock().unwrap() = Some(result);
+                        calibration.resume(fan_state);
+                    }
+                    SweepStatus::Failed(e) => {
+                        log::error!("Failed to calibrate fan {fan}: {e}");
+                        calibration.resume(fan_state);
+                    }
+                }
+            }
+        }
+        if mode != ControlMode::Calibration
+            || fan_state.control_mode.load(Ordering::Relaxed) != ControlMode::Calibration
+        {
+            // Finished, or aborted by switching to another mode
+            calibration = None;
+        }
+        last_mode = mode;
+
+        let requested = fan_state.pwm.load(Ordering::Relaxed);
+        // Someone asked for the boost, so it goes past quiet hours in the schedule
+        let cap = state.scheduled.lock().unwrap().duty_cap.unwrap_or(100);
+        let target = boost.unwrap_or(requested.min(cap));
+        let mut pwm = pwm.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = if fan_state.fail_safe.load(Ordering::Relaxed) {
+            // Another task of this fan has died, the supervisor wants full speed
+            slew.reset_to(100.0);
+            zero_rpm.keep_running(now_ms);
+            pwm.update_unshaped(100)
+        } else if mode == ControlMode::Calibration {
+            // The sweep has to see how the fan behaves without any help
+            slew.reset_to(requested as f32);
+            zero_rpm.keep_running(now_ms);
+            pwm.update_unshaped(requested)
+        } else {
+            let max_duty = fan_state.max_duty.load(Ordering::Relaxed);
+            slew.set_config(*fan_state.slew.lock().unwrap());
+            let duty = slew.update(target.min(max_duty) as f32, PERIOD_MS as f32 / 1000.0);
+            if mode == ControlMode::TargetRpm && boost.is_none() && duty != requested as f32 {
+                // Keep the integral from winding up while the duty is held back
+                pid.reset_to(duty);
+            }
+            zero_rpm.set_config(*fan_state.zero_rpm.lock().unwrap());
+            let duty = if boost.is_some() {
+                // Whoever asked for the boost shouldn't have to wait for the minimum off-time
+                zero_rpm.keep_running(now_ms);
+                duty.round() as u32
+            } else {
+                zero_rpm.update(duty.round() as u32, now_ms)
+            };
+            pwm.set_spin_up_config(*fan_state.spin_up.lock().unwrap());
+            pwm.update(duty, now_ms)
+        };
+        let stopped = zero_rpm.is_stopped();
+        if fan_state.zero_rpm_stopped.swap(stopped, Ordering::Relaxed) != stopped {
+            let action = if stopped { "stopped" } else { "started" };
+            log::info!("Fan {fan} {action} by zero-RPM mode");
+        }
+        match result {
+            Ok(applied) => {
+                pwm_failed = false;
+                fan_state.applied_pwm.store(applied, Ordering::Relaxed);
+            }
+            Err(e) if !pwm_failed => {
+                pwm_failed = true;
+                log::error!("Failed to set PWM duty cycle of fan {fan}: {:?}", e);
             }
+            Err(_) => {}
         }
+        drop(pwm);
         // Small delay to avoid hammering the PWM
-        esp_idf_hal::delay::FreeRtos::delay_ms(100);
+        esp_idf_hal::delay::FreeRtos::delay_ms(PERIOD_MS);
     }
 }
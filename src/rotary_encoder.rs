@@ -1,34 +1,107 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
 
-use esp_idf_hal::{delay::Delay, gpio::InputPin, pcnt::Pcnt, peripheral::Peripheral};
-use fan_control_graphics::InterfaceState;
+use esp_idf_hal::{
+    delay::Delay,
+    gpio::{InputPin, OutputPin, PinDriver, Pull},
+    pcnt::Pcnt,
+    peripheral::Peripheral,
+};
+use fan_control_graphics::{menu::MenuInput, FanState, InterfaceState};
+use fan_control_logic::{
+    button::{ButtonConfig, ButtonRecognizer, Gesture},
+    control::ControlSource,
+    knob::Knob,
+    mode::ControlMode,
+};
 
+use crate::threads;
+
+/// Duty a click switches the fans on at, if they weren't switched off with a click
+const DEFAULT_ON_DUTY: u32 = 50;
+/// Keep turning down this many % once every fan is at 0% to calibrate them all
+const CALIBRATE_OVERTURN: i32 = 15;
+
+/// Reads the knob and its push button.
+///
+/// Turning changes the duty of every fan, with the button held it moves in fine
+/// steps. A click switches the fans off and back on, or ends a boost, a double
+/// click switches to the next profile and a long press opens the settings menu.
+/// In the menu, a click selects, a double click goes back and a long press
+/// closes it.
 pub fn rotary_encoder_thread<PCNT: Pcnt>(
     pcnt: impl Peripheral<P = PCNT>,
     clk: impl Peripheral<P = impl InputPin>,
     dt: impl Peripheral<P = impl InputPin>,
+    sw: impl Peripheral<P = impl InputPin + OutputPin>,
     state: Arc<InterfaceState>,
 ) {
     let encoder = encoder::Encoder::new(pcnt, clk, dt).unwrap();
+    // The switch connects to ground, most encoder boards have a pull-up already
+    let mut sw = PinDriver::input(sw).unwrap();
+    sw.set_pull(Pull::Up).unwrap();
     const TARGET_HZ: u32 = 30;
     const TARGET_PERIOD_US: u32 = 1_000_000 / TARGET_HZ;
     let delay = Delay::new(TARGET_PERIOD_US / 10);
+    let mut knob = Knob::new(*state.knob.lock().unwrap());
+    let mut button = ButtonRecognizer::new(ButtonConfig::default());
     let mut last_value = encoder.get_value().unwrap();
+    let mut overturn = 0;
+    let mut knob_locked = false;
+    let mut switched_off = None;
     loop {
-        let start = SystemTime::now();
+        let start = Instant::now();
         let value = encoder.get_value();
 
         match value {
             Ok(value) => {
-                use std::sync::atomic::Ordering;
-                let diff = value - last_value;
-                if diff != 0 {
-                    let pwm = state.fan_pwm.load(Ordering::Relaxed);
-                    last_value = value;
-                    let new_pwm = pwm as i32 + diff;
-                    state
-                        .fan_pwm
-                        .store(new_pwm.max(0).min(100) as u32, Ordering::Relaxed);
+                let now_ms = threads::uptime_ms();
+                knob.set_config(*state.knob.lock().unwrap());
+                let counts = value - last_value;
+                last_value = value;
+
+                let mut gestures = Vec::new();
+                gestures.extend(button.update(sw.is_low(), now_ms));
+                if state.menu.lock().unwrap().is_open() {
+                    // One item or step per detent, without acceleration
+                    let detents = knob.detents(counts);
+                    match button.turn(detents) {
+                        Some(gesture) => gestures.push(gesture),
+                        None if detents != 0 => {
+                            menu_input(&state, MenuInput::Rotate(detents), now_ms)
+                        }
+                        None => {}
+                    }
+                    for gesture in gestures {
+                        match gesture {
+                            Gesture::Click => menu_input(&state, MenuInput::Click, now_ms),
+                            Gesture::DoubleClick => menu_input(&state, MenuInput::Back, now_ms),
+                            Gesture::LongPress => state.menu.lock().unwrap().close(),
+                            Gesture::PressAndTurn(detents) => {
+                                menu_input(&state, MenuInput::Rotate(detents), now_ms)
+                            }
+                        }
+                    }
+                } else {
+                    if button.is_pressed() {
+                        gestures.extend(button.turn(knob.update_fine(counts)));
+                    } else {
+                        let diff = knob.update(counts, now_ms);
+                        if diff != 0 && knob_allowed(&state, &mut knob_locked) {
+                            turn(&state, diff, &mut overturn);
+                        }
+                    }
+                    for gesture in gestures {
+                        if gesture == Gesture::LongPress {
+                            state.menu.lock().unwrap().open();
+                        } else if gesture == Gesture::DoubleClick {
+                            next_profile(&state, now_ms);
+                        } else if knob_allowed(&state, &mut knob_locked) {
+                            handle_gesture(&state, gesture, &mut switched_off);
+                        }
+                    }
                 }
             }
             Err(e) => {
@@ -36,11 +109,144 @@ pub fn rotary_encoder_thread<PCNT: Pcnt>(
                 delay.delay_ms(1000);
             }
         }
-        let elapsed_micros = start.elapsed().unwrap().as_micros();
+        let elapsed_micros = start.elapsed().as_micros();
         delay.delay_us(TARGET_PERIOD_US.saturating_sub(elapsed_micros as u32));
     }
 }
 
+fn turn(state: &InterfaceState, diff: i32, overturn: &mut i32) {
+    let all_stopped = state.fans.iter().all(|fan| {
+        fan.control_mode.load(Ordering::Relaxed) == ControlMode::Manual
+            && fan.pwm.load(Ordering::Relaxed) == 0
+    });
+    *overturn = if all_stopped && diff < 0 {
+        *overturn - diff
+    } else {
+        0
+    };
+    if *overturn >= CALIBRATE_OVERTURN {
+        *overturn = 0;
+        log::info!("Calibration requested with the knob");
+        for fan in &state.fans {
+            fan.control_mode
+                .store(ControlMode::Calibration, Ordering::Relaxed);
+        }
+    } else {
+        // The knob moves every fan by the same amount, which also aborts a
+        // calibration
+        for fan in &state.fans {
+            let pwm = fan.pwm.load(Ordering::Relaxed);
+            set_manual(fan, (pwm as i32 + diff).clamp(0, 100) as u32);
+        }
+    }
+}
+
+/// `switched_off` keeps the mode and duty of every fan from before a click
+/// switched them off
+fn handle_gesture(
+    state: &InterfaceState,
+    gesture: Gesture,
+    switched_off: &mut Option<Vec<(ControlMode, u32)>>,
+) {
+    match gesture {
+        Gesture::Click => {
+            if let Some(boost) = state.boost.lock().unwrap().cancel(threads::uptime_ms()) {
+                log::info!("Boost to {}% ended early with the knob", boost.duty);
+                return;
+            }
+            let running = state.fans.iter().any(|fan| {
+                fan.control_mode.load(Ordering::Relaxed) != ControlMode::Manual
+                    || fan.pwm.load(Ordering::Relaxed) > 0
+            });
+            if running {
+                log::info!("Fans switched off with the knob");
+                *switched_off = Some(
+                    state
+                        .fans
+                        .iter()
+                        .map(|fan| {
+                            let mode = match fan.control_mode.load(Ordering::Relaxed) {
+                                // Switching back on shouldn't start the sweep over
+                                ControlMode::Calibration => ControlMode::Manual,
+                                mode => mode,
+                            };
+                            (mode, fan.pwm.load(Ordering::Relaxed))
+                        })
+                        .collect(),
+                );
+                state.fans.iter().for_each(|fan| set_manual(fan, 0));
+            } else {
+                log::info!("Fans switched on with the knob");
+                let before = switched_off.take().unwrap_or_default();
+                for (i, fan) in state.fans.iter().enumerate() {
+                    let (mode, pwm) = before
+                        .get(i)
+                        .copied()
+                        .unwrap_or((ControlMode::Manual, DEFAULT_ON_DUTY));
+                    fan.pwm.store(pwm, Ordering::Relaxed);
+                    fan.control_mode.store(mode, Ordering::Relaxed);
+                }
+            }
+        }
+        // Open the menu and switch profiles, see the caller
+        Gesture::LongPress | Gesture::DoubleClick => {}
+        Gesture::PressAndTurn(diff) => {
+            for fan in &state.fans {
+                let pwm = fan.pwm.load(Ordering::Relaxed);
+                set_manual(fan, (pwm as i32 + diff).clamp(0, 100) as u32);
+            }
+        }
+    }
+}
+
+/// The knob always takes back manual control
+fn set_manual(fan: &FanState, pwm: u32) {
+    fan.control_mode
+        .store(ControlMode::Manual, Ordering::Relaxed);
+    fan.pwm.store(pwm, Ordering::Relaxed);
+}
+
+/// Switches every fan to the profile after the active one
+fn next_profile(state: &InterfaceState, now_ms: u64) {
+    let profile = state
+        .profiles
+        .lock()
+        .unwrap()
+        .cycle(1)
+        .map(|profile| profile.name.clone());
+    let Some(profile) = profile else {
+        return;
+    };
+    match state.apply_profile(&profile, ControlSource::RotaryEncoder, now_ms) {
+        Ok(()) => log::info!("Profile {profile} selected with the knob"),
+        Err(e) => log::info!("Ignoring the knob: {e}"),
+    }
+}
+
+fn menu_input(state: &InterfaceState, input: MenuInput, now_ms: u64) {
+    state.menu.lock().unwrap().input(state, input, now_ms);
+}
+
+/// Asks for control of the fans, and logs when the knob stops or starts working
+fn knob_allowed(state: &InterfaceState, knob_locked: &mut bool) -> bool {
+    match state.take_control(ControlSource::RotaryEncoder, threads::uptime_ms()) {
+        Ok(()) => {
+            if *knob_locked {
+                log::info!("Knob unlocked");
+            }
+            *knob_locked = false;
+            true
+        }
+        Err(e) => {
+            if !*knob_locked {
+                log::info!("Ignoring the knob: {e}");
+            }
+            *knob_locked = true;
+            false
+        }
+    }
+}
+
 // Shamelessly stolen from:
 // https://github.com/esp-rs/esp-idf-hal/blob/518a6419a5d4f3577c972f67b01ac97e1085e434/examples/pcnt_rotary_encoder.rs#L55
 mod encoder {
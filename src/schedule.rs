@@ -0,0 +1,104 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::{
+    control::ControlSource,
+    schedule::{self, ProfileSwitcher, ScheduleConfig},
+    timezone::LocalTime,
+};
+
+use crate::threads::{self, EspThread};
+
+const CHECK_INTERVAL_MS: u32 = 1000;
+const STACK_SIZE_KB: usize = 6;
+
+/// Wall clock time in the schedule's time zone, `None` until SNTP has set the clock
+pub fn local_time(state: &InterfaceState, config: &ScheduleConfig) -> Option<LocalTime> {
+    if !state.clock_synced.load(Ordering::Relaxed) {
+        return None;
+    }
+    let unix_secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    // Checked when the schedule was set
+    let time_zone = config.time_zone().unwrap_or_default();
+    Some(time_zone.local_time(unix_secs as i64))
+}
+
+pub fn spawn_schedule_thread(state: Arc<InterfaceState>) -> JoinHandle<()> {
+    EspThread::new("schedule::schedule_thread")
+        .with_stack_size(STACK_SIZE_KB)
+        .spawn(move || schedule_thread(state))
+}
+
+/// Keeps the clock in sync with the configured NTP server, and switches profiles
+/// and duty caps as the schedule says
+fn schedule_thread(state: Arc<InterfaceState>) {
+    // Server the client was started for, not again if that fails
+    let mut ntp_server = None;
+    let mut sntp: Option<EspSntp<'static>> = None;
+    let mut switcher = ProfileSwitcher::default();
+    // Only log the first attempt while automation is held off
+    let mut switch_failed = false;
+    loop {
+        esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+        let config = state.schedule.lock().unwrap().clone();
+
+        if ntp_server.as_ref() != Some(&config.ntp_server) {
+            // There can only be one SNTP client
+            drop(sntp.take());
+            sntp = start_sntp(&config.ntp_server)
+                .inspect_err(|e| log::error!("Failed to start SNTP: {:?}", e))
+                .ok();
+            log::info!("Syncing the clock with {}", config.ntp_server);
+            ntp_server = Some(config.ntp_server.clone());
+        }
+        let synced = sntp
+            .as_ref()
+            .is_some_and(|sntp| sntp.get_sync_status() == SyncStatus::Completed);
+        // The clock keeps running once it's set, even if the server goes away
+        if synced && !state.clock_synced.swap(true, Ordering::Relaxed) {
+            if let Some(now) = local_time(&state, &config) {
+                log::info!("Clock synced: {now}");
+            }
+        }
+
+        let now = local_time(&state, &config);
+        let scheduled = schedule::evaluate(&config.rules, now.as_ref());
+        let mut current = state.scheduled.lock().unwrap();
+        if scheduled.duty_cap != current.duty_cap {
+            match scheduled.duty_cap {
+                Some(cap) => log::info!("Schedule caps the duty at {cap}%"),
+                None => log::info!("Schedule lifted the duty cap"),
+            }
+        }
+        *current = scheduled.clone();
+        drop(current);
+
+        if let Some(profile) = switcher.update(&scheduled) {
+            match state.apply_profile(profile, ControlSource::Automation, threads::uptime_ms()) {
+                Ok(()) => {
+                    log::info!("Schedule switched to profile {profile}");
+                    switcher.switched();
+                    switch_failed = false;
+                }
+                Err(e) => {
+                    if !switch_failed {
+                        log::info!("Schedule can't switch to profile {profile} yet: {e}");
+                    }
+                    switch_failed = true;
+                }
+            }
+        }
+    }
+}
+
+fn start_sntp(server: &str) -> anyhow::Result<EspSntp<'static>> {
+    let mut conf = SntpConf::default();
+    // Only the configured server, a local one may be all there is
+    conf.servers.iter_mut().for_each(|s| *s = server);
+    Ok(EspSntp::new(&conf)?)
+}
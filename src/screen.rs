@@ -1,4 +1,4 @@
-use std::time::SystemTime;
+use std::{sync::Arc, time::Instant};
 
 use embedded_graphics::pixelcolor::Rgb565;
 use embedded_graphics::prelude::*;
@@ -7,8 +7,10 @@ use esp_idf_hal::delay::{Ets, FreeRtos};
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::spi::{Dma, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SPI2};
 use esp_idf_hal::units::FromValueType;
-use fan_control_graphics::Interface;
+use fan_control_graphics::{Interface, InterfaceState};
+use fan_control_logic::metrics::TimingSummary;
 use mipidsi::interface::SpiInterface;
+use mipidsi::options::{Orientation, Rotation};
 
 use crate::threads::debug_dump_stack_info;
 
@@ -70,7 +72,7 @@ pub struct Screen {
     rst: PinDriver<'static, Gpio4, Output>,
 }
 
-pub fn render_loop<'a>(mut interface: Interface, screen: Screen) {
+pub fn render_loop<'a>(mut interface: Interface, state: Arc<InterfaceState>, screen: Screen) {
     debug_dump_stack_info();
 
     let Screen { device, dc, rst } = screen;
@@ -95,26 +97,48 @@ pub fn render_loop<'a>(mut interface: Interface, screen: Screen) {
 
     log::info!("Clearing display and starting render loop");
     display.clear(Rgb565::BLACK).unwrap();
-    let start = SystemTime::now();
+    let start = Instant::now();
     display.clear(Rgb565::WHITE).unwrap();
     interface.render(&mut display, 0).unwrap();
+    let mut flipped = false;
     loop {
-        let before = SystemTime::now();
-        let clock_ms = start.elapsed().unwrap_or_default().as_millis() as u32;
+        let before = Instant::now();
+        // Set in the menu
+        let options = interface.display_options();
+        if options.flipped != flipped {
+            flipped = options.flipped;
+            let rotation = if flipped {
+                Rotation::Deg180
+            } else {
+                Rotation::Deg0
+            };
+            display
+                .set_orientation(Orientation::default().rotate(rotation))
+                .unwrap();
+            display.clear(Rgb565::WHITE).unwrap();
+            interface.redraw();
+        }
+        let clock_ms = start.elapsed().as_millis() as u32;
         interface.render(&mut display, clock_ms).unwrap();
 
-        let elapsed_ms = before.elapsed().unwrap_or_default().as_millis();
+        let elapsed_ms = before.elapsed().as_millis() as u32;
         timings.push(elapsed_ms);
         if timings.len() >= 100 {
-            timings.sort();
-            let sum: u128 = timings.iter().sum();
-            let avg = sum / timings.len() as u128;
-            let min = timings[0];
-            let max = timings[timings.len() - 1];
-            let p50 = timings[timings.len() / 2];
-            let p90 = timings[(timings.len() as f32 * 0.9) as usize];
-            let p99 = timings[(timings.len() as f32 * 0.99) as usize];
-            log::info!("Average render timings:\n * min: {min}ms\n * max: {max}ms\n * avg: {avg}ms\n * p50: {p50}ms\n * p90: {p90}ms\n * p99: {p99}ms");
+            // Also exported by `/metrics`
+            let mut render_timings = state.render_timings.lock().unwrap();
+            render_timings.record(&mut timings);
+            if let Some(TimingSummary {
+                min_ms: min,
+                max_ms: max,
+                avg_ms: avg,
+                p50_ms: p50,
+                p90_ms: p90,
+                p99_ms: p99,
+            }) = render_timings.last
+            {
+                log::info!("Average render timings:\n * min: {min}ms\n * max: {max}ms\n * avg: {avg}ms\n * p50: {p50}ms\n * p90: {p90}ms\n * p99: {p99}ms");
+            }
+            drop(render_timings);
             timings.clear();
         }
 
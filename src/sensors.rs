@@ -0,0 +1,149 @@
+//! Temperature sensors. Each sensor is enabled with its own cargo feature and
+//! runs in its own thread, publishing into [`InterfaceState::publish_temperature`].
+//!
+//! Pins:
+//! * DS18B20: GPIO19, with a 4.7kΩ pull-up to 3.3V
+//! * NTC: GPIO34 (ADC1), thermistor to ground and a 10kΩ resistor to 3.3V
+//! * BME280/SHT3x: I2C on GPIO21 (SDA) and GPIO22 (SCL)
+
+use std::sync::Arc;
+#[cfg(any(feature = "bme280", feature = "sht3x"))]
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use esp_idf_hal::delay::FreeRtos;
+#[cfg(feature = "ntc")]
+use esp_idf_hal::{
+    adc::{
+        attenuation::DB_11,
+        oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+        ADC1,
+    },
+    gpio::Gpio34,
+};
+#[cfg(any(feature = "bme280", feature = "sht3x"))]
+use esp_idf_hal::{
+    delay::Delay,
+    gpio::{Gpio21, Gpio22},
+    i2c::{I2cConfig, I2cDriver, I2C0},
+    units::FromValueType,
+};
+#[cfg(feature = "ds18b20")]
+use esp_idf_hal::{
+    delay::Ets,
+    gpio::{Gpio19, PinDriver},
+};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::sensor::TemperatureSource;
+#[cfg(feature = "ntc")]
+use fan_control_logic::sensor::{
+    ntc::{DividerAdc, Ntc, NtcConfig},
+    SensorError,
+};
+
+use crate::threads::EspThread;
+
+/// How often each sensor is read
+const READ_INTERVAL_MS: u32 = 2000;
+
+const STACK_SIZE_KB: usize = 4;
+
+pub fn sensor_loop<S: TemperatureSource>(mut sensor: S, state: Arc<InterfaceState>) {
+    loop {
+        match sensor.read_celsius() {
+            Ok(celsius) => state.publish_temperature(sensor.name(), Some(celsius)),
+            Err(e) => {
+                log::warn!("Failed to read {}: {e}", sensor.name());
+                state.publish_temperature(sensor.name(), None);
+            }
+        }
+        FreeRtos::delay_ms(READ_INTERVAL_MS);
+    }
+}
+
+fn spawn_sensor<S>(name: &'static str, sensor: S, state: Arc<InterfaceState>) -> JoinHandle<()>
+where
+    S: TemperatureSource + Send + 'static,
+{
+    EspThread::new(name)
+        .with_stack_size(STACK_SIZE_KB)
+        .spawn(move || sensor_loop(sensor, state))
+}
+
+#[cfg(feature = "ds18b20")]
+pub fn spawn_ds18b20(pin: Gpio19, state: Arc<InterfaceState>) -> anyhow::Result<JoinHandle<()>> {
+    use fan_control_logic::sensor::{ds18b20::Ds18b20, onewire::OneWire};
+
+    let pin = PinDriver::input_output_od(pin)?;
+    let sensor = Ds18b20::new(OneWire::new(pin, Ets)?);
+    Ok(spawn_sensor("sensors::ds18b20", sensor, state))
+}
+
+#[cfg(feature = "ntc")]
+pub fn spawn_ntc(
+    adc: ADC1,
+    pin: Gpio34,
+    state: Arc<InterfaceState>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let adc = AdcDriver::new(adc)?;
+    let config = AdcChannelConfig {
+        attenuation: DB_11,
+        ..Default::default()
+    };
+    let channel = AdcChannelDriver::new(adc, pin, &config)?;
+    let sensor = Ntc::new(EspDividerAdc { channel }, NtcConfig::default());
+    Ok(spawn_sensor("sensors::ntc", sensor, state))
+}
+
+#[cfg(feature = "ntc")]
+struct EspDividerAdc {
+    channel: AdcChannelDriver<'static, Gpio34, AdcDriver<'static, ADC1>>,
+}
+
+#[cfg(feature = "ntc")]
+impl DividerAdc for EspDividerAdc {
+    fn read_ratio(&mut self) -> Result<f32, SensorError> {
+        // 12-bit ADC
+        const FULL_SCALE: f32 = 4095.0;
+        let raw = self.channel.read_raw().map_err(SensorError::bus)?;
+        Ok(raw as f32 / FULL_SCALE)
+    }
+}
+
+/// BME280 and/or SHT3x, sharing one I2C bus
+#[cfg(any(feature = "bme280", feature = "sht3x"))]
+pub fn spawn_i2c_sensors(
+    i2c: I2C0,
+    sda: Gpio21,
+    scl: Gpio22,
+    state: Arc<InterfaceState>,
+) -> anyhow::Result<Vec<JoinHandle<()>>> {
+    use embedded_hal_bus::i2c::MutexDevice;
+
+    let config = I2cConfig::new().baudrate(100.kHz().into());
+    let driver = I2cDriver::new(i2c, sda, scl, &config)?;
+    // The bus lives as long as the firmware does, and every sensor thread needs it
+    let bus: &'static Mutex<I2cDriver<'static>> = Box::leak(Box::new(Mutex::new(driver)));
+
+    let mut threads = Vec::new();
+
+    #[cfg(feature = "bme280")]
+    {
+        use fan_control_logic::sensor::bme280::{Bme280, DEFAULT_ADDRESS};
+
+        match Bme280::new(MutexDevice::new(bus), Delay::new_default(), DEFAULT_ADDRESS) {
+            Ok(sensor) => threads.push(spawn_sensor("sensors::bme280", sensor, state.clone())),
+            Err(e) => log::error!("Failed to initialize BME280: {e}"),
+        }
+    }
+
+    #[cfg(feature = "sht3x")]
+    {
+        use fan_control_logic::sensor::sht3x::{Sht3x, DEFAULT_ADDRESS};
+
+        let sensor = Sht3x::new(MutexDevice::new(bus), Delay::new_default(), DEFAULT_ADDRESS);
+        threads.push(spawn_sensor("sensors::sht3x", sensor, state.clone()));
+    }
+
+    Ok(threads)
+}
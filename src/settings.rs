@@ -0,0 +1,184 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread::JoinHandle,
+};
+
+use fan_control_graphics::{FanState, InterfaceState};
+use fan_control_logic::{
+    mode::ControlMode,
+    settings::{self, FanSettings, SaveDebouncer, Settings},
+};
+
+use crate::{
+    storage::Storage,
+    threads::{self, EspThread},
+};
+
+const KEY: &str = "settings";
+const CHECK_INTERVAL_MS: u32 = 1000;
+/// Save once nothing has changed for this long
+const QUIET_MS: u64 = 5_000;
+/// Save at least this often while the knob keeps turning
+const MAX_DELAY_MS: u64 = 60_000;
+const STACK_SIZE_KB: usize = 8;
+
+/// Settings saved by the last run, `None` on first boot or if they can't be read
+pub fn load(storage: &Storage) -> Option<Settings> {
+    match storage.load(KEY) {
+        Ok(Some(value)) => settings::from_json(value)
+            .inspect_err(|e| log::error!("Ignoring saved settings: {e}"))
+            .ok(),
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("Failed to load settings: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Puts saved settings into the state, before any thread has started using it
+pub fn apply(state: &InterfaceState, settings: &Settings) {
+    apply_config(state, settings);
+    for (fan, saved) in state.fans.iter().zip(&settings.fans) {
+        let (pwm, mode) = settings.boot_state(saved);
+        fan.pwm.store(pwm, Ordering::Relaxed);
+        fan.applied_pwm.store(pwm, Ordering::Relaxed);
+        fan.control_mode.store(mode, Ordering::Relaxed);
+    }
+}
+
+/// Replaces the settings while everything is running, e.g. from `PUT /api/v2/config`.
+/// Fans whose duty or mode `settings` changes go to those rather than the boot
+/// duty, the others and fans it has no settings for are left alone.
+pub fn replace(state: &InterfaceState, settings: &Settings) {
+    apply_config(state, settings);
+    for (fan, saved) in state.fans.iter().zip(&settings.fans) {
+        if changes_control_of(fan, saved) {
+            fan.pwm.store(saved.pwm, Ordering::Relaxed);
+            fan.control_mode.store(saved.mode, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether [`replace`] would change the duty or mode of any fan, which needs
+/// control of the fans
+pub fn changes_control(state: &InterfaceState, settings: &Settings) -> bool {
+    state
+        .fans
+        .iter()
+        .zip(&settings.fans)
+        .any(|(fan, saved)| changes_control_of(fan, saved))
+}
+
+/// Outside manual mode the duty follows the mode, a different one in `saved`
+/// is only what it happened to be when the settings were read
+fn changes_control_of(fan: &FanState, saved: &FanSettings) -> bool {
+    let mode = fan.control_mode.load(Ordering::Relaxed);
+    saved.mode != mode
+        || (mode == ControlMode::Manual && saved.pwm != fan.pwm.load(Ordering::Relaxed))
+}
+
+/// Everything but the duty and control mode
+fn apply_config(state: &InterfaceState, settings: &Settings) {
+    *state.boot_duty.lock().unwrap() = settings.boot_duty;
+    state.control.lock().unwrap().set_policy(settings.control);
+    *state.knob.lock().unwrap() = settings.knob;
+    *state.display.lock().unwrap() = settings.display;
+    *state.profiles.lock().unwrap() = settings.profiles.clone();
+    *state.schedule.lock().unwrap() = settings.schedule.clone();
+    *state.telemetry.lock().unwrap() = settings.telemetry;
+    *state.mqtt.lock().unwrap() = settings.mqtt.clone();
+    for (fan, saved) in state.fans.iter().zip(&settings.fans) {
+        fan.target_rpm.store(saved.target_rpm, Ordering::Relaxed);
+        *fan.curve.lock().unwrap() = saved.curve.clone();
+        *fan.spin_up.lock().unwrap() = saved.spin_up;
+        *fan.slew.lock().unwrap() = saved.slew;
+        fan.pulses_per_rev
+            .store(saved.pulses_per_rev, Ordering::Relaxed);
+        fan.max_duty.store(saved.max_duty, Ordering::Relaxed);
+        *fan.zero_rpm.lock().unwrap() = saved.zero_rpm;
+    }
+}
+
+/// Current settings in the state. `last` provides the duty of fans that aren't in
+/// manual mode, their duty changes all the time and isn't worth wearing out flash for.
+pub fn snapshot(state: &InterfaceState, last: &Settings) -> Settings {
+    let fans = state
+        .fans
+        .iter()
+        .enumerate()
+        .map(|(i, fan)| {
+            let mode = fan.control_mode.load(Ordering::Relaxed);
+            let pwm = match (mode, last.fans.get(i)) {
+                (ControlMode::Manual, _) | (_, None) => fan.pwm.load(Ordering::Relaxed),
+                (_, Some(last)) => last.pwm,
+            };
+            FanSettings {
+                pwm,
+                mode,
+                target_rpm: fan.target_rpm.load(Ordering::Relaxed),
+                curve: fan.curve.lock().unwrap().clone(),
+                spin_up: *fan.spin_up.lock().unwrap(),
+                slew: *fan.slew.lock().unwrap(),
+                pulses_per_rev: fan.pulses_per_rev.load(Ordering::Relaxed),
+                max_duty: fan.max_duty.load(Ordering::Relaxed),
+                zero_rpm: *fan.zero_rpm.lock().unwrap(),
+            }
+        })
+        .collect();
+
+    Settings {
+        boot_duty: *state.boot_duty.lock().unwrap(),
+        control: state.control.lock().unwrap().policy(),
+        knob: *state.knob.lock().unwrap(),
+        display: *state.display.lock().unwrap(),
+        profiles: state.profiles.lock().unwrap().clone(),
+        schedule: state.schedule.lock().unwrap().clone(),
+        telemetry: *state.telemetry.lock().unwrap(),
+        mqtt: state.mqtt.lock().unwrap().clone(),
+        fans,
+    }
+}
+
+pub fn spawn_settings_thread(
+    state: Arc<InterfaceState>,
+    storage: Arc<Storage>,
+    saved: Option<Settings>,
+) -> JoinHandle<()> {
+    EspThread::new("settings::settings_thread")
+        .with_stack_size(STACK_SIZE_KB)
+        .spawn(move || settings_thread(state, storage, saved))
+}
+
+fn settings_thread(state: Arc<InterfaceState>, storage: Arc<Storage>, saved: Option<Settings>) {
+    let mut last = saved.clone().unwrap_or_default();
+    let mut debouncer = SaveDebouncer::new(saved, QUIET_MS, MAX_DELAY_MS);
+    loop {
+        esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+
+        // The sweep is all over the place, and shouldn't be resumed after a reboot anyway
+        let calibrating = state
+            .fans
+            .iter()
+            .any(|fan| fan.control_mode.load(Ordering::Relaxed) == ControlMode::Calibration);
+        if calibrating {
+            continue;
+        }
+
+        let current = snapshot(&state, &last);
+        let now_ms = threads::uptime_ms();
+        if let Some(settings) = debouncer.update(&current, now_ms) {
+            let result = settings::to_json(&settings)
+                .map_err(anyhow::Error::from)
+                .and_then(|value| storage.save(KEY, &value));
+            match result {
+                Ok(()) => log::info!("Settings saved"),
+                Err(e) => {
+                    log::error!("Failed to save settings: {:?}", e);
+                    debouncer.save_failed();
+                }
+            }
+        }
+        last = current;
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{de::DeserializeOwned, Serialize};
+
+const NAMESPACE: &str = "fan_control";
+
+/// JSON values in the NVS partition, shared between threads.
+///
+/// Keys can be at most 15 characters long.
+pub struct Storage {
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: Mutex::new(EspNvs::new(partition, NAMESPACE, true)?),
+        })
+    }
+
+    /// `None` if nothing has been saved under `key` yet
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        let nvs = self.nvs.lock().unwrap();
+        let Some(len) = nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        match nvs.get_raw(key, &mut buf)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save<T: Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(value)?;
+        self.nvs.lock().unwrap().set_raw(key, &json)?;
+        Ok(())
+    }
+}
+
+/// Key the calibration of `fan` is saved under
+pub fn calibration_key(fan: usize) -> String {
+    format!("calibration{fan}")
+}
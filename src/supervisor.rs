@@ -0,0 +1,163 @@
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex, TryLockError},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use esp_idf_hal::{
+    peripheral::Peripheral,
+    task::watchdog::{TWDTConfig, TWDTDriver, TWDT},
+};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::supervisor::{
+    Heartbeat, RestartPolicy, TaskAction, TaskMonitor, TaskReport,
+};
+
+use crate::{pwm::PwmControl, threads};
+
+const CHECK_INTERVAL_MS: u32 = 500;
+/// The chip resets if the supervisor doesn't feed the watchdog for this long
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(10);
+const STACK_SIZE_KB: usize = 4;
+
+type SpawnTask = Box<dyn Fn(Arc<Heartbeat>) -> JoinHandle<()> + Send>;
+
+struct Task {
+    name: String,
+    /// Fan that runs at full speed while this task isn't working
+    fan: usize,
+    spawn: SpawnTask,
+    heartbeat: Arc<Heartbeat>,
+    handle: JoinHandle<()>,
+    monitor: TaskMonitor,
+}
+
+/// Watches the fan control threads.
+///
+/// Threads that die are restarted with [`RestartPolicy`], and their fan runs at
+/// full speed until they're back. A thread that stops sending heartbeats can't be
+/// replaced, so the supervisor stops feeding the task watchdog and the chip
+/// resets. The fans run at full speed while the PWM pins are reset anyway.
+pub struct Supervisor {
+    state: Arc<InterfaceState>,
+    pwms: Vec<Arc<Mutex<PwmControl>>>,
+    tasks: Vec<Task>,
+}
+
+impl Supervisor {
+    pub fn new(state: Arc<InterfaceState>, pwms: Vec<Arc<Mutex<PwmControl>>>) -> Self {
+        Self {
+            state,
+            pwms,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Spawns a task and keeps it running. `spawn` is called again for every restart.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        fan: usize,
+        spawn: impl Fn(Arc<Heartbeat>) -> JoinHandle<()> + Send + 'static,
+    ) {
+        let heartbeat = Arc::new(Heartbeat::default());
+        heartbeat.beat(threads::uptime_ms());
+        self.tasks.push(Task {
+            name: name.into(),
+            fan,
+            handle: spawn(heartbeat.clone()),
+            spawn: Box::new(spawn),
+            heartbeat,
+            monitor: TaskMonitor::new(RestartPolicy::default()),
+        });
+    }
+
+    pub fn spawn(self, twdt: impl Peripheral<P = TWDT> + Send + 'static) -> JoinHandle<()> {
+        threads::EspThread::new("supervisor::supervisor_thread")
+            .with_stack_size(STACK_SIZE_KB)
+            .spawn(move || {
+                if let Err(e) = self.run(twdt) {
+                    log::error!("Supervisor failed: {:?}", e);
+                }
+            })
+    }
+
+    fn run(mut self, twdt: impl Peripheral<P = TWDT> + 'static) -> anyhow::Result<()> {
+        let config = TWDTConfig {
+            duration: WATCHDOG_TIMEOUT,
+            panic_on_trigger: true,
+            ..Default::default()
+        };
+        let mut driver = TWDTDriver::new(twdt, &config)?;
+        let mut watchdog = driver.watch_current_task()?;
+
+        loop {
+            let now_ms = threads::uptime_ms();
+            let mut reset = false;
+            let mut fail_safe = vec![false; self.pwms.len()];
+            for task in &mut self.tasks {
+                let status = task.monitor.status();
+                let action = task.monitor.update(
+                    now_ms,
+                    task.heartbeat.last_ms(),
+                    task.handle.is_finished(),
+                );
+                match action {
+                    TaskAction::None => {}
+                    TaskAction::FailSafe => fail_safe[task.fan] = true,
+                    TaskAction::Restart => {
+                        log::warn!("Restarting task {}", task.name);
+                        task.heartbeat.beat(now_ms);
+                        task.handle = (task.spawn)(task.heartbeat.clone());
+                    }
+                    TaskAction::Reset => {
+                        fail_safe[task.fan] = true;
+                        reset = true;
+                    }
+                }
+                if task.monitor.status() != status {
+                    log::error!("Task {} is {}", task.name, task.monitor.status().name());
+                }
+            }
+
+            for (fan, pwm) in self.pwms.iter().enumerate() {
+                // Tells a PWM thread that's still alive to keep the fan at full speed too
+                self.state.fans[fan]
+                    .fail_safe
+                    .store(fail_safe[fan], Ordering::Relaxed);
+                if fail_safe[fan] {
+                    self.full_speed(fan, pwm);
+                }
+            }
+            *self.state.tasks.lock().unwrap() = self
+                .tasks
+                .iter()
+                .map(|task| TaskReport {
+                    name: task.name.clone(),
+                    status: task.monitor.status(),
+                    recent_restarts: task.monitor.recent_restarts(),
+                })
+                .collect();
+
+            if !reset {
+                watchdog.feed()?;
+            }
+            esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+        }
+    }
+
+    fn full_speed(&self, fan: usize, pwm: &Mutex<PwmControl>) {
+        // A hung PWM thread may never let go of the lock, the watchdog handles that
+        let mut pwm = match pwm.try_lock() {
+            Ok(pwm) => pwm,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        match pwm.update_unshaped(100) {
+            Ok(applied) => self.state.fans[fan]
+                .applied_pwm
+                .store(applied, Ordering::Relaxed),
+            Err(e) => log::error!("Failed to run fan {fan} at full speed: {:?}", e),
+        }
+    }
+}
@@ -1,21 +1,74 @@
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, PoisonError};
 
-use anyhow::Context;
 use esp_idf_hal::delay::FreeRtos;
-use esp_idf_hal::gpio::AnyInputPin;
+use esp_idf_hal::gpio::{AnyInputPin, Gpio14, Gpio27, Gpio36, Gpio39};
 use esp_idf_hal::{
     gpio::InputPin,
     pcnt::{
         Pcnt, PcntChannel, PcntChannelConfig, PcntControlMode, PcntCountMode, PcntDriver, PinIndex,
+        PCNT1, PCNT2, PCNT3, PCNT4,
     },
     peripheral::Peripheral,
 };
 use fan_control_graphics::InterfaceState;
+use fan_control_logic::fault::{FaultConfig, FaultDetector};
+use fan_control_logic::mode::ControlMode;
+use fan_control_logic::supervisor::Heartbeat;
+use fan_control_logic::tacho::{RpmFilter, RpmFilterConfig, TachoWindow, TachoWindowConfig};
+use log::*;
+
+use crate::threads;
+
+/// Tacho inputs for up to four fans, one pulse counter unit each.
+///
+/// The tacho wire is open collector, so every input needs a pull-up to 3.3V.
+/// GPIO36/39 are input only and have no internal pull-ups anyway.
+pub struct TachoBuilder {
+    pub pcnt1: PCNT1,
+    pub pcnt2: PCNT2,
+    pub pcnt3: PCNT3,
+    pub pcnt4: PCNT4,
+    pub fan0: Gpio27,
+    pub fan1: Gpio14,
+    pub fan2: Gpio36,
+    pub fan3: Gpio39,
+}
+
+impl TachoBuilder {
+    pub fn build(self, fan_count: usize) -> anyhow::Result<Vec<Tacho>> {
+        let Self {
+            pcnt1,
+            pcnt2,
+            pcnt3,
+            pcnt4,
+            fan0,
+            fan1,
+            fan2,
+            fan3,
+        } = self;
+
+        let mut tachos = vec![Tacho::new(pcnt1, fan0)?];
+        if fan_count > 1 {
+            tachos.push(Tacho::new(pcnt2, fan1)?);
+        }
+        if fan_count > 2 {
+            tachos.push(Tacho::new(pcnt3, fan2)?);
+        }
+        if fan_count > 3 {
+            tachos.push(Tacho::new(pcnt4, fan3)?);
+        }
+        Ok(tachos)
+    }
+}
+
+/// Both edges of every pulse are counted
+const EDGES_PER_PULSE: u32 = 2;
+const COUNTER_LIMIT: i16 = i16::MAX;
 
 pub struct Tacho {
     pcnt_driver: PcntDriver<'static>, // Store the driver
+    window: TachoWindow,
 }
 
 impl Tacho {
@@ -31,7 +84,6 @@ impl Tacho {
             Option::<AnyInputPin>::None,
         )?;
 
-        const EXPECTED_PULSES_PER_SEC: i16 = 160; // For 2400 RPM
         pcnt_driver.channel_config(
             PcntChannel::Channel0,
             PinIndex::Pin0,
@@ -41,7 +93,9 @@ impl Tacho {
                 hctrl_mode: PcntControlMode::Keep,
                 pos_mode: PcntCountMode::Increment,
                 neg_mode: PcntCountMode::Increment,
-                counter_h_lim: EXPECTED_PULSES_PER_SEC * 2, // Allow up to 2x expected speed
+                // Wraps back to 0 here, which takes over 30k edges. Even a 20k RPM fan
+                // is nowhere near that between two samples.
+                counter_h_lim: COUNTER_LIMIT,
                 counter_l_lim: 0,
             },
         )?;
@@ -49,39 +103,79 @@ impl Tacho {
         pcnt_driver.set_filter_value(100)?; // Filter pulses shorter than 100 clock cycles
         pcnt_driver.filter_enable()?;
 
-        Ok(Self { pcnt_driver })
+        // Runs for as long as the firmware does, never paused or cleared again
+        pcnt_driver.counter_pause()?;
+        pcnt_driver.counter_clear()?;
+        pcnt_driver.counter_resume()?;
+
+        Ok(Self {
+            pcnt_driver,
+            window: TachoWindow::new(TachoWindowConfig::default()),
+        })
     }
 
-    pub fn read_rpm(&mut self) -> anyhow::Result<u32> {
-        self.pcnt_driver.counter_pause()?;
-        self.pcnt_driver.counter_clear()?;
-        let start = SystemTime::now();
-        self.pcnt_driver.counter_resume()?;
-
-        const APPROX_SAMPLE_TIME_MS: u32 = 1000;
-        // Sample for 1 second
-        FreeRtos::delay_ms(APPROX_SAMPLE_TIME_MS);
-
-        self.pcnt_driver.counter_pause()?;
-        let elapsed = start
-            .elapsed()
-            .context("Can't get elapsed time")?
-            .as_millis() as u32;
+    /// Samples the pulse counter and returns the RPM over the last second or so.
+    /// Doesn't block, call it regularly.
+    pub fn read_rpm(&mut self, pulses_per_rev: u32) -> anyhow::Result<u32> {
         let count = self.pcnt_driver.get_counter_value()?;
-
-        // Convert pulses per second to RPM
-        let rpm = ((count as f32 / 4.0) / elapsed as f32) * 60_000.0;
-        Ok(rpm as u32)
+        self.window
+            .push(threads::uptime_ms(), count as u32, COUNTER_LIMIT as u32);
+        Ok(self.window.rpm(pulses_per_rev * EDGES_PER_PULSE))
     }
 }
 
-pub fn tacho_loop(state: Arc<InterfaceState>, mut tacho: Tacho) {
+/// Measures one fan. `tacho` is shared so a restarted thread can take over.
+pub fn tacho_loop(
+    state: Arc<InterfaceState>,
+    fan: usize,
+    tacho: Arc<Mutex<Tacho>>,
+    heartbeat: Arc<Heartbeat>,
+) {
+    const SAMPLE_INTERVAL_MS: u32 = 100;
+
+    let fan_state = &state.fans[fan];
+    let mut detector = FaultDetector::new(FaultConfig::default());
+    let mut filter = RpmFilter::new(RpmFilterConfig::default());
+    let mut last_reading_ms = None;
     loop {
-        if let Ok(rpm) = tacho.read_rpm() {
-            state.fan_rpm.store(rpm, Ordering::Relaxed);
+        let uptime_ms = threads::uptime_ms();
+        heartbeat.beat(uptime_ms);
+        let pulses_per_rev = fan_state.pulses_per_rev.load(Ordering::Relaxed);
+        let reading = tacho
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read_rpm(pulses_per_rev);
+        if let Ok(raw_rpm) = reading {
+            let dt_ms = uptime_ms - last_reading_ms.unwrap_or(uptime_ms);
+            last_reading_ms = Some(uptime_ms);
+            let rpm = filter.update(raw_rpm, dt_ms as f32 / 1000.0);
+            fan_state.raw_rpm.store(raw_rpm, Ordering::Relaxed);
+            fan_state.rpm.store(rpm, Ordering::Relaxed);
+            fan_state
+                .rejected_rpm_readings
+                .store(filter.rejected(), Ordering::Relaxed);
+
+            let duty = match fan_state.control_mode.load(Ordering::Relaxed) {
+                // The sweep runs the fan at duties too low to spin on purpose, only
+                // look for over-speed
+                ControlMode::Calibration => 0,
+                // Stopped on purpose, a fan that doesn't spin isn't a stall
+                _ if fan_state.zero_rpm_stopped.load(Ordering::Relaxed) => 0,
+                _ => fan_state.applied_pwm.load(Ordering::Relaxed),
+            };
+            let alarms = detector.update(duty, rpm, uptime_ms);
+            let previous = fan_state.alarms.load(Ordering::Relaxed);
+            if alarms != previous {
+                for alarm in alarms.iter().filter(|&alarm| !previous.contains(alarm)) {
+                    warn!("Fan {fan}: {} at {duty}% duty, {rpm} RPM", alarm.name());
+                }
+                for alarm in previous.iter().filter(|&alarm| !alarms.contains(alarm)) {
+                    info!("Fan {fan}: {} cleared", alarm.name());
+                }
+                fan_state.alarms.store(alarms, Ordering::Relaxed);
+            }
         }
 
-        // Optional small delay between readings
-        FreeRtos::delay_ms(100);
+        FreeRtos::delay_ms(SAMPLE_INTERVAL_MS);
     }
 }
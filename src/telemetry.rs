@@ -0,0 +1,329 @@
+//! Live telemetry over a WebSocket at `/api/v2/ws`, so dashboards don't have to
+//! poll.
+//!
+//! The server pushes `{"type": "telemetry", ...}` whenever the RPM, duty, control
+//! source or alarms change, but not more often than the configured minimum
+//! interval. Clients can send commands back, e.g. `{"command": "pwm", "percent":
+//! 40}`, and get `{"type": "ack"}` or `{"type": "error", "error": {...}}` with the
+//! same `"id"` they sent, if any.
+
+use std::sync::{atomic::Ordering, Arc, Mutex};
+
+use esp_idf_svc::{
+    http::server::{
+        ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+        EspHttpServer,
+    },
+    ws::FrameType,
+};
+use fan_control_graphics::InterfaceState;
+use fan_control_logic::{
+    boost::BoostConfig, control::ControlSource, supervisor::TaskStatus, telemetry::ChangeThrottle,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        self, ActiveProfileCommand, ApiError, ErrorEnvelope, ModeCommand, PwmCommand, RpmCommand,
+    },
+    threads::{self, EspThread},
+    wifi_control::MAX_LEN,
+};
+
+/// Each one takes a socket of the HTTP server, which only has a few
+const MAX_CLIENTS: usize = 4;
+/// How often to look for changes, the configured interval is the limit for sending
+const CHECK_INTERVAL_MS: u32 = 50;
+const STACK_SIZE_KB: usize = 6;
+
+/// Everything that changes while the fans run, also published over MQTT
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Telemetry {
+    pub fans: Vec<FanTelemetry>,
+    pub temperature_c: Option<f32>,
+    /// Who changed the fans last
+    pub changed_via: &'static str,
+    pub active_profile: Option<String>,
+    /// Supervised tasks that aren't running
+    pub failed_tasks: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct FanTelemetry {
+    pub fan: usize,
+    pub rpm: u32,
+    pub pwm_percent: u32,
+    pub applied_pwm_percent: u32,
+    pub mode: &'static str,
+    pub alarms: Vec<&'static str>,
+    pub boosted: bool,
+    pub zero_rpm_stopped: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Telemetry {
+        uptime_ms: u64,
+        #[serde(flatten)]
+        telemetry: &'a Telemetry,
+    },
+    Ack {
+        id: Option<u64>,
+    },
+    Error {
+        id: Option<u64>,
+        #[serde(flatten)]
+        error: ErrorEnvelope<'a>,
+    },
+}
+
+#[derive(Deserialize)]
+struct ClientMessage {
+    /// Sent back with the answer
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+/// The same as the `/api/v2` endpoints with the same name
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Pwm(PwmCommand),
+    Rpm(RpmCommand),
+    Mode(ModeCommand),
+    Profile(ActiveProfileCommand),
+    Boost(BoostConfig),
+    /// Ends the newest boost
+    CancelBoost,
+}
+
+impl Command {
+    fn apply(self, state: &InterfaceState) -> Result<(), ApiError> {
+        match self {
+            Command::Pwm(cmd) => cmd.apply(state),
+            Command::Rpm(cmd) => cmd.apply(state),
+            Command::Mode(cmd) => cmd.apply(state),
+            Command::Profile(cmd) => cmd.apply(state),
+            Command::Boost(config) => {
+                api::selected_fans(state, config.fan)?;
+                config.validate().map_err(ApiError::bad_request)?;
+                let now_ms = threads::uptime_ms();
+                state.take_control(ControlSource::Wifi, now_ms)?;
+                state
+                    .boost
+                    .lock()
+                    .unwrap()
+                    .start(config, now_ms)
+                    .map_err(ApiError::bad_request)?;
+                Ok(())
+            }
+            Command::CancelBoost => {
+                let now_ms = threads::uptime_ms();
+                state.take_control(ControlSource::Wifi, now_ms)?;
+                state.boost.lock().unwrap().cancel(now_ms);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Client {
+    session: i32,
+    sender: EspHttpWsDetachedSender,
+}
+
+/// Registers the WebSocket handler and starts pushing telemetry to whoever connects
+pub fn register(
+    server: &mut EspHttpServer<'static>,
+    state: &Arc<InterfaceState>,
+) -> anyhow::Result<()> {
+    let clients = Arc::new(Mutex::new(Vec::<Client>::new()));
+
+    let state_clone = state.clone();
+    let clients_clone = clients.clone();
+    server.ws_handler("/api/v2/ws", move |ws| {
+        handle_socket(ws, &state_clone, &clients_clone)
+    })?;
+
+    let state = state.clone();
+    EspThread::new("telemetry::push_thread")
+        .with_stack_size(STACK_SIZE_KB)
+        .spawn(move || push_thread(state, clients));
+    Ok(())
+}
+
+fn handle_socket(
+    ws: &mut EspHttpWsConnection,
+    state: &InterfaceState,
+    clients: &Mutex<Vec<Client>>,
+) -> anyhow::Result<()> {
+    if ws.is_new() {
+        let mut clients = clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS {
+            drop(clients);
+            let error = ApiError::new(
+                503,
+                "too_many_clients",
+                format!("Only {MAX_CLIENTS} clients at a time"),
+            );
+            send(ws, &error_message(None, &error))?;
+            ws.send(FrameType::Close, &[])?;
+            return Ok(());
+        }
+        clients.push(Client {
+            session: ws.session(),
+            sender: ws.create_detached_sender()?,
+        });
+        drop(clients);
+        log::info!("Telemetry client {} connected", ws.session());
+        // Everything right away, later only when something changes
+        let telemetry = telemetry(state);
+        return send(ws, &telemetry_message(&telemetry));
+    }
+    if ws.is_closed() {
+        clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.session != ws.session());
+        log::info!("Telemetry client {} disconnected", ws.session());
+        return Ok(());
+    }
+
+    let (frame_type, len) = ws.recv(&mut [])?;
+    if len > MAX_LEN {
+        let error = ApiError::new(
+            413,
+            "payload_too_large",
+            format!("Messages can be up to {MAX_LEN} bytes"),
+        );
+        send(ws, &error_message(None, &error))?;
+        ws.send(FrameType::Close, &[])?;
+        return Ok(());
+    }
+    let mut buf = vec![0; len];
+    ws.recv(&mut buf)?;
+    // Pings are answered by the server, and nothing here is ever split up
+    if frame_type != FrameType::Text(false) {
+        return Ok(());
+    }
+
+    // Text frames come with a terminating NUL
+    let text = String::from_utf8_lossy(&buf);
+    let reply = match serde_json::from_str::<ClientMessage>(text.trim_end_matches('\0')) {
+        Ok(message) => match message.command.apply(state) {
+            Ok(()) => serde_json::to_string(&ServerMessage::Ack { id: message.id })?,
+            Err(error) => error_message(message.id, &error),
+        },
+        Err(e) => error_message(
+            None,
+            &ApiError::new(400, "invalid_json", format!("Invalid command: {e}")),
+        ),
+    };
+    send(ws, &reply)
+}
+
+fn push_thread(state: Arc<InterfaceState>, clients: Arc<Mutex<Vec<Client>>>) {
+    let mut throttle = ChangeThrottle::default();
+    loop {
+        esp_idf_hal::delay::FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+        if clients.lock().unwrap().is_empty() {
+            continue;
+        }
+
+        let telemetry = telemetry(&state);
+        let config = *state.telemetry.lock().unwrap();
+        if !throttle.update(&telemetry, &config, threads::uptime_ms()) {
+            continue;
+        }
+        let message = telemetry_message(&telemetry);
+
+        // Sending waits for the httpd task, which may be waiting for the lock in
+        // handle_socket, so send to copies of the senders
+        let senders: Vec<(i32, EspHttpWsDetachedSender)> = clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|client| (client.session, client.sender.clone()))
+            .collect();
+        let mut failed = Vec::new();
+        for (session, mut sender) in senders {
+            if let Err(e) = sender.send(FrameType::Text(false), message.as_bytes()) {
+                log::info!("Dropping telemetry client {session}: {e}");
+                failed.push(session);
+            }
+        }
+        // The socket is gone if it can't be sent to, closing doesn't always tell
+        if !failed.is_empty() {
+            clients
+                .lock()
+                .unwrap()
+                .retain(|client| !failed.contains(&client.session));
+        }
+    }
+}
+
+pub(crate) fn telemetry(state: &InterfaceState) -> Telemetry {
+    let now_ms = threads::uptime_ms();
+    let boost = state.boost.lock().unwrap();
+    let fans = state
+        .fans
+        .iter()
+        .enumerate()
+        .map(|(i, fan)| FanTelemetry {
+            fan: i,
+            rpm: fan.rpm.load(Ordering::Relaxed),
+            pwm_percent: fan.pwm.load(Ordering::Relaxed),
+            applied_pwm_percent: fan.applied_pwm.load(Ordering::Relaxed),
+            mode: fan.control_mode.load(Ordering::Relaxed).name(),
+            alarms: fan
+                .alarms
+                .load(Ordering::Relaxed)
+                .iter()
+                .map(|alarm| alarm.name())
+                .collect(),
+            boosted: boost.active(i, now_ms).is_some(),
+            zero_rpm_stopped: fan.zero_rpm_stopped.load(Ordering::Relaxed),
+        })
+        .collect();
+    drop(boost);
+
+    Telemetry {
+        fans,
+        temperature_c: state.temperature.load(Ordering::Relaxed),
+        changed_via: state.control.lock().unwrap().last_change().source.name(),
+        active_profile: state.profiles.lock().unwrap().active_profile.clone(),
+        failed_tasks: state
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|task| task.status != TaskStatus::Running)
+            .map(|task| task.name.clone())
+            .collect(),
+    }
+}
+
+fn telemetry_message(telemetry: &Telemetry) -> String {
+    let message = ServerMessage::Telemetry {
+        uptime_ms: threads::uptime_ms(),
+        telemetry,
+    };
+    // Plain data, can't fail
+    serde_json::to_string(&message).unwrap_or_default()
+}
+
+fn error_message(id: Option<u64>, error: &ApiError) -> String {
+    let message = ServerMessage::Error {
+        id,
+        error: ErrorEnvelope { error },
+    };
+    serde_json::to_string(&message).unwrap_or_default()
+}
+
+fn send(ws: &mut EspHttpWsConnection, message: &str) -> anyhow::Result<()> {
+    ws.send(FrameType::Text(false), message.as_bytes())?;
+    Ok(())
+}
@@ -1,13 +1,15 @@
-use std::thread::JoinHandle;
+use std::{sync::Mutex, thread::JoinHandle};
+
+use esp_idf_hal::sys::TaskHandle_t;
 
 pub struct EspThread {
-    name: &'static str,
+    name: String,
     stack_kb: Option<usize>,
 }
 impl EspThread {
-    pub fn new(name: &'static str) -> Self {
+    pub fn new(name: impl Into<String>) -> Self {
         Self {
-            name,
+            name: name.into(),
             stack_kb: None,
         }
     }
@@ -32,7 +34,8 @@ impl EspThread {
         // .set()
         // .unwrap();
 
-        let mut builder = std::thread::Builder::new().name(name.to_string());
+        let task_name = name.clone();
+        let mut builder = std::thread::Builder::new().name(name);
 
         if let Some(stack_kb) = stack_kb {
             builder = builder.stack_size(stack_kb * 1024);
@@ -40,10 +43,12 @@ impl EspThread {
 
         let handle = builder
             .spawn(move || {
+                register_task(task_name, stack_kb);
                 let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(func));
                 if let Err(err) = result {
                     log::error!("Thread panicked: {:?}", err);
                 }
+                unregister_task();
             })
             .unwrap();
 
@@ -53,6 +58,72 @@ impl EspThread {
     }
 }
 
+/// A thread started by [`EspThread`] that hasn't finished yet
+struct Task {
+    name: String,
+    stack_kb: Option<usize>,
+    handle: TaskHandle,
+}
+
+struct TaskHandle(TaskHandle_t);
+
+// SAFETY: Only used to ask FreeRTOS about the task, which is alive for as long as
+// it's in `TASKS`
+unsafe impl Send for TaskHandle {}
+
+static TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+
+fn register_task(name: String, stack_kb: Option<usize>) {
+    // SAFETY: Only reads the current task
+    let handle = TaskHandle(unsafe { esp_idf_hal::sys::xTaskGetCurrentTaskHandle() });
+    TASKS.lock().unwrap().push(Task {
+        name,
+        stack_kb,
+        handle,
+    });
+}
+
+/// Before the task is deleted, its handle mustn't be used afterwards
+fn unregister_task() {
+    // SAFETY: Only reads the current task
+    let handle = unsafe { esp_idf_hal::sys::xTaskGetCurrentTaskHandle() };
+    TASKS.lock().unwrap().retain(|task| task.handle.0 != handle);
+}
+
+/// How much of its stack a thread has used so far
+pub struct StackUsage {
+    pub name: String,
+    /// `None` for the default size
+    pub size_bytes: Option<usize>,
+    /// Least there has ever been left, the high-water mark
+    pub min_free_bytes: u32,
+}
+
+/// Every running thread started by [`EspThread`]. When a thread has been
+/// restarted while the old one still runs, only the newest is listed.
+pub fn stack_usage() -> Vec<StackUsage> {
+    let tasks = TASKS.lock().unwrap();
+    let mut usage: Vec<StackUsage> = Vec::with_capacity(tasks.len());
+    for task in tasks.iter().rev() {
+        if usage.iter().any(|usage| usage.name == task.name) {
+            continue;
+        }
+        usage.push(StackUsage {
+            name: task.name.clone(),
+            size_bytes: task.stack_kb.map(|stack_kb| stack_kb * 1024),
+            // SAFETY: The task is still running, it unregisters before it ends and
+            // can't while `TASKS` is locked
+            min_free_bytes: unsafe { esp_idf_hal::sys::uxTaskGetStackHighWaterMark(task.handle.0) },
+        });
+    }
+    usage
+}
+
+/// Milliseconds since boot, the same clock in every thread
+pub fn uptime_ms() -> u64 {
+    (unsafe { esp_idf_hal::sys::esp_timer_get_time() } / 1000) as u64
+}
+
 pub fn debug_dump_stack_info() {
     let task_name =
         unsafe { std::ffi::CStr::from_ptr(esp_idf_hal::sys::pcTaskGetName(std::ptr::null_mut())) }
@@ -1,5 +1,8 @@
 use core::convert::TryInto;
-use std::{sync::Arc, thread::JoinHandle, time::SystemTime};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread::JoinHandle,
+};
 
 use embedded_svc::{
     http::{Headers, Method},
@@ -9,45 +12,305 @@ use embedded_svc::{
 
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    http::server::EspHttpServer,
+    http::server::{EspHttpConnection, EspHttpServer, Request},
     nvs::EspDefaultNvsPartition,
     wifi::{BlockingWifi, EspWifi},
 };
-use fan_control_graphics::InterfaceState;
+use fan_control_graphics::{FanState, InterfaceState, WifiInfo};
+use fan_control_logic::{
+    boost::{Boost, BoostConfig},
+    calibration::FanCalibration,
+    control::{ControlChange, ControlPolicy, ControlSource},
+    curve::CurveConfig,
+    knob::KnobConfig,
+    mode::ControlMode,
+    profile::Profile,
+    schedule::{ScheduleConfig, ScheduleState},
+    settings::BootDuty,
+    slew::SlewConfig,
+    spin_up::SpinUpConfig,
+    supervisor::TaskStatus,
+    zero_rpm::ZeroRpmConfig,
+};
 use log::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::threads;
+use crate::{api, dashboard, metrics, schedule, settings, telemetry, threads};
 
 const SSID: &str = env!("WIFI_SSID");
 const PASSWORD: &str = env!("WIFI_PASS");
 
-// Max payload length for POST requests
-const MAX_LEN: usize = 128;
+// Max payload length for POST requests, enough for a schedule with a few rules
+pub(crate) const MAX_LEN: usize = 2048;
 
 const STACK_SIZE_KB: usize = 10;
 const STACK_SIZE: usize = STACK_SIZE_KB * 1024;
 
 #[derive(Serialize)]
-struct FanStatus {
+pub(crate) struct FanStatus {
+    /// Same as `fans[0]`, for clients from before there could be more than one fan
     pwm_percent: u32,
     fan_rpm: u32,
+    fans: Vec<FanChannelStatus>,
+    temperature_c: Option<f32>,
     uptime_secs: u64,
+    /// Who changed the fans last, see `GET /control`
+    changed_via: &'static str,
+    /// Profile the fans were last switched to, if nothing changed them since
+    active_profile: Option<String>,
+    /// Supervised tasks that aren't running, see `GET /tasks`
+    failed_tasks: Vec<String>,
 }
 
+#[derive(Serialize)]
+struct FanChannelStatus {
+    fan: usize,
+    pwm_percent: u32,
+    /// `pwm_percent` after slew limiting, spin-up kick and minimum duty
+    applied_pwm_percent: u32,
+    /// Filtered
+    rpm: u32,
+    raw_rpm: u32,
+    /// Tacho readings thrown away as glitches since boot
+    rejected_rpm_readings: u32,
+    pulses_per_rev: u32,
+    mode: &'static str,
+    target_rpm: u32,
+    /// Empty if the fan is fine, see [`fan_control_logic::fault::Alarm::name`]
+    alarms: Vec<&'static str>,
+    /// Until the fan goes back to `pwm_percent`, see `GET /boost`
+    boost_remaining_ms: Option<u64>,
+    /// Stopped on purpose at low duty, see `GET /zerorpm`
+    zero_rpm_stopped: bool,
+}
+
+/// Commands apply to every fan unless `fan` is given
 #[derive(Deserialize)]
 struct PwmCommand {
+    #[serde(default)]
+    fan: Option<usize>,
     percent: u32,
 }
 
+#[derive(Deserialize)]
+struct RpmCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    rpm: u32,
+}
+
+#[derive(Deserialize)]
+struct CurveCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    #[serde(flatten)]
+    curve: CurveConfig,
+}
+
+#[derive(Deserialize)]
+struct TachoCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    pulses_per_rev: u32,
+}
+
+/// Rates left out keep their current value
+#[derive(Deserialize)]
+struct SlewCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    #[serde(default)]
+    rise_per_sec: Option<f32>,
+    #[serde(default)]
+    fall_per_sec: Option<f32>,
+}
+
+impl SlewCommand {
+    fn merge(&self, current: SlewConfig) -> SlewConfig {
+        SlewConfig {
+            rise_per_sec: self.rise_per_sec.unwrap_or(current.rise_per_sec),
+            fall_per_sec: self.fall_per_sec.unwrap_or(current.fall_per_sec),
+        }
+    }
+}
+
+/// Fields left out keep their current value, so changing the kick doesn't undo
+/// a calibration
+#[derive(Deserialize)]
+struct SpinUpCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    #[serde(default)]
+    min_duty: Option<u32>,
+    #[serde(default)]
+    start_duty: Option<u32>,
+    #[serde(default)]
+    kick_duty: Option<u32>,
+    #[serde(default)]
+    kick_ms: Option<u64>,
+}
+
+impl SpinUpCommand {
+    fn merge(&self, current: SpinUpConfig) -> SpinUpConfig {
+        SpinUpConfig {
+            min_duty: self.min_duty.unwrap_or(current.min_duty),
+            start_duty: self.start_duty.unwrap_or(current.start_duty),
+            kick_duty: self.kick_duty.unwrap_or(current.kick_duty),
+            kick_ms: self.kick_ms.unwrap_or(current.kick_ms),
+        }
+    }
+}
+
+/// Fields left out keep their current value, so `{"enabled": true}` keeps the
+/// thresholds
+#[derive(Deserialize)]
+struct ZeroRpmCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    stop_below: Option<u32>,
+    #[serde(default)]
+    start_at: Option<u32>,
+    #[serde(default)]
+    min_off_ms: Option<u64>,
+    #[serde(default)]
+    min_on_ms: Option<u64>,
+}
+
+impl ZeroRpmCommand {
+    fn merge(&self, current: ZeroRpmConfig) -> ZeroRpmConfig {
+        ZeroRpmConfig {
+            enabled: self.enabled.unwrap_or(current.enabled),
+            stop_below: self.stop_below.unwrap_or(current.stop_below),
+            start_at: self.start_at.unwrap_or(current.start_at),
+            min_off_ms: self.min_off_ms.unwrap_or(current.min_off_ms),
+            min_on_ms: self.min_on_ms.unwrap_or(current.min_on_ms),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CalibrateCommand {
+    #[serde(default)]
+    fan: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct BootCommand {
+    boot_duty: BootDuty,
+}
+
+/// Fields left out keep their current value
+#[derive(Deserialize)]
+struct KnobCommand {
+    #[serde(default)]
+    counts_per_detent: Option<u32>,
+    #[serde(default)]
+    slow_step: Option<u32>,
+    #[serde(default)]
+    fast_step: Option<u32>,
+    #[serde(default)]
+    slow_detents_per_sec: Option<f32>,
+    #[serde(default)]
+    fast_detents_per_sec: Option<f32>,
+}
+
+impl KnobCommand {
+    fn merge(&self, current: KnobConfig) -> KnobConfig {
+        KnobConfig {
+            counts_per_detent: self.counts_per_detent.unwrap_or(current.counts_per_detent),
+            slow_step: self.slow_step.unwrap_or(current.slow_step),
+            fast_step: self.fast_step.unwrap_or(current.fast_step),
+            slow_detents_per_sec: self
+                .slow_detents_per_sec
+                .unwrap_or(current.slow_detents_per_sec),
+            fast_detents_per_sec: self
+                .fast_detents_per_sec
+                .unwrap_or(current.fast_detents_per_sec),
+        }
+    }
+}
+
+/// Holds left out keep their current value, so `{"release": true}` only releases
+#[derive(Deserialize)]
+struct ControlCommand {
+    #[serde(default)]
+    manual_hold_ms: Option<u64>,
+    #[serde(default)]
+    knob_lock_ms: Option<u64>,
+    /// Ends a manual override and knob lock straight away
+    #[serde(default)]
+    release: bool,
+}
+
+impl ControlCommand {
+    fn merge(&self, current: ControlPolicy) -> ControlPolicy {
+        ControlPolicy {
+            manual_hold_ms: self.manual_hold_ms.unwrap_or(current.manual_hold_ms),
+            knob_lock_ms: self.knob_lock_ms.unwrap_or(current.knob_lock_ms),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ControlStatus {
+    last_change: ControlChange,
+    now_ms: u64,
+    policy: ControlPolicy,
+    /// Until automation may take over again
+    manual_hold_remaining_ms: Option<u64>,
+    /// Until the knob works again
+    knob_lock_remaining_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BoostCommand {
+    #[serde(flatten)]
+    config: BoostConfig,
+    /// Ends every boost straight away instead of starting one
+    #[serde(default)]
+    cancel: bool,
+}
+
+#[derive(Serialize)]
+struct BoostStatus {
+    now_ms: u64,
+    /// Oldest first, the newest one for a fan is in charge of it
+    boosts: Vec<Boost>,
+}
+
+#[derive(Serialize)]
+struct ScheduleStatus {
+    #[serde(flatten)]
+    config: ScheduleConfig,
+    /// The schedule does nothing until the clock has been set over NTP
+    clock_synced: bool,
+    local_time: Option<String>,
+    /// What the schedule wants right now
+    active: ScheduleState,
+}
+
+#[derive(Deserialize)]
+struct ProfileCommand {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct TemperatureCommand {
+    celsius: f32,
+}
+
 pub fn spawn_wifi_control_thread(
     state: Arc<InterfaceState>,
     modem: esp_idf_hal::modem::Modem,
+    nvs: EspDefaultNvsPartition,
 ) -> JoinHandle<()> {
     threads::EspThread::new("wifi_control")
         .with_stack_size(STACK_SIZE_KB)
         .spawn(move || {
-            if let Err(e) = wifi_control_thread(state, modem) {
+            if let Err(e) = wifi_control_thread(state, modem, nvs) {
                 error!("WiFi control thread failed: {:?}", e);
             }
         })
@@ -56,21 +319,24 @@ pub fn spawn_wifi_control_thread(
 fn wifi_control_thread(
     state: Arc<InterfaceState>,
     modem: esp_idf_hal::modem::Modem,
+    nvs: EspDefaultNvsPartition,
 ) -> anyhow::Result<()> {
-    let start_time = SystemTime::now();
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), Some(nvs))?, sys_loop)?;
 
     connect_wifi(&mut wifi)?;
+    publish_wifi_info(&state, &wifi);
 
     let mut server = create_server()?;
 
-    // GET / - Returns current status
+    // GET / - Returns current status, or the dashboard to a browser
     let state_clone = state.clone();
     server.fn_handler("/", Method::Get, move |req| {
-        let status = create_fan_status(&state_clone, &start_time);
+        if dashboard::wants_html(&req) {
+            return dashboard::serve_index(req);
+        }
+        let status = create_fan_status(&state_clone);
 
         let json = serde_json::to_string(&status)?;
         let mut resp = req.into_ok_response()?;
@@ -80,38 +346,415 @@ fn wifi_control_thread(
 
     // POST /pwm - Sets PWM and returns status
     let state_clone = state.clone();
-    server.fn_handler("/pwm", Method::Post, move |mut req| {
-        let len = req.content_len().unwrap_or(0) as usize;
-        if len > MAX_LEN {
-            req.into_status_response(413)?
-                .write_all("Request too big".as_bytes())?;
-            return Result::<(), anyhow::Error>::Ok(());
-        }
+    server.fn_handler("/pwm", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: PwmCommand| {
+            state_clone.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+            for fan in selected_fans(&state_clone, cmd.fan)? {
+                fan.control_mode
+                    .store(ControlMode::Manual, Ordering::Relaxed);
+                fan.pwm.store(cmd.percent.min(100), Ordering::Relaxed);
+            }
+            Ok(())
+        })
+    })?;
 
-        let mut buf = vec![0; len];
-        req.read_exact(&mut buf)?;
+    // POST /rpm - Holds the given RPM using the PID controller and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/rpm", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: RpmCommand| {
+            state_clone.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+            for fan in selected_fans(&state_clone, cmd.fan)? {
+                fan.target_rpm.store(cmd.rpm, Ordering::Relaxed);
+                fan.control_mode
+                    .store(ControlMode::TargetRpm, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+    })?;
 
-        match serde_json::from_slice::<PwmCommand>(&buf) {
-            Ok(cmd) => {
-                // Update PWM
-                state_clone
-                    .fan_pwm
-                    .store(cmd.percent.min(100), std::sync::atomic::Ordering::Relaxed);
+    // GET /curve - Returns the fan curve of every fan
+    let state_clone = state.clone();
+    server.fn_handler("/curve", Method::Get, move |req| {
+        let curves: Vec<CurveConfig> = state_clone
+            .fans
+            .iter()
+            .map(|fan| fan.curve.lock().unwrap().clone())
+            .collect();
+        let json = serde_json::to_string(&curves)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
 
-                let status = create_fan_status(&state_clone, &start_time);
+    // POST /curve - Sets the fan curve, switches to following it and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/curve", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: CurveCommand| {
+            cmd.curve.to_curve()?;
+            state_clone.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+            for fan in selected_fans(&state_clone, cmd.fan)? {
+                *fan.curve.lock().unwrap() = cmd.curve.clone();
+                fan.control_mode
+                    .store(ControlMode::Curve, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+    })?;
 
-                let json = serde_json::to_string(&status)?;
-                let mut resp = req.into_ok_response()?;
-                resp.write_all(json.as_bytes())?;
+    // POST /tacho - Sets the tacho pulses per revolution and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/tacho", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: TachoCommand| {
+            if !(1..=8).contains(&cmd.pulses_per_rev) {
+                anyhow::bail!("Pulses per revolution must be 1-8");
             }
-            Err(e) => {
-                req.into_status_response(400)?
-                    .write_all(format!("Invalid JSON: {}", e).as_bytes())?;
+            for fan in selected_fans(&state_clone, cmd.fan)? {
+                fan.pulses_per_rev
+                    .store(cmd.pulses_per_rev, Ordering::Relaxed);
             }
-        }
-        Ok(())
+            Ok(())
+        })
     })?;
 
+    // GET /slew - Returns how fast the applied duty follows the requested one, for every fan
+    let state_clone = state.clone();
+    server.fn_handler("/slew", Method::Get, move |req| {
+        let configs: Vec<SlewConfig> = state_clone
+            .fans
+            .iter()
+            .map(|fan| *fan.slew.lock().unwrap())
+            .collect();
+        let json = serde_json::to_string(&configs)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /slew - Sets the rise and fall rates in %/s and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/slew", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: SlewCommand| {
+            let fans = selected_fans(&state_clone, cmd.fan)?;
+            for fan in fans {
+                cmd.merge(*fan.slew.lock().unwrap()).validate()?;
+            }
+            for fan in fans {
+                let mut slew = fan.slew.lock().unwrap();
+                *slew = cmd.merge(*slew);
+            }
+            Ok(())
+        })
+    })?;
+
+    // GET /spinup - Returns the spin-up kick and minimum duty of every fan
+    let state_clone = state.clone();
+    server.fn_handler("/spinup", Method::Get, move |req| {
+        let configs: Vec<SpinUpConfig> = state_clone
+            .fans
+            .iter()
+            .map(|fan| *fan.spin_up.lock().unwrap())
+            .collect();
+        let json = serde_json::to_string(&configs)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /spinup - Sets the spin-up kick and minimum duty and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/spinup", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: SpinUpCommand| {
+            let fans = selected_fans(&state_clone, cmd.fan)?;
+            for fan in fans {
+                cmd.merge(*fan.spin_up.lock().unwrap()).validate()?;
+            }
+            for fan in fans {
+                let mut spin_up = fan.spin_up.lock().unwrap();
+                *spin_up = cmd.merge(*spin_up);
+            }
+            Ok(())
+        })
+    })?;
+
+    // GET /zerorpm - Returns when every fan stops at low duty
+    let state_clone = state.clone();
+    server.fn_handler("/zerorpm", Method::Get, move |req| {
+        let configs: Vec<ZeroRpmConfig> = state_clone
+            .fans
+            .iter()
+            .map(|fan| *fan.zero_rpm.lock().unwrap())
+            .collect();
+        let json = serde_json::to_string(&configs)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /zerorpm - Sets the zero-RPM thresholds and minimum on/off times and returns
+    // status
+    let state_clone = state.clone();
+    server.fn_handler("/zerorpm", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: ZeroRpmCommand| {
+            let fans = selected_fans(&state_clone, cmd.fan)?;
+            for fan in fans {
+                cmd.merge(*fan.zero_rpm.lock().unwrap()).validate()?;
+            }
+            for fan in fans {
+                let mut zero_rpm = fan.zero_rpm.lock().unwrap();
+                *zero_rpm = cmd.merge(*zero_rpm);
+            }
+            Ok(())
+        })
+    })?;
+
+    // GET /calibration - Returns the last calibration of every fan, null if never calibrated
+    let state_clone = state.clone();
+    server.fn_handler("/calibration", Method::Get, move |req| {
+        let calibrations: Vec<Option<FanCalibration>> = state_clone
+            .fans
+            .iter()
+            .map(|fan| fan.calibration.lock().unwrap().clone())
+            .collect();
+        let json = serde_json::to_string(&calibrations)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /calibrate - Starts a calibration sweep and returns status, takes a few minutes
+    let state_clone = state.clone();
+    server.fn_handler("/calibrate", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: CalibrateCommand| {
+            state_clone.take_control(ControlSource::Wifi, threads::uptime_ms())?;
+            for fan in selected_fans(&state_clone, cmd.fan)? {
+                fan.control_mode
+                    .store(ControlMode::Calibration, Ordering::Relaxed);
+            }
+            Ok(())
+        })
+    })?;
+
+    // GET /settings - Returns the settings that are kept across reboots
+    let state_clone = state.clone();
+    server.fn_handler("/settings", Method::Get, move |req| {
+        let snapshot = settings::snapshot(&state_clone, &Default::default());
+        let json = serde_json::to_string(&snapshot.redacted())?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /boot - Sets the duty to start at after a reboot and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/boot", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: BootCommand| {
+            if let BootDuty::Fixed(duty) = cmd.boot_duty {
+                if duty > 100 {
+                    anyhow::bail!("Boot duty must be 0-100");
+                }
+            }
+            *state_clone.boot_duty.lock().unwrap() = cmd.boot_duty;
+            Ok(())
+        })
+    })?;
+
+    // GET /control - Returns who changed the fans last and who may change them now
+    let state_clone = state.clone();
+    server.fn_handler("/control", Method::Get, move |req| {
+        let now_ms = threads::uptime_ms();
+        let control = state_clone.control.lock().unwrap();
+        let status = ControlStatus {
+            last_change: control.last_change(),
+            now_ms,
+            policy: control.policy(),
+            manual_hold_remaining_ms: control.manual_hold_remaining(now_ms),
+            knob_lock_remaining_ms: control.knob_lock_remaining(now_ms),
+        };
+        drop(control);
+        let json = serde_json::to_string(&status)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /control - Sets how long manual changes hold off automation and lock the knob,
+    // and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/control", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: ControlCommand| {
+            let mut control = state_clone.control.lock().unwrap();
+            let policy = cmd.merge(control.policy());
+            control.set_policy(policy);
+            if cmd.release {
+                control.release();
+            }
+            Ok(())
+        })
+    })?;
+
+    // GET /knob - Returns how the rotary knob changes the duty
+    let state_clone = state.clone();
+    server.fn_handler("/knob", Method::Get, move |req| {
+        let json = serde_json::to_string(&*state_clone.knob.lock().unwrap())?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /knob - Sets the counts per detent and knob acceleration and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/knob", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: KnobCommand| {
+            let mut knob = state_clone.knob.lock().unwrap();
+            let config = cmd.merge(*knob);
+            config.validate()?;
+            *knob = config;
+            Ok(())
+        })
+    })?;
+
+    // GET /profiles - Returns every profile and the active one
+    let state_clone = state.clone();
+    server.fn_handler("/profiles", Method::Get, move |req| {
+        let json = serde_json::to_string(&*state_clone.profiles.lock().unwrap())?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /profiles - Adds a profile or replaces the one with the same name, and returns
+    // status
+    let state_clone = state.clone();
+    server.fn_handler("/profiles", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: Profile| {
+            state_clone.profiles.lock().unwrap().insert(cmd)?;
+            Ok(())
+        })
+    })?;
+
+    // DELETE /profiles - Removes the profile called `name` and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/profiles", Method::Delete, move |req| {
+        handle_command(req, &state_clone, |cmd: ProfileCommand| {
+            state_clone.profiles.lock().unwrap().remove(&cmd.name)?;
+            Ok(())
+        })
+    })?;
+
+    // POST /profile - Switches every fan to the profile called `name` and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/profile", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: ProfileCommand| {
+            state_clone.apply_profile(&cmd.name, ControlSource::Wifi, threads::uptime_ms())?;
+            Ok(())
+        })
+    })?;
+
+    // GET /schedule - Returns the schedule, the local time and what applies right now
+    let state_clone = state.clone();
+    server.fn_handler("/schedule", Method::Get, move |req| {
+        let config = state_clone.schedule.lock().unwrap().clone();
+        let status = ScheduleStatus {
+            clock_synced: state_clone.clock_synced.load(Ordering::Relaxed),
+            local_time: schedule::local_time(&state_clone, &config).map(|now| now.to_string()),
+            active: state_clone.scheduled.lock().unwrap().clone(),
+            config,
+        };
+        let json = serde_json::to_string(&status)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /schedule - Sets the NTP server, time zone and schedule rules and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/schedule", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: ScheduleConfig| {
+            cmd.validate()?;
+            *state_clone.schedule.lock().unwrap() = cmd;
+            Ok(())
+        })
+    })?;
+
+    // GET /boost - Returns the running boosts
+    let state_clone = state.clone();
+    server.fn_handler("/boost", Method::Get, move |req| {
+        let now_ms = threads::uptime_ms();
+        let status = BoostStatus {
+            now_ms,
+            boosts: state_clone
+                .boost
+                .lock()
+                .unwrap()
+                .running(now_ms)
+                .copied()
+                .collect(),
+        };
+        let json = serde_json::to_string(&status)?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /boost - Runs the fans at `duty` for `duration_ms`, then goes back to what they
+    // were doing, and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/boost", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: BoostCommand| {
+            let now_ms = threads::uptime_ms();
+            if cmd.cancel {
+                state_clone.take_control(ControlSource::Wifi, now_ms)?;
+                state_clone.boost.lock().unwrap().clear();
+                return Ok(());
+            }
+            selected_fans(&state_clone, cmd.config.fan)?;
+            cmd.config.validate()?;
+            state_clone.take_control(ControlSource::Wifi, now_ms)?;
+            state_clone
+                .boost
+                .lock()
+                .unwrap()
+                .start(cmd.config, now_ms)?;
+            Ok(())
+        })
+    })?;
+
+    // GET /tasks - Returns the health of the supervised fan control tasks
+    let state_clone = state.clone();
+    server.fn_handler("/tasks", Method::Get, move |req| {
+        let json = serde_json::to_string(&*state_clone.tasks.lock().unwrap())?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // GET /sensors - Returns the latest reading of every temperature sensor
+    let state_clone = state.clone();
+    server.fn_handler("/sensors", Method::Get, move |req| {
+        let json = serde_json::to_string(&state_clone.sensors.snapshot())?;
+        let mut resp = req.into_ok_response()?;
+        resp.write_all(json.as_bytes())?;
+        Result::<(), anyhow::Error>::Ok(())
+    })?;
+
+    // POST /temperature - Feeds the fan curve from an external sensor and returns status
+    let state_clone = state.clone();
+    server.fn_handler("/temperature", Method::Post, move |req| {
+        handle_command(req, &state_clone, |cmd: TemperatureCommand| {
+            if !cmd.celsius.is_finite() {
+                anyhow::bail!("Temperature must be a number");
+            }
+            state_clone.publish_temperature("external", Some(cmd.celsius));
+            Ok(())
+        })
+    })?;
+
+    dashboard::register(&mut server)?;
+    metrics::register(&mut server, &state)?;
+    // Before the API, it would answer /api/v2/ws with a 404
+    telemetry::register(&mut server, &state)?;
+    api::register(&mut server, &state)?;
+
     loop {
         if !wifi.is_connected()? {
             error!("WiFi connection lost, attempting to reconnect...");
@@ -129,20 +772,132 @@ fn wifi_control_thread(
                 }
             }
         }
+        publish_wifi_info(&state, &wifi);
         std::thread::sleep(std::time::Duration::from_secs(5));
     }
 }
 
-fn create_fan_status(state: &InterfaceState, start_time: &SystemTime) -> FanStatus {
-    let uptime = SystemTime::now()
-        .duration_since(*start_time)
-        .unwrap_or_default()
-        .as_secs();
+/// Keeps the WiFi page of the menu up to date
+fn publish_wifi_info(state: &InterfaceState, wifi: &BlockingWifi<EspWifi<'static>>) {
+    let connected = wifi.is_connected().unwrap_or(false);
+    let ip = connected
+        .then(|| wifi.wifi().sta_netif().get_ip_info().ok())
+        .flatten()
+        .map(|ip_info| ip_info.ip.to_string());
+    let rssi = connected.then(rssi).flatten();
+    *state.wifi.lock().unwrap() = WifiInfo {
+        ssid: SSID.to_string(),
+        ip,
+        rssi,
+    };
+}
+
+/// Signal strength of the access point in dBm
+fn rssi() -> Option<i8> {
+    let mut ap_info = esp_idf_svc::sys::wifi_ap_record_t::default();
+    // Fails while not connected, which is all that can go wrong
+    esp_idf_svc::sys::esp!(unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut ap_info) })
+        .ok()?;
+    Some(ap_info.rssi)
+}
+
+/// Parses the request body as a `C`, applies it and responds with the new status
+fn handle_command<C: DeserializeOwned>(
+    mut req: Request<&mut EspHttpConnection>,
+    state: &InterfaceState,
+    apply: impl FnOnce(C) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > MAX_LEN {
+        req.into_status_response(413)?
+            .write_all("Request too big".as_bytes())?;
+        return Ok(());
+    }
+
+    let mut buf = vec![0; len];
+    req.read_exact(&mut buf)?;
+
+    match serde_json::from_slice::<C>(&buf) {
+        Ok(cmd) => {
+            if let Err(e) = apply(cmd) {
+                req.into_status_response(400)?
+                    .write_all(format!("Invalid command: {}", e).as_bytes())?;
+                return Ok(());
+            }
+
+            let status = create_fan_status(state);
+
+            let json = serde_json::to_string(&status)?;
+            let mut resp = req.into_ok_response()?;
+            resp.write_all(json.as_bytes())?;
+        }
+        Err(e) => {
+            req.into_status_response(400)?
+                .write_all(format!("Invalid JSON: {}", e).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn create_fan_status(state: &InterfaceState) -> FanStatus {
+    let now_ms = threads::uptime_ms();
+    let boost = state.boost.lock().unwrap();
+    let fans: Vec<FanChannelStatus> = state
+        .fans
+        .iter()
+        .enumerate()
+        .map(|(i, fan)| FanChannelStatus {
+            fan: i,
+            pwm_percent: fan.pwm.load(Ordering::Relaxed),
+            applied_pwm_percent: fan.applied_pwm.load(Ordering::Relaxed),
+            rpm: fan.rpm.load(Ordering::Relaxed),
+            raw_rpm: fan.raw_rpm.load(Ordering::Relaxed),
+            rejected_rpm_readings: fan.rejected_rpm_readings.load(Ordering::Relaxed),
+            pulses_per_rev: fan.pulses_per_rev.load(Ordering::Relaxed),
+            mode: fan.control_mode.load(Ordering::Relaxed).name(),
+            target_rpm: fan.target_rpm.load(Ordering::Relaxed),
+            alarms: fan
+                .alarms
+                .load(Ordering::Relaxed)
+                .iter()
+                .map(|alarm| alarm.name())
+                .collect(),
+            boost_remaining_ms: boost
+                .active(i, now_ms)
+                .map(|boost| boost.remaining_ms(now_ms)),
+            zero_rpm_stopped: fan.zero_rpm_stopped.load(Ordering::Relaxed),
+        })
+        .collect();
+    drop(boost);
 
     FanStatus {
-        pwm_percent: state.fan_pwm.load(std::sync::atomic::Ordering::Relaxed),
-        fan_rpm: state.fan_rpm.load(std::sync::atomic::Ordering::Relaxed),
-        uptime_secs: uptime,
+        pwm_percent: fans.first().map_or(0, |fan| fan.pwm_percent),
+        fan_rpm: fans.first().map_or(0, |fan| fan.rpm),
+        fans,
+        temperature_c: state.temperature.load(Ordering::Relaxed),
+        uptime_secs: now_ms / 1000,
+        changed_via: state.control.lock().unwrap().last_change().source.name(),
+        active_profile: state.profiles.lock().unwrap().active_profile.clone(),
+        failed_tasks: state
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|task| task.status != TaskStatus::Running)
+            .map(|task| task.name.clone())
+            .collect(),
+    }
+}
+
+/// The fan a command is for, or every fan if it doesn't say
+pub(crate) fn selected_fans(
+    state: &InterfaceState,
+    fan: Option<usize>,
+) -> anyhow::Result<&[FanState]> {
+    match fan {
+        None => Ok(&state.fans),
+        Some(fan) if fan < state.fans.len() => Ok(&state.fans[fan..=fan]),
+        Some(fan) => anyhow::bail!("There is no fan {fan}, fans are 0-{}", state.fans.len() - 1),
     }
 }
 
@@ -173,6 +928,10 @@ fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> anyhow::Result<()>
 fn create_server() -> anyhow::Result<EspHttpServer<'static>> {
     let server_configuration = esp_idf_svc::http::server::Configuration {
         stack_size: STACK_SIZE,
+        // Both APIs need more than the default of 32
+        max_uri_handlers: 64,
+        // For /api/v2 resources like /api/v2/curves/0
+        uri_match_wildcard: true,
         ..Default::default()
     };
 
@@ -0,0 +1,365 @@
+"use strict";
+
+// Live view and controls on top of /api/v2, see the README for the endpoints
+
+const HISTORY_MS = 5 * 60 * 1000;
+const POLL_MS = 2000;
+const RECONNECT_MS = 5000;
+// Keep telemetry from moving the slider while someone is using it
+const SLIDER_HOLD_MS = 3000;
+const COLORS = ["#4c9aff", "#f5a524", "#3fa66a", "#c264fe"];
+// Per-fan settings in the form, as paths into `fans[i]` of the config
+const FAN_FIELDS = [
+  "max_duty",
+  "spin_up.min_duty",
+  "slew.rise_per_sec",
+  "slew.fall_per_sec",
+  "pulses_per_rev",
+  "zero_rpm.enabled",
+  "zero_rpm.stop_below",
+  "zero_rpm.start_at",
+];
+const DEVICE_FIELDS = ["display.animation", "display.flipped", "telemetry.min_interval_ms"];
+
+const $ = (id) => document.getElementById(id);
+
+let fanCount = 0;
+// Per fan, `{t, rpm, duty}` oldest first
+let history = [];
+let sliderTouchedAt = 0;
+let socket = null;
+let pollTimer = null;
+// Settings as loaded, with the form's changes to fans other than the one shown
+let config = null;
+// As last loaded or saved, to tell what the form changed
+let savedConfig = null;
+let settingsFan = 0;
+
+async function api(method, path, body) {
+  const options = { method, headers: {} };
+  if (body !== undefined) {
+    options.headers["Content-Type"] = "application/json";
+    options.body = JSON.stringify(body);
+  }
+  const response = await fetch("/api/v2" + path, options);
+  const json = await response.json();
+  if (!response.ok) {
+    throw new Error(json.error ? json.error.message : response.statusText);
+  }
+  return json;
+}
+
+function showError(error) {
+  const box = $("error");
+  box.textContent = error ? String(error.message || error) : "";
+  box.hidden = !error;
+}
+
+// Runs a command and shows what went wrong, if anything
+async function run(action) {
+  try {
+    await action();
+    showError(null);
+  } catch (error) {
+    showError(error);
+  }
+}
+
+// Live data
+
+function connect() {
+  const url = (location.protocol === "https:" ? "wss://" : "ws://") + location.host + "/api/v2/ws";
+  socket = new WebSocket(url);
+  socket.onopen = () => {
+    stopPolling();
+    setConnection("Live", true);
+  };
+  socket.onmessage = (event) => {
+    const message = JSON.parse(event.data);
+    if (message.type === "telemetry") {
+      update(message);
+    } else if (message.type === "error") {
+      showError(message.error.message);
+    }
+  };
+  socket.onclose = () => {
+    socket = null;
+    startPolling();
+    setTimeout(connect, RECONNECT_MS);
+  };
+}
+
+function startPolling() {
+  if (pollTimer) {
+    return;
+  }
+  setConnection("Polling", false);
+  const poll = () => api("GET", "/status").then(update, () => setConnection("Offline", false));
+  poll();
+  pollTimer = setInterval(poll, POLL_MS);
+}
+
+function stopPolling() {
+  clearInterval(pollTimer);
+  pollTimer = null;
+}
+
+function setConnection(text, live) {
+  const badge = $("connection");
+  badge.textContent = text;
+  badge.classList.toggle("live", live);
+}
+
+// Takes telemetry from the socket or a status from `GET /api/v2/status`
+function update(status) {
+  if (status.fans.length !== fanCount) {
+    setFanCount(status.fans.length);
+  }
+
+  const now = Date.now();
+  status.fans.forEach((fan, i) => {
+    history[i].push({ t: now, rpm: fan.rpm, duty: fan.applied_pwm_percent });
+    while (history[i].length > 1 && history[i][1].t < now - HISTORY_MS) {
+      history[i].shift();
+    }
+  });
+
+  $("fans").replaceChildren(...status.fans.map(fanCard));
+  $("temperature").textContent = status.temperature_c == null ? "--" : status.temperature_c.toFixed(1) + " °C";
+  $("changed-via").textContent = status.changed_via;
+  $("active-profile").textContent = status.active_profile || "--";
+  if (status.active_profile) {
+    $("profile").value = status.active_profile;
+  }
+
+  if (now - sliderTouchedAt > SLIDER_HOLD_MS) {
+    const fan = status.fans[Number($("control-fan").value) || 0];
+    $("duty").value = fan.pwm_percent;
+    $("duty-value").textContent = fan.pwm_percent;
+  }
+}
+
+function fanCard(fan) {
+  const card = document.createElement("div");
+  card.className = "fan";
+  const boosted = fan.boosted || fan.boost_remaining_ms != null;
+  let state = fan.mode.replace("_", " ");
+  if (boosted) {
+    state += " · boost";
+  }
+  if (fan.zero_rpm_stopped) {
+    state = "Stopped (zero-RPM)";
+  }
+  const duty = fan.applied_pwm_percent === fan.pwm_percent
+    ? fan.pwm_percent + "%"
+    : fan.applied_pwm_percent + "% → " + fan.pwm_percent + "%";
+  card.innerHTML = `
+    <div class="status">Fan ${fan.fan + 1}</div>
+    <div class="rpm">${fan.rpm}</div>
+    <div class="status">RPM · ${duty}</div>
+    <div class="status">${state}</div>
+    <div class="alarm">${fan.alarms.join(", ")}</div>`;
+  return card;
+}
+
+function setFanCount(count) {
+  fanCount = count;
+  history = Array.from({ length: count }, () => []);
+  const options = (all) => [
+    ...(all ? [new Option("All fans", "")] : []),
+    ...Array.from({ length: count }, (_, i) => new Option("Fan " + (i + 1), i)),
+  ];
+  $("control-fan").replaceChildren(...options(count > 1));
+  $("settings-fan").replaceChildren(...options(false));
+  $("legend").innerHTML = Array.from(
+    { length: count },
+    (_, i) => `<span style="color:${COLORS[i % COLORS.length]}">■ Fan ${i + 1}</span>`,
+  ).join("");
+}
+
+// Charts
+
+function drawChart(canvas, key, maxValue) {
+  const width = (canvas.width = canvas.clientWidth * devicePixelRatio);
+  const height = (canvas.height = canvas.clientHeight * devicePixelRatio);
+  const context = canvas.getContext("2d");
+  context.clearRect(0, 0, width, height);
+
+  const now = Date.now();
+  const max = Math.max(maxValue, ...history.flat().map((point) => point[key])) || 1;
+  const x = (t) => ((t - (now - HISTORY_MS)) / HISTORY_MS) * width;
+  const y = (value) => height - 4 - (value / max) * (height - 8);
+
+  context.fillStyle = "#8a96a6";
+  context.font = 11 * devicePixelRatio + "px system-ui";
+  context.fillText(String(Math.round(max)), 4, 12 * devicePixelRatio);
+
+  history.forEach((points, i) => {
+    if (points.length === 0) {
+      return;
+    }
+    context.strokeStyle = COLORS[i % COLORS.length];
+    context.lineWidth = 2;
+    context.beginPath();
+    // Steps, telemetry only comes when something changed
+    points.forEach((point, j) => {
+      if (j === 0) {
+        context.moveTo(x(point.t), y(point[key]));
+      } else {
+        context.lineTo(x(point.t), y(points[j - 1][key]));
+        context.lineTo(x(point.t), y(point[key]));
+      }
+    });
+    context.lineTo(x(now), y(points[points.length - 1][key]));
+    context.stroke();
+  });
+}
+
+function drawCharts() {
+  drawChart($("rpm-chart"), "rpm", 500);
+  drawChart($("duty-chart"), "duty", 100);
+}
+
+// Controls
+
+function selectedFan() {
+  const value = $("control-fan").value;
+  return value === "" ? {} : { fan: Number(value) };
+}
+
+async function loadProfiles() {
+  const profiles = await api("GET", "/profiles");
+  $("profile").replaceChildren(
+    new Option("--", ""),
+    ...profiles.profiles.map((profile) => new Option(profile.name, profile.name)),
+  );
+  $("profile").value = profiles.active_profile || "";
+}
+
+function setupControls() {
+  $("duty").addEventListener("input", () => {
+    sliderTouchedAt = Date.now();
+    $("duty-value").textContent = $("duty").value;
+  });
+  $("duty").addEventListener("change", () => {
+    sliderTouchedAt = Date.now();
+    run(() => api("POST", "/pwm", { ...selectedFan(), percent: Number($("duty").value) }));
+  });
+  $("follow-curve").addEventListener("click", () =>
+    run(() => api("POST", "/mode", { ...selectedFan(), mode: "curve" })),
+  );
+  $("profile").addEventListener("change", () => {
+    if ($("profile").value) {
+      run(() => api("PUT", "/active_profile", { name: $("profile").value }));
+    }
+  });
+  // Same as the boost in the menu, and cancelling it like a click of the knob
+  $("boost").addEventListener("click", () =>
+    run(() => post("/boost", { ...selectedFan(), duty: 100, duration_ms: 10 * 60 * 1000 })),
+  );
+  $("cancel-boost").addEventListener("click", () => run(() => post("/boost", { cancel: true })));
+}
+
+// For endpoints that are only in the first API, which answers errors as text
+async function post(path, body) {
+  const response = await fetch(path, { method: "POST", body: JSON.stringify(body) });
+  if (!response.ok) {
+    throw new Error(await response.text());
+  }
+}
+
+// Settings
+
+function getPath(object, path) {
+  return path.split(".").reduce((value, key) => value[key], object);
+}
+
+function setPath(object, path, value) {
+  const keys = path.split(".");
+  const last = keys.pop();
+  keys.reduce((value, key) => (value[key] ??= {}), object)[last] = value;
+}
+
+// Merge patch with the fields at `paths` that differ between the two
+function changes(current, saved, paths) {
+  const patch = {};
+  paths
+    .filter((path) => getPath(current, path) !== getPath(saved, path))
+    .forEach((path) => setPath(patch, path, getPath(current, path)));
+  return patch;
+}
+
+function fillForm() {
+  const form = $("settings");
+  const fill = (source, path) => {
+    const input = form.elements[path];
+    const value = getPath(source, path);
+    if (input.type === "checkbox") {
+      input.checked = value;
+    } else {
+      input.value = value;
+    }
+  };
+  FAN_FIELDS.forEach((path) => fill(config.fans[settingsFan], path));
+  DEVICE_FIELDS.forEach((path) => fill(config, path));
+}
+
+// Puts what's in the form into `config`
+function readForm() {
+  const form = $("settings");
+  const read = (target, path) => {
+    const input = form.elements[path];
+    setPath(target, path, input.type === "checkbox" ? input.checked : Number(input.value));
+  };
+  FAN_FIELDS.forEach((path) => read(config.fans[settingsFan], path));
+  DEVICE_FIELDS.forEach((path) => read(config, path));
+}
+
+async function loadSettings() {
+  config = await api("GET", "/config");
+  savedConfig = structuredClone(config);
+  settingsFan = Math.min(settingsFan, config.fans.length - 1);
+  $("settings-fan").value = settingsFan;
+  fillForm();
+}
+
+async function saveSettings() {
+  readForm();
+  // Only what the form changed, anything else may have changed on the device
+  // since loading, the duty and mode of the fans in particular
+  let result = null;
+  for (const [i, fan] of config.fans.entries()) {
+    const patch = changes(fan, savedConfig.fans[i], FAN_FIELDS);
+    if (Object.keys(patch).length > 0) {
+      result = await api("PATCH", `/config/fans/${i}`, patch);
+    }
+  }
+  const patch = changes(config, savedConfig, DEVICE_FIELDS);
+  if (Object.keys(patch).length > 0 || result === null) {
+    result = await api("PATCH", "/config", patch);
+  }
+  config = result;
+  savedConfig = structuredClone(config);
+  fillForm();
+}
+
+function setupSettings() {
+  $("settings-fan").addEventListener("change", () => {
+    readForm();
+    settingsFan = Number($("settings-fan").value);
+    fillForm();
+  });
+  $("settings").addEventListener("submit", (event) => {
+    event.preventDefault();
+    run(saveSettings);
+  });
+  $("reload-settings").addEventListener("click", () => run(loadSettings));
+}
+
+setupControls();
+setupSettings();
+startPolling();
+connect();
+run(loadProfiles);
+run(loadSettings);
+setInterval(drawCharts, 1000);